@@ -0,0 +1,21 @@
+#! /bin/bash
+
+# generate a certificate revocation list that revokes ../revoked/certificate
+db=$(mktemp -d)
+touch $db/index.txt
+echo 1000 > $db/crlnumber
+cat > $db/ca.cnf <<EOF
+[ ca ]
+default_ca = netidx
+
+[ netidx ]
+database = $db/index.txt
+crlnumber = $db/crlnumber
+certificate = ./certificate
+private_key = ./private.key
+default_md = sha512
+default_crl_days = 7300
+EOF
+openssl ca -config $db/ca.cnf -revoke ../revoked/certificate
+openssl ca -config $db/ca.cnf -gencrl -out crl
+rm -rf $db
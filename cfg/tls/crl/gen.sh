@@ -0,0 +1,17 @@
+#! /bin/bash
+
+# re-issue the client, publisher, and resolver certificates for the
+# crl tests, reusing their private keys and signing requests
+for name in client publisher resolver; do
+  openssl x509 -req -in ../$name/request -CA ../ca/certificate -CAkey ../ca/private.key \
+    -CAcreateserial -out $name/certificate -days 730 -extfile <(cat <<EOF2
+basicConstraints=critical, CA:FALSE
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid:always, issuer:always
+keyUsage=nonRepudiation,digitalSignature,keyEncipherment
+subjectAltName=DNS:$name.example.com
+EOF2
+)
+  # check it
+  openssl verify -trusted ../ca/certificate -CRLfile ../ca/crl -crl_check $name/certificate
+done
@@ -0,0 +1,13 @@
+#! /bin/bash
+
+# generate the private key if it doesn't exist
+if ! test -f private.key; then
+  openssl genrsa -out private.key 4096
+fi
+
+# generate a self signed certificate that isn't trusted by the example.com CA
+openssl req -new -key ./private.key -x509 -sha512 -out certificate -days 730 \
+  -subj "/CN=untrusted.example.com/C=US/ST=State/L=City/O=example" \
+  -addext "basicConstraints=critical, CA:FALSE" \
+  -addext "keyUsage=nonRepudiation,digitalSignature,keyEncipherment" \
+  -addext "subjectAltName=DNS:untrusted.example.com"
@@ -1244,7 +1244,8 @@ fn parse_auth(cfg: &Config, opts: &glib::VariantDict) -> DesiredAuth {
         {
             auth @ (DesiredAuth::Local
             | DesiredAuth::Anonymous
-            | DesiredAuth::Tls { .. }) => auth,
+            | DesiredAuth::Tls { .. }
+            | DesiredAuth::Token { .. }) => auth,
             DesiredAuth::Krb5 { .. } => {
                 match opts.lookup_value("upn", Some(&glib::VariantTy::STRING)) {
                     None => DesiredAuth::Krb5 { upn: None, spn: None },
@@ -249,6 +249,21 @@ impl<T: Poolable + Send + 'static> Pool<T> {
         t
     }
 
+    /// the number of idle objects currently retained by the pool
+    pub fn len(&self) -> usize {
+        self.0.pool.len()
+    }
+
+    /// true if the pool retains no idle objects
+    pub fn is_empty(&self) -> bool {
+        self.0.pool.is_empty()
+    }
+
+    /// the maximum number of idle objects the pool will retain
+    pub fn capacity(&self) -> usize {
+        self.0.pool.capacity()
+    }
+
     /// takes an item from the pool, creating one if none are available.
     pub fn take(&self) -> Pooled<T> {
         let object = self.0.pool.pop().unwrap_or_else(Poolable::empty);
@@ -1,8 +1,8 @@
 use crate::{resolver::UserInfo, value::Value};
 use bytes::Bytes;
-use netidx_core::path::Path;
+use netidx_core::{chars::Chars, path::Path};
 use netidx_derive::Pack;
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, sync::Arc};
 
 atomic_id!(Id);
 
@@ -45,6 +45,15 @@ pub enum Hello {
     /// the server AND the client must have certificates that are
     /// signed by a CA they mutually trust.
     Tls(#[pack(default)] Option<UserInfo>),
+    /// Authenticate using a bearer token issued to the subscriber
+    /// and verified by the resolver server. Like local
+    /// authentication the connection is not encrypted.
+    Token(#[pack(default)] Option<UserInfo>),
+    /// Sent by the resolver server to a default publisher that lists
+    /// on demand before it answers a listing of the path. The
+    /// publisher advertises the children of the path, and replies
+    /// with the same message once the resolver server has them.
+    List(Path),
 }
 
 #[derive(Debug, Clone, PartialEq, Pack)]
@@ -67,6 +76,18 @@ pub enum To {
     Unsubscribe(Id),
     /// Send a write to the specified value.
     Write(Id, bool, Value, #[pack(default)] WriteId),
+    /// A timestamped heartbeat, the publisher will echo the
+    /// timestamp back in a `HeartbeatReply` so the subscriber can
+    /// measure the round trip time.
+    Heartbeat(u64),
+    /// Send several writes as one atomic batch. The publisher will
+    /// deliver them to the write handlers together, or not at all,
+    /// and if a reply is requested it will send one
+    /// `WriteBatchResult` for the whole batch.
+    WriteBatch(Vec<(Id, Value)>, bool, WriteId),
+    /// Sent in reply to `From::Migrate` once the subscriber has moved
+    /// all the durable subscriptions it is going to move.
+    Migrated,
 }
 
 #[derive(Debug, Clone, PartialEq, Pack)]
@@ -84,8 +105,10 @@ pub enum From {
     /// You are now subscribed to Path with subscription id `Id`, and
     /// The next message contains the first value for Id. All further
     /// communications about this subscription will only refer to the
-    /// Id.
-    Subscribed(Path, Id, Value),
+    /// Id. The flag is true if the value is stale, it was restored
+    /// from a snapshot and hasn't been updated since. The next update
+    /// makes it fresh.
+    Subscribed(Path, Id, Value, #[pack(default)] bool),
     /// A value update to Id
     Update(Id, Value),
     /// Indicates that the publisher is idle, but still
@@ -93,4 +116,130 @@ pub enum From {
     Heartbeat,
     /// Indicates the result of a write request
     WriteResult(Id, Value, #[pack(default)] WriteId),
+    /// A partial update to Id, the current value of which must be an
+    /// array. Apply the patch to the current value to get the new
+    /// value.
+    Patch(Id, Arc<Patch>),
+    /// The reply to a timestamped heartbeat from the subscriber
+    HeartbeatReply(u64),
+    /// The publisher is shutting down and has handed it's values to
+    /// the publisher at the specified address. Durable subscriptions
+    /// should move to that publisher before this one unsubscribes
+    /// them.
+    Migrate(SocketAddr),
+    /// Indicates the result of an atomic write batch
+    WriteBatchResult(Value, WriteId),
+}
+
+/// The reason a publisher rejected a write. It is sent to the writer
+/// as the array `["write-denied", code, reason]`, use `from_value` to
+/// recover it from the result of a write with recipt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteDenied {
+    /// A short machine readable code, e.g. "read-only"
+    pub code: Chars,
+    /// A human readable explanation
+    pub reason: Chars,
+}
+
+impl fmt::Display for WriteDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "write denied ({}): {}", self.code, self.reason)
+    }
+}
+
+impl WriteDenied {
+    pub fn new<C: Into<Chars>, R: Into<Chars>>(code: C, reason: R) -> Self {
+        WriteDenied { code: code.into(), reason: reason.into() }
+    }
+
+    const TAG: &'static str = "write-denied";
+
+    /// The value sent back to the writer
+    pub fn to_value(&self) -> Value {
+        Value::Array(Arc::from([
+            Value::String(Chars::from(Self::TAG)),
+            Value::String(self.code.clone()),
+            Value::String(self.reason.clone()),
+        ]))
+    }
+
+    /// If `v` is a write denial return it
+    pub fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Array(a) => match &**a {
+                [Value::String(tag), Value::String(code), Value::String(reason)]
+                    if &**tag == Self::TAG =>
+                {
+                    Some(WriteDenied::new(code.clone(), reason.clone()))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A set of element level changes to a `Value::Array`. Maps are
+/// represented as arrays of pairs, so this also covers them.
+#[derive(Debug, Clone, PartialEq, Pack)]
+pub struct Patch {
+    /// The length of the array after the patch is applied
+    pub len: u32,
+    /// (index, value) pairs in ascending index order. Elements that
+    /// are not mentioned keep their old value, every element past
+    /// the end of the old array must be mentioned.
+    pub changes: Vec<(u32, Value)>,
+}
+
+impl Patch {
+    /// Compute the patch that turns `old` into `new`. Returns `None`
+    /// if either value isn't an array.
+    pub fn diff(old: &Value, new: &Value) -> Option<Patch> {
+        match (old, new) {
+            (Value::Array(old), Value::Array(new)) => {
+                let changes = new
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, v)| old.get(*i) != Some(*v))
+                    .map(|(i, v)| (i as u32, v.clone()))
+                    .collect();
+                Some(Patch { len: new.len() as u32, changes })
+            }
+            (_, _) => None,
+        }
+    }
+
+    /// Apply the patch to `v`. Returns `None` if `v` isn't an array,
+    /// or the patch doesn't fit it.
+    pub fn apply(&self, v: &Value) -> Option<Value> {
+        match v {
+            Value::Array(old) => {
+                let len = self.len as usize;
+                let mut new = Vec::with_capacity(len);
+                new.extend(old.iter().take(len).cloned());
+                let mut prev = None;
+                for (i, v) in &self.changes {
+                    let i = *i as usize;
+                    if prev.map(|p| i <= p).unwrap_or(false) || i >= len {
+                        return None;
+                    }
+                    prev = Some(i);
+                    if i < new.len() {
+                        new[i] = v.clone();
+                    } else if i == new.len() {
+                        new.push(v.clone());
+                    } else {
+                        return None;
+                    }
+                }
+                if new.len() == len {
+                    Some(Value::Array(Arc::from(new)))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
 }
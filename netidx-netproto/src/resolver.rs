@@ -16,6 +16,7 @@ use std::{
     hash::{Hash, Hasher},
     net::SocketAddr,
     result,
+    sync::Arc,
 };
 
 type Error = PackError;
@@ -38,6 +39,7 @@ pub enum AuthRead {
     Krb5,
     Local,
     Tls,
+    Token,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -47,6 +49,7 @@ pub enum AuthWrite {
     Krb5 { spn: Chars },
     Local,
     Tls { name: Chars },
+    Token,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -112,6 +115,13 @@ pub enum ToRead {
     ListMatching(GlobSet),
     /// Get the change nr for the specified path
     GetChangeNr(Path),
+    /// Explain the permissions the calling user has at the specified path
+    Explain(Path),
+    /// Get the resource usage of every publisher. Admins only.
+    Usage,
+    /// Find the paths matching the specified glob set whose metadata
+    /// matches the query
+    Search(GlobSet, MetadataQuery),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -120,6 +130,7 @@ pub enum Auth {
     Local { path: Chars },
     Krb5 { spn: Chars },
     Tls { name: Chars },
+    Token,
 }
 
 atomic_id!(PublisherId);
@@ -130,13 +141,14 @@ pub enum TargetAuth {
     Local,
     Krb5 { spn: Chars },
     Tls { name: Chars },
+    Token,
 }
 
 impl TargetAuth {
     pub fn is_anonymous(&self) -> bool {
         match self {
             Self::Anonymous => true,
-            Self::Krb5 { .. } | Self::Local | Self::Tls { .. } => false,
+            Self::Krb5 { .. } | Self::Local | Self::Tls { .. } | Self::Token => false,
         }
     }
 }
@@ -151,6 +163,7 @@ impl TryFrom<AuthWrite> for TargetAuth {
             AuthWrite::Krb5 { spn } => Ok(Self::Krb5 { spn }),
             AuthWrite::Reuse => bail!("no session to reuse"),
             AuthWrite::Tls { name } => Ok(Self::Tls { name }),
+            AuthWrite::Token => Ok(Self::Token),
         }
     }
 }
@@ -164,6 +177,98 @@ pub struct UserInfo {
     pub token: Bytes,
 }
 
+/// The load a publisher advertises to subscribers that balance
+/// between publishers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Pack)]
+pub struct Load {
+    /// The relative capacity of the publisher. A publisher with
+    /// weight 2 should get twice as many subscribers as a publisher
+    /// with weight 1.
+    pub weight: u32,
+    /// The current load of the publisher, e.g. the number of
+    /// connected subscribers or outstanding requests
+    pub load: u32,
+}
+
+/// Where a publisher or subscriber runs, as a list of labels from
+/// the most general to the most specific, e.g. region, zone, rack.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Pack)]
+pub struct Locality(pub Arc<Vec<Chars>>);
+
+impl Locality {
+    pub fn new<I: IntoIterator<Item = Chars>>(labels: I) -> Self {
+        Locality(Arc::new(labels.into_iter().collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The number of leading labels the two localities have in
+    /// common, larger is closer.
+    pub fn closeness(&self, other: &Locality) -> usize {
+        self.0.iter().zip(other.0.iter()).take_while(|(l0, l1)| l0 == l1).count()
+    }
+}
+
+/// Small, optional metadata a publisher may attach to a path it
+/// publishes, kept by the resolver and returned by searches.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Pack)]
+pub struct Metadata {
+    /// What the path is
+    pub doc: Option<Chars>,
+    /// A hint about the type of the value, e.g. "f64"
+    pub typ: Option<Chars>,
+    /// The units of the value, e.g. "ms"
+    pub units: Option<Chars>,
+    /// Free form tags
+    pub tags: Vec<Chars>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.doc.is_none()
+            && self.typ.is_none()
+            && self.units.is_none()
+            && self.tags.is_empty()
+    }
+}
+
+/// What a search matches in path metadata
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
+pub enum MetadataQuery {
+    /// Paths with the tag
+    Tag(Chars),
+    /// Paths that have the key, one of "doc", "type", "units", or "tags"
+    Key(Chars),
+    /// Paths with exactly the type hint
+    Typ(Chars),
+    /// Paths with exactly the units
+    Units(Chars),
+    /// Paths whose doc string contains the text
+    Doc(Chars),
+}
+
+impl MetadataQuery {
+    pub fn matches(&self, md: &Metadata) -> bool {
+        match self {
+            MetadataQuery::Tag(t) => md.tags.contains(t),
+            MetadataQuery::Key(k) => match &**k {
+                "doc" => md.doc.is_some(),
+                "type" => md.typ.is_some(),
+                "units" => md.units.is_some(),
+                "tags" => !md.tags.is_empty(),
+                _ => false,
+            },
+            MetadataQuery::Typ(t) => md.typ.as_ref() == Some(t),
+            MetadataQuery::Units(u) => md.units.as_ref() == Some(u),
+            MetadataQuery::Doc(d) => {
+                md.doc.as_ref().map(|doc| doc.contains(&**d)).unwrap_or(false)
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub struct Publisher {
     pub resolver: SocketAddr,
@@ -173,6 +278,12 @@ pub struct Publisher {
     pub target_auth: TargetAuth,
     #[pack(default)]
     pub user_info: Option<UserInfo>,
+    /// The load last advertised by the publisher, if any
+    #[pack(default)]
+    pub load: Option<Load>,
+    /// The locality advertised by the publisher, if any
+    #[pack(default)]
+    pub locality: Locality,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -188,6 +299,10 @@ pub struct Resolved {
     pub timestamp: u64,
     pub flags: u32,
     pub permissions: u32,
+    /// If the path was below an alias, the path it stands for, which
+    /// is the path to subscribe to on the publisher.
+    #[pack(default)]
+    pub target: Option<Path>,
 }
 
 #[derive(Clone, Debug, Pack)]
@@ -225,6 +340,12 @@ pub struct ListMatching {
     pub referrals: Pooled<Vec<Referral>>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Pack)]
+pub struct Search {
+    pub matched: Pooled<Vec<(Path, Metadata)>>,
+    pub referrals: Pooled<Vec<Referral>>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub struct GetChangeNr {
     pub change_number: Z64,
@@ -232,6 +353,60 @@ pub struct GetChangeNr {
     pub referrals: Pooled<Vec<Referral>>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Pack)]
+pub struct PermissionEntry {
+    /// The path of the permission entry. Dynamic entries end in
+    /// $[user] or $[group].
+    pub path: Path,
+    /// The entity the entry applies to, as it is written in the
+    /// permissions file. The empty string is the anonymous user.
+    pub entity: ArcStr,
+    /// The permission bits of the entry
+    pub permissions: u32,
+    /// The bits this entry granted. Each bit is credited to exactly
+    /// one entry, the first that gave it to a user who didn't already
+    /// have it.
+    pub granted: u32,
+    /// The bits this entry took away. Each bit is credited to exactly
+    /// one entry.
+    pub denied: u32,
+}
+
+/// The resources used by a publisher, and the limits that apply to it
+#[derive(Clone, Debug, PartialEq, Eq, Pack)]
+pub struct PublisherUsage {
+    pub publisher: SocketAddr,
+    /// The user the publisher is authenticated as, `None` for anonymous
+    pub user: Option<ArcStr>,
+    pub published: u64,
+    pub max_published: Option<u64>,
+    pub default_published: u64,
+    pub max_default_published: Option<u64>,
+    /// The number of paths published by all the publishers of the
+    /// user, the quota applies to this total
+    #[pack(default)]
+    pub user_published: u64,
+    /// The number of default publishers published by all the
+    /// publishers of the user, the quota applies to this total
+    #[pack(default)]
+    pub user_default_published: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Pack)]
+pub struct Explanation {
+    pub resolver: SocketAddr,
+    pub path: Path,
+    /// The user the explanation is for, `None` for anonymous
+    pub user: Option<UserInfo>,
+    /// false if the resolver doesn't enforce permissions at all
+    pub enforced: bool,
+    /// The effective permissions at path
+    pub permissions: u32,
+    /// The permission entries that applied to the user, in the
+    /// order they were applied.
+    pub entries: Vec<PermissionEntry>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub enum FromRead {
     Publisher(Publisher),
@@ -243,6 +418,16 @@ pub enum FromRead {
     Error(Chars),
     ListMatching(ListMatching),
     GetChangeNr(GetChangeNr),
+    Explain(Explanation),
+    Usage(Vec<PublisherUsage>),
+    Search(Search),
+    /// The server is shutting down, retry on another member. Only
+    /// sent to clients speaking protocol version 4 or later.
+    Draining,
+    /// The next n requests in the batch were rejected because the
+    /// client's user is over it's read rate limit. Only sent to
+    /// clients speaking protocol version 4 or later.
+    RateLimited(Z64),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
@@ -263,6 +448,32 @@ pub enum ToWrite {
     PublishDefaultWithFlags(Path, u32),
     /// Unpublish a default publisher
     UnpublishDefault(Path),
+    /// Register a child cluster at the referral path. The
+    /// registration expires after the referral's ttl unless it is
+    /// registered again.
+    RegisterReferral(Referral),
+    /// Remove a child cluster registration
+    UnregisterReferral(Path),
+    /// Make the first path an alias for the second. Resolve, list,
+    /// and table queries at or below the alias are answered as if
+    /// they were made at the same place below the target. Setting an
+    /// existing alias replaces it's target.
+    SetAlias(Path, Path),
+    /// Remove an alias
+    RemoveAlias(Path),
+    /// Advertise the publisher's weight and current load
+    SetLoad(Load),
+    /// Advertise the publisher's locality
+    SetLocality(Locality),
+    /// Attach metadata to a path the publisher publishes, replacing
+    /// any metadata it already had. Empty metadata removes it. The
+    /// resolver drops the metadata when nobody publishes the path.
+    SetMetadata(Path, Metadata),
+    /// A timestamped heartbeat, the resolver will echo the timestamp
+    /// back in a `HeartbeatReply` so the publisher can measure the
+    /// round trip time. Only sent to servers speaking protocol
+    /// version 4 or later.
+    TimedHeartbeat(u64),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
@@ -272,4 +483,9 @@ pub enum FromWrite {
     Referral(Referral),
     Denied,
     Error(Chars),
+    /// The server is shutting down, reconnect to another member.
+    /// Only sent to clients speaking protocol version 4 or later.
+    Draining,
+    /// The reply to `ToWrite::TimedHeartbeat`
+    HeartbeatReply(u64),
 }
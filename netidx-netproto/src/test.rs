@@ -63,13 +63,16 @@ mod resolver {
         glob::{Glob, GlobSet},
         resolver::{
             Auth, AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite,
-            FromRead, FromWrite, GetChangeNr, HashMethod, ListMatching, Publisher,
-            PublisherId, PublisherRef, ReadyForOwnershipCheck, Referral, Resolved,
-            Secret, ServerHelloWrite, Table, TargetAuth, ToRead, ToWrite,
+            Explanation, FromRead, FromWrite, GetChangeNr, HashMethod, ListMatching,
+            Load, Locality, Metadata, MetadataQuery, PermissionEntry, Publisher,
+            PublisherId, PublisherRef, PublisherUsage, ReadyForOwnershipCheck, Referral,
+            Resolved, Search, Secret, ServerHelloWrite, Table, TargetAuth, ToRead,
+            ToWrite,
         },
     };
     use netidx_core::pack::PackError;
-    use proptest::collection;
+    use netidx_derive::Pack;
+    use proptest::{collection, option};
     use std::net::SocketAddr;
 
     fn fuzz(b: Bytes) {
@@ -80,17 +83,23 @@ mod resolver {
         let _: Result<AuthWrite> = Pack::decode(&mut &*b);
         let _: Result<ClientHello> = Pack::decode(&mut &*b);
         let _: Result<ClientHelloWrite> = Pack::decode(&mut &*b);
+        let _: Result<Explanation> = Pack::decode(&mut &*b);
         let _: Result<FromRead> = Pack::decode(&mut &*b);
         let _: Result<FromWrite> = Pack::decode(&mut &*b);
         let _: Result<GetChangeNr> = Pack::decode(&mut &*b);
         let _: Result<HashMethod> = Pack::decode(&mut &*b);
         let _: Result<ListMatching> = Pack::decode(&mut &*b);
+        let _: Result<Metadata> = Pack::decode(&mut &*b);
+        let _: Result<MetadataQuery> = Pack::decode(&mut &*b);
+        let _: Result<PermissionEntry> = Pack::decode(&mut &*b);
         let _: Result<Publisher> = Pack::decode(&mut &*b);
         let _: Result<PublisherId> = Pack::decode(&mut &*b);
         let _: Result<PublisherRef> = Pack::decode(&mut &*b);
+        let _: Result<PublisherUsage> = Pack::decode(&mut &*b);
         let _: Result<ReadyForOwnershipCheck> = Pack::decode(&mut &*b);
         let _: Result<Referral> = Pack::decode(&mut &*b);
         let _: Result<Resolved> = Pack::decode(&mut &*b);
+        let _: Result<Search> = Pack::decode(&mut &*b);
         let _: Result<Secret> = Pack::decode(&mut &*b);
         let _: Result<ServerHelloWrite> = Pack::decode(&mut &*b);
         let _: Result<Table> = Pack::decode(&mut &*b);
@@ -110,6 +119,7 @@ mod resolver {
             Just(AuthRead::Krb5),
             Just(AuthRead::Local),
             Just(AuthRead::Tls),
+            Just(AuthRead::Token),
         ]
     }
 
@@ -119,7 +129,8 @@ mod resolver {
             Just(AuthWrite::Reuse),
             Just(AuthWrite::Local),
             chars().prop_map(|name| AuthWrite::Tls { name }),
-            chars().prop_map(|spn| AuthWrite::Krb5 { spn })
+            chars().prop_map(|spn| AuthWrite::Krb5 { spn }),
+            Just(AuthWrite::Token)
         ]
     }
 
@@ -129,6 +140,7 @@ mod resolver {
             Just(TargetAuth::Local),
             chars().prop_map(|name| TargetAuth::Tls { name }),
             chars().prop_map(|spn| TargetAuth::Krb5 { spn }),
+            Just(TargetAuth::Token),
         ]
     }
 
@@ -167,6 +179,25 @@ mod resolver {
         })
     }
 
+    fn metadata() -> impl Strategy<Value = Metadata> {
+        let doc = option(chars());
+        let typ = option(chars());
+        let units = option(chars());
+        let tags = collection::vec(chars(), (0, 10));
+        (doc, typ, units, tags)
+            .prop_map(|(doc, typ, units, tags)| Metadata { doc, typ, units, tags })
+    }
+
+    fn metadata_query() -> impl Strategy<Value = MetadataQuery> {
+        prop_oneof![
+            chars().prop_map(MetadataQuery::Tag),
+            chars().prop_map(MetadataQuery::Key),
+            chars().prop_map(MetadataQuery::Typ),
+            chars().prop_map(MetadataQuery::Units),
+            chars().prop_map(MetadataQuery::Doc),
+        ]
+    }
+
     fn to_read() -> impl Strategy<Value = ToRead> {
         prop_oneof![
             path().prop_map(ToRead::Resolve),
@@ -174,6 +205,9 @@ mod resolver {
             path().prop_map(ToRead::Table),
             globset().prop_map(ToRead::ListMatching),
             path().prop_map(ToRead::GetChangeNr),
+            path().prop_map(ToRead::Explain),
+            Just(ToRead::Usage),
+            (globset(), metadata_query()).prop_map(|(g, q)| ToRead::Search(g, q)),
         ]
     }
 
@@ -185,6 +219,14 @@ mod resolver {
         prop_oneof![Just(HashMethod::Sha3_512)]
     }
 
+    fn load() -> impl Strategy<Value = Load> {
+        (any::<u32>(), any::<u32>()).prop_map(|(weight, load)| Load { weight, load })
+    }
+
+    fn locality() -> impl Strategy<Value = Locality> {
+        collection::vec(chars(), (0, 4)).prop_map(Locality::new)
+    }
+
     fn publisher() -> impl Strategy<Value = Publisher> {
         let resolver = any::<SocketAddr>();
         let id = publisher_id();
@@ -192,16 +234,32 @@ mod resolver {
         let hash_method = hash_method();
         let target_auth = target_auth();
         let user_info = option(user_info());
-        (resolver, id, addr, hash_method, target_auth, user_info).prop_map(
-            |(resolver, id, addr, hash_method, target_auth, user_info)| Publisher {
-                resolver,
-                id,
-                addr,
-                hash_method,
-                target_auth,
-                user_info,
-            },
-        )
+        let load = option(load());
+        let locality = locality();
+        (resolver, id, addr, hash_method, target_auth, user_info, load, locality)
+            .prop_map(
+                |(
+                    resolver,
+                    id,
+                    addr,
+                    hash_method,
+                    target_auth,
+                    user_info,
+                    load,
+                    locality,
+                )| {
+                    Publisher {
+                        resolver,
+                        id,
+                        addr,
+                        hash_method,
+                        target_auth,
+                        user_info,
+                        load,
+                        locality,
+                    }
+                },
+            )
     }
 
     fn publisher_ref() -> impl Strategy<Value = PublisherRef> {
@@ -215,13 +273,15 @@ mod resolver {
         let timestamp = any::<u64>();
         let flags = any::<u32>();
         let permissions = any::<u32>();
-        (resolver, publishers, timestamp, flags, permissions).prop_map(
-            |(resolver, publishers, timestamp, flags, permissions)| Resolved {
+        let target = option::of(path());
+        (resolver, publishers, timestamp, flags, permissions, target).prop_map(
+            |(resolver, publishers, timestamp, flags, permissions, target)| Resolved {
                 resolver,
                 publishers,
                 timestamp,
                 flags,
                 permissions,
+                target,
             },
         )
     }
@@ -231,6 +291,7 @@ mod resolver {
             Just(Auth::Anonymous),
             chars().prop_map(|path| Auth::Local { path }),
             chars().prop_map(|spn| Auth::Krb5 { spn }),
+            Just(Auth::Token),
         ]
     }
 
@@ -269,6 +330,13 @@ mod resolver {
             .prop_map(|(matched, referrals)| ListMatching { matched, referrals })
     }
 
+    fn search() -> impl Strategy<Value = Search> {
+        let matched =
+            collection::vec((path(), metadata()), (0, 100)).prop_map(Pooled::orphan);
+        let referrals = collection::vec(referral(), (0, 100)).prop_map(Pooled::orphan);
+        (matched, referrals).prop_map(|(matched, referrals)| Search { matched, referrals })
+    }
+
     fn get_change_nr() -> impl Strategy<Value = GetChangeNr> {
         let change_number = any::<u64>().prop_map(|v| Z64(v));
         let resolver = any::<SocketAddr>();
@@ -282,6 +350,79 @@ mod resolver {
         )
     }
 
+    fn permission_entry() -> impl Strategy<Value = PermissionEntry> {
+        (path(), arcstr(), any::<u32>(), any::<u32>(), any::<u32>()).prop_map(
+            |(path, entity, permissions, granted, denied)| PermissionEntry {
+                path,
+                entity,
+                permissions,
+                granted,
+                denied,
+            },
+        )
+    }
+
+    fn explanation() -> impl Strategy<Value = Explanation> {
+        let resolver = any::<SocketAddr>();
+        let path = path();
+        let user = option(user_info());
+        let enforced = any::<bool>();
+        let permissions = any::<u32>();
+        let entries = collection::vec(permission_entry(), (0, 10));
+        (resolver, path, user, enforced, permissions, entries).prop_map(
+            |(resolver, path, user, enforced, permissions, entries)| Explanation {
+                resolver,
+                path,
+                user,
+                enforced,
+                permissions,
+                entries,
+            },
+        )
+    }
+
+    fn publisher_usage() -> impl Strategy<Value = PublisherUsage> {
+        let publisher = any::<SocketAddr>();
+        let user = option(arcstr());
+        let published = any::<u64>();
+        let max_published = option(any::<u64>());
+        let default_published = any::<u64>();
+        let max_default_published = option(any::<u64>());
+        let user_published = any::<u64>();
+        let user_default_published = any::<u64>();
+        (
+            publisher,
+            user,
+            published,
+            max_published,
+            default_published,
+            max_default_published,
+            user_published,
+            user_default_published,
+        )
+            .prop_map(
+                |(
+                    publisher,
+                    user,
+                    published,
+                    max_published,
+                    default_published,
+                    max_default_published,
+                    user_published,
+                    user_default_published,
+                )| PublisherUsage {
+                    publisher,
+                    user,
+                    published,
+                    max_published,
+                    default_published,
+                    max_default_published,
+                    user_published,
+                    user_default_published,
+                },
+            )
+    }
+
     fn from_read() -> impl Strategy<Value = FromRead> {
         prop_oneof![
             publisher().prop_map(FromRead::Publisher),
@@ -293,7 +434,12 @@ mod resolver {
             table().prop_map(FromRead::Table),
             referral().prop_map(FromRead::Referral),
             Just(FromRead::Denied),
-            chars().prop_map(FromRead::Error)
+            chars().prop_map(FromRead::Error),
+            explanation().prop_map(FromRead::Explain),
+            collection::vec(publisher_usage(), (0, 10)).prop_map(FromRead::Usage),
+            search().prop_map(FromRead::Search),
+            Just(FromRead::Draining),
+            any::<u64>().prop_map(|n| FromRead::RateLimited(Z64(n)))
         ]
     }
 
@@ -316,7 +462,15 @@ mod resolver {
                 .prop_map(|(path, flags)| ToWrite::PublishWithFlags(path, flags)),
             (path(), any::<u32>())
                 .prop_map(|(path, flags)| ToWrite::PublishDefaultWithFlags(path, flags)),
-            path().prop_map(ToWrite::UnpublishDefault)
+            path().prop_map(ToWrite::UnpublishDefault),
+            referral().prop_map(ToWrite::RegisterReferral),
+            path().prop_map(ToWrite::UnregisterReferral),
+            (path(), path()).prop_map(|(path, target)| ToWrite::SetAlias(path, target)),
+            path().prop_map(ToWrite::RemoveAlias),
+            load().prop_map(ToWrite::SetLoad),
+            locality().prop_map(ToWrite::SetLocality),
+            (path(), metadata()).prop_map(|(path, md)| ToWrite::SetMetadata(path, md)),
+            any::<u64>().prop_map(ToWrite::TimedHeartbeat)
         ]
     }
 
@@ -326,7 +480,9 @@ mod resolver {
             Just(FromWrite::Unpublished),
             referral().prop_map(FromWrite::Referral),
             Just(FromWrite::Denied),
-            chars().prop_map(FromWrite::Error)
+            chars().prop_map(FromWrite::Error),
+            Just(FromWrite::Draining),
+            any::<u64>().prop_map(FromWrite::HeartbeatReply)
         ]
     }
 
@@ -386,11 +542,12 @@ mod resolver {
 mod publisher {
     use super::*;
     use crate::{
-        publisher::{From, Hello, Id, To, WriteId},
+        publisher::{From, Hello, Id, Patch, To, WriteDenied, WriteId},
         value::Value,
     };
     use chrono::prelude::*;
     use netidx_core::pack::PackError;
+    use netidx_derive::Pack;
     use proptest::collection;
     use std::{net::SocketAddr, time::Duration};
 
@@ -409,7 +566,9 @@ mod publisher {
             option(user_info()).prop_map(Hello::Krb5),
             option(user_info()).prop_map(Hello::Local),
             option(user_info()).prop_map(Hello::Tls),
-            any::<SocketAddr>().prop_map(Hello::ResolverAuthenticate)
+            any::<SocketAddr>().prop_map(Hello::ResolverAuthenticate),
+            option(user_info()).prop_map(Hello::Token),
+            path().prop_map(Hello::List)
         ]
     }
 
@@ -426,7 +585,15 @@ mod publisher {
             ),
             any::<u64>().prop_map(|i| To::Unsubscribe(Id::mk(i))),
             (any::<u64>(), value(), any::<bool>(), any::<u64>())
-                .prop_map(|(i, v, r, w)| To::Write(Id::mk(i), r, v, WriteId::mk(w)))
+                .prop_map(|(i, v, r, w)| To::Write(Id::mk(i), r, v, WriteId::mk(w))),
+            any::<u64>().prop_map(To::Heartbeat),
+            Just(To::Migrated),
+            (collection::vec((any::<u64>(), value()), 0..10), any::<bool>(), any::<u64>())
+                .prop_map(|(w, r, i)| To::WriteBatch(
+                    w.into_iter().map(|(i, v)| (Id::mk(i), v)).collect(),
+                    r,
+                    WriteId::mk(i)
+                ))
         ]
     }
 
@@ -476,18 +643,66 @@ mod publisher {
             path().prop_map(From::NoSuchValue),
             path().prop_map(From::Denied),
             any::<u64>().prop_map(|i| From::Unsubscribed(Id::mk(i))),
-            (path(), any::<u64>(), value()).prop_map(|(p, i, v)| From::Subscribed(
-                p,
-                Id::mk(i),
-                v
-            )),
+            (path(), any::<u64>(), value(), any::<bool>()).prop_map(
+                |(p, i, v, s)| From::Subscribed(p, Id::mk(i), v, s)
+            ),
             (any::<u64>(), value()).prop_map(|(i, v)| From::Update(Id::mk(i), v)),
             Just(From::Heartbeat),
             (any::<u64>(), value(), any::<u64>())
-                .prop_map(|(i, v, w)| From::WriteResult(Id::mk(i), v, WriteId::mk(w)))
+                .prop_map(|(i, v, w)| From::WriteResult(Id::mk(i), v, WriteId::mk(w))),
+            (any::<u64>(), array(), array()).prop_map(|(i, a, b)| From::Patch(
+                Id::mk(i),
+                Arc::new(Patch::diff(&a, &b).unwrap())
+            )),
+            any::<u64>().prop_map(From::HeartbeatReply),
+            any::<SocketAddr>().prop_map(From::Migrate),
+            (value(), any::<u64>())
+                .prop_map(|(v, w)| From::WriteBatchResult(v, WriteId::mk(w)))
         ]
     }
 
+    /// `From` as a version 3 subscriber decodes it
+    #[derive(Debug, Clone, PartialEq, Pack)]
+    enum FromV3 {
+        NoSuchValue(Path),
+        Denied(Path),
+        Unsubscribed(Id),
+        Subscribed(Path, Id, Value),
+        Update(Id, Value),
+        Heartbeat,
+        WriteResult(Id, Value, #[pack(default)] WriteId),
+    }
+
+    /// Everything a publisher may send to a version 3 subscriber
+    fn from_v3() -> impl Strategy<Value = (From, FromV3)> {
+        prop_oneof![
+            path().prop_map(|p| (From::NoSuchValue(p.clone()), FromV3::NoSuchValue(p))),
+            path().prop_map(|p| (From::Denied(p.clone()), FromV3::Denied(p))),
+            any::<u64>().prop_map(|i| (
+                From::Unsubscribed(Id::mk(i)),
+                FromV3::Unsubscribed(Id::mk(i))
+            )),
+            (path(), any::<u64>(), value()).prop_map(|(p, i, v)| (
+                From::Subscribed(p.clone(), Id::mk(i), v.clone(), false),
+                FromV3::Subscribed(p, Id::mk(i), v)
+            )),
+            (any::<u64>(), value()).prop_map(|(i, v)| (
+                From::Update(Id::mk(i), v.clone()),
+                FromV3::Update(Id::mk(i), v)
+            )),
+            Just((From::Heartbeat, FromV3::Heartbeat)),
+            (any::<u64>(), value(), any::<u64>()).prop_map(|(i, v, w)| (
+                From::WriteResult(Id::mk(i), v.clone(), WriteId::mk(w)),
+                FromV3::WriteResult(Id::mk(i), v, WriteId::mk(w))
+            )),
+        ]
+    }
+
+    fn array() -> impl Strategy<Value = Value> {
+        collection::vec((0..4u64).prop_map(Value::U64), 0..50)
+            .prop_map(|a| Value::Array(Arc::from(a)))
+    }
+
     fn vequiv(v0: &Value, v1: &Value) -> bool {
         match (v0, v1) {
             (Value::Duration(d0), Value::Duration(d1)) => {
@@ -532,9 +747,27 @@ mod publisher {
             check(a)
         }
 
+        #[test]
+        fn test_from_v3((a, b) in from_v3()) {
+            let mut buf = pack(&a).expect("encode failed");
+            assert_eq!(FromV3::decode(&mut buf).expect("decode failed"), b)
+        }
+
         #[test]
         fn test_value_roundtrip(v in value()) {
             round_trip(v)
         }
+
+        #[test]
+        fn test_patch(a in array(), b in array()) {
+            let p = Patch::diff(&a, &b).unwrap();
+            assert_eq!(p.apply(&a), Some(b))
+        }
+
+        #[test]
+        fn test_write_denied(code in chars(), reason in chars()) {
+            let d = WriteDenied::new(code, reason);
+            assert_eq!(WriteDenied::from_value(&d.to_value()), Some(d))
+        }
     }
 }
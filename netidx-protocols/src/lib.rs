@@ -8,6 +8,7 @@ extern crate anyhow;
 extern crate netidx_core;
 
 pub mod cluster;
+pub mod namespace;
 pub mod rpc;
 pub mod view;
 pub mod channel;
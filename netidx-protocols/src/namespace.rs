@@ -0,0 +1,541 @@
+use anyhow::Result;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, BoxFuture},
+    prelude::*,
+    select_biased,
+    stream::FuturesUnordered,
+};
+use fxhash::{FxHashMap, FxHashSet};
+use log::warn;
+use netidx::{
+    path::Path,
+    publisher::{
+        DefaultHandle, Event, Id, PublishFlags, Publisher, UpdateBatch, Val, Value,
+    },
+};
+use parking_lot::Mutex;
+use std::{
+    cmp::max,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{task, time};
+
+// the shortest interval at which the negative cache and the
+// unclaimed values are checked
+const MIN_TICK: Duration = Duration::from_secs(1);
+
+/// The source of a virtual namespace served by a `Namespace`. Paths
+/// are only loaded when a subscriber asks for them, and are
+/// forgotten again when the last subscriber goes away.
+pub trait NamespaceProvider: Send + Sync + 'static {
+    /// Return true if `path` exists. Paths that don't exist are
+    /// negatively cached for a while, so `exists` won't be asked
+    /// about them again until the cache entry expires.
+    fn exists(&self, path: &Path) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Load the current value of `path`. This is only called if
+    /// `exists` returned true.
+    fn load(&self, path: &Path) -> impl Future<Output = Result<Value>> + Send;
+
+    /// Return the children of `path`. This is called whenever the
+    /// resolver server is asked to list `path`, whether or not it is
+    /// loaded, and the children are advertised in the resolver
+    /// server to answer the listing. Children outside the namespace
+    /// are ignored.
+    fn list_children(
+        &self,
+        path: &Path,
+    ) -> impl Future<Output = Result<Vec<Path>>> + Send;
+
+    /// Called when the last subscriber unsubscribes from `path` and
+    /// it's value is destroyed. A subscription to `path` that
+    /// arrives while this is running will wait for it to finish
+    /// before loading `path` again.
+    fn on_idle(&self, path: &Path) -> impl Future<Output = ()> + Send {
+        let _ = path;
+        future::ready(())
+    }
+}
+
+#[derive(Default)]
+struct Loaded {
+    by_path: FxHashMap<Path, Val>,
+    by_id: FxHashMap<Id, Path>,
+}
+
+enum Cmd {
+    Refresh(Path, oneshot::Sender<Result<()>>),
+    Invalidate(Path),
+}
+
+// who is waiting for a listing to be advertised
+enum ListReply {
+    Refresh(oneshot::Sender<Result<()>>),
+    Resolver(oneshot::Sender<()>),
+}
+
+enum Job {
+    Loaded(Path, Result<Option<Value>>),
+    Listed(Path, Result<Vec<Path>>, ListReply),
+    Idle(Path),
+}
+
+async fn load<P: NamespaceProvider>(provider: &P, path: &Path) -> Result<Option<Value>> {
+    if !provider.exists(path).await? {
+        return Ok(None);
+    }
+    Ok(Some(provider.load(path).await?))
+}
+
+struct Driver<P: NamespaceProvider> {
+    provider: Arc<P>,
+    publisher: Publisher,
+    base: Path,
+    default: DefaultHandle,
+    loaded: Arc<Mutex<Loaded>>,
+    negative_ttl: Duration,
+    claim_timeout: Duration,
+    negative: FxHashMap<Path, Instant>,
+    pending: FxHashMap<Path, Vec<oneshot::Sender<()>>>,
+    idling: FxHashSet<Path>,
+    // loaded values nobody has subscribed to yet
+    unclaimed: FxHashMap<Id, Instant>,
+    // the advertised children of each listed path, and when it was
+    // listed
+    advertised: FxHashMap<Path, (FxHashSet<Path>, Instant)>,
+    jobs: FuturesUnordered<BoxFuture<'static, Job>>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl<P: NamespaceProvider> Driver<P> {
+    fn start_load(&mut self, path: Path) {
+        let provider = self.provider.clone();
+        self.jobs.push(
+            async move {
+                let r = load(&*provider, &path).await;
+                Job::Loaded(path, r)
+            }
+            .boxed(),
+        )
+    }
+
+    fn start_list(&mut self, path: Path, reply: ListReply) {
+        let provider = self.provider.clone();
+        self.jobs.push(
+            async move {
+                let r = provider.list_children(&path).await;
+                Job::Listed(path, r, reply)
+            }
+            .boxed(),
+        )
+    }
+
+    fn advertise_children(&mut self, path: Path, children: Vec<Path>) {
+        let children = children
+            .into_iter()
+            .filter(|c| Path::is_parent(&self.base, c))
+            .collect::<FxHashSet<_>>();
+        let old = self.advertised.remove(&path).map(|(c, _)| c).unwrap_or_default();
+        for c in old.difference(&children) {
+            self.default.remove_advertisement(c)
+        }
+        for c in children.difference(&old) {
+            if let Err(e) = self.default.advertise(c.clone()) {
+                warn!("failed to advertise {}: {}", c, e)
+            }
+        }
+        if !children.is_empty() {
+            self.advertised.insert(path, (children, Instant::now()));
+        }
+    }
+
+    fn request(&mut self, path: Path, reply: oneshot::Sender<()>) {
+        if self.loaded.lock().by_path.contains_key(&path) {
+            let _ = reply.send(());
+            return;
+        }
+        if let Some(ts) = self.negative.get(&path) {
+            if ts.elapsed() < self.negative_ttl {
+                let _ = reply.send(());
+                return;
+            }
+            self.negative.remove(&path);
+        }
+        match self.pending.entry(path) {
+            Entry::Occupied(mut e) => e.get_mut().push(reply),
+            Entry::Vacant(e) => {
+                let path = e.key().clone();
+                e.insert(vec![reply]);
+                if !self.idling.contains(&path) {
+                    self.start_load(path)
+                }
+            }
+        }
+    }
+
+    fn destroyed(&mut self, id: Id) {
+        let val = {
+            let mut loaded = self.loaded.lock();
+            match loaded.by_id.remove(&id) {
+                None => return,
+                Some(path) => loaded.by_path.remove(&path).map(|v| (path, v)),
+            }
+        };
+        self.unclaimed.remove(&id);
+        if let Some((path, val)) = val {
+            drop(val);
+            let provider = self.provider.clone();
+            self.idling.insert(path.clone());
+            self.jobs.push(
+                async move {
+                    provider.on_idle(&path).await;
+                    Job::Idle(path)
+                }
+                .boxed(),
+            )
+        }
+    }
+
+    fn job(&mut self, job: Job) {
+        match job {
+            Job::Loaded(path, r) => {
+                let replies = self.pending.remove(&path).unwrap_or_else(Vec::new);
+                match r {
+                    Ok(None) => {
+                        if !self.negative_ttl.is_zero() {
+                            self.negative.insert(path, Instant::now());
+                        }
+                    }
+                    Err(e) => warn!("failed to load {}: {}", path, e),
+                    Ok(Some(v)) => {
+                        let flags = PublishFlags::DESTROY_ON_IDLE;
+                        match self.publisher.publish_with_flags(flags, path.clone(), v) {
+                            Err(e) => warn!("failed to publish {}: {}", path, e),
+                            Ok(val) => {
+                                let id = val.id();
+                                self.publisher.events_for_id(id, self.events.clone());
+                                self.unclaimed.insert(id, Instant::now());
+                                let mut loaded = self.loaded.lock();
+                                loaded.by_id.insert(id, path.clone());
+                                loaded.by_path.insert(path.clone(), val);
+                            }
+                        }
+                    }
+                }
+                for reply in replies {
+                    let _ = reply.send(());
+                }
+            }
+            Job::Listed(path, r, reply) => match (r, reply) {
+                (Ok(children), reply) => {
+                    self.advertise_children(path, children);
+                    match reply {
+                        ListReply::Refresh(reply) => {
+                            let _ = reply.send(Ok(()));
+                        }
+                        ListReply::Resolver(reply) => {
+                            let _ = reply.send(());
+                        }
+                    }
+                }
+                (Err(e), ListReply::Refresh(reply)) => {
+                    let _ = reply.send(Err(e));
+                }
+                (Err(e), ListReply::Resolver(reply)) => {
+                    warn!("failed to list {}: {}", path, e);
+                    let _ = reply.send(());
+                }
+            },
+            Job::Idle(path) => {
+                self.idling.remove(&path);
+                if self.pending.contains_key(&path) {
+                    self.start_load(path)
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.claim_timeout;
+        // the subscriber that asked for these went away before it
+        // subscribed, DESTROY_ON_IDLE will never fire for them
+        let orphans = self
+            .unclaimed
+            .iter()
+            .filter(|(_, ts)| ts.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in orphans {
+            self.destroyed(id)
+        }
+        // listings are only needed until the resolver has answered
+        let listed = self
+            .advertised
+            .iter()
+            .filter(|(_, (_, ts))| ts.elapsed() >= timeout)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in listed {
+            self.advertise_children(path, vec![])
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut cmds: mpsc::UnboundedReceiver<Cmd>,
+        mut lists: mpsc::UnboundedReceiver<(Path, oneshot::Sender<()>)>,
+        mut events: mpsc::UnboundedReceiver<Event>,
+    ) {
+        let mut prune = time::interval(max(self.negative_ttl, MIN_TICK));
+        let mut claim = time::interval(max(self.claim_timeout / 2, MIN_TICK));
+        loop {
+            select_biased! {
+                cmd = cmds.next() => match cmd {
+                    None => break,
+                    Some(Cmd::Refresh(path, reply)) => {
+                        self.start_list(path, ListReply::Refresh(reply))
+                    }
+                    Some(Cmd::Invalidate(path)) => {
+                        self.negative.remove(&path);
+                    }
+                },
+                job = self.jobs.select_next_some() => self.job(job),
+                e = events.select_next_some() => match e {
+                    Event::Destroyed(id) => self.destroyed(id),
+                    Event::Subscribe(id, _) => {
+                        self.unclaimed.remove(&id);
+                    }
+                    Event::Unsubscribe(_, _) => (),
+                },
+                (path, reply) = self.default.select_next_some() => {
+                    self.request(path, reply)
+                },
+                (path, reply) = lists.select_next_some() => {
+                    self.start_list(path, ListReply::Resolver(reply))
+                },
+                _ = prune.tick().fuse() => {
+                    let ttl = self.negative_ttl;
+                    self.negative.retain(|_, ts| ts.elapsed() < ttl);
+                },
+                _ = claim.tick().fuse() => self.expire(),
+            }
+        }
+    }
+}
+
+/// A sparse virtual namespace rooted at a default publisher. When a
+/// subscriber asks for a path under `base` the `NamespaceProvider`
+/// is asked if it exists, and if so it is loaded and published with
+/// `DESTROY_ON_IDLE`. Concurrent requests for the same path share
+/// one load, and paths that don't exist are negatively cached for
+/// `negative_ttl`, or not at all if it is zero.
+///
+/// The default publisher lists on demand
+/// (`PublishFlags::LIST_ON_DEMAND`), so listings of `base` and every
+/// path under it come from the provider, whether or not the path is
+/// loaded. The children are advertised in the resolver server to
+/// answer the listing, and withdrawn again after `claim_timeout`, so
+/// the resolver only holds the part of the namespace that is in use.
+/// A value loaded for a subscriber that goes away before it
+/// subscribes is unpublished again after `claim_timeout`.
+///
+/// When the `Namespace` is dropped the default publisher, all the
+/// advertisements, and all the loaded values are unpublished.
+pub struct Namespace {
+    loaded: Arc<Mutex<Loaded>>,
+    cmds: mpsc::UnboundedSender<Cmd>,
+    publisher: Publisher,
+}
+
+impl Namespace {
+    pub async fn new<P: NamespaceProvider>(
+        publisher: &Publisher,
+        base: Path,
+        negative_ttl: Duration,
+        claim_timeout: Duration,
+        provider: P,
+    ) -> Result<Namespace> {
+        let flags = PublishFlags::LIST_ON_DEMAND;
+        let default = publisher.publish_default_with_flags(flags, base.clone())?;
+        let (tx_lists, rx_lists) = mpsc::unbounded();
+        default.list_requests(tx_lists);
+        let loaded = Arc::new(Mutex::new(Loaded::default()));
+        let (tx_cmds, rx_cmds) = mpsc::unbounded();
+        let (tx_events, rx_events) = mpsc::unbounded();
+        let driver = Driver {
+            provider: Arc::new(provider),
+            publisher: publisher.clone(),
+            base,
+            default,
+            loaded: loaded.clone(),
+            negative_ttl,
+            claim_timeout,
+            negative: HashMap::default(),
+            pending: HashMap::default(),
+            idling: HashSet::default(),
+            unclaimed: HashMap::default(),
+            advertised: HashMap::default(),
+            jobs: FuturesUnordered::new(),
+            events: tx_events,
+        };
+        task::spawn(driver.run(rx_cmds, rx_lists, rx_events));
+        publisher.flushed().await;
+        Ok(Namespace { loaded, cmds: tx_cmds, publisher: publisher.clone() })
+    }
+
+    /// Return the id of `path` if it is currently loaded.
+    pub fn id(&self, path: &Path) -> Option<Id> {
+        self.loaded.lock().by_path.get(path).map(|v| v.id())
+    }
+
+    /// Queue an update to `path` in `batch` if it is currently
+    /// loaded. Return false if it isn't loaded.
+    pub fn update(&self, batch: &mut UpdateBatch, path: &Path, v: Value) -> bool {
+        match self.loaded.lock().by_path.get(path) {
+            None => false,
+            Some(val) => {
+                val.update(batch, v);
+                true
+            }
+        }
+    }
+
+    /// Ask the provider for the children of `path` again, and
+    /// advertise them as if `path` had just been listed. When this
+    /// returns the resolver server is up to date.
+    pub async fn refresh(&self, path: Path) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.cmds
+            .unbounded_send(Cmd::Refresh(path, tx))
+            .map_err(|_| anyhow!("namespace is dead"))?;
+        rx.await.map_err(|_| anyhow!("namespace is dead"))??;
+        self.publisher.flushed().await;
+        Ok(())
+    }
+
+    /// Forget that `path` doesn't exist, the next subscription to it
+    /// will ask the provider again.
+    pub fn invalidate(&self, path: Path) {
+        let _ = self.cmds.unbounded_send(Cmd::Invalidate(path));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::runtime::Runtime;
+
+    struct Provider {
+        values: BTreeMap<Path, Value>,
+        exists: AtomicUsize,
+        loads: AtomicUsize,
+        lists: AtomicUsize,
+        idle: Mutex<Vec<Path>>,
+    }
+
+    impl NamespaceProvider for Arc<Provider> {
+        async fn exists(&self, path: &Path) -> Result<bool> {
+            self.exists.fetch_add(1, Ordering::Relaxed);
+            Ok(self.values.contains_key(path))
+        }
+
+        async fn load(&self, path: &Path) -> Result<Value> {
+            self.loads.fetch_add(1, Ordering::Relaxed);
+            time::sleep(Duration::from_millis(100)).await;
+            Ok(self.values[path].clone())
+        }
+
+        async fn list_children(&self, path: &Path) -> Result<Vec<Path>> {
+            self.lists.fetch_add(1, Ordering::Relaxed);
+            Ok(self
+                .values
+                .keys()
+                .filter(|p| Path::is_immediate_parent(path, p))
+                .cloned()
+                .collect())
+        }
+
+        async fn on_idle(&self, path: &Path) {
+            self.idle.lock().push(path.clone())
+        }
+    }
+
+    #[test]
+    fn virtual_namespace() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let p = |s: &str| Path::from(String::from(s));
+            let provider = Arc::new(Provider {
+                values: BTreeMap::from_iter([
+                    (p("/ns/a"), Value::U64(1)),
+                    (p("/ns/b"), Value::U64(2)),
+                    (p("/ns/b/c"), Value::U64(3)),
+                ]),
+                exists: AtomicUsize::new(0),
+                loads: AtomicUsize::new(0),
+                lists: AtomicUsize::new(0),
+                idle: Mutex::new(vec![]),
+            });
+            let ns = Namespace::new(
+                &ctx.publisher,
+                p("/ns"),
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                provider.clone(),
+            )
+            .await
+            .unwrap();
+            let r = ctx.subscriber.resolver();
+            let mut l = r.list(p("/ns")).await.unwrap();
+            l.sort();
+            assert_eq!(&**l, &[p("/ns/a"), p("/ns/b")]);
+            // listings come from the provider even if nothing is loaded
+            let l = r.list(p("/ns/b")).await.unwrap();
+            assert_eq!(&**l, &[p("/ns/b/c")]);
+            assert_eq!(provider.lists.load(Ordering::Relaxed), 2);
+            assert_eq!(provider.loads.load(Ordering::Relaxed), 0);
+            let (b0, b1) = future::join(
+                ctx.subscriber.subscribe_nondurable_one(p("/ns/b"), None),
+                ctx.subscriber.subscribe_nondurable_one(p("/ns/b"), None),
+            )
+            .await;
+            let (b0, b1) = (b0.unwrap(), b1.unwrap());
+            assert_eq!(provider.loads.load(Ordering::Relaxed), 1);
+            assert!(ns.id(&p("/ns/b")).is_some());
+            let missing = p("/ns/missing");
+            assert!(ctx
+                .subscriber
+                .subscribe_nondurable_one(missing.clone(), None)
+                .await
+                .is_err());
+            let n = provider.exists.load(Ordering::Relaxed);
+            assert!(ctx
+                .subscriber
+                .subscribe_nondurable_one(missing.clone(), None)
+                .await
+                .is_err());
+            assert_eq!(provider.exists.load(Ordering::Relaxed), n);
+            drop(b0);
+            drop(b1);
+            for _ in 0..100 {
+                if !provider.idle.lock().is_empty() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(100)).await;
+            }
+            assert_eq!(&*provider.idle.lock(), &[p("/ns/b")]);
+            assert!(ns.id(&p("/ns/b")).is_none());
+            // idle paths can still be listed
+            let l = r.list(p("/ns/b")).await.unwrap();
+            assert_eq!(&**l, &[p("/ns/b/c")]);
+        })
+    }
+}
@@ -599,4 +599,41 @@ mod test {
             })
             .unwrap()
     }
+
+    #[test]
+    fn call_proc_write_batch() {
+        let _ = env_logger::try_init();
+        Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let ctx = Ctx::new().await;
+                let proc_name = Path::from("/rpc/procedure");
+                let _server_proc = define_rpc!(
+                    &ctx.publisher,
+                    proc_name.clone(),
+                    "test rpc procedure",
+                    |mut c: RpcCall, a: Value| -> Option<()> {
+                        assert_eq!(a, Value::from("hello rpc"));
+                        c.reply.send(Value::U32(42));
+                        None
+                    },
+                    None,
+                    arg1: Value = Value::Null; "arg1 doc"
+                )
+                .unwrap();
+                time::sleep(Duration::from_millis(100)).await;
+                let arg = ctx.subscriber.subscribe(proc_name.append("arg1/val"));
+                let call = ctx.subscriber.subscribe(proc_name.clone());
+                arg.wait_subscribed().await?;
+                call.wait_subscribed().await?;
+                let mut receipts = ctx.subscriber.write_batch([
+                    (&arg, Value::from("hello rpc")),
+                    (&call, Value::Null),
+                ])?;
+                assert_eq!(receipts.len(), 1);
+                assert_eq!(receipts.pop().unwrap().1.await?, Value::U32(42));
+                Ok::<(), anyhow::Error>(())
+            })
+            .unwrap()
+    }
 }
@@ -16,6 +16,11 @@ pub struct ClientParams {
         help = "the tls identity to publish as, default_identity if omitted"
     )]
     pub identity: Option<String>,
+    #[structopt(
+        long = "token",
+        help = "path to the token file, only if auth = token, $NETIDX_TOKEN if omitted"
+    )]
+    pub token: Option<String>,
 }
 
 impl ClientParams {
@@ -32,10 +37,14 @@ impl ClientParams {
             DesiredAuth::Tls { .. } => {
                 DesiredAuth::Tls { identity: self.identity.clone() }
             }
+            DesiredAuth::Token { .. } => DesiredAuth::Token { token: self.token.clone() },
         };
         match &auth {
             DesiredAuth::Krb5 { .. } => (),
-            DesiredAuth::Anonymous | DesiredAuth::Local | DesiredAuth::Tls { .. } => {
+            DesiredAuth::Anonymous
+            | DesiredAuth::Local
+            | DesiredAuth::Tls { .. }
+            | DesiredAuth::Token { .. } => {
                 if self.upn.is_some() || self.spn.is_some() {
                     panic!("upn/spn may only be specified for krb5 auth")
                 }
@@ -43,12 +52,26 @@ impl ClientParams {
         }
         match &auth {
             DesiredAuth::Tls { .. } => (),
-            DesiredAuth::Anonymous | DesiredAuth::Local | DesiredAuth::Krb5 { .. } => {
+            DesiredAuth::Anonymous
+            | DesiredAuth::Local
+            | DesiredAuth::Krb5 { .. }
+            | DesiredAuth::Token { .. } => {
                 if self.identity.is_some() {
                     panic!("identity may only be specified for tls auth")
                 }
             }
         }
+        match &auth {
+            DesiredAuth::Token { .. } => (),
+            DesiredAuth::Anonymous
+            | DesiredAuth::Local
+            | DesiredAuth::Krb5 { .. }
+            | DesiredAuth::Tls { .. } => {
+                if self.token.is_some() {
+                    panic!("token may only be specified for token auth")
+                }
+            }
+        }
         (cfg, auth)
     }
 }
@@ -7,6 +7,8 @@ mod stress_channel_subscriber;
 mod stress_publisher;
 mod stress_subscriber;
 mod subscriber;
+mod tls;
+mod token;
 mod wsproxy;
 
 #[cfg(unix)]
@@ -129,6 +131,16 @@ enum Opt {
         #[structopt(flatten)]
         proxy: netidx_wsproxy::config::Config,
     },
+    #[structopt(name = "token", about = "manage token authentication keys and tokens")]
+    Token {
+        #[structopt(subcommand)]
+        cmd: token::Cmd,
+    },
+    #[structopt(name = "tls", about = "manage a development certificate authority")]
+    Tls {
+        #[structopt(subcommand)]
+        cmd: tls::Cmd,
+    },
 }
 
 #[tokio::main]
@@ -182,6 +194,8 @@ async fn tokio_main() -> Result<()> {
             let (cfg, auth) = common.load();
             wsproxy::run(cfg, auth, publisher, proxy).await
         }
+        Opt::Token { cmd } => token::run(cmd),
+        Opt::Tls { cmd } => tls::run(cmd),
     }
 }
 
@@ -4,8 +4,15 @@ use netidx::{
     chars::Chars,
     config::Config,
     path::Path,
-    protocol::glob::{Glob, GlobSet},
-    resolver_client::{ChangeTracker, DesiredAuth, ResolverRead, ResolverWrite},
+    protocol::{
+        glob::{Glob, GlobSet},
+        resolver::{FromRead, MetadataQuery},
+    },
+    resolver_client::{
+        ChangeTracker, DesiredAuth, Explanation, ResolveTrace, ResolverRead,
+        ResolverWrite,
+    },
+    resolver_server::auth::Permissions,
 };
 use std::{collections::HashSet, iter, net::SocketAddr, time::Duration};
 use structopt::StructOpt;
@@ -32,11 +39,38 @@ pub(super) enum ResolverCmd {
         #[structopt(name = "pattern")]
         path: Option<String>,
     },
+    #[structopt(name = "search", about = "find paths by their metadata")]
+    Search {
+        #[structopt(long = "tag", help = "find paths with the tag")]
+        tag: Option<String>,
+        #[structopt(long = "key", help = "find paths with doc, type, units, or tags")]
+        key: Option<String>,
+        #[structopt(long = "type", help = "find paths with the type hint")]
+        typ: Option<String>,
+        #[structopt(long = "units", help = "find paths with the units")]
+        units: Option<String>,
+        #[structopt(long = "doc", help = "find paths whose doc contains the text")]
+        doc: Option<String>,
+        #[structopt(name = "pattern")]
+        path: Option<String>,
+    },
     #[structopt(name = "table", about = "table descriptor for path")]
     Table {
         #[structopt(name = "path")]
         path: Option<Path>,
     },
+    #[structopt(name = "explain", about = "explain your permissions at path")]
+    Explain {
+        #[structopt(name = "path")]
+        path: Path,
+    },
+    #[structopt(name = "usage", about = "show the resource usage of every publisher")]
+    Usage,
+    #[structopt(name = "trace", about = "show every resolver consulted to resolve path")]
+    Trace {
+        #[structopt(name = "path")]
+        path: Path,
+    },
     #[structopt(name = "add", about = "add a new entry")]
     Add {
         #[structopt(name = "path")]
@@ -53,6 +87,77 @@ pub(super) enum ResolverCmd {
     },
 }
 
+pub(crate) fn print_explanation(e: &Explanation) {
+    let perms = |bits: u32| Permissions::from_bits_truncate(bits).to_string();
+    println!("path: {}", e.path);
+    println!("resolver: {}", e.resolver);
+    match &e.user {
+        None => println!("user: anonymous"),
+        Some(u) => {
+            println!("user: {}", u.name);
+            println!("primary group: {}", u.primary_group);
+            println!("groups: {}", u.groups.join(", "));
+        }
+    }
+    if !e.enforced {
+        println!("permissions are not enforced by this resolver");
+    }
+    println!("effective permissions: {}", perms(e.permissions));
+    for ent in e.entries.iter() {
+        let entity = if ent.entity.is_empty() { "<anonymous>" } else { &ent.entity };
+        print!("{} {}: {}", ent.path, entity, perms(ent.permissions));
+        if ent.granted != 0 {
+            print!(" granted: {}", perms(ent.granted));
+        }
+        if ent.denied != 0 {
+            print!(" denied: {}", perms(ent.denied));
+        }
+        println!()
+    }
+}
+
+fn print_trace(t: &ResolveTrace) {
+    let perms = |bits: u32| Permissions::from_bits_truncate(bits).to_string();
+    println!("path: {}", t.path);
+    println!("desired auth: {:?}", t.desired_auth);
+    for (i, hop) in t.hops.iter().enumerate() {
+        let ttl = match hop.referral.ttl {
+            None => String::from("forever"),
+            Some(ttl) => format!("{}s", ttl),
+        };
+        let source = if hop.cached { "cached" } else { "not cached" };
+        println!("hop {}: {} ttl: {} ({})", i, hop.referral.path, ttl, source);
+        for (addr, auth) in hop.referral.addrs.iter() {
+            println!("    resolver: {} auth: {:?}", addr, auth);
+        }
+        println!("    answered by: {} negotiated auth: {:?}", hop.resolver, hop.auth);
+        match &hop.reply {
+            FromRead::Referral(r) => {
+                let addrs = r.addrs.iter().map(|(a, _)| a.to_string());
+                let addrs = addrs.collect::<Vec<_>>().join(", ");
+                println!("    referral: {} [{}]", r.path, addrs)
+            }
+            FromRead::Resolved(r) => {
+                let ids = r.publishers.iter().map(|p| format!("{:?}", p.id));
+                println!(
+                    "    resolved by: {} flags: {} permissions: {} publishers: [{}]",
+                    r.resolver,
+                    r.flags,
+                    perms(r.permissions),
+                    ids.collect::<Vec<_>>().join(", ")
+                );
+                if let Some(target) = &r.target {
+                    println!("    alias of: {}", target)
+                }
+            }
+            m => println!("    reply: {:?}", m),
+        }
+    }
+    for pb in t.publishers.iter() {
+        println!("publisher: {:?}", pb);
+    }
+}
+
 pub(super) async fn run(
     config: Config,
     auth: DesiredAuth,
@@ -112,6 +217,41 @@ pub(super) async fn run(
                 }
             }
         }
+        ResolverCmd::Search { tag, key, typ, units, doc, path } => {
+            let mut queries = tag
+                .map(|t| MetadataQuery::Tag(Chars::from(t)))
+                .into_iter()
+                .chain(key.map(|k| MetadataQuery::Key(Chars::from(k))))
+                .chain(typ.map(|t| MetadataQuery::Typ(Chars::from(t))))
+                .chain(units.map(|u| MetadataQuery::Units(Chars::from(u))))
+                .chain(doc.map(|d| MetadataQuery::Doc(Chars::from(d))));
+            let query = match (queries.next(), queries.next()) {
+                (Some(q), None) => q,
+                _ => bail!("specify exactly one of --tag, --key, --type, --units, --doc"),
+            };
+            let resolver = ResolverRead::new(config, auth);
+            let pat = path.unwrap_or_else(|| String::from("/**"));
+            let glob = Glob::new(Chars::from(pat)).context("parse pattern")?;
+            let globs = GlobSet::new(true, iter::once(glob)).context("globset")?;
+            let mut matched = resolver.search(&globs, &query).await.context("search")?;
+            matched.sort_by(|(p0, _), (p1, _)| p0.cmp(p1));
+            for (path, md) in matched.iter() {
+                println!("{}", path);
+                if let Some(doc) = &md.doc {
+                    println!("    doc: {}", doc);
+                }
+                if let Some(typ) = &md.typ {
+                    println!("    type: {}", typ);
+                }
+                if let Some(units) = &md.units {
+                    println!("    units: {}", units);
+                }
+                if md.tags.len() > 0 {
+                    let tags = md.tags.iter().map(|t| &**t).collect::<Vec<_>>();
+                    println!("    tags: {}", tags.join(", "));
+                }
+            }
+        }
         ResolverCmd::Table { path } => {
             let resolver = ResolverRead::new(config, auth);
             let path = path.unwrap_or_else(|| Path::from("/"));
@@ -125,6 +265,39 @@ pub(super) async fn run(
                 println!("{}", row);
             }
         }
+        ResolverCmd::Explain { path } => {
+            let resolver = ResolverRead::new(config, auth);
+            let e = resolver.explain(path).await.context("explain")?;
+            print_explanation(&e);
+        }
+        ResolverCmd::Usage => {
+            let resolver = ResolverRead::new(config, auth);
+            let mut usage = resolver.usage().await.context("usage")?;
+            usage.sort_by_key(|u| u.publisher);
+            let limit = |l: Option<u64>| match l {
+                None => String::from("unlimited"),
+                Some(l) => l.to_string(),
+            };
+            for u in usage.iter() {
+                println!(
+                    "{} user: {} published: {} ({}/{} by user) \
+                     default: {} ({}/{} by user)",
+                    u.publisher,
+                    u.user.as_ref().map(|u| u.as_str()).unwrap_or("<anonymous>"),
+                    u.published,
+                    u.user_published,
+                    limit(u.max_published),
+                    u.default_published,
+                    u.user_default_published,
+                    limit(u.max_default_published)
+                )
+            }
+        }
+        ResolverCmd::Trace { path } => {
+            let resolver = ResolverRead::new(config, auth);
+            let t = resolver.trace_resolve(path).await.context("trace")?;
+            print_trace(&t);
+        }
         ResolverCmd::Add { path, socketaddr } => {
             let resolver = ResolverWrite::new(config, auth, socketaddr)
                 .context("create resolver write")?;
@@ -1,13 +1,39 @@
 use anyhow::{Context, Result};
 #[cfg(unix)]
 use daemonize::Daemonize;
-use futures::future;
 #[cfg(unix)]
 use netidx::resolver_server::config::file;
-use netidx::resolver_server::{config::Config, Server};
+use netidx::{
+    path::Path,
+    resolver_server::{
+        auth::{self, Identity},
+        config::Config,
+        Server,
+    },
+};
 #[cfg(unix)]
 use std::fs::File;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::signal;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum ServerCmd {
+    #[structopt(name = "explain", about = "explain the permissions of a user at path")]
+    Explain {
+        #[structopt(long = "user", help = "the user, anonymous if omitted")]
+        user: Option<String>,
+        #[structopt(
+            long = "primary-group",
+            help = "the user's primary group, if omitted use the id map"
+        )]
+        primary_group: Option<String>,
+        #[structopt(long = "group", help = "a group the user is a member of")]
+        groups: Vec<String>,
+        #[structopt(name = "path")]
+        path: Path,
+    },
+}
 
 #[derive(StructOpt, Debug)]
 pub(crate) struct Params {
@@ -27,17 +53,63 @@ pub(crate) struct Params {
         default_value = "0"
     )]
     id: usize,
+    #[structopt(
+        long = "drain-timeout",
+        help = "on shutdown wait this many seconds for clients to move",
+        default_value = "30"
+    )]
+    drain_timeout: u64,
+    #[structopt(subcommand)]
+    cmd: Option<ServerCmd>,
 }
 
 #[tokio::main]
 async fn tokio_run(config: Config, params: Params) -> Result<()> {
-    let _server = Server::new(config, params.delay_reads, params.id)
+    let server = Server::new(config, params.delay_reads, params.id)
         .await
         .context("starting server")?;
-    future::pending::<Result<()>>().await
+    #[cfg(unix)]
+    {
+        use futures::{prelude::*, select_biased};
+        use signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        select_biased! {
+            _ = sigterm.recv().fuse() => (),
+            r = signal::ctrl_c().fuse() => r.context("ctrl-c handler failed")?,
+        }
+    }
+    #[cfg(windows)]
+    signal::ctrl_c().await.context("ctrl-c handler failed")?;
+    server.drain(Duration::from_secs(params.drain_timeout)).await;
+    Ok(())
 }
 
-pub(crate) fn run(params: Params) -> Result<()> {
+#[tokio::main]
+async fn explain(config: Config, id: usize, cmd: ServerCmd) -> Result<()> {
+    match cmd {
+        ServerCmd::Explain { user, primary_group, groups, path } => {
+            let identity = match (user, primary_group) {
+                (None, None) if groups.is_empty() => Identity::Anonymous,
+                (None, _) => bail!("groups may only be specified with a user"),
+                (Some(user), None) if groups.is_empty() => Identity::Mapped(user),
+                (Some(_), None) => bail!("groups require a primary group"),
+                (Some(user), Some(primary_group)) => {
+                    Identity::Explicit { user, primary_group, groups }
+                }
+            };
+            let e = auth::explain(&config, id, &identity, &path).await?;
+            crate::resolver::print_explanation(&e);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn run(mut params: Params) -> Result<()> {
+    if let Some(cmd) = params.cmd.take() {
+        let config = Config::load(params.config.clone())
+            .context("failed to load resolver server config")?;
+        return explain(config, params.id, cmd);
+    }
     #[cfg(unix)]
     {
         if params.foreground {
@@ -0,0 +1,386 @@
+//! A minimal certificate authority for development and testing. It
+//! issues ECDSA P-256 certificates shaped the way netidx expects,
+//! exactly one DNS subjectAltName, and prints the config needed to
+//! use them.
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use netidx::tls::{load_key_password, load_private_key};
+use pkcs8::{
+    der::pem::{self, LineEnding, PemLabel},
+    pkcs5::pbes2,
+    EncryptedPrivateKeyInfo, PrivateKeyInfo,
+};
+use rand::{thread_rng, RngCore};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose as Eku, IsCa, KeyPair, KeyUsagePurpose as Ku, SanType,
+    PKCS_ECDSA_P256_SHA256,
+};
+use serde_json::json;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct Output {
+    #[structopt(short = "d", long = "dir", help = "the directory to write to")]
+    dir: PathBuf,
+    #[structopt(long = "days", help = "how many days the certificate is valid")]
+    days: Option<u32>,
+    #[structopt(long = "encrypt", help = "encrypt the private key")]
+    encrypt: bool,
+    #[structopt(
+        long = "askpass",
+        help = "program to ask for passwords if they aren't in the keychain"
+    )]
+    askpass: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct Issuer {
+    #[structopt(long = "ca", help = "the directory of the certificate authority")]
+    ca: PathBuf,
+    #[structopt(flatten)]
+    out: Output,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Cmd {
+    #[structopt(name = "init-ca", about = "create a development certificate authority")]
+    InitCa {
+        #[structopt(flatten)]
+        out: Output,
+        #[structopt(
+            short = "n",
+            long = "name",
+            help = "the domain of the CA",
+            default_value = "example.com"
+        )]
+        name: String,
+    },
+    #[structopt(name = "issue-resolver", about = "issue a resolver server certificate")]
+    IssueResolver {
+        #[structopt(flatten)]
+        issuer: Issuer,
+        #[structopt(short = "n", long = "name", help = "the resolver's host name")]
+        name: String,
+        #[structopt(
+            short = "a",
+            long = "addr",
+            help = "the resolver server's address",
+            default_value = "127.0.0.1:4564"
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "issue-publisher", about = "issue a publisher certificate")]
+    IssuePublisher {
+        #[structopt(flatten)]
+        issuer: Issuer,
+        #[structopt(short = "n", long = "name", help = "the publisher's host name")]
+        name: String,
+    },
+    #[structopt(name = "issue-user", about = "issue a user certificate")]
+    IssueUser {
+        #[structopt(flatten)]
+        issuer: Issuer,
+        #[structopt(short = "u", long = "user", help = "the user name")]
+        user: String,
+        #[structopt(short = "n", long = "name", help = "the dns name, default user.ca")]
+        name: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Ca,
+    Resolver,
+    Publisher,
+    User,
+}
+
+/// Build a certificate with a new key, common name `cn` and dns
+/// subjectAltName `dns`, signed by `issuer`, or self signed if
+/// `issuer` is None. Returns the DER encoded certificate and the
+/// pkcs8 encoded private key.
+fn certificate(
+    kind: Kind,
+    cn: &str,
+    dns: &str,
+    days: u32,
+    issuer: Option<&Certificate>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let date = |t: DateTime<Utc>| date_time_ymd(t.year(), t.month() as u8, t.day() as u8);
+    let now = Utc::now();
+    let mut params = CertificateParams::default();
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+    params.not_before = date(now - chrono::Duration::days(1));
+    params.not_after = date(now + chrono::Duration::days(days as i64));
+    params.distinguished_name.push(DnType::CommonName, cn);
+    params.subject_alt_names = vec![SanType::DnsName(dns.into())];
+    params.use_authority_key_identifier_extension = issuer.is_some();
+    let (is_ca, key_usages, eku) = match kind {
+        Kind::Ca => (
+            IsCa::Ca(BasicConstraints::Unconstrained),
+            vec![Ku::KeyCertSign, Ku::CrlSign, Ku::DigitalSignature],
+            vec![],
+        ),
+        Kind::Resolver => {
+            (IsCa::ExplicitNoCa, vec![Ku::DigitalSignature], vec![Eku::ServerAuth])
+        }
+        Kind::Publisher => (
+            IsCa::ExplicitNoCa,
+            vec![Ku::DigitalSignature],
+            vec![Eku::ServerAuth, Eku::ClientAuth],
+        ),
+        Kind::User => {
+            (IsCa::ExplicitNoCa, vec![Ku::DigitalSignature], vec![Eku::ClientAuth])
+        }
+    };
+    params.is_ca = is_ca;
+    params.key_usages = key_usages;
+    params.extended_key_usages = eku;
+    let cert = Certificate::from_params(params)?;
+    let der = match issuer {
+        None => cert.serialize_der()?,
+        Some(issuer) => cert.serialize_der_with_signer(issuer)?,
+    };
+    Ok((der, cert.serialize_private_key_der()))
+}
+
+struct Files {
+    certificate: PathBuf,
+    private_key: PathBuf,
+}
+
+impl Files {
+    fn new(dir: &Path) -> Result<Files> {
+        let dir =
+            dir.canonicalize().with_context(|| format!("opening {}", dir.display()))?;
+        Ok(Files {
+            certificate: dir.join("certificate"),
+            private_key: dir.join("private.key"),
+        })
+    }
+
+    /// Files in `dir` for a new certificate, which must not exist
+    fn create(dir: &Path) -> Result<Files> {
+        fs::create_dir_all(dir)?;
+        let files = Files::new(dir)?;
+        for f in [&files.certificate, &files.private_key] {
+            if f.exists() {
+                bail!("{} already exists, refusing to overwrite it", f.display())
+            }
+        }
+        Ok(files)
+    }
+
+    fn write(&self, out: &Output, cert: &[u8], key: &[u8]) -> Result<()> {
+        let key_pem = if out.encrypt {
+            let path = self.private_key.to_string_lossy();
+            let askpass = out.askpass.as_ref().map(|s| s.as_str());
+            let password = load_key_password(askpass, &path)?;
+            let info = PrivateKeyInfo::try_from(key)
+                .map_err(|e| anyhow!("invalid pkcs8 {}", e))?;
+            let mut salt = [0u8; 16];
+            let mut iv = [0u8; 16];
+            thread_rng().fill_bytes(&mut salt);
+            thread_rng().fill_bytes(&mut iv);
+            // pbkdf2 rather than the default scrypt so openssl can
+            // read the key with its default memory limit
+            let params = pbes2::Parameters::pbkdf2_sha256_aes256cbc(600_000, &salt, &iv)
+                .map_err(|e| anyhow!("invalid encryption parameters {}", e))?;
+            let doc = info
+                .encrypt_with_params(params, password.as_bytes())
+                .map_err(|e| anyhow!("failed to encrypt key {}", e))?;
+            doc.to_pem(EncryptedPrivateKeyInfo::PEM_LABEL, LineEnding::LF)
+                .map_err(|e| anyhow!("failed to encode key {}", e))?
+                .to_string()
+        } else {
+            pem::encode_string(PrivateKeyInfo::PEM_LABEL, LineEnding::LF, key)
+                .map_err(|e| anyhow!("failed to encode key {}", e))?
+        };
+        let cert_pem = pem::encode_string("CERTIFICATE", LineEnding::LF, cert)
+            .map_err(|e| anyhow!("failed to encode certificate {}", e))?;
+        #[cfg(unix)]
+        {
+            use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt};
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&self.private_key)?;
+            f.write_all(key_pem.as_bytes())?;
+        }
+        #[cfg(not(unix))]
+        fs::write(&self.private_key, key_pem.as_bytes())?;
+        fs::write(&self.certificate, cert_pem.as_bytes())?;
+        println!("wrote {}", self.certificate.display());
+        println!("wrote {}", self.private_key.display());
+        Ok(())
+    }
+}
+
+struct Ca {
+    files: Files,
+    /// the domain of the CA, e.g. example.com
+    name: String,
+    /// the CA certificate and key, for signing
+    cert: Certificate,
+}
+
+impl Ca {
+    fn load(dir: &Path, askpass: Option<&str>) -> Result<Ca> {
+        let files = Files::new(dir)?;
+        let pem = fs::read(&files.certificate)
+            .with_context(|| format!("reading {}", files.certificate.display()))?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)
+            .map_err(|e| anyhow!("invalid CA certificate {}", e))?;
+        let (_, cert) = X509Certificate::from_der(&pem.contents)
+            .map_err(|e| anyhow!("invalid CA certificate {}", e))?;
+        let name = match cert.subject().iter_common_name().next() {
+            None => bail!("the CA certificate has no common name"),
+            Some(cn) => String::from(cn.as_str()?),
+        };
+        let key = load_private_key(askpass, &files.private_key.to_string_lossy())?;
+        let key = KeyPair::from_der(&key.0)
+            .map_err(|e| anyhow!("invalid CA private key {}", e))?;
+        let params = CertificateParams::from_ca_cert_der(&pem.contents, key)?;
+        let cert = Certificate::from_params(params)?;
+        Ok(Ca { files, name, cert })
+    }
+
+    fn issue(
+        &self,
+        kind: Kind,
+        out: &Output,
+        files: &Files,
+        cn: &str,
+        dns: &str,
+    ) -> Result<()> {
+        let days = out.days.unwrap_or(730);
+        let (cert, key) = certificate(kind, cn, dns, days, Some(&self.cert))?;
+        files.write(out, &cert, &key)
+    }
+
+    fn client_config(&self, files: &Files) -> serde_json::Value {
+        json!({
+            "identities": {
+                &self.name: {
+                    "trusted": self.files.certificate,
+                    "certificate": files.certificate,
+                    "private_key": files.private_key,
+                }
+            }
+        })
+    }
+}
+
+fn print_snippet(what: &str, v: &serde_json::Value) -> Result<()> {
+    println!("\n{}:\n{}", what, serde_json::to_string_pretty(v)?);
+    Ok(())
+}
+
+impl Issuer {
+    /// Load the CA and prepare the output files
+    fn load(&self) -> Result<(Ca, Files)> {
+        let files = Files::create(&self.out.dir)?;
+        let ca = Ca::load(&self.ca, self.out.askpass.as_deref())?;
+        Ok((ca, files))
+    }
+}
+
+pub(crate) fn run(cmd: Cmd) -> Result<()> {
+    match cmd {
+        Cmd::InitCa { out, name } => {
+            let files = Files::create(&out.dir)?;
+            let days = out.days.unwrap_or(3650);
+            let (cert, key) = certificate(Kind::Ca, &name, &name, days, None)?;
+            files.write(&out, &cert, &key)?;
+        }
+        Cmd::IssueResolver { issuer, name, addr } => {
+            let (ca, files) = issuer.load()?;
+            ca.issue(Kind::Resolver, &issuer.out, &files, &name, &name)?;
+            let auth = json!({
+                "Tls": {
+                    "name": name,
+                    "trusted": ca.files.certificate,
+                    "certificate": files.certificate,
+                    "private_key": files.private_key,
+                }
+            });
+            print_snippet("resolver server member \"auth\"", &auth)?;
+            let addrs = json!([[addr, { "Tls": name }]]);
+            print_snippet("client config \"addrs\"", &addrs)?;
+        }
+        Cmd::IssuePublisher { issuer, name } => {
+            let (ca, files) = issuer.load()?;
+            ca.issue(Kind::Publisher, &issuer.out, &files, &name, &name)?;
+            print_snippet("client config \"tls\"", &ca.client_config(&files))?;
+        }
+        Cmd::IssueUser { issuer, user, name } => {
+            let (ca, files) = issuer.load()?;
+            let name = name.unwrap_or_else(|| format!("{}.{}", user, ca.name));
+            ca.issue(Kind::User, &issuer.out, &files, &user, &name)?;
+            print_snippet("client config \"tls\"", &ca.client_config(&files))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x509_parser::{extensions::GeneralName, pem::parse_x509_pem};
+
+    fn output(dir: PathBuf) -> Output {
+        Output { dir, days: None, encrypt: false, askpass: None }
+    }
+
+    fn issuer(dir: &Path, name: &str) -> Issuer {
+        Issuer { ca: dir.join("ca"), out: output(dir.join(name)) }
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        let dir = std::env::temp_dir()
+            .join(format!("netidx-tools-test-tls-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let name = String::from("example.com");
+        run(Cmd::InitCa { out: output(dir.join("ca")), name }).unwrap();
+        let name = String::from("resolver.example.com");
+        let addr = "127.0.0.1:4564".parse().unwrap();
+        run(Cmd::IssueResolver { issuer: issuer(&dir, "resolver"), name, addr }).unwrap();
+        let user = String::from("alice");
+        run(Cmd::IssueUser { issuer: issuer(&dir, "user"), user, name: None }).unwrap();
+        let read = |name: &str| {
+            let pem = fs::read(dir.join(name).join("certificate")).unwrap();
+            parse_x509_pem(&pem).unwrap().1
+        };
+        let ca_pem = read("ca");
+        let ca = ca_pem.parse_x509().unwrap();
+        assert!(ca.is_ca());
+        assert!(ca.verify_signature(None).is_ok());
+        for (name, dns, server, client) in [
+            ("resolver", "resolver.example.com", true, false),
+            ("user", "alice.example.com", false, true),
+        ] {
+            let pem = read(name);
+            let cert = pem.parse_x509().unwrap();
+            assert!(!cert.is_ca());
+            assert_eq!(cert.issuer().as_raw(), ca.subject().as_raw());
+            assert!(cert.verify_signature(Some(ca.public_key())).is_ok());
+            let san = cert.subject_alternative_name().unwrap().unwrap();
+            assert_eq!(san.value.general_names, vec![GeneralName::DNSName(dns)]);
+            let eku = cert.extended_key_usage().unwrap().unwrap();
+            assert_eq!((eku.value.server_auth, eku.value.client_auth), (server, client));
+            let key = dir.join(name).join("private.key");
+            assert!(load_private_key(None, &key.to_string_lossy()).is_ok());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use netidx::token::KeySet;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Cmd {
+    #[structopt(name = "gen-key", about = "generate a new random token signing key")]
+    GenKey,
+    #[structopt(name = "issue", about = "issue a token")]
+    Issue {
+        #[structopt(short = "k", long = "keys", help = "the key set file")]
+        keys: String,
+        #[structopt(long = "key", help = "the key to sign with, signing_key if omitted")]
+        key: Option<String>,
+        #[structopt(short = "u", long = "user", help = "the user the token is for")]
+        user: String,
+        #[structopt(long = "primary-group", help = "the user's primary group")]
+        primary_group: String,
+        #[structopt(short = "g", long = "group", help = "additional groups")]
+        groups: Vec<String>,
+        #[structopt(
+            long = "valid-for",
+            help = "how long the token is valid in seconds",
+            default_value = "86400"
+        )]
+        valid_for: u64,
+    },
+    #[structopt(name = "verify", about = "verify a token and print its claims")]
+    Verify {
+        #[structopt(short = "k", long = "keys", help = "the key set file")]
+        keys: String,
+        #[structopt(name = "token")]
+        token: String,
+    },
+}
+
+pub(crate) fn run(cmd: Cmd) -> Result<()> {
+    match cmd {
+        Cmd::GenKey => println!("{}", KeySet::generate_key()),
+        Cmd::Issue { keys, key, user, primary_group, groups, valid_for } => {
+            let keys = KeySet::load(&keys)?;
+            let valid_for = Duration::from_secs(valid_for);
+            let token = match key {
+                None => keys.issue(&user, &primary_group, &groups, valid_for)?,
+                Some(key) => keys.sign(&netidx::token::Claims {
+                    key,
+                    user,
+                    primary_group,
+                    groups,
+                    expires: chrono::Utc::now() + chrono::Duration::from_std(valid_for)?,
+                })?,
+            };
+            println!("{}", token)
+        }
+        Cmd::Verify { keys, token } => {
+            let claims = KeySet::load(&keys)?.verify(token.as_bytes())?;
+            println!("{}", serde_json::to_string_pretty(&claims)?)
+        }
+    }
+    Ok(())
+}
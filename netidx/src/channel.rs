@@ -102,6 +102,63 @@ pub(crate) async fn read_raw<T: Pack, S: AsyncRead + Unpin>(socket: &mut S) -> R
     Ok(res)
 }
 
+/// The newest protocol version we speak. Version 4 adds coalesced
+/// resolver rate limit errors, resolver drain notices, array patches
+/// from publishers, timestamped heartbeats from subscribers and
+/// publishers, atomic write batches, confirmed publisher migration,
+/// and listing on demand.
+pub(crate) const VERSION: u64 = 4;
+
+/// The oldest protocol version we speak
+pub(crate) const MIN_VERSION: u64 = 3;
+
+/// The peer only speaks an older protocol version. A peer older than
+/// version 4 closes the connection when it is offered a version it
+/// doesn't speak, so the connection must be reestablished offering
+/// this version instead.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OlderPeer(pub(crate) u64);
+
+impl std::fmt::Display for OlderPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "peer speaks protocol version {}", self.0)
+    }
+}
+
+impl std::error::Error for OlderPeer {}
+
+/// Offer `version` to the peer that accepted the connection, and
+/// return the version it chose. If the peer is older than `version`
+/// return an `OlderPeer` error.
+pub(crate) async fn offer_version<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    version: u64,
+) -> Result<u64> {
+    write_raw(socket, &version).await?;
+    let peer: u64 = read_raw(socket).await?;
+    if peer == version {
+        Ok(peer)
+    } else if peer >= MIN_VERSION && peer < version {
+        Err(Error::from(OlderPeer(peer)))
+    } else {
+        bail!("incompatible protocol version {}", peer)
+    }
+}
+
+/// Read the version offered by the peer that made the connection, and
+/// reply with the version we will both use.
+pub(crate) async fn accept_version<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> Result<u64> {
+    let peer: u64 = read_raw(socket).await?;
+    if peer < MIN_VERSION {
+        bail!("incompatible protocol version {}", peer)
+    }
+    let version = std::cmp::min(peer, VERSION);
+    write_raw(socket, &version).await?;
+    Ok(version)
+}
+
 async fn flush_buf<B: Buf, S: AsyncWrite + Send + 'static>(
     soc: &mut WriteHalf<S>,
     buf: B,
@@ -153,6 +210,7 @@ pub(crate) struct WriteChannel {
     to_flush: Sender<BytesMut>,
     buf: BytesMut,
     boundries: Vec<usize>,
+    sent: u64,
 }
 
 impl WriteChannel {
@@ -167,6 +225,7 @@ impl WriteChannel {
             to_flush: flush_task(ctx, socket),
             buf: BytesMut::with_capacity(BUF),
             boundries: Vec::new(),
+            sent: 0,
         }
     }
 
@@ -212,6 +271,11 @@ impl WriteChannel {
         self.buf.remaining()
     }
 
+    /// Return the total number of bytes handed to the socket.
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.sent
+    }
+
     /// Initiate sending all outgoing messages. The actual send will
     /// be done on a background task. If there is sufficient room in
     /// the buffer flush will complete immediately.
@@ -232,8 +296,10 @@ impl WriteChannel {
         while self.buf.has_remaining() {
             let boundry = self.boundries.first().copied().unwrap_or(self.buf.len());
             let chunk = self.buf.split_to(boundry);
+            let len = chunk.len();
             match self.to_flush.try_send(chunk) {
                 Ok(()) => {
+                    self.sent += len as u64;
                     if self.boundries.len() > 0 {
                         self.boundries.remove(0);
                     }
@@ -446,6 +512,7 @@ pub(crate) struct ReadChannel {
     buf: PBuf,
     _stop: oneshot::Sender<()>,
     incoming: stream::Fuse<Receiver<PBuf>>,
+    received: u64,
 }
 
 impl ReadChannel {
@@ -461,12 +528,14 @@ impl ReadChannel {
             buf: PBuf::default(),
             _stop: stop_tx,
             incoming: read_task(stop_rx, socket, k5ctx).fuse(),
+            received: 0,
         }
     }
 
     /// Read a load of bytes from the socket into the read buffer
     pub(crate) async fn fill_buffer(&mut self) -> Result<()> {
         if let Some(chunk) = self.incoming.next().await {
+            self.received += chunk.remaining() as u64;
             self.buf = chunk;
             Ok(())
         } else {
@@ -474,6 +543,11 @@ impl ReadChannel {
         }
     }
 
+    /// Return the total number of bytes read from the socket.
+    pub(crate) fn bytes_received(&self) -> u64 {
+        self.received
+    }
+
     pub(crate) async fn receive<T: Pack + Debug>(&mut self) -> Result<T> {
         if !self.buf.has_remaining() {
             self.fill_buffer().await?;
@@ -1,7 +1,8 @@
 use crate::{
+    chars::Chars,
     path::Path,
     pool::Pooled,
-    protocol::resolver::{Auth, Referral},
+    protocol::resolver::{Auth, Locality, Referral},
     publisher,
     subscriber::DesiredAuth,
     tls, utils,
@@ -31,6 +32,7 @@ pub mod file {
         Krb5(String),
         Local(String),
         Tls(String),
+        Token,
     }
 
     impl Into<crate::protocol::resolver::Auth> for Auth {
@@ -41,6 +43,7 @@ pub mod file {
                 Self::Krb5(spn) => A::Krb5 { spn: Chars::from(spn) },
                 Self::Local(path) => A::Local { path: Chars::from(path) },
                 Self::Tls(name) => A::Tls { name: Chars::from(name) },
+                Self::Token => A::Token,
             }
         }
     }
@@ -49,6 +52,9 @@ pub mod file {
     #[serde(deny_unknown_fields)]
     pub struct TlsIdentity {
         pub trusted: String,
+        /// A file containing certificate revocation lists
+        #[serde(default)]
+        pub crl: Option<String>,
         pub certificate: String,
         pub private_key: String,
     }
@@ -74,6 +80,10 @@ pub mod file {
         pub default_auth: super::DefaultAuthMech,
         #[serde(default)]
         pub default_bind_config: Option<String>,
+        /// Where this machine is, from the most general label to the
+        /// most specific, e.g. ["us-east", "zone-a", "rack-12"]
+        #[serde(default)]
+        pub locality: Vec<String>,
     }
 
     impl Config {
@@ -138,6 +148,7 @@ pub mod file {
 #[derive(Debug, Clone)]
 pub struct TlsIdentity {
     pub trusted: String,
+    pub crl: Option<String>,
     pub name: String,
     pub certificate: String,
     pub private_key: String,
@@ -200,6 +211,11 @@ impl Tls {
             if let Err(e) = tls::load_certs(&id.trusted) {
                 bail!("trusted certs {} cannot be read {}", id.trusted, e)
             }
+            if let Some(crl) = &id.crl {
+                if let Err(e) = tls::load_crls(crl) {
+                    bail!("crl {} cannot be read {}", crl, e)
+                }
+            }
             let dns = match tls::load_certs(&id.certificate) {
                 Err(e) => bail!("certificate can't be read {}", e),
                 Ok(certs) => {
@@ -220,6 +236,7 @@ impl Tls {
                 name,
                 TlsIdentity {
                     trusted: id.trusted,
+                    crl: id.crl,
                     name: dns,
                     certificate: id.certificate,
                     private_key: id.private_key,
@@ -236,6 +253,7 @@ pub enum DefaultAuthMech {
     Local,
     Krb5,
     Tls,
+    Token,
 }
 
 impl Default for DefaultAuthMech {
@@ -251,6 +269,9 @@ pub struct Config {
     pub tls: Option<Tls>,
     pub default_auth: DefaultAuthMech,
     pub default_bind_config: publisher::BindCfg,
+    /// The locality advertised by publishers, and preferred by
+    /// subscribers, see `PublishFlags::PREFER_LOCAL`
+    pub locality: Locality,
 }
 
 impl Config {
@@ -262,7 +283,8 @@ impl Config {
         match cfg.default_auth {
             DefaultAuthMech::Anonymous
             | DefaultAuthMech::Local
-            | DefaultAuthMech::Krb5 => (),
+            | DefaultAuthMech::Krb5
+            | DefaultAuthMech::Token => (),
             DefaultAuthMech::Tls => {
                 if cfg.tls.is_none() {
                     bail!("tls identities require for tls auth")
@@ -277,7 +299,7 @@ impl Config {
             use file::Auth as FAuth;
             utils::check_addr::<()>(addr.ip(), &[])?;
             match auth {
-                FAuth::Anonymous | FAuth::Krb5(_) => (),
+                FAuth::Anonymous | FAuth::Krb5(_) | FAuth::Token => (),
                 FAuth::Tls(name) => match &tls {
                     None => bail!("tls auth requires a valid tls configuration"),
                     Some(tls) => {
@@ -312,6 +334,7 @@ impl Config {
                 None => publisher::BindCfg::default(),
                 Some(s) => s.parse()?,
             },
+            locality: Locality::new(cfg.locality.into_iter().map(Chars::from)),
         })
     }
 
@@ -321,6 +344,7 @@ impl Config {
             DefaultAuthMech::Local => DesiredAuth::Local,
             DefaultAuthMech::Krb5 => DesiredAuth::Krb5 { upn: None, spn: None },
             DefaultAuthMech::Tls => DesiredAuth::Tls { identity: None },
+            DefaultAuthMech::Token => DesiredAuth::Token { token: None },
         }
     }
 
@@ -119,5 +119,9 @@ pub mod publisher;
 pub mod resolver_client;
 pub mod resolver_server;
 pub mod subscriber;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod token;
+mod transport;
 #[cfg(test)]
 mod test;
@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Result};
+use arcstr::ArcStr;
+use fxhash::FxHashMap;
+use log::warn;
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::fs;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    primary_group: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    uid: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    groups: FxHashMap<ArcStr, (ArcStr, Vec<ArcStr>)>,
+    uids: FxHashMap<u32, ArcStr>,
+}
+
+impl Table {
+    fn parse(path: &Path, s: &str) -> Result<Table> {
+        let entries: HashMap<String, Entry> =
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("toml") => toml::from_str(s)?,
+                _ => serde_json::from_str(s)?,
+            };
+        let mut t = Table::default();
+        for (user, e) in entries {
+            let user = ArcStr::from(user);
+            if let Some(uid) = e.uid {
+                if t.uids.insert(uid, user.clone()).is_some() {
+                    bail!("uid {} is assigned to more than one user", uid)
+                }
+            }
+            let primary = ArcStr::from(e.primary_group);
+            let groups = e.groups.into_iter().map(ArcStr::from).collect();
+            t.groups.insert(user, (primary, groups));
+        }
+        Ok(t)
+    }
+}
+
+/// A static user to groups table read from a json or toml file. The
+/// file is reloaded when it's modification time changes, if the new
+/// file fails to parse the previous table is kept.
+///
+/// e.g.
+/// ```json
+/// {
+///   "eric": { "primary_group": "eric", "groups": ["eric", "wheel"], "uid": 1000 }
+/// }
+/// ```
+pub(crate) struct FileMap {
+    path: PathBuf,
+    table: RwLock<(Option<SystemTime>, Arc<Table>)>,
+}
+
+impl FileMap {
+    pub(crate) async fn new(path: &str) -> Result<FileMap> {
+        let path = PathBuf::from(path);
+        let modified = fs::metadata(&path).await?.modified()?;
+        let table = Table::parse(&path, &fs::read_to_string(&path).await?)?;
+        Ok(FileMap { path, table: RwLock::new((Some(modified), Arc::new(table))) })
+    }
+
+    async fn table(&self) -> Arc<Table> {
+        let modified = match fs::metadata(&self.path).await.and_then(|m| m.modified()) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                warn!("failed to stat id map file {:?}: {}", self.path, e);
+                None
+            }
+        };
+        let (last, table) = {
+            let t = self.table.read();
+            (t.0, Arc::clone(&t.1))
+        };
+        if modified.is_none() || modified == last {
+            return table;
+        }
+        let res = fs::read_to_string(&self.path)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Table::parse(&self.path, &s));
+        let mut t = self.table.write();
+        match res {
+            Ok(table) => *t = (modified, Arc::new(table)),
+            Err(e) => {
+                warn!("failed to reload id map file {:?}: {}", self.path, e);
+                t.0 = modified;
+            }
+        }
+        Arc::clone(&t.1)
+    }
+
+    pub(crate) async fn groups(&self, user: &str) -> Result<(ArcStr, Vec<ArcStr>)> {
+        self.table()
+            .await
+            .groups
+            .get(user)
+            .cloned()
+            .ok_or_else(|| anyhow!("user {} not found in {:?}", user, self.path))
+    }
+
+    #[cfg(unix)]
+    pub(crate) async fn user(&self, uid: u32) -> Result<ArcStr> {
+        self.table()
+            .await
+            .uids
+            .get(&uid)
+            .cloned()
+            .ok_or_else(|| anyhow!("uid {} not found in {:?}", uid, self.path))
+    }
+}
@@ -1,5 +1,7 @@
 use anyhow::Result;
 
+pub(crate) mod id_file;
+
 #[cfg(unix)]
 pub(crate) mod unix;
 
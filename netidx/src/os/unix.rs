@@ -1,22 +1,26 @@
+use super::id_file::FileMap;
 use crate::resolver_server::config::{Config, IdMap, MemberServer};
 use anyhow::{anyhow, Result};
 use arcstr::ArcStr;
+use std::sync::Arc;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
     process::Command,
+    task,
 };
 
 // Unix group membership is a little complex, it can come from a
 // lot of places, and it's not entirely standardized at the api
-// level, it seems libc provides getgrouplist on most platforms,
-// but unfortunatly Apple doesn't implement it. Luckily the 'id'
-// command is specified in POSIX.
+// level. libc provides getgrouplist on the platforms we support,
+// and the 'id' command is specified in POSIX.
 #[derive(Clone)]
 pub(crate) enum Mapper {
     DoNotMap,
     Socket(ArcStr),
     Command(ArcStr),
+    File(Arc<FileMap>),
+    GetGroupList,
 }
 
 impl Mapper {
@@ -25,6 +29,8 @@ impl Mapper {
             IdMap::DoNotMap => Ok(Mapper::DoNotMap),
             IdMap::Command(cmd) => Ok(Mapper::Command(ArcStr::from(cmd))),
             IdMap::Socket(path) => Ok(Mapper::Socket(ArcStr::from(path))),
+            IdMap::File(path) => Ok(Mapper::File(Arc::new(FileMap::new(path).await?))),
+            IdMap::GetGroupList => Ok(Mapper::GetGroupList),
             IdMap::PlatformDefault => {
                 let out = Command::new("sh").arg("-c").arg("which id").output().await?;
                 let buf = String::from_utf8_lossy(&out.stdout);
@@ -61,6 +67,11 @@ impl Mapper {
                 sock.read_to_end(&mut reply).await?;
                 parse(String::from_utf8_lossy(&reply).as_ref())
             }
+            Mapper::File(map) => map.groups(user).await,
+            Mapper::GetGroupList => {
+                let user = String::from(user);
+                task::spawn_blocking(move || getgrouplist::groups(&user)).await?
+            }
         }
     }
 
@@ -86,6 +97,10 @@ impl Mapper {
                 sock.read_to_end(&mut reply).await?;
                 parse(String::from_utf8_lossy(&reply).as_ref())
             }
+            Mapper::File(map) => map.user(user).await,
+            Mapper::GetGroupList => {
+                task::spawn_blocking(move || getgrouplist::user(user)).await?
+            }
         }
     }
 
@@ -114,6 +129,141 @@ impl Mapper {
     }
 }
 
+// Look up users and groups in process using the reentrant libc
+// functions, this consults whatever nss is configured, and so
+// sees the same groups as id, but without forking.
+mod getgrouplist {
+    use anyhow::Result;
+    use arcstr::ArcStr;
+    use std::{
+        ffi::{CStr, CString},
+        mem::MaybeUninit,
+        ptr,
+    };
+
+    const MAX_BUF: usize = 1024 * 1024;
+
+    // call one of the *_r functions, growing the buffer until
+    // it's big enough
+    fn with_buf<T, F>(mut f: F) -> Result<Option<T>>
+    where
+        F: FnMut(&mut [libc::c_char]) -> Result<Option<T>, i32>,
+    {
+        let mut buf = vec![0 as libc::c_char; 1024];
+        loop {
+            match f(&mut buf) {
+                Ok(r) => break Ok(r),
+                Err(libc::ERANGE) if buf.len() < MAX_BUF => buf.resize(buf.len() * 2, 0),
+                Err(e) => bail!("lookup failed {}", std::io::Error::from_raw_os_error(e)),
+            }
+        }
+    }
+
+    fn passwd_by_name(name: &CStr) -> Result<Option<(libc::uid_t, libc::gid_t)>> {
+        with_buf(|buf| unsafe {
+            let mut pw = MaybeUninit::<libc::passwd>::uninit();
+            let mut res = ptr::null_mut();
+            let e = libc::getpwnam_r(
+                name.as_ptr(),
+                pw.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut res,
+            );
+            if e != 0 {
+                Err(e)
+            } else if res.is_null() {
+                Ok(None)
+            } else {
+                let pw = pw.assume_init();
+                Ok(Some((pw.pw_uid, pw.pw_gid)))
+            }
+        })
+    }
+
+    fn group_name(gid: libc::gid_t) -> Result<ArcStr> {
+        let name = with_buf(|buf| unsafe {
+            let mut gr = MaybeUninit::<libc::group>::uninit();
+            let mut res = ptr::null_mut();
+            let e = libc::getgrgid_r(
+                gid,
+                gr.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut res,
+            );
+            if e != 0 {
+                Err(e)
+            } else if res.is_null() {
+                Ok(None)
+            } else {
+                let gr = gr.assume_init();
+                Ok(Some(ArcStr::from(&*CStr::from_ptr(gr.gr_name).to_string_lossy())))
+            }
+        })?;
+        // like id, fall back to the numeric gid if the group has no name
+        Ok(name.unwrap_or_else(|| ArcStr::from(gid.to_string())))
+    }
+
+    pub(super) fn groups(user: &str) -> Result<(ArcStr, Vec<ArcStr>)> {
+        let name = CString::new(user)?;
+        let gid = match passwd_by_name(&name)? {
+            None => bail!("user {} not found", user),
+            Some((_, gid)) => gid,
+        };
+        let mut gids: Vec<libc::gid_t> = vec![0; 64];
+        loop {
+            let mut n = gids.len() as libc::c_int;
+            let r = unsafe {
+                libc::getgrouplist(
+                    name.as_ptr(),
+                    gid as _,
+                    gids.as_mut_ptr() as _,
+                    &mut n,
+                )
+            };
+            if r >= 0 {
+                gids.truncate(n as usize);
+                break;
+            } else if gids.len() < 65536 {
+                let len = std::cmp::max(n as usize, gids.len() * 2);
+                gids.resize(len, 0);
+            } else {
+                bail!("user {} is a member of too many groups", user)
+            }
+        }
+        let primary = group_name(gid)?;
+        let groups = gids.into_iter().map(group_name).collect::<Result<Vec<_>>>()?;
+        Ok((primary, groups))
+    }
+
+    pub(super) fn user(uid: u32) -> Result<ArcStr> {
+        let name = with_buf(|buf| unsafe {
+            let mut pw = MaybeUninit::<libc::passwd>::uninit();
+            let mut res = ptr::null_mut();
+            let e = libc::getpwuid_r(
+                uid,
+                pw.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut res,
+            );
+            if e != 0 {
+                Err(e)
+            } else if res.is_null() {
+                Ok(None)
+            } else {
+                let pw = pw.assume_init();
+                Ok(Some(ArcStr::from(&*CStr::from_ptr(pw.pw_name).to_string_lossy())))
+            }
+        })?;
+        match name {
+            None => bail!("user not found"),
+            Some(name) => Ok(name),
+        }
+    }
+}
+
 pub(crate) mod local_auth {
     use super::Mapper;
     use crate::{
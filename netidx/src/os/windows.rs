@@ -1,7 +1,9 @@
+use super::id_file::FileMap;
 use crate::resolver_server::config::{Config, IdMap, MemberServer};
 use anyhow::bail;
 use anyhow::{anyhow, Result};
 use arcstr::ArcStr;
+use std::sync::Arc;
 use tokio::process::Command;
 
 // Unix group membership is a little complex, it can come from a
@@ -13,6 +15,7 @@ use tokio::process::Command;
 pub(crate) enum Mapper {
     DoNotMap,
     Command(ArcStr),
+    File(Arc<FileMap>),
 }
 
 impl Mapper {
@@ -21,6 +24,8 @@ impl Mapper {
             IdMap::DoNotMap => Ok(Mapper::DoNotMap),
             IdMap::Command(cmd) => Ok(Mapper::Command(ArcStr::from(cmd))),
             IdMap::Socket(_) => bail!("id-map sockets are not supported on windows"),
+            IdMap::File(path) => Ok(Mapper::File(Arc::new(FileMap::new(path).await?))),
+            IdMap::GetGroupList => bail!("getgrouplist is not supported on windows"),
             IdMap::PlatformDefault => Ok(Mapper::DoNotMap),
         }
     }
@@ -42,6 +47,7 @@ impl Mapper {
                 let out = Command::new(&**cmd).arg(user).output().await?;
                 parse(String::from_utf8_lossy(&out.stdout).as_ref())
             },
+            Mapper::File(map) => map.groups(user).await,
         }
     }
 
@@ -0,0 +1,152 @@
+use super::{
+    ClId, Publisher, PublisherWeak, UpdateBatch, Val, Value, BATCH, BATCHES, RAWBATCH,
+    UPDATES,
+};
+use crate::{chars::Chars, path::Path};
+use fxhash::{FxHashMap, FxHashSet};
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio::{task, time};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+struct ClientMetrics {
+    id: ClId,
+    user: Option<Chars>,
+    queued: usize,
+    subscribed: usize,
+}
+
+struct Metrics {
+    base: Path,
+    published: FxHashMap<Path, (Val, Value)>,
+    live: FxHashSet<Path>,
+    last_updates: u64,
+    // updates to the metrics themselves, excluded from the rate
+    own_updates: u64,
+    last_tick: Instant,
+}
+
+impl Metrics {
+    fn set(&mut self, pb: &Publisher, batch: &mut UpdateBatch, path: Path, v: Value) {
+        match self.published.get_mut(&path) {
+            Some((val, cur)) => {
+                if *cur != v {
+                    self.own_updates += 1;
+                    val.update(batch, v.clone());
+                    *cur = v;
+                }
+            }
+            None => match pb.publish(path.clone(), v.clone()) {
+                Ok(val) => {
+                    self.published.insert(path.clone(), (val, v));
+                }
+                Err(e) => warn!("failed to publish metric {}: {}", path, e),
+            },
+        }
+        self.live.insert(path);
+    }
+
+    async fn tick(&mut self, pb: &Publisher) {
+        let (updates, writes, writes_denied, flush_timeouts, clients, resolver) = {
+            let pb = pb.0.lock();
+            let clients = pb
+                .clients
+                .iter()
+                .map(|(id, cl)| ClientMetrics {
+                    id: *id,
+                    user: cl.user.as_ref().map(|u| Chars::from(u.name.clone())),
+                    queued: cl.queued.load(Ordering::Relaxed),
+                    subscribed: cl.subscribed.len(),
+                })
+                .collect::<Vec<_>>();
+            (
+                pb.stats.updates,
+                pb.stats.writes,
+                pb.stats.writes_denied,
+                pb.stats.flush_timeouts,
+                clients,
+                pb.resolver.clone(),
+            )
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_tick).as_secs_f64();
+        let app_updates = (updates - self.last_updates).saturating_sub(self.own_updates);
+        let rate = app_updates as f64 / elapsed;
+        self.last_tick = now;
+        self.last_updates = updates;
+        self.own_updates = 0;
+        let base = self.base.clone();
+        let mut batch = pb.start_batch();
+        let nclients = Value::U64(clients.len() as u64);
+        self.set(pb, &mut batch, base.append("clients"), nclients);
+        self.set(pb, &mut batch, base.append("updates-per-second"), Value::F64(rate));
+        self.set(pb, &mut batch, base.append("writes"), Value::U64(writes));
+        let denied = Value::U64(writes_denied);
+        self.set(pb, &mut batch, base.append("writes-denied"), denied);
+        self.set(
+            pb,
+            &mut batch,
+            base.append("flush-timeouts"),
+            Value::U64(flush_timeouts),
+        );
+        for cl in clients {
+            let path = base.append("client").append(&cl.id.inner().to_string());
+            let user = cl.user.map(Value::String).unwrap_or(Value::Null);
+            self.set(pb, &mut batch, path.append("user"), user);
+            let queued = Value::U64(cl.queued as u64);
+            self.set(pb, &mut batch, path.append("queued-bytes"), queued);
+            self.set(
+                pb,
+                &mut batch,
+                path.append("subscribed"),
+                Value::U64(cl.subscribed as u64),
+            );
+        }
+        for (addr, latency) in resolver.latency() {
+            let path = base.append("resolver-rtt").append(&addr.to_string());
+            self.set(pb, &mut batch, path, Value::Duration(latency));
+        }
+        let pools = [
+            ("write-batches", BATCHES.len(), BATCHES.capacity()),
+            ("update-batches", RAWBATCH.len(), RAWBATCH.capacity()),
+            ("updates", UPDATES.len(), UPDATES.capacity()),
+            ("client-batches", BATCH.len(), BATCH.capacity()),
+        ];
+        for (name, len, cap) in pools {
+            let path = base.append("pools").append(name);
+            self.set(pb, &mut batch, path.append("idle"), Value::U64(len as u64));
+            self.set(pb, &mut batch, path.append("capacity"), Value::U64(cap as u64));
+        }
+        let live = &self.live;
+        self.published.retain(|p, _| live.contains(p));
+        self.live.clear();
+        batch.commit(None).await
+    }
+}
+
+pub(super) fn start(publisher: &Publisher, base: Path) {
+    let publisher: PublisherWeak = publisher.downgrade();
+    let mut metrics = Metrics {
+        base,
+        published: HashMap::default(),
+        live: HashSet::default(),
+        last_updates: 0,
+        own_updates: 0,
+        last_tick: Instant::now(),
+    };
+    task::spawn(async move {
+        let mut interval = time::interval(INTERVAL);
+        loop {
+            interval.tick().await;
+            match publisher.upgrade() {
+                None => break,
+                Some(pb) => metrics.tick(&pb).await,
+            }
+        }
+    });
+}
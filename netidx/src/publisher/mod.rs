@@ -1,9 +1,13 @@
+mod metrics;
 mod server;
+mod snapshot;
 pub use crate::protocol::{
-    publisher::Id,
+    publisher::{Id, Patch, WriteDenied},
+    resolver::{Load, Locality, Metadata},
     value::{FromValue, Typ, Value},
 };
 pub use crate::resolver_client::DesiredAuth;
+pub(crate) use server::answer_ownership_checks;
 use crate::{
     config::Config,
     path::Path,
@@ -11,7 +15,7 @@ use crate::{
     protocol::{publisher, resolver::UserInfo},
     resolver_client::ResolverWrite,
     resolver_server::auth::Permissions,
-    tls,
+    tls, transport,
     utils::{self, ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
@@ -35,13 +39,14 @@ use std::{
     default::Default,
     iter, mem,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     pin::Pin,
     result,
     str::FromStr,
-    sync::{Arc, Weak},
+    sync::{atomic::AtomicUsize, Arc, Weak},
     time::Duration, fmt,
 };
-use tokio::{net::TcpListener, task};
+use tokio::{net::TcpListener, task, time};
 
 /// Control how the publisher picks a bind address. The address we
 /// give to the resolver server must be uniquely routable back to us,
@@ -292,11 +297,21 @@ impl BindCfg {
 
 atomic_id!(ClId);
 
+// arrays shorter than this are always sent whole
+const PATCH_MIN_LEN: usize = 64;
+
+// the first protocol version that understands patches
+const PATCH_VERSION: u64 = 4;
+
+// the first protocol version that understands migration
+const MIGRATE_VERSION: u64 = 4;
+
 lazy_static! {
     static ref BATCHES: Pool<Vec<WriteRequest>> = Pool::new(100, 10_000);
     static ref TOPUB: Pool<HashMap<Path, Option<u32>>> = Pool::new(10, 10_000);
     static ref TOUPUB: Pool<HashSet<Path>> = Pool::new(5, 10_000);
     static ref TOUSUB: Pool<HashMap<Id, Subscribed>> = Pool::new(5, 10_000);
+    static ref TOMD: Pool<HashMap<Path, Metadata>> = Pool::new(5, 10_000);
     static ref RAWBATCH: Pool<Vec<BatchMsg>> = Pool::new(100, 10_000);
     static ref UPDATES: Pool<Vec<publisher::From>> = Pool::new(100, 10_000);
     static ref RAWUNSUBS: Pool<Vec<(ClId, Id)>> = Pool::new(100, 10_000);
@@ -382,12 +397,14 @@ bitflags! {
         ///
         /// - on the same host
         /// - in the same subnet
+        /// - sharing the most leading locality labels, see `Config::locality`
         /// - any
         ///
         /// Meaning a subsciber with this flag set will first try the
         /// publisher on the local machine, and if that fails, then it
-        /// will try the publisher in the same subnet with it, and only
-        /// if that fails will it try all the other publishers.
+        /// will try the publisher in the same subnet with it, then the
+        /// publishers in the same rack, zone, or region, and only if
+        /// that fails will it try all the other publishers.
         const PREFER_LOCAL = 0x08;
 
         /// This is the same as PREFER_LOCAL except when mixed with
@@ -396,6 +413,26 @@ bitflags! {
         /// to the same publisher then do not use this flag. e.g. do
         /// not use this flag for rpcs.
         const FORCE_LOCAL = 0x10;
+
+        /// If the subscriber has a choice between publishers, it
+        /// will balance subscriptions between them according to it's
+        /// `Balance` policy, using the weight and load each publisher
+        /// advertises, instead of choosing uniformly at random. See
+        /// `Publisher::set_load`.
+        ///
+        /// Combined with USE_EXISTING an existing connection is
+        /// still preferred, the policy only decides which publisher
+        /// to connect to when there is no existing connection. This
+        /// keeps rpc parameters on one publisher while spreading
+        /// callers between publishers.
+        const LOAD_BALANCE = 0x20;
+
+        /// Only meaningful for default publishers. Before it answers
+        /// a listing of a path under the default publisher the
+        /// resolver server asks the publisher to advertise the
+        /// children of the path, see `DefaultHandle::list_requests`.
+        /// A publisher that is slow to answer slows down listings.
+        const LIST_ON_DEMAND = 0x40;
     }
 }
 
@@ -427,6 +464,11 @@ pub struct WriteRequest {
     /// the value being written
     pub value: Value,
     pub send_result: Option<SendResult>,
+    /// true if this write is part of an atomic write batch, see
+    /// `Subscriber::write_batch`. The writes in an atomic batch are
+    /// delivered alone in one `Pooled<Vec<WriteRequest>>`, and they
+    /// all share the same `send_result`.
+    pub atomic: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -471,6 +513,23 @@ impl fmt::Debug for ExtendedAuthWrap {
     }
 }
 
+/// Write authorization hook
+pub type WriteAuth = Box<
+    dyn Fn(&WriteRequest, Option<&UserInfo>) -> result::Result<(), WriteDenied>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+#[repr(transparent)]
+struct WriteAuthWrap(WriteAuth);
+
+impl fmt::Debug for WriteAuthWrap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "<Fn>")
+    }
+}
+
 /// This represents a published value. When it is dropped the value
 /// will be unpublished.
 pub struct Val(Id);
@@ -500,6 +559,9 @@ impl Val {
     ///
     /// Clients that subscribe after an update is queued, but before
     /// the batch is committed will still receive the update.
+    ///
+    /// If both the current and the new value are large arrays only
+    /// the changed elements may be sent, see `Patch`.
     pub fn update<T: Into<Value>>(&self, batch: &mut UpdateBatch, v: T) {
         batch.updates.push(BatchMsg::Update(None, self.0, v.into()))
     }
@@ -682,6 +744,19 @@ impl DefaultHandle {
             }
         }
     }
+
+    /// Register `tx` to receive the list requests of a default
+    /// publisher published with `PublishFlags::LIST_ON_DEMAND`. Each
+    /// request is for a path at or under the base path. Advertise
+    /// the children of the path, and then send () to the oneshot
+    /// channel, the publisher will tell the resolver server once the
+    /// advertisements have reached it. Without a registered channel
+    /// list requests are answered immediately.
+    pub fn list_requests(&self, tx: UnboundedSender<(Path, oneshot::Sender<()>)>) {
+        if let Some(pb) = self.publisher.upgrade() {
+            pb.0.lock().listers.insert(self.path.clone(), tx);
+        }
+    }
 }
 
 impl Drop for DefaultHandle {
@@ -689,6 +764,7 @@ impl Drop for DefaultHandle {
         if let Some(t) = self.publisher.upgrade() {
             let mut pb = t.0.lock();
             pb.default.remove(self.path.as_ref());
+            pb.listers.remove(self.path.as_ref());
             pb.to_unpublish_default.insert(self.path.clone());
             if let Some(paths) = pb.advertised.remove(&self.path) {
                 for path in paths {
@@ -759,42 +835,57 @@ impl UpdateBatch {
         }
         let fut = {
             let mut batch = BATCH.take();
-            let mut pb = self.origin.0.lock();
+            let mut updates = 0;
+            let mut guard = self.origin.0.lock();
+            let pb = &mut *guard;
             for m in self.updates.drain(..) {
                 match m {
                     BatchMsg::Update(None, id, v) => {
                         if let Some(pbl) = pb.by_id.get_mut(&id) {
+                            updates += 1;
+                            pb.stale.remove(&id);
+                            let m = pbl.update_msg(id, pb.diverged.remove(&id), &v);
                             for cl in pbl.subscribed.iter() {
                                 batch
                                     .entry(*cl)
                                     .or_insert_with(Update::new)
                                     .updates
-                                    .push(publisher::From::Update(id, v.clone()));
+                                    .push(m.get(pb.clients.get(cl)));
                             }
                             pbl.current = v;
                         }
                     }
                     BatchMsg::UpdateChanged(id, v) => {
                         if let Some(pbl) = pb.by_id.get_mut(&id) {
+                            pb.stale.remove(&id);
                             if pbl.current != v {
+                                updates += 1;
+                                let m = pbl.update_msg(id, pb.diverged.remove(&id), &v);
                                 for cl in pbl.subscribed.iter() {
                                     batch
                                         .entry(*cl)
                                         .or_insert_with(Update::new)
                                         .updates
-                                        .push(publisher::From::Update(id, v.clone()));
+                                        .push(m.get(pb.clients.get(cl)));
                                 }
                                 pbl.current = v;
                             }
                         }
                     }
-                    BatchMsg::Update(Some(cl), id, v) => batch
-                        .entry(cl)
-                        .or_insert_with(Update::new)
-                        .updates
-                        .push(publisher::From::Update(id, v)),
+                    BatchMsg::Update(Some(cl), id, v) => {
+                        updates += 1;
+                        if pb.by_id.contains_key(&id) {
+                            pb.diverged.insert(id);
+                        }
+                        batch
+                            .entry(cl)
+                            .or_insert_with(Update::new)
+                            .updates
+                            .push(publisher::From::Update(id, v))
+                    }
                 }
             }
+            pb.stats.updates += updates;
             if let Some(usubs) = &mut self.unsubscribes {
                 for (cl, id) in usubs.drain(..) {
                     let update = batch.entry(cl).or_insert_with(Update::new);
@@ -828,6 +919,19 @@ struct Client {
     msg_queue: MsgQ,
     subscribed: FxHashMap<Id, Permissions>,
     user: Option<UserInfo>,
+    queued: Arc<AtomicUsize>,
+    // the protocol version negotiated with the client
+    version: u64,
+    // the client has moved it's durable subscriptions away
+    migrated: bool,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    updates: u64,
+    writes: u64,
+    writes_denied: u64,
+    flush_timeouts: u64,
 }
 
 #[derive(Debug)]
@@ -838,7 +942,43 @@ pub struct Published {
     aliases: Option<Box<FxHashSet<Path>>>,
 }
 
+// The messages that bring subscribers from the current value of a
+// published value to a new one
+struct UpdateMsg {
+    full: publisher::From,
+    // sent instead of full to clients that understand patches
+    patch: Option<publisher::From>,
+}
+
+impl UpdateMsg {
+    fn get(&self, client: Option<&Client>) -> publisher::From {
+        match (&self.patch, client) {
+            (Some(patch), Some(cl)) if cl.version >= PATCH_VERSION => patch.clone(),
+            (_, _) => self.full.clone(),
+        }
+    }
+}
+
 impl Published {
+    // Large arrays are patched if less than half of their elements
+    // changed, unless some subscriber was sent it's own value since
+    // the last full update (`diverged`). Elements are assumed to be
+    // about the same size, so the patch doesn't have to be encoded to
+    // decide.
+    fn update_msg(&self, id: Id, diverged: bool, v: &Value) -> UpdateMsg {
+        let full = publisher::From::Update(id, v.clone());
+        let patch = match (&self.current, v) {
+            (_, _) if diverged => None,
+            (Value::Array(_), Value::Array(a)) if a.len() >= PATCH_MIN_LEN => {
+                Patch::diff(&self.current, v)
+                    .filter(|p| p.changes.len() * 2 < a.len())
+                    .map(|p| publisher::From::Patch(id, Arc::new(p)))
+            }
+            (_, _) => None,
+        };
+        UpdateMsg { full, patch }
+    }
+
     pub fn current(&self) -> &Value {
         &self.current
     }
@@ -861,23 +1001,33 @@ struct PublisherInner {
     by_path: HashMap<Path, Id>,
     by_id: FxHashMap<Id, Published>,
     destroy_on_idle: FxHashSet<Id>,
+    diverged: FxHashSet<Id>,
     on_write_chans: FxHashMap<ChanWrap<Pooled<Vec<WriteRequest>>>, (ChanId, HashSet<Id>)>,
     on_event_chans: Vec<UnboundedSender<Event>>,
     on_event_by_id_chans: FxHashMap<Id, Vec<UnboundedSender<Event>>>,
     extended_auth: Option<ExtendedAuthWrap>,
+    write_auth: Option<WriteAuthWrap>,
     on_write: FxHashMap<Id, Vec<(ChanId, Sender<Pooled<Vec<WriteRequest>>>)>>,
     resolver: ResolverWrite,
+    load: Option<Load>,
+    load_changed: bool,
     advertised: HashMap<Path, HashSet<Path>>,
     to_publish: Pooled<HashMap<Path, Option<u32>>>,
     to_publish_default: Pooled<HashMap<Path, Option<u32>>>,
     to_unpublish: Pooled<HashSet<Path>>,
     to_unpublish_default: Pooled<HashSet<Path>>,
     to_unsubscribe: Pooled<HashMap<Id, Subscribed>>,
+    to_set_metadata: Pooled<HashMap<Path, Metadata>>,
     publish_triggered: bool,
     trigger_publish: UnboundedSender<Option<oneshot::Sender<()>>>,
     wait_clients: FxHashMap<Id, Vec<oneshot::Sender<()>>>,
     wait_any_client: Vec<oneshot::Sender<()>>,
     default: BTreeMap<Path, UnboundedSender<(Path, oneshot::Sender<()>)>>,
+    listers: BTreeMap<Path, UnboundedSender<(Path, oneshot::Sender<()>)>>,
+    stats: Stats,
+    restored: HashMap<Path, Id>,
+    stale: FxHashSet<Id>,
+    snapshot: Option<oneshot::Sender<()>>,
 }
 
 impl PublisherInner {
@@ -888,6 +1038,7 @@ impl PublisherInner {
                 let _ = stop.send(());
                 self.clients.clear();
                 self.by_id.clear();
+                self.snapshot = None;
                 true
             }
         }
@@ -926,6 +1077,7 @@ impl PublisherInner {
         self.by_path.remove(path);
         if !self.is_advertised(path) {
             self.to_publish.remove(path);
+            self.to_set_metadata.remove(path);
             self.to_unpublish.insert(path.clone());
             self.trigger_publish();
         }
@@ -939,6 +1091,8 @@ impl PublisherInner {
                 self.unpublish(path)
             }
             self.wait_clients.remove(&id);
+            self.stale.remove(&id);
+            self.diverged.remove(&id);
             if let Some(chans) = self.on_write.remove(&id) {
                 for (_, c) in chans {
                     match self.on_write_chans.entry(ChanWrap(c)) {
@@ -1033,6 +1187,50 @@ fn rand_port(current: u16) -> u16 {
     current + rng.gen_range(0u16..10u16)
 }
 
+/// Bind a listener according to `bind_cfg`, returning the address to
+/// advertise to the resolver `resolver` and the listener.
+pub(crate) async fn bind(
+    bind_cfg: &BindCfg,
+    resolver: &Config,
+) -> Result<(SocketAddr, TcpListener)> {
+    let (public, private) = bind_cfg.select()?;
+    utils::check_addr(public, &resolver.addrs)?;
+    Ok(match bind_cfg {
+        BindCfg::Exact(addr) => {
+            let l = TcpListener::bind(addr).await?;
+            (l.local_addr()?, l)
+        }
+        BindCfg::ElasticExact { public, private } => {
+            let l = TcpListener::bind(private).await?;
+            (*public, l)
+        }
+        BindCfg::Match { .. } | BindCfg::Local | BindCfg::Elastic { .. } => {
+            let mkaddr = |ip: IpAddr, port: u16| -> Result<SocketAddr> {
+                Ok((ip, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("socketaddrs bug"))?)
+            };
+            let mut port = 5000;
+            loop {
+                if port >= 32768 {
+                    bail!("couldn't allocate a port");
+                }
+                port = rand_port(port);
+                let addr = mkaddr(private, port)?;
+                match TcpListener::bind(&addr).await {
+                    Ok(l) => break (mkaddr(public, port)?, l),
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::AddrInUse {
+                            bail!(e)
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
 #[derive(Debug)]
 pub struct PublisherBuilder {
     config: Option<Config>,
@@ -1040,6 +1238,11 @@ pub struct PublisherBuilder {
     bind_cfg: Option<BindCfg>,
     max_clients: usize,
     slack: usize,
+    weight: Option<u32>,
+    locality: Option<Locality>,
+    metrics: Option<Path>,
+    restore_from: Option<PathBuf>,
+    loopback: bool,
 }
 
 impl PublisherBuilder {
@@ -1050,15 +1253,41 @@ impl PublisherBuilder {
             bind_cfg: None,
             max_clients: 768,
             slack: 3,
+            weight: None,
+            locality: None,
+            metrics: None,
+            restore_from: None,
+            loopback: false,
         }
     }
 
     pub async fn build(&mut self) -> Result<Publisher> {
-        let cfg = self.config.take().unwrap();
+        let mut cfg = self.config.take().unwrap();
+        if let Some(locality) = self.locality.take() {
+            cfg.locality = locality;
+        }
         let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
         let bind_cfg =
             self.bind_cfg.take().unwrap_or_else(|| cfg.default_bind_config.clone());
-        Publisher::new(cfg, desired_auth, bind_cfg, self.max_clients, self.slack).await
+        let publisher = Publisher::new_internal(
+            cfg,
+            desired_auth,
+            bind_cfg,
+            self.max_clients,
+            self.slack,
+            self.loopback,
+        )
+        .await?;
+        if let Some(weight) = self.weight {
+            publisher.set_weight(weight);
+        }
+        if let Some(base) = self.metrics.take() {
+            metrics::start(&publisher, base);
+        }
+        if let Some(file) = self.restore_from.take() {
+            publisher.restore(snapshot::read(&file).await?);
+        }
+        Ok(publisher)
     }
 
     /// The desired authentication mechanism you want to use. If not
@@ -1089,6 +1318,57 @@ impl PublisherBuilder {
         self.slack = slack;
         self
     }
+
+    /// The weight advertised to subscribers that balance between
+    /// publishers, see `PublishFlags::LOAD_BALANCE`. If not
+    /// specified no load is advertised, and subscribers treat the
+    /// publisher as weight 1 with no load.
+    pub fn weight(&mut self, weight: u32) -> &mut Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// The locality advertised to subscribers, overriding the one in
+    /// the config.
+    pub fn locality(&mut self, locality: Locality) -> &mut Self {
+        self.locality = Some(locality);
+        self
+    }
+
+    /// Publish metrics about the publisher itself under `base`,
+    /// refreshed once per second. This includes the number of
+    /// clients, each client's user, subscriptions, and queued bytes,
+    /// the update rate not counting the metrics themselves, the
+    /// number of writes received and denied, the number of clients
+    /// disconnected by a flush timeout, the heartbeat round trip
+    /// time to each resolver server, and the number of idle objects
+    /// in each of the publisher's pools along with the most it will
+    /// retain. Default none.
+    pub fn metrics(&mut self, base: Option<Path>) -> &mut Self {
+        self.metrics = base;
+        self
+    }
+
+    /// Republish the values in a snapshot written by
+    /// `Publisher::snapshot_to`. Restored values are marked stale
+    /// until they are updated, see `Publisher::is_stale`, subscribers
+    /// see the mark with `Val::is_stale`. When the
+    /// application publishes a restored path it claims the restored
+    /// value, its initial value is ignored, so subscribers keep
+    /// seeing the last known value instead of a placeholder. A
+    /// missing snapshot file is not an error. Default none.
+    pub fn restore_from(&mut self, file: Option<PathBuf>) -> &mut Self {
+        self.restore_from = file;
+        self
+    }
+
+    /// Also accept subscribers over the in process transport in
+    /// `testing`. Default false.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn loopback(&mut self, loopback: bool) -> &mut Self {
+        self.loopback = loopback;
+        self
+    }
 }
 
 /// Publish values. Publisher is internally wrapped in an Arc, so
@@ -1112,44 +1392,27 @@ impl Publisher {
         max_clients: usize,
         slack: usize,
     ) -> Result<Publisher> {
-        let (public, private) = bind_cfg.select()?;
-        utils::check_addr(public, &resolver.addrs)?;
-        let (addr, listener) = match bind_cfg {
-            BindCfg::Exact(addr) => {
-                let l = TcpListener::bind(&addr).await?;
-                (l.local_addr()?, l)
-            }
-            BindCfg::ElasticExact { public, private } => {
-                let l = TcpListener::bind(&private).await?;
-                (public, l)
-            }
-            BindCfg::Match { .. } | BindCfg::Local | BindCfg::Elastic { .. } => {
-                let mkaddr = |ip: IpAddr, port: u16| -> Result<SocketAddr> {
-                    Ok((ip, port)
-                        .to_socket_addrs()?
-                        .next()
-                        .ok_or_else(|| anyhow!("socketaddrs bug"))?)
-                };
-                let mut port = 5000;
-                loop {
-                    if port >= 32768 {
-                        bail!("couldn't allocate a port");
-                    }
-                    port = rand_port(port);
-                    let addr = mkaddr(private, port)?;
-                    match TcpListener::bind(&addr).await {
-                        Ok(l) => break (mkaddr(public, port)?, l),
-                        Err(e) => {
-                            if e.kind() != std::io::ErrorKind::AddrInUse {
-                                bail!(e)
-                            }
-                        }
-                    }
-                }
-            }
-        };
+        Self::new_internal(resolver, desired_auth, bind_cfg, max_clients, slack, false)
+            .await
+    }
+
+    async fn new_internal(
+        resolver: Config,
+        desired_auth: DesiredAuth,
+        bind_cfg: BindCfg,
+        max_clients: usize,
+        slack: usize,
+        loopback: bool,
+    ) -> Result<Publisher> {
+        let (addr, listener) = bind(&bind_cfg, &resolver).await?;
+        let loopback = transport::listen(addr, loopback);
         let tls_ctx = resolver.tls.clone().map(tls::CachedAcceptor::new);
+        let locality = resolver.locality.clone();
         let resolver = ResolverWrite::new(resolver, desired_auth.clone(), addr)?;
+        if !locality.is_empty() {
+            // nothing is connected yet, so this just records it
+            resolver.set_locality(locality).await?;
+        }
         let (stop, receive_stop) = oneshot::channel();
         let (tx_trigger, rx_trigger) = unbounded();
         let pb = Publisher(Arc::new(Mutex::new(PublisherInner {
@@ -1160,23 +1423,33 @@ impl Publisher {
             by_path: HashMap::new(),
             by_id: HashMap::default(),
             destroy_on_idle: HashSet::default(),
+            diverged: HashSet::default(),
             on_write_chans: HashMap::default(),
             on_event_chans: Vec::new(),
             on_event_by_id_chans: HashMap::default(),
             extended_auth: None,
+            write_auth: None,
             on_write: HashMap::default(),
             resolver,
+            load: None,
+            load_changed: false,
             advertised: HashMap::new(),
             to_publish: TOPUB.take(),
             to_publish_default: TOPUB.take(),
             to_unpublish: TOUPUB.take(),
             to_unpublish_default: TOUPUB.take(),
             to_unsubscribe: TOUSUB.take(),
+            to_set_metadata: TOMD.take(),
             publish_triggered: false,
             trigger_publish: tx_trigger,
             wait_clients: HashMap::default(),
             wait_any_client: Vec::new(),
             default: BTreeMap::new(),
+            listers: BTreeMap::new(),
+            stats: Stats::default(),
+            restored: HashMap::new(),
+            stale: HashSet::default(),
+            snapshot: None,
         })));
         task::spawn({
             let pb_weak = pb.downgrade();
@@ -1184,6 +1457,7 @@ impl Publisher {
                 server::start(
                     pb_weak.clone(),
                     listener,
+                    loopback,
                     receive_stop,
                     desired_auth,
                     tls_ctx,
@@ -1225,6 +1499,141 @@ impl Publisher {
         self.0.lock().extended_auth = None;
     }
 
+    /// Set a write authorization function for the publisher. `f`
+    /// will be called on every write that the resolver permits, with
+    /// the write request and the user info of the writer. If it
+    /// returns an error the write will not be delivered, and if the
+    /// writer asked for a recipt it will receive the denial, see
+    /// `WriteDenied::from_value`.
+    ///
+    /// Only one write authorization function may be set for a given
+    /// publisher. If a new one is set the old one will be
+    /// overwritten.
+    pub fn set_write_authorization(&self, f: WriteAuth) {
+        self.0.lock().write_auth = Some(WriteAuthWrap(f));
+    }
+
+    /// Remove the write authorization function
+    pub fn clear_write_authorization(&self) {
+        self.0.lock().write_auth = None;
+    }
+
+    fn restore(&self, values: Vec<(Path, Value)>) {
+        let mut pb = self.0.lock();
+        for (path, current) in values {
+            if pb.check_publish(&path).is_ok() {
+                let id = Id::new();
+                let subscribed = pb
+                    .hc_subscribed
+                    .entry(BTreeSet::new())
+                    .or_insert_with(|| Arc::new(HashSet::default()))
+                    .clone();
+                pb.by_id.insert(
+                    id,
+                    Published {
+                        current,
+                        subscribed,
+                        path: path.clone(),
+                        aliases: None,
+                    },
+                );
+                pb.restored.insert(path.clone(), id);
+                pb.stale.insert(id);
+                pb.publish(id, PublishFlags::empty(), path);
+            }
+        }
+    }
+
+    /// Write the current value of every published path to `file`
+    /// now, and then again every `interval` until the publisher is
+    /// dropped. Values published with `DESTROY_ON_IDLE` are not
+    /// included. Use `PublisherBuilder::restore_from` to republish
+    /// the snapshot after a restart. Calling `snapshot_to` again
+    /// replaces the previous checkpoint schedule.
+    pub async fn snapshot_to(&self, file: PathBuf, interval: Duration) -> Result<()> {
+        snapshot::write(self, &file).await?;
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().snapshot = Some(tx);
+        snapshot::start(self, file, interval, rx);
+        Ok(())
+    }
+
+    /// Stop the periodic snapshot started by `snapshot_to`
+    pub fn stop_snapshot(&self) {
+        self.0.lock().snapshot = None;
+    }
+
+    /// Return true if `id` was restored from a snapshot and hasn't
+    /// been updated since.
+    pub fn is_stale(&self, id: Id) -> bool {
+        self.0.lock().stale.contains(&id)
+    }
+
+    /// Return the paths of all values that were restored from a
+    /// snapshot and haven't been updated since.
+    pub fn stale(&self) -> Vec<Path> {
+        let pb = self.0.lock();
+        pb.stale
+            .iter()
+            .filter_map(|id| pb.by_id.get(id).map(|pbl| pbl.path.clone()))
+            .collect()
+    }
+
+    /// Unpublish all values restored from a snapshot that the
+    /// application has not published again. Call this once the
+    /// application has finished publishing to remove paths that no
+    /// longer exist.
+    pub fn unpublish_unclaimed(&self) {
+        let mut pb = self.0.lock();
+        let unclaimed = mem::take(&mut pb.restored);
+        for (_, id) in unclaimed {
+            pb.destroy_val(id)
+        }
+    }
+
+    /// Hand the subscribers of this publisher to the publisher at
+    /// `successor`, e.g. a replacement process started with
+    /// `PublisherBuilder::restore_from` pointed at a snapshot of this
+    /// publisher (see `snapshot_to`). Every client is told to move
+    /// it's durable subscriptions to `successor`, which must already
+    /// publish the same paths, and then we wait up to `timeout` for
+    /// them to confirm that they have. Durable subscriptions move
+    /// without ever seeing `Event::Unsubscribed`, non durable
+    /// subscriptions are not moved, and neither are subscriptions
+    /// from clients too old to understand migration. Once this
+    /// returns the publisher should be shutdown.
+    pub async fn migrate(&self, successor: SocketAddr, timeout: Duration) {
+        let fut = {
+            let pb = self.0.lock();
+            let clients = pb.clients.values().filter(|cl| cl.version >= MIGRATE_VERSION);
+            future::join_all(clients.map(|cl| {
+                let mut q = cl.msg_queue.clone();
+                let mut update = Update::new();
+                update.updates.push(publisher::From::Migrate(successor));
+                async move {
+                    let _: Result<_, _> = q.send((None, update)).await;
+                }
+            }))
+        };
+        fut.await;
+        let _ = time::timeout(timeout, async {
+            let mut interval = time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                let pb = self.0.lock();
+                let done = |cl: &Client| {
+                    cl.version < MIGRATE_VERSION
+                        || cl.migrated
+                        || cl.subscribed.is_empty()
+                };
+                if pb.clients.values().all(done) {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+
     /// Perform a clean shutdown of the publisher, remove all
     /// published paths from the resolver server, shutdown the
     /// listener, and close the connection to all clients. Dropping
@@ -1252,6 +1661,51 @@ impl Publisher {
         self.0.lock().addr
     }
 
+    pub(crate) fn resolver(&self) -> ResolverWrite {
+        self.0.lock().resolver.clone()
+    }
+
+    fn update_load(&self, f: impl FnOnce(&mut Load)) {
+        let mut pb = self.0.lock();
+        let prev = pb.load;
+        let mut load = prev.unwrap_or(Load { weight: 1, load: 0 });
+        f(&mut load);
+        if prev != Some(load) {
+            pb.load = Some(load);
+            pb.load_changed = true;
+            pb.trigger_publish();
+        }
+    }
+
+    /// Set the weight advertised to subscribers that balance between
+    /// publishers, see `PublishFlags::LOAD_BALANCE`.
+    pub fn set_weight(&self, weight: u32) {
+        self.update_load(|l| l.weight = weight)
+    }
+
+    /// Set the current load advertised to subscribers that balance
+    /// between publishers, e.g. the number of requests in
+    /// flight. What load means is up to the application, subscribers
+    /// with the `LeastLoaded` policy prefer the publisher with the
+    /// lowest load relative to it's weight. The resolver is only
+    /// updated once per publish batch, so it's fine to call this
+    /// often.
+    pub fn set_load(&self, load: u32) {
+        self.update_load(|l| l.load = load)
+    }
+
+    /// Attach metadata, e.g. a doc string or tags, to `path` in the
+    /// resolver, where subscribers can find it with
+    /// `ResolverRead::search`. `path` must be published by this
+    /// publisher, either directly or as a default publisher. Setting
+    /// empty metadata removes it, and it is removed automatically
+    /// when the path is unpublished.
+    pub fn set_metadata(&self, path: Path, metadata: Metadata) {
+        let mut pb = self.0.lock();
+        pb.to_set_metadata.insert(path, metadata);
+        pb.trigger_publish();
+    }
+
     /// Publish `Path` with initial value `init` and flags `flags`. It
     /// is an error for the same publisher to publish the same path
     /// twice, however different publishers may publish a given path
@@ -1278,6 +1732,18 @@ impl Publisher {
         let destroy_on_idle = flags.contains(PublishFlags::DESTROY_ON_IDLE);
         flags.remove(PublishFlags::DESTROY_ON_IDLE);
         let mut pb = self.0.lock();
+        if let Some(id) = pb.restored.remove(&path) {
+            if destroy_on_idle {
+                pb.destroy_on_idle.insert(id);
+            }
+            if let Some(tx) = tx {
+                pb.writes(id, tx);
+            }
+            if !flags.is_empty() {
+                pb.publish(id, flags, path);
+            }
+            return Ok(Val(id));
+        }
         pb.check_publish(&path)?;
         let subscribed = pb
             .hc_subscribed
@@ -1286,7 +1752,12 @@ impl Publisher {
             .clone();
         pb.by_id.insert(
             id,
-            Published { current: init, subscribed, path: path.clone(), aliases: None },
+            Published {
+                current: init,
+                subscribed,
+                path: path.clone(),
+                aliases: None,
+            },
         );
         if destroy_on_idle {
             pb.destroy_on_idle.insert(id);
@@ -1419,8 +1890,8 @@ impl Publisher {
     ///
     /// # notes
     ///
-    /// At the moment none of the `PublishFlags` are relevant to
-    /// default publishers.
+    /// At the moment `LIST_ON_DEMAND` is the only one of the
+    /// `PublishFlags` relevant to default publishers.
     pub fn publish_default_with_flags(
         &self,
         flags: PublishFlags,
@@ -1629,6 +2100,11 @@ impl Publisher {
     /// then `Value::Ok` will be sent. `SendReply::send` may only be
     /// called once, further calls will be silently ignored.
     ///
+    /// Writes that are part of an atomic batch have `atomic` set, and
+    /// arrive together in one batch containing nothing else. Every id
+    /// in an atomic batch must be registered with the same channels,
+    /// if they are not the batch is rejected and nothing is written.
+    ///
     /// If you no longer wish to accept writes for an id you can drop
     /// all registered channels, or call `stop_writes`.
     pub fn writes(&self, id: Id, tx: Sender<Pooled<Vec<WriteRequest>>>) {
@@ -1671,6 +2147,8 @@ async fn publish_loop(
             let mut to_unpublish;
             let mut to_unpublish_default;
             let mut to_unsubscribe;
+            let mut to_set_metadata;
+            let load;
             let resolver = {
                 let mut pb = publisher.0.lock();
                 to_publish = mem::replace(&mut pb.to_publish, TOPUB.take());
@@ -1680,6 +2158,11 @@ async fn publish_loop(
                 to_unpublish_default =
                     mem::replace(&mut pb.to_unpublish_default, TOUPUB.take());
                 to_unsubscribe = mem::replace(&mut pb.to_unsubscribe, TOUSUB.take());
+                to_set_metadata = mem::replace(&mut pb.to_set_metadata, TOMD.take());
+                // the resolver client sends load to clusters it
+                // connects to later, only changes need to be sent
+                load = pb.load.filter(|_| pb.load_changed);
+                pb.load_changed = false;
                 pb.publish_triggered = false;
                 pb.resolver.clone()
             };
@@ -1695,6 +2178,16 @@ async fn publish_loop(
                     error!("failed to publish_default some paths {} will retry", e)
                 }
             }
+            if to_set_metadata.len() > 0 {
+                if let Err(e) = resolver.set_metadata(to_set_metadata.drain()).await {
+                    error!("failed to set metadata {}", e)
+                }
+            }
+            if let Some(load) = load {
+                if let Err(e) = resolver.set_load(load).await {
+                    error!("failed to set load {}", e)
+                }
+            }
             if to_unpublish.len() > 0 {
                 if let Err(e) = resolver.unpublish(to_unpublish.drain()).await {
                     error!("failed to unpublish some paths {} will retry", e)
@@ -16,6 +16,7 @@ use crate::{
     resolver_client::DesiredAuth,
     resolver_server::{auth::Permissions, krb5_authentication},
     tls,
+    transport::{self, MemListener},
     utils::{self, BatchItem, Batched, ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
@@ -34,6 +35,7 @@ use fxhash::FxHashMap;
 use log::{debug, info};
 use parking_lot::RwLock;
 use protocol::resolver::{AuthChallenge, HashMethod, UserInfo};
+use smallvec::SmallVec;
 use std::{
     boxed::Box,
     collections::{hash_map::Entry, BTreeSet, Bound, HashMap, HashSet},
@@ -43,15 +45,30 @@ use std::{
     mem,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     task, time,
 };
 
 const MAX_DEFERRED: usize = 1000000;
+
+// Writes waiting to be delivered to each write channel. Atomic
+// batches are kept apart from other writes so that they arrive alone.
+type WriteBatches = FxHashMap<
+    ChanId,
+    (SmallVec<[Pooled<Vec<WriteRequest>>; 1]>, Sender<Pooled<Vec<WriteRequest>>>),
+>;
+
+// A reply to a write, or to an atomic batch of writes if the id is None
+type WaitWriteRes = Vec<(Option<Id>, WriteId, oneshot::Receiver<Value>)>;
+
 type DeferredSubs =
     Batched<SelectAll<Box<dyn Stream<Item = (Path, Permissions)> + Send + Sync + Unpin>>>;
 
@@ -120,7 +137,8 @@ fn subscribe(
                         e.insert(Arc::clone(&ut.subscribed));
                     }
                 }
-                let m = publisher::From::Subscribed(path, id, ut.current.clone());
+                let stale = t.stale.contains(&id);
+                let m = publisher::From::Subscribed(path, id, ut.current.clone(), stale);
                 con.queue_send(&m)?;
                 if let Some(waiters) = t.wait_clients.remove(&id) {
                     for tx in waiters {
@@ -171,11 +189,8 @@ fn write(
     con: &mut WriteChannel,
     client: ClId,
     gc_on_write: &mut Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
-    wait_write_res: &mut Vec<(Id, WriteId, oneshot::Receiver<Value>)>,
-    write_batches: &mut FxHashMap<
-        ChanId,
-        (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>),
-    >,
+    wait_write_res: &mut WaitWriteRes,
+    write_batches: &mut WriteBatches,
     id: Id,
     v: Value,
     r: bool,
@@ -196,11 +211,29 @@ fn write(
         };
     }
     use protocol::publisher::From;
+    t.stats.writes += 1;
     let cl = or_qwe!(t.clients.get(&client), "cannot write to unsubscribed value");
     let perms = or_qwe!(cl.subscribed.get(&id), "cannot write to unsubscribed value");
     if !perms.contains(Permissions::WRITE) {
         or_qwe!(None, "write permission denied")
     }
+    if let (Some(auth), Some(pbv)) = (&t.write_auth, t.by_id.get(&id)) {
+        let req = WriteRequest {
+            id,
+            path: pbv.path.clone(),
+            client,
+            value: v.clone(),
+            send_result: None,
+            atomic: false,
+        };
+        if let Err(denied) = (auth.0)(&req, cl.user.as_ref()) {
+            t.stats.writes_denied += 1;
+            if r {
+                con.queue_send(&From::WriteResult(id, denied.to_value(), wid))?
+            }
+            return Ok(());
+        }
+    }
     let ow = or_qwe!(t.on_write.get_mut(&id), "writes not accepted");
     ow.retain(|(_, c)| {
         if c.is_closed() {
@@ -217,7 +250,7 @@ fn write(
         None
     } else {
         let (send_result, wait) = SendResult::new();
-        wait_write_res.push((id, wid, wait));
+        wait_write_res.push((Some(id), wid, wait));
         Some(send_result)
     };
     for (cid, ch) in ow.iter() {
@@ -228,14 +261,134 @@ fn write(
                 client,
                 value: v.clone(),
                 send_result: send_result.clone(),
+                atomic: false,
             };
-            write_batches
+            let (batches, _) = write_batches
+                .entry(*cid)
+                .or_insert_with(|| (SmallVec::new(), ch.clone()));
+            match batches.last_mut() {
+                Some(batch) if !batch.first().map(|r| r.atomic).unwrap_or(false) => {
+                    batch.push(req)
+                }
+                Some(_) | None => {
+                    let mut batch = BATCHES.take();
+                    batch.push(req);
+                    batches.push(batch)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_batch(
+    t: &mut PublisherInner,
+    con: &mut WriteChannel,
+    client: ClId,
+    gc_on_write: &mut Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
+    wait_write_res: &mut WaitWriteRes,
+    write_batches: &mut WriteBatches,
+    writes: Vec<(Id, Value)>,
+    r: bool,
+    wid: WriteId,
+) -> Result<()> {
+    macro_rules! or_qwe {
+        ($v:expr, $m:expr) => {
+            match $v {
+                Some(v) => v,
+                None => {
+                    if r {
+                        let m = Value::Error(Chars::from($m));
+                        con.queue_send(&From::WriteBatchResult(m, wid))?
+                    }
+                    return Ok(());
+                }
+            }
+        };
+    }
+    use protocol::publisher::From;
+    t.stats.writes += writes.len() as u64;
+    let cl = or_qwe!(t.clients.get(&client), "cannot write to unsubscribed value");
+    // check every write before delivering any of them, the batch
+    // is delivered whole or not at all
+    let mut reqs = Vec::with_capacity(writes.len());
+    let mut chans: SmallVec<[ChanId; 2]> = SmallVec::new();
+    for (id, v) in writes {
+        let perms = or_qwe!(cl.subscribed.get(&id), "cannot write to unsubscribed value");
+        if !perms.contains(Permissions::WRITE) {
+            or_qwe!(None, "write permission denied")
+        }
+        let pbv = or_qwe!(t.by_id.get(&id), "cannot write to unsubscribed value");
+        let req = WriteRequest {
+            id,
+            path: pbv.path.clone(),
+            client,
+            value: v,
+            send_result: None,
+            atomic: true,
+        };
+        if let Some(auth) = &t.write_auth {
+            if let Err(denied) = (auth.0)(&req, cl.user.as_ref()) {
+                t.stats.writes_denied += 1;
+                if r {
+                    con.queue_send(&From::WriteBatchResult(denied.to_value(), wid))?
+                }
+                return Ok(());
+            }
+        }
+        let ow = or_qwe!(t.on_write.get_mut(&id), "writes not accepted");
+        ow.retain(|(_, c)| {
+            if c.is_closed() {
+                gc_on_write.push(ChanWrap(c.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        if ow.len() == 0 {
+            or_qwe!(None, "writes not accepted");
+        }
+        // every channel must see the whole batch, otherwise it isn't atomic
+        let mut ids = ow.iter().map(|(cid, _)| *cid).collect::<SmallVec<[ChanId; 2]>>();
+        ids.sort_unstable();
+        if chans.is_empty() {
+            chans = ids;
+        } else if chans != ids {
+            or_qwe!(None, "atomic batch spans more than one write channel")
+        }
+        reqs.push(req);
+    }
+    let send_result = if !r {
+        None
+    } else {
+        let (send_result, wait) = SendResult::new();
+        wait_write_res.push((None, wid, wait));
+        Some(send_result)
+    };
+    let mut batches: FxHashMap<
+        ChanId,
+        (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>),
+    > = HashMap::default();
+    for req in reqs {
+        for (cid, ch) in t.on_write.get(&req.id).into_iter().flatten() {
+            let req = WriteRequest {
+                id: req.id,
+                path: req.path.clone(),
+                client,
+                value: req.value.clone(),
+                send_result: send_result.clone(),
+                atomic: true,
+            };
+            batches
                 .entry(*cid)
                 .or_insert_with(|| (BATCHES.take(), ch.clone()))
                 .0
                 .push(req)
         }
     }
+    for (cid, (batch, ch)) in batches {
+        write_batches.entry(cid).or_insert_with(|| (SmallVec::new(), ch)).0.push(batch)
+    }
     Ok(())
 }
 
@@ -286,17 +439,17 @@ struct ClientCtx {
     publisher: PublisherWeak,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
     batch: Vec<publisher::To>,
-    write_batches:
-        FxHashMap<ChanId, (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>)>,
+    write_batches: WriteBatches,
     blocked_writes: FuturesUnordered<BlockedWriteFut>,
     flushing_updates: bool,
     flush_timeout: Option<Duration>,
     deferred_subs: DeferredSubs,
     deferred_subs_batch: Vec<(Path, Permissions)>,
-    wait_write_res: Vec<(Id, WriteId, oneshot::Receiver<Value>)>,
+    wait_write_res: WaitWriteRes,
     gc_on_write: Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
     msg_sent: bool,
     tls_ctx: Option<tls::CachedAcceptor>,
+    queued: Arc<AtomicUsize>,
 }
 
 impl ClientCtx {
@@ -306,6 +459,7 @@ impl ClientCtx {
         publisher: PublisherWeak,
         desired_auth: DesiredAuth,
         tls_ctx: Option<tls::CachedAcceptor>,
+        queued: Arc<AtomicUsize>,
     ) -> ClientCtx {
         let mut deferred_subs: DeferredSubs =
             Batched::new(SelectAll::new(), MAX_DEFERRED);
@@ -326,6 +480,7 @@ impl ClientCtx {
             gc_on_write: Vec::new(),
             msg_sent: false,
             tls_ctx,
+            queued,
         }
     }
 
@@ -338,6 +493,14 @@ impl ClientCtx {
         }
     }
 
+    fn set_version(&mut self, version: u64) {
+        if let Some(pb) = self.publisher.upgrade() {
+            if let Some(ci) = pb.0.lock().clients.get_mut(&self.client) {
+                ci.version = version;
+            }
+        }
+    }
+
     fn set_user(&mut self, ifo: Option<UserInfo>) {
         if let Some(ifo) = ifo {
             if let Some(secret) = self.secrets.read().get(&ifo.resolver).copied() {
@@ -360,35 +523,44 @@ impl ClientCtx {
     }
 
     // CR estokes: Implement periodic rekeying to improve security
-    async fn hello(&mut self, mut con: TcpStream) -> Result<Channel> {
+    async fn hello<S>(&mut self, mut con: S) -> Result<Channel>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         use protocol::publisher::Hello;
         static NO: &str = "authentication mechanism not supported";
         debug!("hello_client");
-        channel::write_raw(&mut con, &3u64).await?;
-        if channel::read_raw::<u64, _>(&mut con).await? != 3 {
-            bail!("incompatible protocol version")
-        }
+        let version = channel::accept_version(&mut con).await?;
+        self.set_version(version);
         let hello: Hello = channel::read_raw(&mut con).await?;
         debug!("hello_client received {:?}", hello);
         match hello {
             Hello::Anonymous => {
                 channel::write_raw(&mut con, &Hello::Anonymous).await?;
                 self.client_arrived();
-                Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                Ok(Channel::new::<ServerCtx, S>(None, con))
             }
             Hello::Local(uifo) => {
                 channel::write_raw(&mut con, &Hello::Local(None)).await?;
                 self.set_user(uifo);
                 self.client_arrived();
-                Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                Ok(Channel::new::<ServerCtx, S>(None, con))
+            }
+            Hello::Token(uifo) => {
+                channel::write_raw(&mut con, &Hello::Token(None)).await?;
+                self.set_user(uifo);
+                self.client_arrived();
+                Ok(Channel::new::<ServerCtx, S>(None, con))
             }
             Hello::Krb5(uifo) => match &self.desired_auth {
-                DesiredAuth::Anonymous | DesiredAuth::Tls { .. } => bail!(NO),
+                DesiredAuth::Anonymous
+                | DesiredAuth::Tls { .. }
+                | DesiredAuth::Token { .. } => bail!(NO),
                 DesiredAuth::Local => {
                     channel::write_raw(&mut con, &Hello::Local(None)).await?;
                     self.set_user(uifo);
                     self.client_arrived();
-                    Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                    Ok(Channel::new::<ServerCtx, S>(None, con))
                 }
                 DesiredAuth::Krb5 { upn: _, spn } => {
                     let spn = spn.as_ref().map(|s| s.as_str());
@@ -401,12 +573,14 @@ impl ClientCtx {
                 }
             },
             Hello::Tls(uifo) => match &self.desired_auth {
-                DesiredAuth::Anonymous | DesiredAuth::Krb5 { .. } => bail!(NO),
+                DesiredAuth::Anonymous
+                | DesiredAuth::Krb5 { .. }
+                | DesiredAuth::Token { .. } => bail!(NO),
                 DesiredAuth::Local => {
                     channel::write_raw(&mut con, &Hello::Local(None)).await?;
                     self.set_user(uifo);
                     self.client_arrived();
-                    Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                    Ok(Channel::new::<ServerCtx, S>(None, con))
                 }
                 DesiredAuth::Tls { identity } => {
                     let tls =
@@ -421,7 +595,7 @@ impl ClientCtx {
                     self.set_user(uifo);
                     let mut con = Channel::new::<
                         ServerCtx,
-                        tokio_rustls::server::TlsStream<TcpStream>,
+                        tokio_rustls::server::TlsStream<S>,
                     >(None, tls);
                     con.send_one(&Hello::Tls(None)).await?;
                     self.client_arrived();
@@ -429,27 +603,14 @@ impl ClientCtx {
                 }
             },
             Hello::ResolverAuthenticate(id) => {
-                info!("hello_client processing listener ownership check from resolver");
-                let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
-                let secret = self
-                    .secrets
-                    .read()
-                    .get(&id)
-                    .copied()
-                    .ok_or_else(|| anyhow!("no secret"))?;
-                let challenge: AuthChallenge = con.receive().await?;
-                if challenge.hash_method != HashMethod::Sha3_512 {
-                    bail!("requested hash method not supported")
-                }
-                let reply = utils::make_sha3_token([
-                    &challenge.challenge.to_be_bytes()[..],
-                    &secret.to_be_bytes()[..],
-                ]);
-                con.send_one(&BoundedBytes::<4096>(reply)).await?;
-                // prevent fishing for the key
-                time::sleep(Duration::from_secs(1)).await;
+                resolver_authenticate(&self.secrets, id, con).await?;
                 bail!("resolver authentication complete");
             }
+            Hello::List(path) => {
+                list_children(&self.publisher, path.clone()).await;
+                channel::write_raw(&mut con, &Hello::List(path)).await?;
+                bail!("resolver list request complete");
+            }
         }
     }
 
@@ -512,7 +673,8 @@ impl ClientCtx {
                         )?,
                         DesiredAuth::Krb5 { .. }
                         | DesiredAuth::Local
-                        | DesiredAuth::Tls { .. } => match secrets.get(&resolver) {
+                        | DesiredAuth::Tls { .. }
+                        | DesiredAuth::Token { .. } => match secrets.get(&resolver) {
                             None => {
                                 debug!("denied, no stored secret for {}", resolver);
                                 con.queue_send(&From::Denied(path))?
@@ -555,11 +717,28 @@ impl ClientCtx {
                     r,
                     wid,
                 )?,
+                WriteBatch(writes, r, wid) => write_batch(
+                    &mut *pb,
+                    con,
+                    self.client,
+                    &mut self.gc_on_write,
+                    &mut self.wait_write_res,
+                    &mut self.write_batches,
+                    writes,
+                    r,
+                    wid,
+                )?,
                 Unsubscribe(id) => {
                     gc = true;
                     unsubscribe(&mut *pb, self.client, id);
                     con.queue_send(&From::Unsubscribed(id))?;
                 }
+                Heartbeat(ts) => con.queue_send(&From::HeartbeatReply(ts))?,
+                Migrated => {
+                    if let Some(cl) = pb.clients.get_mut(&self.client) {
+                        cl.migrated = true;
+                    }
+                }
             }
         }
         if gc {
@@ -576,21 +755,26 @@ impl ClientCtx {
         self.handle_batch_inner(con)?;
         if self.write_batches.len() > 0 || self.wait_write_res.len() > 0 {
             self.blocked_writes.extend(self.write_batches.drain().map(
-                |(_, (batch, mut sender))| {
+                |(_, (batches, mut sender))| {
                     Box::pin(async move {
-                        let _ = sender.send(batch).await;
+                        for batch in batches {
+                            let _ = sender.send(batch).await;
+                        }
                         BlockedWrite::Wrote
                     }) as BlockedWriteFut
                 },
             ));
-            self.blocked_writes.extend(self.wait_write_res.drain(..).map(|(id, wid, rx)| {
-                Box::pin(async move {
-                    BlockedWrite::Reply(match rx.await {
-                        Ok(v) => From::WriteResult(id, v, wid),
-                        Err(_) => From::WriteResult(id, Value::Ok, wid),
-                    })
-                }) as BlockedWriteFut
-            }));
+            self.blocked_writes.extend(self.wait_write_res.drain(..).map(
+                |(id, wid, rx)| {
+                    Box::pin(async move {
+                        let v = rx.await.unwrap_or(Value::Ok);
+                        BlockedWrite::Reply(match id {
+                            Some(id) => From::WriteResult(id, v, wid),
+                            None => From::WriteBatchResult(v, wid),
+                        })
+                    }) as BlockedWriteFut
+                },
+            ));
         }
         Ok(())
     }
@@ -620,11 +804,14 @@ impl ClientCtx {
         Ok(())
     }
 
-    async fn run(
+    async fn run<S>(
         mut self,
-        con: TcpStream,
+        con: S,
         mut updates: Receiver<(Option<Duration>, Update)>,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         async fn flush(c: &mut WriteChannel, timeout: Option<Duration>) -> Result<()> {
             if c.bytes_queued() > 0 {
                 if let Some(timeout) = timeout {
@@ -671,7 +858,14 @@ impl ClientCtx {
         loop {
             select_biased! {
                 r = flush(&mut write_con, self.flush_timeout).fuse() => {
-                    r?;
+                    if let Err(e) = r {
+                        if e.is::<time::error::Elapsed>() {
+                            if let Some(pb) = self.publisher.upgrade() {
+                                pb.0.lock().stats.flush_timeouts += 1;
+                            }
+                        }
+                        return Err(e)
+                    }
                     self.flushing_updates = false;
                     self.flush_timeout = None;
                 },
@@ -702,13 +896,162 @@ impl ClientCtx {
                     }
                 },
             }
+            self.queued.store(write_con.bytes_queued(), Ordering::Relaxed);
+        }
+    }
+}
+
+// returns false if the publisher is dead
+fn accept<S>(
+    t: &PublisherWeak,
+    s: S,
+    desired_auth: &DesiredAuth,
+    tls_ctx: &Option<tls::CachedAcceptor>,
+    max_clients: usize,
+    slack: usize,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let clid = ClId::new();
+    let t_weak = t.clone();
+    let t = match t.upgrade() {
+        None => return false,
+        Some(t) => t,
+    };
+    let mut pb = t.0.lock();
+    let secrets = pb.resolver.secrets();
+    let (tx, rx) = channel(slack);
+    if pb.clients.len() < max_clients {
+        let queued = Arc::new(AtomicUsize::new(0));
+        pb.clients.insert(
+            clid,
+            Client {
+                msg_queue: tx,
+                subscribed: HashMap::default(),
+                user: None,
+                queued: queued.clone(),
+                version: channel::MIN_VERSION,
+                migrated: false,
+            },
+        );
+        let desired_auth = desired_auth.clone();
+        let tls_ctx = tls_ctx.clone();
+        task::spawn(async move {
+            let ctx = ClientCtx::new(
+                clid,
+                secrets,
+                t_weak.clone(),
+                desired_auth,
+                tls_ctx,
+                queued,
+            );
+            let r = ctx.run(s, rx).await;
+            info!("accept_loop client shutdown {:?}", r);
+            if let Some(t) = t_weak.upgrade() {
+                let mut pb = t.0.lock();
+                if let Some(cl) = pb.clients.remove(&clid) {
+                    for (id, _) in cl.subscribed {
+                        unsubscribe(&mut *pb, clid, id);
+                    }
+                    pb.hc_subscribed.retain(|_, v| Arc::get_mut(v).is_none());
+                }
+            }
+        });
+    }
+    true
+}
+
+async fn resolver_authenticate<S>(
+    secrets: &RwLock<FxHashMap<SocketAddr, u128>>,
+    id: SocketAddr,
+    con: S,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!("hello_client processing listener ownership check from resolver");
+    let mut con = Channel::new::<ServerCtx, S>(None, con);
+    let secret = secrets.read().get(&id).copied().ok_or_else(|| anyhow!("no secret"))?;
+    let challenge: AuthChallenge = con.receive().await?;
+    if challenge.hash_method != HashMethod::Sha3_512 {
+        bail!("requested hash method not supported")
+    }
+    let reply = utils::make_sha3_token([
+        &challenge.challenge.to_be_bytes()[..],
+        &secret.to_be_bytes()[..],
+    ]);
+    con.send_one(&BoundedBytes::<4096>(reply)).await?;
+    // prevent fishing for the key
+    time::sleep(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+/// Ask the default publisher `path` is under to advertise it's
+/// children, and wait for them to reach the resolver server.
+async fn list_children(publisher: &PublisherWeak, path: Path) {
+    let rx = match publisher.upgrade() {
+        None => return,
+        Some(pb) => {
+            let pbl = pb.0.lock();
+            match Path::dirnames(&path).filter_map(|p| pbl.listers.get(p)).last() {
+                None => return,
+                Some(tx) => {
+                    let (tx_done, rx_done) = oneshot::channel();
+                    let _ = tx.unbounded_send((path, tx_done));
+                    rx_done
+                }
+            }
         }
+    };
+    let _ = rx.await;
+    if let Some(pb) = publisher.upgrade() {
+        pb.flushed().await
+    }
+}
+
+/// Answer the resolver's listener ownership checks for a write
+/// client that doesn't publish anything, using it's `secrets`.
+/// Nothing else is served on `serv`.
+pub(crate) async fn answer_ownership_checks(
+    serv: TcpListener,
+    secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+) {
+    use protocol::publisher::Hello;
+    loop {
+        let mut con = match serv.accept().await {
+            Ok((con, _)) => con,
+            Err(e) => {
+                info!("accept error {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let secrets = secrets.clone();
+        task::spawn(async move {
+            let r = time::timeout(HELLO_TIMEOUT, async {
+                channel::accept_version(&mut con).await?;
+                match channel::read_raw(&mut con).await? {
+                    Hello::ResolverAuthenticate(id) => {
+                        resolver_authenticate(&secrets, id, con).await
+                    }
+                    _ => bail!("only listener ownership checks are supported"),
+                }
+            })
+            .await;
+            match r {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => info!("ownership check failed {}", e),
+                Err(_) => info!("ownership check timed out"),
+            }
+        });
     }
 }
 
 pub(super) async fn start(
     t: PublisherWeak,
     serv: TcpListener,
+    loopback: Option<MemListener>,
     stop: oneshot::Receiver<()>,
     desired_auth: DesiredAuth,
     tls_ctx: Option<tls::CachedAcceptor>,
@@ -716,6 +1059,7 @@ pub(super) async fn start(
     slack: usize,
 ) {
     let mut stop = stop.fuse();
+    let mut loopback = loopback;
     loop {
         select_biased! {
             _ = stop => break,
@@ -723,46 +1067,18 @@ pub(super) async fn start(
                 Err(e) => info!("accept error {}", e), // CR estokes: Handle this
                 Ok((s, addr)) => {
                     debug!("accepted client {:?}", addr);
-                    let clid = ClId::new();
-                    let t_weak = t.clone();
-                    let t = match t.upgrade() {
-                        None => return,
-                        Some(t) => t
-                    };
-                    let mut pb = t.0.lock();
-                    let secrets = pb.resolver.secrets();
-                    let (tx, rx) = channel(slack);
                     try_cf!("nodelay", continue, s.set_nodelay(true));
-                    if pb.clients.len() < max_clients {
-                        pb.clients.insert(clid, Client {
-                            msg_queue: tx,
-                            subscribed: HashMap::default(),
-                            user: None,
-                        });
-                        let desired_auth = desired_auth.clone();
-                        let tls_ctx = tls_ctx.clone();
-                        task::spawn(async move {
-                            let ctx = ClientCtx::new(
-                                clid,
-                                secrets,
-                                t_weak.clone(),
-                                desired_auth,
-                                tls_ctx,
-                            );
-                            let r = ctx.run(s, rx).await;
-                            info!("accept_loop client shutdown {:?}", r);
-                            if let Some(t) = t_weak.upgrade() {
-                                let mut pb = t.0.lock();
-                                if let Some(cl) = pb.clients.remove(&clid) {
-                                    for (id, _) in cl.subscribed {
-                                        unsubscribe(&mut *pb, clid, id);
-                                    }
-                                    pb.hc_subscribed.retain(|_, v| {
-                                        Arc::get_mut(v).is_none()
-                                    });
-                                }
-                            }
-                        });
+                    if !accept(&t, s, &desired_auth, &tls_ctx, max_clients, slack) {
+                        return
+                    }
+                }
+            },
+            s = transport::accept_mem(&mut loopback).fuse() => match s {
+                None => loopback = None,
+                Some(s) => {
+                    debug!("accepted loopback client");
+                    if !accept(&t, s, &desired_auth, &tls_ctx, max_clients, slack) {
+                        return
                     }
                 }
             },
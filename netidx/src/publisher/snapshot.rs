@@ -0,0 +1,102 @@
+use super::{Publisher, PublisherWeak, Value};
+use crate::{pack::Pack, path::Path};
+use anyhow::Result;
+use bytes::BytesMut;
+use futures::{channel::oneshot, prelude::*, select_biased};
+use log::warn;
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path as FsPath, PathBuf},
+    time::Duration,
+};
+use tokio::{fs, io::AsyncWriteExt, task, time};
+
+const VERSION: u8 = 0;
+
+fn collect(publisher: &Publisher) -> Vec<(Path, Value)> {
+    let pb = publisher.0.lock();
+    pb.by_id
+        .iter()
+        .filter(|(id, _)| !pb.destroy_on_idle.contains(id))
+        .map(|(_, pbl)| (pbl.path.clone(), pbl.current.clone()))
+        .collect()
+}
+
+/// Write the current value of every published path to `file`. The
+/// snapshot is written to a temporary file next to `file`, synced,
+/// and then renamed over it, and the rename is synced, so a crash
+/// while writing never leaves a torn snapshot behind.
+pub(super) async fn write(publisher: &Publisher, file: &FsPath) -> Result<()> {
+    let values = collect(publisher);
+    let mut buf = BytesMut::with_capacity(1 + values.encoded_len());
+    VERSION.encode(&mut buf)?;
+    values.encode(&mut buf)?;
+    let mut tmp = OsString::from(file.as_os_str());
+    tmp.push(".tmp");
+    let mut f = fs::File::create(&tmp).await?;
+    f.write_all(&buf).await?;
+    f.sync_all().await?;
+    drop(f);
+    fs::rename(&tmp, file).await?;
+    sync_dir(file).await
+}
+
+// sync the directory containing file, so a rename into it is durable
+#[cfg(unix)]
+async fn sync_dir(file: &FsPath) -> Result<()> {
+    let dir = match file.parent() {
+        Some(dir) if dir != FsPath::new("") => dir,
+        _ => FsPath::new("."),
+    };
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+// directories can't be synced like this on windows, the rename is
+// only as durable as the file system makes it
+#[cfg(not(unix))]
+async fn sync_dir(_file: &FsPath) -> Result<()> {
+    Ok(())
+}
+
+/// Read a snapshot written by `write`. A missing file is an empty
+/// snapshot.
+pub(super) async fn read(file: &FsPath) -> Result<Vec<(Path, Value)>> {
+    let buf = match fs::read(file).await {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = &*buf;
+    match u8::decode(&mut buf)? {
+        VERSION => Ok(Vec::decode(&mut buf)?),
+        v => bail!("unknown snapshot version {}", v),
+    }
+}
+
+pub(super) fn start(
+    publisher: &Publisher,
+    file: PathBuf,
+    interval: Duration,
+    stop: oneshot::Receiver<()>,
+) {
+    let publisher: PublisherWeak = publisher.downgrade();
+    task::spawn(async move {
+        let mut stop = stop.fuse();
+        let mut interval = time::interval_at(time::Instant::now() + interval, interval);
+        loop {
+            select_biased! {
+                _ = stop => break,
+                _ = interval.tick().fuse() => match publisher.upgrade() {
+                    None => break,
+                    Some(pb) => {
+                        if let Err(e) = write(&pb, &file).await {
+                            warn!("failed to write snapshot {:?}: {}", file, e)
+                        }
+                    }
+                },
+            }
+        }
+    });
+}
@@ -13,7 +13,10 @@ use futures::channel::oneshot;
 use fxhash::FxHashMap;
 use netidx_core::pack::BoundedBytes;
 use std::{fmt::Debug, str::FromStr, time::Duration};
-use tokio::{net::TcpStream, task, time};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task, time,
+};
 
 pub(super) const HELLO_TO: Duration = Duration::from_secs(15);
 
@@ -62,6 +65,12 @@ pub enum DesiredAuth {
     /// used. Otherwise identity should be the name of an identity in
     /// the configuration.
     Tls { identity: Option<String> },
+    /// Use a shared secret bearer token for authentication. Traffic
+    /// is not encrypted. token is the path of a file containing the
+    /// token, it is read every time a connection is made so it may be
+    /// refreshed externally. If token is `None` then the token will be
+    /// read from the `NETIDX_TOKEN` environment variable.
+    Token { token: Option<String> },
 }
 
 impl FromStr for DesiredAuth {
@@ -73,7 +82,8 @@ impl FromStr for DesiredAuth {
             "local" => Ok(DesiredAuth::Local),
             "krb5" => Ok(DesiredAuth::Krb5 { upn: None, spn: None }),
             "tls" => Ok(DesiredAuth::Tls { identity: None }),
-            _ => bail!("expected, anonymous, local, krb5, or tls"),
+            "token" => Ok(DesiredAuth::Token { token: None }),
+            _ => bail!("expected, anonymous, local, krb5, tls, or token"),
         }
     }
 }
@@ -83,12 +93,12 @@ pub(super) type Response<F> =
 
 pub(super) type ResponseChan<F> = oneshot::Receiver<Response<F>>;
 
-pub(crate) async fn krb5_authentication(
+pub(crate) async fn krb5_authentication<S: AsyncRead + AsyncWrite + Unpin>(
     principal: Option<&str>,
     target_principal: &str,
-    con: &mut TcpStream,
+    con: &mut S,
 ) -> Result<ClientCtx> {
-    async fn send(con: &mut TcpStream, token: &[u8]) -> Result<()> {
+    async fn send<S: AsyncWrite + Unpin>(con: &mut S, token: &[u8]) -> Result<()> {
         let token = BoundedBytes::<L>(utils::bytes(&*token));
         Ok(time::timeout(HELLO_TO, channel::write_raw(con, &token)).await??)
     }
@@ -4,7 +4,7 @@ mod write_client;
 
 pub use crate::protocol::{
     glob::{Glob, GlobSet},
-    resolver::{Resolved, Table},
+    resolver::{Explanation, Metadata, MetadataQuery, PublisherUsage, Resolved, Table},
 };
 use crate::{
     config::Config,
@@ -12,7 +12,8 @@ use crate::{
     path::Path,
     pool::{Pool, Pooled},
     protocol::resolver::{
-        FromRead, FromWrite, Publisher, PublisherId, Referral, ToRead, ToWrite,
+        AuthRead, FromRead, FromWrite, Load, Locality, Publisher, PublisherId, Referral,
+        ToRead, ToWrite,
     },
     tls,
 };
@@ -35,8 +36,9 @@ use std::{
         Bound::{self, Included, Unbounded},
         HashMap, HashSet,
     },
-    iter::IntoIterator,
+    iter::{self, IntoIterator},
     marker::PhantomData,
+    mem,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     result,
     sync::Arc,
@@ -54,8 +56,14 @@ trait ToPath {
 impl ToPath for ToRead {
     fn path(&self) -> Option<&Path> {
         match self {
-            ToRead::List(p) | ToRead::Table(p) | ToRead::Resolve(p) => Some(p),
-            ToRead::ListMatching(_) | ToRead::GetChangeNr(_) => None,
+            ToRead::List(p)
+            | ToRead::Table(p)
+            | ToRead::Resolve(p)
+            | ToRead::Explain(p) => Some(p),
+            ToRead::ListMatching(_)
+            | ToRead::Search(_, _)
+            | ToRead::GetChangeNr(_)
+            | ToRead::Usage => None,
         }
     }
 }
@@ -63,13 +71,22 @@ impl ToPath for ToRead {
 impl ToPath for ToWrite {
     fn path(&self) -> Option<&Path> {
         match self {
-            ToWrite::Clear | ToWrite::Heartbeat => None,
+            ToWrite::Clear
+            | ToWrite::Heartbeat
+            | ToWrite::TimedHeartbeat(_)
+            | ToWrite::SetLoad(_)
+            | ToWrite::SetLocality(_) => None,
             ToWrite::Publish(p)
             | ToWrite::Unpublish(p)
             | ToWrite::UnpublishDefault(p)
             | ToWrite::PublishDefault(p)
             | ToWrite::PublishWithFlags(p, _)
-            | ToWrite::PublishDefaultWithFlags(p, _) => Some(p),
+            | ToWrite::PublishDefaultWithFlags(p, _)
+            | ToWrite::UnregisterReferral(p)
+            | ToWrite::SetAlias(p, _)
+            | ToWrite::RemoveAlias(p)
+            | ToWrite::SetMetadata(p, _) => Some(p),
+            ToWrite::RegisterReferral(r) => Some(&r.path),
         }
     }
 }
@@ -94,58 +111,41 @@ impl Router {
     {
         let now = Instant::now();
         let mut batches = HashMap::new();
-        let mut gc = Vec::new();
         let mut id = 0;
         for v in batch.iter() {
             let v = v.clone();
-            match v.path() {
-                None => batches.entry(None).or_insert_with(|| pool.take()).push((id, v)),
-                Some(path) => {
-                    let mut r = self.cached.range::<str, (Bound<&str>, Bound<&str>)>((
-                        Unbounded,
-                        Included(path.as_ref()),
-                    ));
-                    loop {
-                        match r.next_back() {
-                            None => {
-                                batches
-                                    .entry(None)
-                                    .or_insert_with(|| pool.take())
-                                    .push((id, v));
-                                break;
-                            }
-                            Some((p, (exp, r))) => {
-                                if !Path::is_parent(p, path) {
-                                    continue;
-                                } else {
-                                    if exp.is_none() || now < exp.unwrap() {
-                                        batches
-                                            .entry(Some(r.clone()))
-                                            .or_insert_with(|| pool.take())
-                                            .push((id, v))
-                                    } else {
-                                        gc.push(p.clone());
-                                        batches
-                                            .entry(None)
-                                            .or_insert_with(|| pool.take())
-                                            .push((id, v))
-                                    }
-                                    break;
-                                }
-                            }
+            let r = v.path().and_then(|path| self.route(now, path));
+            batches.entry(r).or_insert_with(|| pool.take()).push((id, v));
+            id += 1;
+        }
+        batches.into_iter()
+    }
+
+    /// Find the cached referral responsible for path, if any,
+    /// removing it if it has expired.
+    fn route(&mut self, now: Instant, path: &Path) -> Option<Arc<Referral>> {
+        let expired = {
+            let mut r = self.cached.range::<str, (Bound<&str>, Bound<&str>)>((
+                Unbounded,
+                Included(path.as_ref()),
+            ));
+            loop {
+                match r.next_back() {
+                    None => return None,
+                    Some((p, (exp, r))) => {
+                        if !Path::is_parent(p, path) {
+                            continue;
+                        } else if exp.is_none() || now < exp.unwrap() {
+                            return Some(r.clone());
+                        } else {
+                            break p.clone();
                         }
                     }
                 }
             }
-            id += 1;
-        }
-        for p in gc {
-            self.cached.remove(p.as_ref());
-        }
-        batches.into_iter().map(|(p, batch)| match p {
-            None => (None, batch),
-            Some(p) => (Some(p), batch),
-        })
+        };
+        self.cached.remove(&expired);
+        None
     }
 
     fn add_referral(&mut self, r: Arc<Referral>) -> Arc<Referral> {
@@ -188,6 +188,7 @@ where
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+        latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
         tls: Option<tls::CachedConnector>,
     ) -> Self;
     fn send(&mut self, batch: Pooled<Vec<(usize, T)>>) -> ResponseChan<F>;
@@ -199,6 +200,7 @@ impl Connection<ToRead, FromRead> for ReadClient {
         desired_auth: DesiredAuth,
         _writer_addr: SocketAddr,
         _secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+        _latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
         tls: Option<tls::CachedConnector>,
     ) -> Self {
         ReadClient::new(resolver, desired_auth, tls)
@@ -215,9 +217,10 @@ impl Connection<ToWrite, FromWrite> for WriteClient {
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+        latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
         tls: Option<tls::CachedConnector>,
     ) -> Self {
-        WriteClient::new(resolver, desired_auth, writer_addr, secrets, tls)
+        WriteClient::new(resolver, desired_auth, writer_addr, secrets, latency, tls)
     }
 
     fn send(&mut self, batch: Pooled<Vec<(usize, ToWrite)>>) -> ResponseChan<FromWrite> {
@@ -237,7 +240,10 @@ where
     by_server: HashMap<Arc<Referral>, C>,
     writer_addr: SocketAddr,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+    latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
     tls: Option<tls::CachedConnector>,
+    // sent first on every new connection
+    advertised: Vec<T>,
     phantom: PhantomData<(T, F)>,
     f_pool: Pool<Vec<F>>,
     fi_pool: Pool<Vec<(usize, F)>>,
@@ -250,27 +256,37 @@ where
     T: ToPath + Clone + Send + Sync + 'static,
     F: ToReferral + Clone + Send + Sync + 'static,
 {
-    fn send_to_server(
-        &mut self,
-        server: Option<Arc<Referral>>,
-        batch: Pooled<Vec<(usize, T)>>,
-    ) -> ResponseChan<F> {
+    fn connection(&mut self, server: Option<Arc<Referral>>) -> &mut C {
         let r = server.unwrap_or_else(|| self.default.clone());
-        match self.by_server.get_mut(&r) {
-            Some(con) => con.send(batch),
-            None => {
+        match self.by_server.entry(r) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
                 let mut con = C::new(
-                    r.clone(),
+                    e.key().clone(),
                     self.desired_auth.clone(),
                     self.writer_addr,
                     self.secrets.clone(),
+                    self.latency.clone(),
                     self.tls.clone(),
                 );
-                self.by_server.insert(r, con.clone());
-                con.send(batch)
+                if !self.advertised.is_empty() {
+                    let mut adv = self.ti_pool.take();
+                    adv.extend(self.advertised.iter().map(|m| (0, m.clone())));
+                    // the connection keeps and resends what it is advertising
+                    drop(con.send(adv));
+                }
+                e.insert(con)
             }
         }
     }
+
+    fn send_to_server(
+        &mut self,
+        server: Option<Arc<Referral>>,
+        batch: Pooled<Vec<(usize, T)>>,
+    ) -> ResponseChan<F> {
+        self.connection(server).send(batch)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -294,6 +310,7 @@ where
         ti_pool: Pool<Vec<(usize, T)>>,
     ) -> ResolverWrap<C, T, F> {
         let secrets = Arc::new(RwLock::new(HashMap::default()));
+        let latency = Arc::new(RwLock::new(HashMap::default()));
         let tls = default.tls.clone().map(tls::CachedConnector::new);
         let mut router = Router::new();
         let default: Arc<Referral> = Arc::new(default.to_referral());
@@ -305,10 +322,12 @@ where
             by_server: HashMap::new(),
             writer_addr,
             secrets,
+            latency,
             tls,
             f_pool,
             fi_pool,
             ti_pool,
+            advertised: Vec::new(),
             phantom: PhantomData,
         })))
     }
@@ -317,6 +336,12 @@ where
         Arc::clone(&self.0.lock().secrets)
     }
 
+    fn latency(&self) -> Vec<(SocketAddr, Duration)> {
+        let latency = Arc::clone(&self.0.lock().latency);
+        let latency = latency.read();
+        latency.iter().map(|(a, d)| (*a, *d)).collect()
+    }
+
     async fn send(
         &self,
         batch: &Pooled<Vec<T>>,
@@ -387,6 +412,36 @@ impl ChangeTracker {
     }
 }
 
+/// One resolver cluster consulted while tracing a resolution
+#[derive(Debug, Clone)]
+pub struct TraceHop {
+    /// The referral used to reach the cluster, including it's
+    /// addresses, the auth each address advertises, and the ttl.
+    pub referral: Referral,
+    /// true if the referral came from the client's cache, false if
+    /// it is the default resolver or was returned by the previous hop
+    pub cached: bool,
+    /// The member of the cluster that answered
+    pub resolver: SocketAddr,
+    /// The auth mechanism negotiated with `resolver`
+    pub auth: AuthRead,
+    /// What the cluster answered
+    pub reply: FromRead,
+}
+
+/// The chain of resolvers consulted to resolve a path, in the order
+/// they were asked.
+#[derive(Debug, Clone)]
+pub struct ResolveTrace {
+    pub path: Path,
+    /// The auth mechanism requested from every resolver, see
+    /// `TraceHop::auth` for what each one actually negotiated
+    pub desired_auth: DesiredAuth,
+    pub hops: Vec<TraceHop>,
+    /// The publishers referenced by the final answer
+    pub publishers: Vec<Publisher>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ResolverRead(ResolverWrap<ReadClient, ToRead, FromRead>);
 
@@ -454,8 +509,10 @@ impl ResolverRead {
                 m => bail!("unexpected result from list {:?}", m),
             };
             from_server.sort();
-            for p in (self.0).0.lock().router.cached.keys() {
-                if Path::is_immediate_parent(&path, p) {
+            let now = Instant::now();
+            for (p, (exp, _)) in (self.0).0.lock().router.cached.iter() {
+                let live = exp.map(|exp| now < exp).unwrap_or(true);
+                if live && Path::is_immediate_parent(&path, p) {
                     if let Err(i) = from_server.binary_search(p) {
                         from_server.insert(i, p.clone())
                     }
@@ -538,6 +595,28 @@ impl ResolverRead {
         Ok(results)
     }
 
+    /// Find the paths in the cluster matching `globset` whose
+    /// metadata matches `query`, along with their metadata. Only
+    /// paths that have metadata can match, and the user must be
+    /// allowed to list every glob in `globset`.
+    pub async fn search(
+        &self,
+        globset: &GlobSet,
+        query: &MetadataQuery,
+    ) -> Result<Vec<(Path, Metadata)>> {
+        let mut results = vec![];
+        let m = ToRead::Search(globset.clone(), query.clone());
+        self.send_and_aggregate(m, |reply| match reply {
+            FromRead::Search(mut s) => {
+                results.extend(s.matched.drain(..));
+                Ok(s.referrals)
+            }
+            m => bail!("unexpected search response {:?}", m),
+        })
+        .await?;
+        Ok(results)
+    }
+
     /// Check whether that have been any changes to the specified path
     /// or any of it's children on any server in the resolver
     /// cluster. A change in this context consists of,
@@ -584,6 +663,93 @@ impl ResolverRead {
         Ok(res)
     }
 
+    /// Explain the permissions the current user has at `path`. The
+    /// result lists every permission entry that applied to the user,
+    /// and which bits each entry granted or denied.
+    pub async fn explain(&self, path: Path) -> Result<Explanation> {
+        let mut to = RAWTOREADPOOL.take();
+        to.push(ToRead::Explain(path));
+        let (_, mut result) = self.send(&to).await?;
+        if result.len() != 1 {
+            bail!("expected 1 result from explain got {}", result.len());
+        } else {
+            match result.pop().unwrap() {
+                FromRead::Explain(e) => Ok(e),
+                m => bail!("unexpected result from explain {:?}", m),
+            }
+        }
+    }
+
+    /// Get the resource usage of every publisher, and the limits
+    /// that apply to them. Only resolver admins may do this.
+    pub async fn usage(&self) -> Result<Vec<PublisherUsage>> {
+        let mut to = RAWTOREADPOOL.take();
+        to.push(ToRead::Usage);
+        let (_, mut result) = self.send(&to).await?;
+        if result.len() != 1 {
+            bail!("expected 1 result from usage got {}", result.len());
+        } else {
+            match result.pop().unwrap() {
+                FromRead::Usage(u) => Ok(u),
+                FromRead::Denied => bail!("permission denied"),
+                m => bail!("unexpected result from usage {:?}", m),
+            }
+        }
+    }
+
+    /// Resolve `path` one hop at a time, recording every resolver
+    /// that was asked and what it answered. This is meant for
+    /// debugging referral setups, use `resolve` for normal work.
+    pub async fn trace_resolve(&self, path: Path) -> Result<ResolveTrace> {
+        let desired_auth = (self.0).0.lock().desired_auth.clone();
+        let mut hops = Vec::new();
+        let mut next: Option<Arc<Referral>> = None;
+        loop {
+            let (referral, cached, reply, answered) = {
+                let mut inner = (self.0).0.lock();
+                let (referral, cached) = match next.take() {
+                    Some(r) => (r, false),
+                    None => match inner.router.route(Instant::now(), &path) {
+                        None => (inner.default.clone(), false),
+                        Some(r) => {
+                            let cached = !Arc::ptr_eq(&r, &inner.default);
+                            (r, cached)
+                        }
+                    },
+                };
+                let mut to = inner.ti_pool.take();
+                to.push((0, ToRead::Resolve(path.clone())));
+                let (reply, answered) =
+                    inner.connection(Some(referral.clone())).send_traced(to);
+                (referral, cached, reply, answered)
+            };
+            let (mut publishers, mut result) = reply.await?;
+            let (resolver, auth) = answered.await?;
+            if result.len() != 1 {
+                bail!("expected 1 result from resolve got {}", result.len());
+            }
+            let (_, reply) = result.pop().unwrap();
+            let referred = match &reply {
+                FromRead::Referral(r) => {
+                    Some((self.0).0.lock().router.add_referral(Arc::new(r.clone())))
+                }
+                _ => None,
+            };
+            let referral = (*referral).clone();
+            hops.push(TraceHop { referral, cached, resolver, auth, reply });
+            match referred {
+                Some(r) => next = Some(r),
+                None => {
+                    let publishers = publishers.drain().map(|(_, p)| p).collect();
+                    break Ok(ResolveTrace { path, desired_auth, hops, publishers });
+                }
+            }
+            if hops.len() > MAX_REFERRALS {
+                bail!("maximum referral depth {} reached, giving up", MAX_REFERRALS);
+            }
+        }
+    }
+
     pub async fn table(&self, path: Path) -> Result<Table> {
         let mut to = RAWTOREADPOOL.take();
         to.push(ToRead::Table(path.clone()));
@@ -626,7 +792,8 @@ impl ResolverWrite {
             DesiredAuth::Local
             | DesiredAuth::Anonymous
             | DesiredAuth::Krb5 { .. }
-            | DesiredAuth::Tls { identity: None } => (),
+            | DesiredAuth::Tls { identity: None }
+            | DesiredAuth::Token { .. } => (),
             DesiredAuth::Tls { identity: Some(id) } => match &default.tls {
                 None => bail!("tls auth selected an no tls config"),
                 Some(tls) => {
@@ -725,6 +892,96 @@ impl ResolverWrite {
         self.send_expect(batch, FromWrite::Unpublished, ToWrite::UnpublishDefault).await
     }
 
+    /// Attach metadata to paths this writer publishes, replacing any
+    /// metadata they already had. Empty metadata removes it. The
+    /// resolver forgets the metadata of a path when nobody publishes
+    /// it anymore.
+    pub async fn set_metadata<I: IntoIterator<Item = (Path, Metadata)>>(
+        &self,
+        batch: I,
+    ) -> Result<()> {
+        self.send_expect(batch, FromWrite::Published, |(path, md)| {
+            ToWrite::SetMetadata(path, md)
+        })
+        .await
+    }
+
+    /// Register a child cluster at `referral.path`. The parent
+    /// removes the registration if it isn't renewed within the
+    /// referral's ttl, so this must be called periodically.
+    pub async fn register_referral(&self, referral: Referral) -> Result<()> {
+        self.send_expect(iter::once(referral), FromWrite::Published, ToWrite::RegisterReferral)
+            .await
+    }
+
+    /// Remove the registration of the child cluster at `path`
+    pub async fn unregister_referral(&self, path: Path) -> Result<()> {
+        self.send_expect(iter::once(path), FromWrite::Unpublished, ToWrite::UnregisterReferral)
+            .await
+    }
+
+    /// Make `path` an alias for `target`. Resolve, list, and table
+    /// queries at or below `path` will be answered from the same
+    /// place below `target`, which must be served by the same
+    /// cluster. Setting an existing alias switches it to the new
+    /// target atomically. Aliases are kept by the resolver servers
+    /// and don't survive a restart.
+    pub async fn set_alias(&self, path: Path, target: Path) -> Result<()> {
+        self.send_expect(iter::once((path, target)), FromWrite::Published, |(p, t)| {
+            ToWrite::SetAlias(p, t)
+        })
+        .await
+    }
+
+    /// Remove the alias at `path`
+    pub async fn remove_alias(&self, path: Path) -> Result<()> {
+        self.send_expect(iter::once(path), FromWrite::Unpublished, ToWrite::RemoveAlias)
+            .await
+    }
+
+    // send msg to every resolver cluster this writer has talked to,
+    // and remember it for the clusters it talks to later
+    async fn advertise(&self, msg: ToWrite) -> Result<()> {
+        let waiters = {
+            let mut guard = (self.0).0.lock();
+            let inner = &mut *guard;
+            let kind = mem::discriminant(&msg);
+            inner.advertised.retain(|m| mem::discriminant(m) != kind);
+            inner.advertised.push(msg.clone());
+            let servers = inner.by_server.keys().cloned().collect::<Vec<_>>();
+            servers
+                .into_iter()
+                .map(|r| {
+                    let mut batch = inner.ti_pool.take();
+                    batch.push((0, msg.clone()));
+                    inner.send_to_server(Some(r), batch)
+                })
+                .collect::<Vec<_>>()
+        };
+        for r in future::join_all(waiters).await {
+            let (_, mut r) = r?;
+            match r.pop() {
+                Some((_, FromWrite::Published)) => (),
+                m => bail!("unexpected response to {:?} {:?}", msg, m),
+            }
+        }
+        Ok(())
+    }
+
+    /// Advertise the publisher's weight and load to every resolver
+    /// cluster this writer talks to. Each connection sends it once,
+    /// and again when it reconnects.
+    pub async fn set_load(&self, load: Load) -> Result<()> {
+        self.advertise(ToWrite::SetLoad(load)).await
+    }
+
+    /// Advertise the publisher's locality to every resolver cluster
+    /// this writer talks to. Each connection sends it once, and again
+    /// when it reconnects.
+    pub async fn set_locality(&self, locality: Locality) -> Result<()> {
+        self.advertise(ToWrite::SetLocality(locality)).await
+    }
+
     // CR estokes: this is broken on complex clusters, but it's also
     // redundant, consider removing it.
     pub async fn clear(&self) -> Result<()> {
@@ -744,4 +1001,12 @@ impl ResolverWrite {
     pub(crate) fn secrets(&self) -> Arc<RwLock<FxHashMap<SocketAddr, u128>>> {
         self.0.secrets()
     }
+
+    /// The most recent heartbeat round trip time to each resolver
+    /// server we write to. Resolvers speaking protocol versions
+    /// older than 4 don't answer heartbeats, so they aren't
+    /// measured.
+    pub fn latency(&self) -> Vec<(SocketAddr, Duration)> {
+        self.0.latency()
+    }
 }
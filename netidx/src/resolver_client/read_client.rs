@@ -4,12 +4,14 @@ use super::common::{
 };
 use crate::{
     channel::{self, Channel, K5CtxWrap},
+    chars::Chars,
     os::local_auth::AuthClient,
     pool::Pooled,
     protocol::resolver::{
         Auth, AuthRead, ClientHello, FromRead, Publisher, Referral, ToRead,
     },
-    tls,
+    tls, token,
+    transport::{self, Socket},
     utils::Either,
 };
 use anyhow::{Context, Error, Result};
@@ -18,14 +20,14 @@ use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
 };
-use fxhash::FxHashSet;
+use fxhash::{FxHashMap, FxHashSet};
 use log::{info, warn};
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use std::{
     cmp::max, collections::HashSet, fmt::Debug, net::SocketAddr, sync::Arc,
     time::Duration,
 };
-use tokio::{net::TcpStream, task, time};
+use tokio::{task, time};
 
 // continue with timeout
 macro_rules! cwt {
@@ -43,9 +45,10 @@ async fn connect(
     resolver: &Referral,
     desired_auth: &DesiredAuth,
     tls: &Option<tls::CachedConnector>,
-) -> Result<Channel> {
+) -> Result<(SocketAddr, AuthRead, Channel)> {
     let mut addrs = resolver.addrs.clone();
     addrs.as_mut_slice().shuffle(&mut thread_rng());
+    let mut older: FxHashMap<SocketAddr, u64> = HashMap::default();
     let mut n = 0;
     loop {
         let (addr, auth) = &addrs[n % addrs.len()];
@@ -64,7 +67,7 @@ async fn connect(
             time::sleep(Duration::from_secs(wait)).await;
         }
         n += 1;
-        let mut con = match time::timeout(HELLO_TO, TcpStream::connect(&addr)).await {
+        let mut con = match time::timeout(HELLO_TO, transport::connect(*addr)).await {
             Ok(Ok(con)) => con,
             Err(_) => {
                 warn!(
@@ -80,48 +83,97 @@ async fn connect(
                 continue;
             }
         };
-        try_cf!("no delay", con.set_nodelay(true));
-        cwt!("send version", channel::write_raw(&mut con, &3u64));
-        if cwt!("recv version", channel::read_raw::<u64, _>(&mut con)) != 3 {
-            continue;
+        let version = older.get(addr).copied().unwrap_or(channel::VERSION);
+        match time::timeout(HELLO_TO, channel::offer_version(&mut con, version)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => match e.downcast_ref::<channel::OlderPeer>() {
+                Some(channel::OlderPeer(v)) if !older.contains_key(addr) => {
+                    // the server will hang up, try it again with it's version
+                    older.insert(*addr, *v);
+                    n -= 1;
+                    continue;
+                }
+                Some(_) | None => {
+                    warn!("version negotiation with {} failed {}", addr, e);
+                    continue;
+                }
+            },
+            Err(_) => {
+                warn!("version negotiation with {} timed out", addr);
+                continue;
+            }
         }
         let con = match (desired_auth, auth) {
             (DesiredAuth::Anonymous, _) => {
-                let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
+                let mut con = Channel::new::<ClientCtx, Socket>(None, con);
                 cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Anonymous)));
                 match cwt!("reply", con.receive::<AuthRead>()) {
                     AuthRead::Anonymous => (),
-                    AuthRead::Local | AuthRead::Krb5 | AuthRead::Tls => {
+                    AuthRead::Local
+                    | AuthRead::Krb5
+                    | AuthRead::Tls
+                    | AuthRead::Token => {
                         bail!("protocol error")
                     }
                 }
-                con
+                (AuthRead::Anonymous, con)
             }
             (
-                DesiredAuth::Krb5 { .. } | DesiredAuth::Local | DesiredAuth::Tls { .. },
+                DesiredAuth::Krb5 { .. }
+                | DesiredAuth::Local
+                | DesiredAuth::Tls { .. }
+                | DesiredAuth::Token { .. },
                 Auth::Anonymous,
             ) => {
                 bail!("requested authentication mechanism not supported")
             }
             (
-                DesiredAuth::Local | DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. },
+                DesiredAuth::Local
+                | DesiredAuth::Krb5 { .. }
+                | DesiredAuth::Tls { .. }
+                | DesiredAuth::Token { .. },
                 Auth::Local { path },
             ) => {
-                let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
+                let mut con = Channel::new::<ClientCtx, Socket>(None, con);
                 let tok = cwt!("local token", AuthClient::token(&*path));
                 cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Local)));
                 cwt!("token", con.send_one(&tok));
                 match cwt!("reply", con.receive::<AuthRead>()) {
                     AuthRead::Local => (),
-                    AuthRead::Krb5 | AuthRead::Anonymous | AuthRead::Tls => {
+                    AuthRead::Krb5
+                    | AuthRead::Anonymous
+                    | AuthRead::Tls
+                    | AuthRead::Token => {
                         bail!("protocol error")
                     }
                 }
-                con
+                (AuthRead::Local, con)
             }
-            (DesiredAuth::Local, Auth::Krb5 { .. } | Auth::Tls { .. }) => {
+            (DesiredAuth::Local, Auth::Krb5 { .. } | Auth::Tls { .. } | Auth::Token) => {
                 bail!("local auth not supported")
             }
+            (DesiredAuth::Token { token }, Auth::Token) => {
+                let mut con = Channel::new::<ClientCtx, Socket>(None, con);
+                let tok = token::load(token)?;
+                cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Token)));
+                cwt!("token", con.send_one(&tok));
+                match cwt!("reply", con.receive::<AuthRead>()) {
+                    AuthRead::Token => (),
+                    AuthRead::Krb5
+                    | AuthRead::Anonymous
+                    | AuthRead::Local
+                    | AuthRead::Tls => {
+                        bail!("protocol error")
+                    }
+                }
+                (AuthRead::Token, con)
+            }
+            (DesiredAuth::Token { .. }, Auth::Krb5 { .. } | Auth::Tls { .. }) => {
+                bail!("token auth not supported")
+            }
+            (DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. }, Auth::Token) => {
+                bail!("token auth is required")
+            }
             (DesiredAuth::Krb5 { .. }, Auth::Tls { .. }) => {
                 bail!("krb5 authentication is not supported")
             }
@@ -131,8 +183,13 @@ async fn connect(
                 cwt!("hello", channel::write_raw(&mut con, &hello));
                 let ctx = cwt!("k5auth", krb5_authentication(upn, &*spn, &mut con));
                 match cwt!("reply", channel::read_raw::<AuthRead, _>(&mut con)) {
-                    AuthRead::Krb5 => Channel::new(Some(K5CtxWrap::new(ctx)), con),
-                    AuthRead::Local | AuthRead::Anonymous | AuthRead::Tls => {
+                    AuthRead::Krb5 => {
+                        (AuthRead::Krb5, Channel::new(Some(K5CtxWrap::new(ctx)), con))
+                    }
+                    AuthRead::Local
+                    | AuthRead::Anonymous
+                    | AuthRead::Tls
+                    | AuthRead::Token => {
                         bail!("protocol error")
                     }
                 }
@@ -156,33 +213,50 @@ async fn connect(
                 let tls = ctx.connect(name, con).await?;
                 let mut con = Channel::new::<
                     ClientCtx,
-                    tokio_rustls::client::TlsStream<TcpStream>,
+                    tokio_rustls::client::TlsStream<Socket>,
                 >(None, tls);
                 match cwt!("reply", con.receive::<AuthRead>()) {
-                    AuthRead::Tls => con,
-                    AuthRead::Local | AuthRead::Anonymous | AuthRead::Krb5 { .. } => {
+                    AuthRead::Tls => (AuthRead::Tls, con),
+                    AuthRead::Local
+                    | AuthRead::Anonymous
+                    | AuthRead::Krb5 { .. }
+                    | AuthRead::Token => {
                         bail!("protocol error")
                     }
                 }
             }
         };
-        break Ok(con);
+        let (auth, con) = con;
+        break Ok((*addr, auth, con));
     }
 }
 
-type Batch = (Pooled<Vec<(usize, ToRead)>>, oneshot::Sender<Response<FromRead>>);
+/// The resolver server that answered a batch, and the auth
+/// mechanism negotiated with it
+pub(super) type Answered = (SocketAddr, AuthRead);
+
+type Batch = (
+    Pooled<Vec<(usize, ToRead)>>,
+    oneshot::Sender<Response<FromRead>>,
+    Option<oneshot::Sender<Answered>>,
+);
 
 fn partition_publishers(m: FromRead) -> Either<FromRead, Publisher> {
     match m {
         FromRead::Publisher(p) => Either::Right(p),
         FromRead::Denied
+        | FromRead::Draining
         | FromRead::Error(_)
+        | FromRead::Explain(_)
         | FromRead::GetChangeNr(_)
         | FromRead::List(_)
         | FromRead::ListMatching(_)
         | FromRead::Referral(_)
         | FromRead::Resolved(_)
-        | FromRead::Table(_) => Either::Left(m),
+        | FromRead::Search(_)
+        | FromRead::Table(_)
+        | FromRead::Usage(_)
+        | FromRead::RateLimited(_) => Either::Left(m),
     }
 }
 
@@ -192,12 +266,12 @@ async fn connection(
     desired_auth: DesiredAuth,
     tls: Option<tls::CachedConnector>,
 ) {
-    let mut con: Option<Channel> = None;
+    let mut con: Option<(SocketAddr, AuthRead, Channel)> = None;
     let mut bad_addrs: FxHashSet<SocketAddr> = HashSet::default();
     'main: loop {
         match receiver.next().await {
             None => break,
-            Some((tx_batch, reply)) => {
+            Some((tx_batch, reply, answered)) => {
                 let mut tries: usize = 0;
                 'batch: loop {
                     if tries > 3 {
@@ -208,15 +282,16 @@ async fn connection(
                         time::sleep(Duration::from_secs(wait)).await
                     }
                     tries += 1;
-                    let c = match con {
-                        Some(ref mut c) => c,
+                    let (addr, auth, c) = match con {
+                        Some((ref addr, ref auth, ref mut c)) => (*addr, auth.clone(), c),
                         None => {
                             match connect(&mut bad_addrs, &resolver, &desired_auth, &tls)
                                 .await
                             {
                                 Ok(c) => {
                                     con = Some(c);
-                                    con.as_mut().unwrap()
+                                    let (addr, auth, c) = con.as_mut().unwrap();
+                                    (*addr, auth.clone(), c)
                                 }
                                 Err(e) => {
                                     con = None;
@@ -234,7 +309,9 @@ async fn connection(
                         max(HELLO_TO, Duration::from_micros(tx_batch.len() as u64 * 50));
                     for (_, m) in &*tx_batch {
                         match m {
-                            ToRead::List(_) | ToRead::ListMatching(_) => {
+                            ToRead::List(_)
+                            | ToRead::ListMatching(_)
+                            | ToRead::Search(_, _) => {
                                 timeout += HELLO_TO;
                             }
                             _ => (),
@@ -256,17 +333,37 @@ async fn connection(
                         Ok(()) => {
                             let mut rx_batch = RAWFROMREADPOOL.take();
                             let mut publishers = PUBLISHERPOOL.take();
+                            let mut draining = false;
                             while rx_batch.len() < tx_batch.len() {
                                 let f =
                                     c.receive_batch_fn(|m| {
                                         match partition_publishers(m) {
+                                            Either::Left(FromRead::Draining) => {
+                                                draining = true
+                                            }
+                                            Either::Left(FromRead::RateLimited(n)) => {
+                                                let e = Chars::from(
+                                                    "read rate limit exceeded",
+                                                );
+                                                for _ in 0..n.0 {
+                                                    rx_batch
+                                                        .push(FromRead::Error(e.clone()))
+                                                }
+                                            }
                                             Either::Left(m) => rx_batch.push(m),
                                             Either::Right(p) => {
                                                 publishers.insert(p.id, p);
                                             }
                                         }
                                     });
-                                match time::timeout(timeout, f).await {
+                                let r = time::timeout(timeout, f).await;
+                                match r {
+                                    Ok(Ok(())) if draining => {
+                                        info!("resolver server {} is draining", addr);
+                                        bad_addrs.insert(addr);
+                                        con = None;
+                                        continue 'batch;
+                                    }
                                     Ok(Ok(())) => (),
                                     Ok(Err(e)) => {
                                         warn!("read connection failed {}", e);
@@ -288,6 +385,9 @@ async fn connection(
                                     .map(|(i, m)| (tx_batch[i].0, m)),
                             );
                             let _ = reply.send((publishers, result));
+                            if let Some(answered) = answered {
+                                let _ = answered.send((addr, auth));
+                            }
                             break;
                         }
                     }
@@ -319,7 +419,19 @@ impl ReadClient {
         batch: Pooled<Vec<(usize, ToRead)>>,
     ) -> ResponseChan<FromRead> {
         let (tx, rx) = oneshot::channel();
-        let _ = self.0.unbounded_send((batch, tx));
+        let _ = self.0.unbounded_send((batch, tx, None));
         rx
     }
+
+    /// Like `send`, but also report which resolver server answered
+    /// the batch, and the auth mechanism negotiated with it.
+    pub(crate) fn send_traced(
+        &mut self,
+        batch: Pooled<Vec<(usize, ToRead)>>,
+    ) -> (ResponseChan<FromRead>, oneshot::Receiver<Answered>) {
+        let (tx, rx) = oneshot::channel();
+        let (tx_answered, rx_answered) = oneshot::channel();
+        let _ = self.0.unbounded_send((batch, tx, Some(tx_answered)));
+        (rx, rx_answered)
+    }
 }
@@ -11,9 +11,12 @@ use crate::{
     pool::Pooled,
     protocol::resolver::{
         Auth, AuthChallenge, AuthWrite, ClientHello, ClientHelloWrite, FromWrite,
-        HashMethod, ReadyForOwnershipCheck, Referral, Secret, ServerHelloWrite, ToWrite,
+        HashMethod, Load, Locality, Metadata, ReadyForOwnershipCheck, Referral, Secret,
+        ServerHelloWrite, ToWrite,
     },
-    tls, utils,
+    tls, token,
+    transport::{self, Socket},
+    utils,
 };
 use anyhow::{anyhow, Result};
 use cross_krb5::{ClientCtx, K5Ctx};
@@ -30,7 +33,6 @@ use parking_lot::{Mutex, RwLock};
 use rand::{thread_rng, Rng};
 use std::{cmp::max, fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
-    net::TcpStream,
     sync::broadcast::{self, error::RecvError},
     task,
     time::{self, Instant, Interval},
@@ -60,14 +62,24 @@ macro_rules! wt {
 
 const HB: Duration = Duration::from_secs(TTL / 2);
 const LINGER: Duration = Duration::from_secs(TTL / 10);
+const LATENCY_PERIOD: Duration = Duration::from_secs(10);
+
+// resolvers older than this don't answer timestamped heartbeats
+const HEARTBEAT_VERSION: u64 = 4;
 
 struct Connection {
     con: Option<Channel>,
+    version: u64,
+    epoch: Instant,
     resolver_addr: SocketAddr,
     resolver_auth: Auth,
     write_addr: SocketAddr,
     published: IndexMap<Path, ToWrite, FxBuildHasher>,
+    metadata: FxHashMap<Path, Metadata>,
+    load: Option<Load>,
+    locality: Option<Locality>,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+    latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
     security_context: Option<K5CtxWrap<ClientCtx>>,
     tls: Option<tls::CachedConnector>,
     desired_auth: DesiredAuth,
@@ -87,6 +99,18 @@ impl Connection {
     }
 
     async fn republish(&mut self, con: &mut Channel, ttl_expired: bool) -> Result<()> {
+        let advertised = self
+            .load
+            .map(ToWrite::SetLoad)
+            .into_iter()
+            .chain(self.locality.clone().map(ToWrite::SetLocality));
+        for msg in advertised {
+            con.send_one(&msg).await?;
+            match con.receive().await? {
+                FromWrite::Published => (),
+                r => warn!("unexpected response to {:?} {:?}", msg, r),
+            }
+        }
         let len = self.published.len();
         if len == 0 {
             info!("connected to resolver {:?} for write", self.resolver_addr);
@@ -142,7 +166,15 @@ impl Connection {
                             warn!("republish unexpected response to {:?} from resolver {:?}", msg, r)
                         }
                     },
-                    ToWrite::Heartbeat => (),
+                    ToWrite::Heartbeat
+                    | ToWrite::TimedHeartbeat(_)
+                    | ToWrite::RegisterReferral(_)
+                    | ToWrite::UnregisterReferral(_)
+                    | ToWrite::SetAlias(_, _)
+                    | ToWrite::RemoveAlias(_)
+                    | ToWrite::SetLoad(_)
+                    | ToWrite::SetLocality(_)
+                    | ToWrite::SetMetadata(_, _) => (),
                 }
             }
             for p in to_remove {
@@ -169,6 +201,19 @@ impl Connection {
                 self.resolver_addr, success, self.degraded
             );
         }
+        // metadata can only be set once the paths are published
+        if self.metadata.len() > 0 {
+            for (path, md) in self.metadata.iter() {
+                con.queue_send(&ToWrite::SetMetadata(path.clone(), md.clone()))?
+            }
+            con.flush().await?;
+            for path in self.metadata.keys() {
+                match con.receive().await? {
+                    FromWrite::Published => (),
+                    r => warn!("unexpected response to set metadata {} {:?}", path, r),
+                }
+            }
+        }
         Ok(())
     }
 
@@ -185,14 +230,19 @@ impl Connection {
             Ok(con.send_one(&answer).await?)
         }
         info!("write_con connecting to resolver {:?}", self.resolver_addr);
-        let mut con = wt!("connect", TcpStream::connect(&self.resolver_addr))??;
-        debug!("setting no delay = true");
-        con.set_nodelay(true)?;
-        debug!("writing protocol version 3");
-        wt!("write version", channel::write_raw(&mut con, &3u64))??;
-        debug!("reading protocol version");
-        if wt!("read version", channel::read_raw::<u64, _>(&mut con))?? != 3 {
-            bail!("incompatible protocol version")
+        let mut con = wt!("connect", transport::connect(self.resolver_addr))??;
+        debug!("offering protocol version {}", self.version);
+        match wt!("version", channel::offer_version(&mut con, self.version))? {
+            Ok(_) => (),
+            Err(e) => match e.downcast_ref::<channel::OlderPeer>() {
+                None => return Err(e),
+                Some(channel::OlderPeer(v)) => {
+                    // the server will hang up, try it again with it's version
+                    self.version = *v;
+                    con = wt!("connect", transport::connect(self.resolver_addr))??;
+                    wt!("version", channel::offer_version(&mut con, *v))??;
+                }
+            },
         }
         let sec = Duration::from_secs(1);
         let hello = |auth| {
@@ -215,12 +265,13 @@ impl Connection {
                         "read anonymous",
                         channel::read_raw::<ServerHelloWrite, _>(&mut con)
                     )??;
-                    (Channel::new::<ClientCtx, TcpStream>(None, con), r, false)
+                    (Channel::new::<ClientCtx, Socket>(None, con), r, false)
                 }
                 (
                     DesiredAuth::Krb5 { .. }
                     | DesiredAuth::Tls { .. }
-                    | DesiredAuth::Local,
+                    | DesiredAuth::Local
+                    | DesiredAuth::Token { .. },
                     Auth::Anonymous,
                 ) => {
                     bail!("authentication not supported")
@@ -228,12 +279,13 @@ impl Connection {
                 (
                     DesiredAuth::Local
                     | DesiredAuth::Krb5 { .. }
-                    | DesiredAuth::Tls { .. },
+                    | DesiredAuth::Tls { .. }
+                    | DesiredAuth::Token { .. },
                     Auth::Local { path },
                 ) => {
                     debug!("local authentication selected");
                     let secret = self.secrets.read().get(&self.resolver_addr).map(|u| *u);
-                    let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
+                    let mut con = Channel::new::<ClientCtx, Socket>(None, con);
                     match secret {
                         Some(secret) => {
                             debug!("reusing existing session");
@@ -264,9 +316,52 @@ impl Connection {
                         }
                     }
                 }
-                (DesiredAuth::Local, Auth::Krb5 { .. } | Auth::Tls { .. }) => {
+                (
+                    DesiredAuth::Local,
+                    Auth::Krb5 { .. } | Auth::Tls { .. } | Auth::Token,
+                ) => {
                     bail!("local auth not supported")
                 }
+                (DesiredAuth::Token { token }, Auth::Token) => {
+                    debug!("token authentication selected");
+                    let secret = self.secrets.read().get(&self.resolver_addr).map(|u| *u);
+                    let mut con = Channel::new::<ClientCtx, Socket>(None, con);
+                    match secret {
+                        Some(secret) => {
+                            debug!("reusing existing session");
+                            wt!(
+                                "write token reuse",
+                                con.send_one(&hello(AuthWrite::Reuse))
+                            )??;
+                            wt!("auth challenge", auth_challenge(&mut con, secret))??;
+                            let r = wt!(
+                                "recv token hello",
+                                con.receive::<ServerHelloWrite>()
+                            )??;
+                            (con, r, false)
+                        }
+                        None => {
+                            debug!("starting a new token auth session");
+                            let tok = token::load(token)?;
+                            wt!(
+                                "send token hello",
+                                con.send_one(&hello(AuthWrite::Token))
+                            )??;
+                            wt!("send token", con.send_one(&tok))??;
+                            let r = wt!(
+                                "recv token hello",
+                                con.receive::<ServerHelloWrite>()
+                            )??;
+                            (con, r, true)
+                        }
+                    }
+                }
+                (DesiredAuth::Token { .. }, Auth::Krb5 { .. } | Auth::Tls { .. }) => {
+                    bail!("token auth not supported")
+                }
+                (DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. }, Auth::Token) => {
+                    bail!("token auth is required")
+                }
                 (DesiredAuth::Krb5 { .. }, Auth::Tls { .. }) => {
                     bail!("krb5 auth is not supported")
                 }
@@ -339,7 +434,7 @@ impl Connection {
                             let tls = ctx.connect(name, con).await?;
                             let mut con = Channel::new::<
                                 ClientCtx,
-                                tokio_rustls::client::TlsStream<TcpStream>,
+                                tokio_rustls::client::TlsStream<Socket>,
                             >(None, tls);
                             wt!("tls auth challenge", auth_challenge(&mut con, secret))??;
                             let r: ServerHelloWrite =
@@ -360,7 +455,7 @@ impl Connection {
                             let tls = ctx.connect(name, con).await?;
                             let mut con = Channel::new::<
                                 ClientCtx,
-                                tokio_rustls::client::TlsStream<TcpStream>,
+                                tokio_rustls::client::TlsStream<Socket>,
                             >(None, tls);
                             let r: ServerHelloWrite =
                                 wt!("recv tls hello", con.receive())??;
@@ -384,13 +479,20 @@ impl Connection {
         }
         if !r.ttl_expired && !self.degraded {
             info!("connected to resolver {:?} for write", self.resolver_addr);
-            self.con = Some(con);
-            Ok(self.set_ttl(r.ttl))
         } else {
             self.republish(&mut con, r.ttl_expired).await?;
-            self.con = Some(con);
-            Ok(self.set_ttl(r.ttl))
         }
+        self.con = Some(con);
+        self.set_ttl(r.ttl);
+        // measure the round trip time right away instead of waiting
+        // for the first latency heartbeat
+        if self.version >= HEARTBEAT_VERSION {
+            if let Err(e) = self.heartbeat().await {
+                self.con = None;
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 
     fn handle_failed_connect(&mut self, e: anyhow::Error) {
@@ -399,10 +501,36 @@ impl Connection {
         warn!("write connection {:?} failed {}", self.resolver_addr, e);
     }
 
+    // send a heartbeat on an established connection, and if the
+    // resolver answers them measure the round trip time
+    async fn heartbeat(&mut self) -> Result<()> {
+        let c = match self.con {
+            Some(ref mut c) => c,
+            None => return Ok(()),
+        };
+        if self.version < HEARTBEAT_VERSION {
+            return c.send_one(&ToWrite::Heartbeat).await;
+        }
+        let ts = self.epoch.elapsed().as_micros() as u64;
+        c.send_one(&ToWrite::TimedHeartbeat(ts)).await?;
+        match time::timeout(HELLO_TO, c.receive()).await?? {
+            FromWrite::HeartbeatReply(ts) => {
+                let sent = Duration::from_micros(ts);
+                let rtt = self.epoch.elapsed().saturating_sub(sent);
+                self.latency.write().insert(self.resolver_addr, rtt);
+                Ok(())
+            }
+            FromWrite::Draining => {
+                bail!("resolver server {:?} is draining", self.resolver_addr)
+            }
+            m => bail!("unexpected heartbeat reply {:?}", m),
+        }
+    }
+
     async fn send_heartbeat(&mut self) {
         for _ in 0..3 {
             match self.con {
-                Some(ref mut c) => match c.send_one(&ToWrite::Heartbeat).await {
+                Some(_) => match self.heartbeat().await {
                     Ok(()) => break,
                     Err(e) => {
                         info!("write_con heartbeat send error {}", e);
@@ -444,11 +572,34 @@ impl Connection {
                 }
                 ToWrite::Unpublish(p) | ToWrite::UnpublishDefault(p) => {
                     self.published.swap_remove(p);
+                    self.metadata.remove(p);
                 }
                 ToWrite::Clear => {
                     self.published.clear();
+                    self.metadata.clear();
+                }
+                ToWrite::SetMetadata(p, md) => {
+                    if md.is_empty() {
+                        self.metadata.remove(p);
+                    } else {
+                        self.metadata.insert(p.clone(), md.clone());
+                    }
+                }
+                ToWrite::SetLoad(load) => {
+                    self.load = Some(*load);
+                }
+                ToWrite::SetLocality(locality) => {
+                    self.locality = Some(locality.clone());
                 }
-                ToWrite::Heartbeat => (),
+                // registrations are renewed by the caller, and
+                // aliases are owned by the resolver, they aren't
+                // republished
+                ToWrite::Heartbeat
+                | ToWrite::TimedHeartbeat(_)
+                | ToWrite::RegisterReferral(_)
+                | ToWrite::UnregisterReferral(_)
+                | ToWrite::SetAlias(_, _)
+                | ToWrite::RemoveAlias(_) => (),
             }
         }
         let timeout = max(HELLO_TO, Duration::from_micros(tx.batch.len() as u64 * 100));
@@ -458,7 +609,10 @@ impl Connection {
         c.flush_timeout(timeout).await?;
         let mut rx_batch = RAWFROMWRITEPOOL.take();
         while rx_batch.len() < tx.batch.len() {
-            time::timeout(timeout, c.receive_batch(&mut *rx_batch)).await??
+            time::timeout(timeout, c.receive_batch(&mut *rx_batch)).await??;
+            if rx_batch.contains(&FromWrite::Draining) {
+                bail!("resolver server {:?} is draining", self.resolver_addr)
+            }
         }
         for ((_, tx), rx) in tx.batch.iter().zip(rx_batch.iter()) {
             match tx {
@@ -490,6 +644,7 @@ impl Connection {
         write_addr: SocketAddr,
         desired_auth: DesiredAuth,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+        latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
         tls: Option<tls::CachedConnector>,
     ) {
         let now = Instant::now();
@@ -498,16 +653,23 @@ impl Connection {
             resolver_auth,
             write_addr,
             published: IndexMap::default(),
+            metadata: FxHashMap::default(),
+            load: None,
+            locality: None,
             secrets,
+            latency,
             desired_auth,
             security_context: None,
             tls,
             con: None,
+            version: channel::VERSION,
+            epoch: Instant::now(),
             degraded: false,
             active: false,
             heartbeat: time::interval_at(now + HB, HB),
             disconnect: time::interval_at(now + LINGER, LINGER),
         };
+        let mut ping = time::interval(LATENCY_PERIOD);
         loop {
             #[rustfmt::skip]
             select_biased! {
@@ -526,6 +688,10 @@ impl Connection {
                         t.send_heartbeat().await;
                     }
                 },
+                _ = ping.tick().fuse() => if let Err(e) = t.heartbeat().await {
+                    info!("write_con latency heartbeat error {}", e);
+                    t.con = None;
+                },
                 batch = receiver.recv().fuse() => match batch {
 		    Err(RecvError::Closed) => break,
 		    Err(RecvError::Lagged(_)) => {
@@ -549,7 +715,15 @@ impl Connection {
 				    ToWrite::Clear => {
 					t.published.insert(Path::from(""), ToWrite::Clear);
 				    },
-				    ToWrite::Heartbeat => (),
+				    ToWrite::Heartbeat
+					| ToWrite::TimedHeartbeat(_)
+					| ToWrite::RegisterReferral(_)
+					| ToWrite::UnregisterReferral(_)
+					| ToWrite::SetAlias(_, _)
+					| ToWrite::RemoveAlias(_)
+					| ToWrite::SetLoad(_)
+					| ToWrite::SetLocality(_)
+					| ToWrite::SetMetadata(_, _) => (),
 				}
 			    }
 			    warn!("write batch failed {}", e)
@@ -566,6 +740,7 @@ async fn write_mgr(
     resolver: Arc<Referral>,
     desired_auth: DesiredAuth,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+    latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
     write_addr: SocketAddr,
     tls: Option<tls::CachedConnector>,
 ) -> Result<()> {
@@ -575,6 +750,7 @@ async fn write_mgr(
         let auth = auth.clone();
         let desired_auth = desired_auth.clone();
         let secrets = secrets.clone();
+        let latency = latency.clone();
         let tls = tls.clone();
         let receiver = sender.subscribe();
         task::spawn(async move {
@@ -585,6 +761,7 @@ async fn write_mgr(
                 write_addr,
                 desired_auth,
                 secrets,
+                latency,
                 tls,
             )
             .await;
@@ -620,12 +797,21 @@ impl WriteClient {
         desired_auth: DesiredAuth,
         write_addr: SocketAddr,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
+        latency: Arc<RwLock<FxHashMap<SocketAddr, Duration>>>,
         tls: Option<tls::CachedConnector>,
     ) -> Self {
         let (to_tx, to_rx) = mpsc::unbounded();
         task::spawn(async move {
-            let r =
-                write_mgr(to_rx, resolver, desired_auth, secrets, write_addr, tls).await;
+            let r = write_mgr(
+                to_rx,
+                resolver,
+                desired_auth,
+                secrets,
+                latency,
+                write_addr,
+                tls,
+            )
+            .await;
             info!("write manager exited {:?}", r);
         });
         Self(to_tx)
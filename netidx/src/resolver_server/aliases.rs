@@ -0,0 +1,217 @@
+use super::{
+    audit::{self, Action, Audit, Event},
+    auth::{PMap, Permissions, UserInfo},
+    referrals::Registry,
+};
+use crate::{
+    path::Path,
+    protocol::resolver::{FromRead, FromWrite, Table},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::{
+        BTreeMap, Bound,
+        Bound::{Included, Unbounded},
+    },
+    sync::Arc,
+};
+
+/// Find the alias path is at or below, if any, and return it along
+/// with the path it stands for.
+pub(super) fn rewrite<'a>(
+    aliases: &'a BTreeMap<Path, Path>,
+    path: &Path,
+) -> Option<(&'a Path, Path)> {
+    aliases
+        .range::<str, (Bound<&str>, Bound<&str>)>((Unbounded, Included(path.as_ref())))
+        .rev()
+        .find_map(|(alias, target)| {
+            Path::strip_prefix(alias, path).map(|rest| (alias, target.append(rest)))
+        })
+}
+
+/// How the answer to a read must be adjusted to account for aliases
+#[derive(Debug)]
+pub(super) enum Fixup {
+    /// The query was made below `alias` and was rewritten to `target`
+    Aliased { alias: Path, target: Path },
+    /// The query is a list or table of this path, which may have
+    /// aliases below it
+    List(Path),
+}
+
+// rewrite paths below target to be below alias
+fn unalias(alias: &Path, target: &Path, paths: &mut Vec<Path>) {
+    for p in paths.iter_mut() {
+        if let Some(rest) = Path::strip_prefix(target, p) {
+            *p = alias.append(rest);
+        }
+    }
+}
+
+// add the children of parent that lead to aliases
+fn add_aliases(aliases: &BTreeMap<Path, Path>, parent: &Path, paths: &mut Vec<Path>) {
+    let n = Path::levels(parent);
+    for alias in aliases.keys() {
+        if Path::levels(alias) > n && Path::is_parent(parent, alias) {
+            let mut p: &str = alias.as_ref();
+            while Path::levels(p) > n + 1 {
+                p = Path::dirname(p).unwrap_or("/");
+            }
+            if !paths.iter().any(|l| &**l == p) {
+                paths.push(Path::from(String::from(p)));
+            }
+        }
+    }
+}
+
+impl Fixup {
+    pub(super) fn apply(&self, aliases: &BTreeMap<Path, Path>, r: FromRead) -> FromRead {
+        match (self, r) {
+            (Fixup::Aliased { alias, target }, FromRead::List(mut paths)) => {
+                unalias(alias, target, &mut paths);
+                FromRead::List(paths)
+            }
+            (Fixup::Aliased { alias, target }, FromRead::Table(mut table)) => {
+                unalias(alias, target, &mut table.rows);
+                FromRead::Table(table)
+            }
+            (Fixup::Aliased { target, .. }, FromRead::Resolved(mut r)) => {
+                r.target = Some(target.clone());
+                FromRead::Resolved(r)
+            }
+            (Fixup::Aliased { alias, target }, FromRead::Referral(_)) => {
+                let e = format!(
+                    "the target {} of alias {} is not served by this cluster",
+                    target, alias
+                );
+                FromRead::Error(e.into())
+            }
+            (Fixup::List(parent), FromRead::List(mut paths)) => {
+                add_aliases(aliases, parent, &mut paths);
+                FromRead::List(paths)
+            }
+            (Fixup::List(parent), FromRead::Table(Table { mut rows, cols })) => {
+                add_aliases(aliases, parent, &mut rows);
+                FromRead::Table(Table { rows, cols })
+            }
+            (Fixup::Aliased { .. }, r) | (Fixup::List(_), r) => r,
+        }
+    }
+}
+
+/// The aliases known to this server. Every batch of reads is
+/// rewritten against a snapshot, so changing an alias is atomic with
+/// respect to each batch.
+#[derive(Debug, Clone)]
+pub(super) struct Aliases(Arc<Mutex<Arc<BTreeMap<Path, Path>>>>);
+
+impl Aliases {
+    pub(super) fn new() -> Self {
+        Aliases(Arc::new(Mutex::new(Arc::new(BTreeMap::new()))))
+    }
+
+    pub(super) fn snapshot(&self) -> Arc<BTreeMap<Path, Path>> {
+        self.0.lock().clone()
+    }
+
+    fn check(
+        aliases: &BTreeMap<Path, Path>,
+        path: &Path,
+        target: &Path,
+    ) -> Result<(), String> {
+        if Path::levels(&**path) == 0 {
+            return Err("the root can't be an alias".into());
+        }
+        if Path::is_parent(path, target) || Path::is_parent(target, path) {
+            return Err(format!("alias {} can't be above or below it's target", path));
+        }
+        for (p, t) in aliases.iter().filter(|(p, _)| *p != path) {
+            if Path::is_parent(p, path) || Path::is_parent(path, p) {
+                return Err(format!(
+                    "alias {} can't be above or below alias {}",
+                    path, p
+                ));
+            }
+            if Path::is_parent(p, target) {
+                return Err(format!("the target {} is below alias {}", target, p));
+            }
+            if Path::is_parent(path, t) {
+                return Err(format!("alias {} is above the target of alias {}", path, p));
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn set(
+        &self,
+        pmap: Option<&PMap>,
+        audit: &Audit,
+        uifo: &UserInfo,
+        registry: &Registry,
+        path: Path,
+        target: Path,
+    ) -> FromWrite {
+        if !Path::is_absolute(&*path) || !Path::is_absolute(&*target) {
+            return FromWrite::Error("absolute paths required".into());
+        }
+        if let Some(r) = registry.referral(&path) {
+            return FromWrite::Referral(r);
+        }
+        if registry.referral(&target).is_some() {
+            let e = format!("the target {} is not served by this cluster", target);
+            return FromWrite::Error(e.into());
+        }
+        if !pmap.map(|p| p.allowed(&*path, Permissions::ALIAS, uifo)).unwrap_or(true) {
+            audit.log(|| Event::Denied {
+                user: audit::user(uifo),
+                action: Action::Alias,
+                path: path.clone(),
+            });
+            return FromWrite::Denied;
+        }
+        let mut aliases = self.0.lock();
+        if let Err(e) = Self::check(&aliases, &path, &target) {
+            return FromWrite::Error(e.into());
+        }
+        if aliases.get(&path) != Some(&target) {
+            audit.log(|| Event::Aliased {
+                user: audit::user(uifo),
+                path: path.clone(),
+                target: target.clone(),
+            });
+            Arc::make_mut(&mut *aliases).insert(path, target);
+        }
+        FromWrite::Published
+    }
+
+    pub(super) fn remove(
+        &self,
+        pmap: Option<&PMap>,
+        audit: &Audit,
+        uifo: &UserInfo,
+        registry: &Registry,
+        path: Path,
+    ) -> FromWrite {
+        if !Path::is_absolute(&*path) {
+            return FromWrite::Error("absolute paths required".into());
+        }
+        if let Some(r) = registry.referral(&path) {
+            return FromWrite::Referral(r);
+        }
+        if !pmap.map(|p| p.allowed(&*path, Permissions::ALIAS, uifo)).unwrap_or(true) {
+            audit.log(|| Event::Denied {
+                user: audit::user(uifo),
+                action: Action::Alias,
+                path: path.clone(),
+            });
+            return FromWrite::Denied;
+        }
+        let mut aliases = self.0.lock();
+        if aliases.contains_key(&path) {
+            Arc::make_mut(&mut *aliases).remove(&path);
+            audit.log(|| Event::Unaliased { user: audit::user(uifo), path });
+        }
+        FromWrite::Unpublished
+    }
+}
@@ -0,0 +1,362 @@
+use super::{
+    auth::UserInfo,
+    config::{Audit as AuditConfig, AuditSink},
+};
+use crate::{config::Config as ClientConfig, path::Path, publisher::PublisherBuilder};
+use anyhow::Result;
+use arcstr::ArcStr;
+use chrono::prelude::*;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+    select_biased,
+};
+use log::{error, warn};
+use rand::{thread_rng, Rng};
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    task, time,
+};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Mode {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Action {
+    Resolve,
+    List,
+    ListMatching,
+    Search,
+    Table,
+    Publish,
+    PublishDefault,
+    Register,
+    Alias,
+    Metadata,
+}
+
+/// An auditable resolver server event
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum Event {
+    Authenticated {
+        client: SocketAddr,
+        mode: Mode,
+        mechanism: &'static str,
+        user: Option<ArcStr>,
+    },
+    AuthenticationFailed {
+        client: SocketAddr,
+        mode: Mode,
+        mechanism: &'static str,
+        error: String,
+    },
+    OwnershipCheckFailed {
+        write_addr: SocketAddr,
+        error: String,
+    },
+    Denied {
+        user: Option<ArcStr>,
+        action: Action,
+        path: Path,
+    },
+    Read {
+        user: Option<ArcStr>,
+        action: Action,
+        path: Path,
+    },
+    Published {
+        user: Option<ArcStr>,
+        publisher: SocketAddr,
+        path: Path,
+        default: bool,
+    },
+    Unpublished {
+        user: Option<ArcStr>,
+        publisher: SocketAddr,
+        path: Path,
+        default: bool,
+    },
+    Cleared {
+        user: Option<ArcStr>,
+        publisher: SocketAddr,
+    },
+    Registered {
+        user: Option<ArcStr>,
+        path: Path,
+        addrs: Vec<SocketAddr>,
+    },
+    Unregistered {
+        user: Option<ArcStr>,
+        path: Path,
+    },
+    Aliased {
+        user: Option<ArcStr>,
+        path: Path,
+        target: Path,
+    },
+    Unaliased {
+        user: Option<ArcStr>,
+        path: Path,
+    },
+    /// `count` records were dropped because the sink fell behind
+    Dropped {
+        count: u64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Record {
+    timestamp: DateTime<Utc>,
+    resolver: SocketAddr,
+    #[serde(flatten)]
+    event: Event,
+}
+
+pub(super) fn user(uifo: &UserInfo) -> Option<ArcStr> {
+    uifo.user_info.as_ref().map(|u| u.name.clone())
+}
+
+// The maximum number of records waiting for the sink, after which
+// records are dropped and counted.
+const MAX_QUEUED: usize = 100_000;
+
+#[derive(Default)]
+struct Queue {
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl Queue {
+    fn received(&self, n: usize) {
+        self.queued.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    fn dropped(&self, n: usize) {
+        self.dropped.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+struct AuditInner {
+    resolver: SocketAddr,
+    read_sample_rate: f64,
+    records: UnboundedSender<Record>,
+    queue: Arc<Queue>,
+}
+
+impl AuditInner {
+    fn send(&self, record: Record) -> bool {
+        if self.queue.queued.fetch_add(1, Ordering::Relaxed) >= MAX_QUEUED {
+            self.queue.queued.fetch_sub(1, Ordering::Relaxed);
+            false
+        } else if self.records.unbounded_send(record).is_err() {
+            self.queue.queued.fetch_sub(1, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// A handle to the audit log. Logging is non blocking, records are
+/// written by a background task. If the background task falls too
+/// far behind then records are dropped, and a `Dropped` record
+/// counting them is logged once it catches up. If auditing isn't
+/// configured then logging does nothing.
+#[derive(Clone)]
+pub(super) struct Audit(Option<Arc<AuditInner>>);
+
+impl Audit {
+    pub(super) fn new(resolver: SocketAddr, cfg: Option<&AuditConfig>) -> Self {
+        match cfg {
+            None => Audit(None),
+            Some(cfg) => {
+                let (tx, rx) = unbounded();
+                let queue = Arc::new(Queue::default());
+                match &cfg.sink {
+                    AuditSink::File { path, max_size, max_files } => {
+                        let (path, q) = (path.clone(), queue.clone());
+                        task::spawn(file_sink(rx, q, path, *max_size, *max_files));
+                    }
+                    AuditSink::Netidx { config, path } => {
+                        let (config, q) = (config.clone(), queue.clone());
+                        task::spawn(netidx_sink(rx, q, config, path.clone()));
+                    }
+                }
+                Audit(Some(Arc::new(AuditInner {
+                    resolver,
+                    read_sample_rate: cfg.read_sample_rate,
+                    records: tx,
+                    queue,
+                })))
+            }
+        }
+    }
+
+    /// log the event returned by `f`. `f` is only called if auditing
+    /// is enabled.
+    pub(super) fn log<F: FnOnce() -> Event>(&self, f: F) {
+        if let Some(inner) = &self.0 {
+            let timestamp = Utc::now();
+            let count = inner.queue.dropped.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                let event = Event::Dropped { count };
+                let record = Record { timestamp, resolver: inner.resolver, event };
+                if !inner.send(record) {
+                    inner.queue.dropped.fetch_add(count, Ordering::Relaxed);
+                }
+            }
+            let record = Record { timestamp, resolver: inner.resolver, event: f() };
+            if !inner.send(record) {
+                inner.queue.dropped(1)
+            }
+        }
+    }
+
+    /// log the event returned by `f` subject to the configured read
+    /// sample rate.
+    pub(super) fn log_read<F: FnOnce() -> Event>(&self, f: F) {
+        if let Some(inner) = &self.0 {
+            if inner.read_sample_rate >= 1.
+                || thread_rng().gen_bool(inner.read_sample_rate)
+            {
+                self.log(f)
+            }
+        }
+    }
+}
+
+fn rotated(path: &FsPath, n: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", n));
+    PathBuf::from(s)
+}
+
+async fn rotate(path: &FsPath, max_files: usize) -> Result<()> {
+    for i in (1..max_files).rev() {
+        match fs::rename(rotated(path, i), rotated(path, i + 1)).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(fs::rename(path, rotated(path, 1)).await?)
+}
+
+async fn open(path: &FsPath) -> Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let size = file.metadata().await?.len();
+    Ok((file, size))
+}
+
+async fn file_sink(
+    rx: UnboundedReceiver<Record>,
+    queue: Arc<Queue>,
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+) {
+    let mut rx = rx.ready_chunks(10_000);
+    let mut buf = Vec::new();
+    let mut file: Option<(File, u64)> = None;
+    while let Some(mut batch) = rx.next().await {
+        queue.received(batch.len());
+        for r in batch.drain(..) {
+            match serde_json::to_writer(&mut buf, &r) {
+                Ok(()) => buf.push(b'\n'),
+                Err(e) => error!("failed to serialize audit record {}", e),
+            }
+        }
+        let res: Result<()> = async {
+            if file.is_none() {
+                file = Some(open(&path).await?);
+            }
+            let (f, size) = file.as_mut().unwrap();
+            if *size > 0 && *size + buf.len() as u64 > max_size {
+                f.flush().await?;
+                rotate(&path, max_files).await?;
+                *f = open(&path).await?.0;
+                *size = 0;
+            }
+            f.write_all(&buf).await?;
+            f.flush().await?;
+            *size += buf.len() as u64;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            error!("failed to write audit log {:?}: {}", path, e);
+            file = None;
+        }
+        buf.clear();
+    }
+}
+
+async fn netidx_sink(
+    rx: UnboundedReceiver<Record>,
+    queue: Arc<Queue>,
+    config: Option<PathBuf>,
+    path: Path,
+) {
+    let mut rx = rx.ready_chunks(10_000).fuse();
+    // records that arrive before the publisher is up are dropped, so
+    // the queue doesn't grow while we wait
+    let (publisher, val) = loop {
+        let res: Result<_> = async {
+            let cfg = match &config {
+                None => ClientConfig::load_default()?,
+                Some(config) => ClientConfig::load(config)?,
+            };
+            let publisher = PublisherBuilder::new(cfg).build().await?;
+            let val = publisher.publish(path.clone(), "")?;
+            Ok((publisher, val))
+        }
+        .await;
+        match res {
+            Ok(r) => break r,
+            Err(e) => {
+                warn!("failed to publish audit log at {}, will retry: {}", path, e);
+                let mut retry = Box::pin(time::sleep(Duration::from_secs(10)).fuse());
+                loop {
+                    select_biased! {
+                        () = retry => break,
+                        batch = rx.next() => match batch {
+                            None => return,
+                            Some(batch) => {
+                                queue.received(batch.len());
+                                queue.dropped(batch.len());
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    };
+    while let Some(mut batch) = rx.next().await {
+        queue.received(batch.len());
+        let mut updates = publisher.start_batch();
+        for r in batch.drain(..) {
+            match serde_json::to_string(&r) {
+                Ok(s) => val.update(&mut updates, s),
+                Err(e) => error!("failed to serialize audit record {}", e),
+            }
+        }
+        updates.commit(None).await
+    }
+}
@@ -1,8 +1,11 @@
-use super::config;
+use super::config::{self, Config, MemberServer};
 use crate::{
     os::Mapper,
     path::Path,
-    protocol::{glob::Scope, resolver::Referral},
+    protocol::{
+        glob::Scope,
+        resolver::{Explanation, PermissionEntry, Referral},
+    },
 };
 use anyhow::{anyhow, Error, Result};
 use arcstr::ArcStr;
@@ -10,11 +13,12 @@ use chrono::prelude::*;
 use fxhash::FxHashMap;
 use netidx_core::pool::Pool;
 use netidx_netproto::resolver;
+use smallvec::SmallVec;
 use std::{
     cell::RefCell,
     collections::{BTreeMap, Bound, HashMap},
     convert::TryFrom,
-    iter,
+    fmt, iter,
     net::SocketAddr,
     sync::Arc,
 };
@@ -28,6 +32,8 @@ bitflags! {
         const LIST             = 0x08;
         const PUBLISH          = 0x10;
         const PUBLISH_DEFAULT  = 0x20;
+        const REGISTER         = 0x40;
+        const ALIAS            = 0x80;
     }
 }
 
@@ -60,9 +66,15 @@ impl TryFrom<&str> for Permissions {
                 'd' => {
                     p |= Permissions::PUBLISH_DEFAULT;
                 }
+                'r' => {
+                    p |= Permissions::REGISTER;
+                }
+                'a' => {
+                    p |= Permissions::ALIAS;
+                }
                 c => {
                     return Err(anyhow!(
-                        "unrecognized permission bit {}, valid bits are !swlpd",
+                        "unrecognized permission bit {}, valid bits are !swlpdra",
                         c
                     ))
                 }
@@ -72,6 +84,27 @@ impl TryFrom<&str> for Permissions {
     }
 }
 
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits = [
+            (Permissions::DENY, '!'),
+            (Permissions::SUBSCRIBE, 's'),
+            (Permissions::WRITE, 'w'),
+            (Permissions::LIST, 'l'),
+            (Permissions::PUBLISH, 'p'),
+            (Permissions::PUBLISH_DEFAULT, 'd'),
+            (Permissions::REGISTER, 'r'),
+            (Permissions::ALIAS, 'a'),
+        ];
+        for (bit, c) in bits {
+            if self.contains(bit) {
+                write!(f, "{}", c)?
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Entity(u32);
 
@@ -138,6 +171,31 @@ impl UserDb {
         }
     }
 
+    pub(super) fn make_ifo(
+        &mut self,
+        resolver: SocketAddr,
+        user: &str,
+        primary_group_s: ArcStr,
+        groups_s: Vec<ArcStr>,
+    ) -> Arc<UserInfo> {
+        let primary_group = self.entity(&primary_group_s);
+        let groups = groups_s.iter().map(|b| self.entity(b)).collect::<Vec<_>>();
+        let id = self.entity(user);
+        Arc::new(UserInfo {
+            timestamp: Utc::now(),
+            id,
+            primary_group,
+            groups,
+            user_info: Some(resolver::UserInfo {
+                name: ArcStr::from(user),
+                primary_group: primary_group_s,
+                groups: groups_s.into(),
+                resolver,
+                token: bytes::Bytes::new(),
+            }),
+        })
+    }
+
     pub(crate) async fn ifo(
         &mut self,
         resolver: SocketAddr,
@@ -150,24 +208,8 @@ impl UserDb {
                 Some(user) if now - user.timestamp < self.timeout => Ok(user.clone()),
                 Some(_) | None => {
                     let (primary_group_s, groups_s) = self.mapper.groups(user).await?;
-                    let primary_group = self.entity(&primary_group_s);
-                    let groups =
-                        groups_s.iter().map(|b| self.entity(b)).collect::<Vec<_>>();
-                    let id = self.entity(user);
-                    let ifo = Arc::new(UserInfo {
-                        timestamp: Utc::now(),
-                        id,
-                        primary_group,
-                        groups,
-                        user_info: Some(resolver::UserInfo {
-                            name: ArcStr::from(user),
-                            primary_group: primary_group_s,
-                            groups: groups_s.into(),
-                            resolver,
-                            token: bytes::Bytes::new(),
-                        }),
-                    });
-                    self.users.insert(self.names[&id].clone(), ifo.clone());
+                    let ifo = self.make_ifo(resolver, user, primary_group_s, groups_s);
+                    self.users.insert(self.names[&ifo.id].clone(), ifo.clone());
                     Ok(ifo)
                 }
             },
@@ -175,6 +217,75 @@ impl UserDb {
     }
 }
 
+/// The identity to explain permissions for
+#[derive(Debug, Clone)]
+pub enum Identity {
+    /// The anonymous user
+    Anonymous,
+    /// A user whose groups will be looked up using the configured
+    /// id map of the member server
+    Mapped(String),
+    /// A user with the specified primary group and groups. The id
+    /// map will not be consulted.
+    Explicit { user: String, primary_group: String, groups: Vec<String> },
+}
+
+/// Explain the permissions `identity` would have at `path` on member
+/// server `member` of the resolver cluster described by `cfg`. This
+/// does not require a running resolver server.
+pub async fn explain(
+    cfg: &Config,
+    member: usize,
+    identity: &Identity,
+    path: &Path,
+) -> Result<Explanation> {
+    let member: &MemberServer = match cfg.member_servers.get(member) {
+        None => bail!("no such member server {}", member),
+        Some(m) => m,
+    };
+    if let Some((child, _)) = cfg
+        .children
+        .range::<str, (Bound<&str>, Bound<&str>)>((
+            Bound::Unbounded,
+            Bound::Included(path),
+        ))
+        .next_back()
+    {
+        if Path::is_parent(child, path) {
+            bail!("{} is handled by the child resolver cluster at {}", path, child)
+        }
+    }
+    if !Path::is_parent(cfg.root(), path) {
+        bail!("{} is not under the root of this cluster {}", path, cfg.root())
+    }
+    let mut users = UserDb::new(member.id_map_timeout, Mapper::new(cfg, member).await?);
+    let pmap = PMap::from_file(&cfg.perms, &mut users, cfg.root(), &cfg.children)?;
+    let uifo = match identity {
+        Identity::Anonymous => ANONYMOUS.clone(),
+        Identity::Mapped(user) => users.ifo(member.addr, Some(user)).await?,
+        Identity::Explicit { user, primary_group, groups } => users.make_ifo(
+            member.addr,
+            user,
+            ArcStr::from(primary_group.as_str()),
+            groups.iter().map(|g| ArcStr::from(g.as_str())).collect(),
+        ),
+    };
+    match &member.auth {
+        config::Auth::Anonymous => Ok(Explanation {
+            resolver: member.addr,
+            path: path.clone(),
+            user: None,
+            enforced: false,
+            permissions: Permissions::all().bits(),
+            entries: vec![],
+        }),
+        config::Auth::Local { .. }
+        | config::Auth::Krb5 { .. }
+        | config::Auth::Tls { .. }
+        | config::Auth::Token { .. } => Ok(pmap.explain(member.addr, path, &uifo)),
+    }
+}
+
 fn subst(expr: &str, dst: &mut String, sub: &str) {
     dst.clear();
     for c in expr.chars() {
@@ -199,8 +310,18 @@ pub(super) struct PMap {
     normal: BTreeMap<Path, FxHashMap<Entity, Permissions>>,
     user_dynamic: BTreeMap<Path, Permissions>,
     group_dynamic: BTreeMap<Path, Vec<(String, Permissions)>>,
+    names: FxHashMap<Entity, ArcStr>,
 }
 
+enum Source<'a> {
+    Entity(&'a Entity),
+    User,
+    Group(&'a str),
+}
+
+type Explain<'a> =
+    Option<&'a mut dyn FnMut(&str, Source<'_>, Permissions, Permissions, Permissions)>;
+
 impl PMap {
     pub(super) fn from_file(
         file: &config::PMap,
@@ -209,6 +330,7 @@ impl PMap {
         children: &BTreeMap<Path, Referral>,
     ) -> Result<Self> {
         let mut normal = BTreeMap::new();
+        let mut names = HashMap::default();
         let mut user_dynamic = BTreeMap::new();
         let mut group_dynamic = BTreeMap::new();
         for (path, tbl) in file.0.iter() {
@@ -262,12 +384,13 @@ impl PMap {
                 let mut entry = HashMap::default();
                 for (ent, perm) in tbl.iter() {
                     let entity = if ent == "" { ANONYMOUS.id } else { db.entity(ent) };
+                    names.insert(entity, ArcStr::from(ent.as_str()));
                     entry.insert(entity, Permissions::try_from(perm.as_str())?);
                 }
                 normal.insert(path, entry);
             }
         }
-        Ok(PMap { normal, user_dynamic, group_dynamic })
+        Ok(PMap { normal, user_dynamic, group_dynamic, names })
     }
 
     pub(crate) fn allowed(
@@ -316,6 +439,62 @@ impl PMap {
     }
 
     pub(crate) fn permissions(&self, path: &str, user: &UserInfo) -> Permissions {
+        self.walk(path, user, None)
+    }
+
+    /// Explain how the permissions of `user` at `path` were arrived
+    /// at.
+    pub(crate) fn explain(
+        &self,
+        resolver: SocketAddr,
+        path: &Path,
+        user: &UserInfo,
+    ) -> Explanation {
+        let mut entries = Vec::new();
+        let permissions = self.walk(
+            path,
+            user,
+            Some(&mut |s, src, p, gr, dn| {
+                let (path, entity) = match src {
+                    Source::Entity(e) => (
+                        Path::from(ArcStr::from(s)),
+                        self.names.get(e).cloned().unwrap_or_else(|| ArcStr::from("")),
+                    ),
+                    Source::User => {
+                        let parent = Path::dirname(s).unwrap_or("/");
+                        (
+                            Path::from(ArcStr::from(parent)).append("$[user]"),
+                            ArcStr::from("$[user]"),
+                        )
+                    }
+                    Source::Group(expr) => {
+                        let parent = Path::dirname(s).unwrap_or("/");
+                        (
+                            Path::from(ArcStr::from(parent)).append("$[group]"),
+                            ArcStr::from(expr),
+                        )
+                    }
+                };
+                entries.push(PermissionEntry {
+                    path,
+                    entity,
+                    permissions: p.bits(),
+                    granted: gr.bits(),
+                    denied: (dn & !Permissions::DENY).bits(),
+                })
+            }),
+        );
+        Explanation {
+            resolver,
+            path: path.clone(),
+            user: user.user_info.clone(),
+            enforced: true,
+            permissions: permissions.bits(),
+            entries,
+        }
+    }
+
+    fn walk(&self, path: &str, user: &UserInfo, mut explain: Explain) -> Permissions {
         Path::dirnames(path).fold(Permissions::empty(), |p, s| {
             let (basename, dirname) = (Path::basename(s), Path::dirname(s));
             let uifo = user.user_info.as_ref();
@@ -326,35 +505,39 @@ impl PMap {
                         let p = match self.user_dynamic.get(parent) {
                             None => p,
                             Some(ud) if sub == uifo.name => {
-                                if ud.contains(Permissions::DENY) {
+                                let np = if ud.contains(Permissions::DENY) {
                                     p & !*ud
                                 } else {
                                     p | *ud
+                                };
+                                if let Some(f) = explain.as_mut() {
+                                    f(s, Source::User, *ud, np & !p, p & !np)
                                 }
+                                np
                             }
                             Some(_) => p,
                         };
                         let p = match self.group_dynamic.get(parent) {
                             None => p,
                             Some(gd) => gd.iter().fold(p, |p, (expr, gd)| {
-                                BUF.with(|buf| {
+                                let matched = BUF.with(|buf| {
                                     let mut buf = buf.borrow_mut();
                                     subst(&expr, &mut *buf, sub);
-                                    if uifo
-                                        .groups
-                                        .iter()
-                                        .find(|g| &**buf == &**g)
-                                        .is_some()
-                                    {
-                                        if gd.contains(Permissions::DENY) {
-                                            p & !*gd
-                                        } else {
-                                            p | *gd
-                                        }
+                                    uifo.groups.iter().find(|g| &**buf == &**g).is_some()
+                                });
+                                if matched {
+                                    let np = if gd.contains(Permissions::DENY) {
+                                        p & !*gd
                                     } else {
-                                        p
+                                        p | *gd
+                                    };
+                                    if let Some(f) = explain.as_mut() {
+                                        f(s, Source::Group(expr), *gd, np & !p, p & !np)
                                     }
-                                })
+                                    np
+                                } else {
+                                    p
+                                }
                             }),
                         };
                         p
@@ -376,6 +559,30 @@ impl PMap {
                                 }
                             }
                         });
+                    if let Some(f) = explain.as_mut() {
+                        // credit each bit to the first entry that
+                        // granted or denied it
+                        let mut granting = ap & !dp & !p;
+                        let mut denying = ap & dp;
+                        let mut seen: SmallVec<[&Entity; 16]> = SmallVec::new();
+                        for e in user.entities() {
+                            if seen.contains(&e) {
+                                continue;
+                            }
+                            seen.push(e);
+                            if let Some(p_) = set.get(e) {
+                                if p_.contains(Permissions::DENY) {
+                                    let dn = *p_ & denying;
+                                    denying &= !dn;
+                                    f(s, Source::Entity(e), *p_, Permissions::empty(), dn)
+                                } else {
+                                    let gr = *p_ & granting;
+                                    granting &= !gr;
+                                    f(s, Source::Entity(e), *p_, gr, Permissions::empty())
+                                }
+                            }
+                        }
+                    }
                     ap & !dp
                 }
             };
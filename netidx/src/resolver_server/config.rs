@@ -3,7 +3,9 @@ use crate::{
     chars::Chars,
     path::Path,
     protocol::resolver::{self, Referral},
-    tls, utils,
+    tls,
+    token::KeySet,
+    utils,
 };
 use anyhow::Result;
 use serde_json::from_str;
@@ -11,14 +13,15 @@ use std::{
     collections::{
         BTreeMap, Bound,
         Bound::{Excluded, Unbounded},
-        HashMap,
+        HashMap, HashSet,
     },
     convert::AsRef,
     convert::Into,
+    convert::TryFrom,
     default::Default,
     fs::read_to_string,
     net::{IpAddr, SocketAddr},
-    path::Path as FsPath,
+    path::{Path as FsPath, PathBuf},
     time::Duration,
 };
 
@@ -28,9 +31,24 @@ type Entity = String;
 #[derive(Debug, Clone)]
 pub enum Auth {
     Anonymous,
-    Local { path: Chars },
-    Krb5 { spn: Chars },
-    Tls { name: Chars, trusted: Chars, certificate: Chars, private_key: Chars },
+    Local {
+        path: Chars,
+    },
+    Krb5 {
+        spn: Chars,
+    },
+    Tls {
+        name: Chars,
+        trusted: Chars,
+        crl: Option<Chars>,
+        certificate: Chars,
+        private_key: Chars,
+        user: Vec<tls::CertField>,
+        groups: Vec<tls::CertField>,
+    },
+    Token {
+        keys: Chars,
+    },
 }
 
 impl Into<resolver::Auth> for Auth {
@@ -40,6 +58,7 @@ impl Into<resolver::Auth> for Auth {
             Self::Local { path } => resolver::Auth::Local { path },
             Self::Krb5 { spn } => resolver::Auth::Krb5 { spn },
             Self::Tls { name, .. } => resolver::Auth::Tls { name },
+            Self::Token { .. } => resolver::Auth::Token,
         }
     }
 }
@@ -50,12 +69,28 @@ impl From<file::Auth> for Auth {
             file::Auth::Anonymous => Self::Anonymous,
             file::Auth::Krb5(spn) => Self::Krb5 { spn: Chars::from(spn) },
             file::Auth::Local(path) => Self::Local { path: Chars::from(path) },
-            file::Auth::Tls { name, trusted, certificate, private_key } => Self::Tls {
+            file::Auth::Tls {
+                name,
+                trusted,
+                crl,
+                certificate,
+                private_key,
+                user,
+                groups,
+            } => Self::Tls {
                 name: Chars::from(name),
                 trusted: Chars::from(trusted),
+                crl: crl.map(Chars::from),
                 certificate: Chars::from(certificate),
                 private_key: Chars::from(private_key),
+                user: if user.is_empty() {
+                    vec![tls::CertField::CommonName]
+                } else {
+                    user
+                },
+                groups,
             },
+            file::Auth::Token(keys) => Self::Token { keys: Chars::from(keys) },
         }
     }
 }
@@ -81,6 +116,7 @@ pub(crate) fn check_addrs<T: Clone + Into<resolver::Auth>>(
             }
             // CR estokes: verify the certificates
             resolver::Auth::Tls { .. } => (),
+            resolver::Auth::Token => (),
         }
     }
     if !a.iter().all(|(a, _)| a.ip().is_loopback())
@@ -104,9 +140,10 @@ impl Default for PMap {
 /// The on disk format, encoded as JSON
 pub mod file {
     use super::{super::config::check_addrs, resolver, Chars, PMap};
-    use crate::{path::Path, pool::Pooled};
+    use crate::{path::Path, pool::Pooled, tls::CertField};
     use anyhow::Result;
     use std::{
+        collections::HashMap,
         net::{IpAddr, Ipv4Addr, SocketAddr},
         path::PathBuf,
     };
@@ -117,7 +154,27 @@ pub mod file {
         Anonymous,
         Krb5(String),
         Local(String),
-        Tls { name: String, trusted: String, certificate: String, private_key: String },
+        Tls {
+            name: String,
+            trusted: String,
+            /// A file containing certificate revocation lists
+            #[serde(default)]
+            crl: Option<String>,
+            certificate: String,
+            private_key: String,
+            /// Where to find the user name in client certificates,
+            /// the first field present is used. The default is the
+            /// common name.
+            #[serde(default)]
+            user: Vec<CertField>,
+            /// Where to find additional groups in client
+            /// certificates. Groups found in the certificate are
+            /// added to the groups from the id map.
+            #[serde(default)]
+            groups: Vec<CertField>,
+        },
+        /// The path to the key set used to verify tokens
+        Token(String),
     }
 
     impl Into<resolver::Auth> for Auth {
@@ -127,6 +184,7 @@ pub mod file {
                 Self::Krb5(spn) => resolver::Auth::Krb5 { spn: Chars::from(spn) },
                 Self::Local(path) => resolver::Auth::Local { path: Chars::from(path) },
                 Self::Tls { name, .. } => resolver::Auth::Tls { name: Chars::from(name) },
+                Self::Token(_) => resolver::Auth::Token,
             }
         }
     }
@@ -138,6 +196,7 @@ pub mod file {
         Krb5(String),
         Local(String),
         Tls(String),
+        Token,
     }
 
     impl Into<resolver::Auth> for RefAuth {
@@ -147,6 +206,7 @@ pub mod file {
                 Self::Krb5(spn) => resolver::Auth::Krb5 { spn: Chars::from(spn) },
                 Self::Local(path) => resolver::Auth::Local { path: Chars::from(path) },
                 Self::Tls(name) => resolver::Auth::Tls { name: Chars::from(name) },
+                Self::Token => resolver::Auth::Token,
             }
         }
     }
@@ -202,6 +262,12 @@ pub mod file {
         DoNotMap,
         Command,
         Socket,
+        /// read user to group mappings from the file specified by
+        /// id_map_command. The file is toml if its name ends in
+        /// .toml, otherwise json.
+        File,
+        /// look up groups in process using getpwnam and getgrouplist
+        GetGroupList,
     }
 
     fn default_id_map_type() -> IdMapType {
@@ -212,6 +278,99 @@ pub mod file {
         3600
     }
 
+    fn default_audit_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_audit_max_files() -> usize {
+        10
+    }
+
+    fn default_audit_sample_rate() -> f64 {
+        1.
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub enum AuditSink {
+        /// write json lines to path, rotating it when it grows
+        /// larger than max_size bytes, and keeping at most max_files
+        /// old logs (path.1 ... path.max_files)
+        File {
+            path: PathBuf,
+            #[serde(default = "default_audit_max_size")]
+            max_size: u64,
+            #[serde(default = "default_audit_max_files")]
+            max_files: usize,
+        },
+        /// publish each record as a json string at path, using the
+        /// specified client config, or the default client config if
+        /// none is specified.
+        Netidx {
+            #[serde(default)]
+            config: Option<PathBuf>,
+            path: String,
+        },
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Audit {
+        pub sink: AuditSink,
+        /// the fraction of successful reads (resolve, list, table)
+        /// to log, between 0 and 1
+        #[serde(default = "default_audit_sample_rate")]
+        pub read_sample_rate: f64,
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Limit {
+        /// the maximum number of paths all the publishers of a user
+        /// may publish together. Each anonymous publisher has it's
+        /// own quota.
+        #[serde(default)]
+        pub max_published: Option<usize>,
+        /// the maximum number of default publishers all the
+        /// publishers of a user may publish together
+        #[serde(default)]
+        pub max_default_published: Option<usize>,
+        /// the maximum number of read requests per second for all
+        /// the read clients of a user together. Each anonymous read
+        /// client has it's own limit.
+        #[serde(default)]
+        pub reads_per_second: Option<u32>,
+        /// the maximum number of paths a list_matching or search
+        /// query may return
+        #[serde(default)]
+        pub max_glob_results: Option<usize>,
+        /// the maximum encoded size, in bytes, of the metadata a
+        /// publisher may attach to one path
+        #[serde(default)]
+        pub max_metadata_bytes: Option<usize>,
+        /// the maximum encoded size, in bytes, of all the metadata
+        /// one publisher may attach to the paths it publishes
+        #[serde(default)]
+        pub max_publisher_metadata_bytes: Option<usize>,
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Limits {
+        /// the limits for users without a more specific entry
+        #[serde(default)]
+        pub default: Limit,
+        /// limits for specific users or groups. A user's own entry
+        /// takes precedence over entries for their groups, if there
+        /// are entries for more than one of a user's groups the most
+        /// permissive limit wins.
+        #[serde(default)]
+        pub entities: HashMap<String, Limit>,
+        /// users and groups allowed to see the usage of every publisher
+        #[serde(default)]
+        pub admins: Vec<String>,
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[serde(deny_unknown_fields)]
     pub struct MemberServer {
@@ -230,6 +389,23 @@ pub mod file {
         pub id_map_type: IdMapType,
         #[serde(default = "default_id_map_timeout")]
         pub id_map_timeout: u64,
+        #[serde(default)]
+        pub audit: Option<Audit>,
+        #[serde(default)]
+        pub limits: Limits,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Register {
+        /// the client config used to reach the parent cluster, or
+        /// the default client config if none is specified.
+        #[serde(default)]
+        pub config: Option<PathBuf>,
+        /// how long in seconds the parent will keep the registration
+        /// without a heartbeat. Heartbeats are sent every ttl / 2
+        /// seconds.
+        pub ttl: u16,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -237,6 +413,10 @@ pub mod file {
     pub struct Config {
         pub children: Vec<Referral>,
         pub parent: Option<Referral>,
+        /// register this cluster as a child of the parent instead of
+        /// being listed in the parent's children
+        #[serde(default)]
+        pub register: Option<Register>,
         pub member_servers: Vec<MemberServer>,
         pub perms: PMap,
     }
@@ -248,6 +428,99 @@ pub enum IdMap {
     PlatformDefault,
     Command(String),
     Socket(String),
+    File(String),
+    GetGroupList,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuditSink {
+    File { path: PathBuf, max_size: u64, max_files: usize },
+    Netidx { config: Option<PathBuf>, path: Path },
+}
+
+#[derive(Debug, Clone)]
+pub struct Audit {
+    pub(super) sink: AuditSink,
+    pub(super) read_sample_rate: f64,
+}
+
+impl TryFrom<file::Audit> for Audit {
+    type Error = anyhow::Error;
+
+    fn try_from(a: file::Audit) -> Result<Self> {
+        if !(0. ..=1.).contains(&a.read_sample_rate) {
+            bail!("read_sample_rate must be between 0 and 1")
+        }
+        let sink = match a.sink {
+            file::AuditSink::File { path, max_size, max_files } => {
+                if max_size == 0 {
+                    bail!("audit max_size must be positive")
+                }
+                if max_files == 0 {
+                    bail!("audit max_files must be positive")
+                }
+                AuditSink::File { path, max_size, max_files }
+            }
+            file::AuditSink::Netidx { config, path } => {
+                if !Path::is_absolute(&path) {
+                    bail!("audit path must be absolute")
+                }
+                AuditSink::Netidx { config, path: Path::from(path) }
+            }
+        };
+        Ok(Audit { sink, read_sample_rate: a.read_sample_rate })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limit {
+    pub(super) max_published: Option<usize>,
+    pub(super) max_default_published: Option<usize>,
+    pub(super) reads_per_second: Option<u32>,
+    pub(super) max_glob_results: Option<usize>,
+    pub(super) max_metadata_bytes: Option<usize>,
+    pub(super) max_publisher_metadata_bytes: Option<usize>,
+}
+
+impl TryFrom<file::Limit> for Limit {
+    type Error = anyhow::Error;
+
+    fn try_from(l: file::Limit) -> Result<Self> {
+        if l.reads_per_second == Some(0) {
+            bail!("reads_per_second must be positive")
+        }
+        Ok(Limit {
+            max_published: l.max_published,
+            max_default_published: l.max_default_published,
+            reads_per_second: l.reads_per_second,
+            max_glob_results: l.max_glob_results,
+            max_metadata_bytes: l.max_metadata_bytes,
+            max_publisher_metadata_bytes: l.max_publisher_metadata_bytes,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub(super) default: Limit,
+    pub(super) entities: HashMap<String, Limit>,
+    pub(super) admins: HashSet<String>,
+}
+
+impl TryFrom<file::Limits> for Limits {
+    type Error = anyhow::Error;
+
+    fn try_from(l: file::Limits) -> Result<Self> {
+        Ok(Limits {
+            default: Limit::try_from(l.default)?,
+            entities: l
+                .entities
+                .into_iter()
+                .map(|(e, l)| Ok((e, Limit::try_from(l)?)))
+                .collect::<Result<_>>()?,
+            admins: l.admins.into_iter().collect(),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -262,12 +535,21 @@ pub struct MemberServer {
     #[allow(dead_code)]
     pub(crate) id_map: IdMap,
     pub(crate) id_map_timeout: chrono::Duration,
+    pub(super) audit: Option<Audit>,
+    pub(super) limits: Limits,
+}
+
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub(super) config: Option<PathBuf>,
+    pub(super) ttl: u16,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub(super) parent: Option<Referral>,
     pub(super) children: BTreeMap<Path, Referral>,
+    pub(super) register: Option<Register>,
     pub(super) perms: PMap,
     pub member_servers: Vec<MemberServer>,
 }
@@ -314,6 +596,18 @@ impl Config {
             }
             children
         };
+        let register = match cfg.register {
+            None => None,
+            Some(r) => {
+                if parent.is_none() {
+                    bail!("register requires a parent")
+                }
+                if r.ttl == 0 {
+                    bail!("register ttl must be non zero")
+                }
+                Some(Register { config: r.config, ttl: r.ttl })
+            }
+        };
         let member_servers = cfg
             .member_servers
             .into_iter()
@@ -324,6 +618,11 @@ impl Config {
 			None => bail!("you must specify the socket path as id_map_command"),
 			Some(path) => IdMap::Socket(path),
 		    }
+		    IdMapType::File => match m.id_map_command {
+			None => bail!("you must specify the file path as id_map_command"),
+			Some(path) => IdMap::File(path),
+		    }
+		    IdMapType::GetGroupList => IdMap::GetGroupList,
 		    IdMapType::Command => match m.id_map_command {
 			None => IdMap::PlatformDefault,
 			Some(cmd) => {
@@ -352,10 +651,31 @@ impl Config {
                     file::Auth::Anonymous
                     | file::Auth::Krb5 { .. }
                     | file::Auth::Local { .. } => (),
-                    file::Auth::Tls { name, trusted, certificate, private_key } => {
+                    file::Auth::Token(keys) => {
+                        if let Err(e) = KeySet::load(keys) {
+                            bail!("failed to load the token key set {}", e)
+                        }
+                    }
+                    file::Auth::Tls {
+                        name,
+                        trusted,
+                        crl,
+                        certificate,
+                        private_key,
+                        user,
+                        groups,
+                    } => {
                         if let Err(e) = tls::load_certs(&trusted) {
                             bail!("failed to load trusted certificates {}", e)
                         }
+                        if let Some(crl) = crl {
+                            if let Err(e) = tls::load_crls(crl) {
+                                bail!("failed to load the crl {}", e)
+                            }
+                        }
+                        for f in user.iter().chain(groups.iter()) {
+                            f.check()?
+                        }
                         if let Err(e) = tls::load_private_key(None, private_key) {
                             bail!("failed to load the private key {}", e)
                         }
@@ -400,10 +720,12 @@ impl Config {
                     writer_ttl: Duration::from_secs(m.writer_ttl),
                     id_map,
 		    id_map_timeout: chrono::Duration::seconds(m.id_map_timeout as i64),
+                    audit: m.audit.map(Audit::try_from).transpose()?,
+                    limits: Limits::try_from(m.limits)?,
                 })
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(Config { parent, children, perms: cfg.perms, member_servers })
+        Ok(Config { parent, children, register, perms: cfg.perms, member_servers })
     }
 
     /// Load the cluster config from the specified file.
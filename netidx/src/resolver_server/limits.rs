@@ -0,0 +1,414 @@
+use super::{
+    auth::UserInfo,
+    config::{Limit, Limits},
+};
+use crate::{
+    chars::Chars,
+    pack::Pack,
+    path::Path,
+    protocol::resolver::{
+        Load, Locality, Metadata, Publisher, PublisherId, PublisherUsage,
+    },
+};
+use arcstr::ArcStr;
+use fxhash::{FxHashMap, FxHashSet};
+use parking_lot::Mutex;
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::time::Instant;
+
+fn most_permissive(l0: Limit, l1: Limit) -> Limit {
+    fn max<T: Ord>(v0: Option<T>, v1: Option<T>) -> Option<T> {
+        match (v0, v1) {
+            (None, _) | (_, None) => None,
+            (Some(v0), Some(v1)) => Some(std::cmp::max(v0, v1)),
+        }
+    }
+    Limit {
+        max_published: max(l0.max_published, l1.max_published),
+        max_default_published: max(l0.max_default_published, l1.max_default_published),
+        reads_per_second: max(l0.reads_per_second, l1.reads_per_second),
+        max_glob_results: max(l0.max_glob_results, l1.max_glob_results),
+        max_metadata_bytes: max(l0.max_metadata_bytes, l1.max_metadata_bytes),
+        max_publisher_metadata_bytes: max(
+            l0.max_publisher_metadata_bytes,
+            l1.max_publisher_metadata_bytes,
+        ),
+    }
+}
+
+impl Limits {
+    /// Return the limits that apply to the specified user
+    pub(super) fn get(&self, uifo: &UserInfo) -> Limit {
+        match &uifo.user_info {
+            None => self.default,
+            Some(u) => match self.entities.get(&*u.name) {
+                Some(l) => *l,
+                None => iter::once(&u.primary_group)
+                    .chain(u.groups.iter())
+                    .filter_map(|g| self.entities.get(&**g))
+                    .fold(None, |acc, l| match acc {
+                        None => Some(*l),
+                        Some(acc) => Some(most_permissive(acc, *l)),
+                    })
+                    .unwrap_or(self.default),
+            },
+        }
+    }
+
+    /// Return true if the specified user may see the usage of every
+    /// publisher
+    pub(super) fn is_admin(&self, uifo: &UserInfo) -> bool {
+        match &uifo.user_info {
+            None => false,
+            Some(u) => {
+                self.admins.contains(&*u.name)
+                    || iter::once(&u.primary_group)
+                        .chain(u.groups.iter())
+                        .any(|g| self.admins.contains(&**g))
+            }
+        }
+    }
+}
+
+fn take(n: &AtomicUsize, max: Option<usize>) -> bool {
+    let max = max.unwrap_or(usize::MAX);
+    n.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+        if n < max {
+            Some(n + 1)
+        } else {
+            None
+        }
+    })
+    .is_ok()
+}
+
+/// The resources used by all the publishers of a user. Anonymous
+/// publishers have no identity to share, so each one has it's own
+/// quota.
+struct Quota {
+    limit: Limit,
+    published: AtomicUsize,
+    defaults: AtomicUsize,
+}
+
+/// The load and locality advertised by each publisher. Resolving
+/// takes a snapshot of this, so advertising copies the map on write
+/// instead of making every read batch lock each publisher.
+type Advertised = Arc<Mutex<Arc<FxHashMap<PublisherId, (Option<Load>, Locality)>>>>;
+
+/// The encoded size of the metadata a publisher has set
+#[derive(Default)]
+struct MetadataUsage {
+    total: usize,
+    by_path: FxHashMap<Path, usize>,
+}
+
+/// The resources used by a publisher
+pub(super) struct Usage {
+    publisher: Arc<Publisher>,
+    user: Option<ArcStr>,
+    quota: Arc<Quota>,
+    published: AtomicUsize,
+    defaults: Mutex<FxHashSet<Path>>,
+    metadata: Mutex<MetadataUsage>,
+    advertised: Advertised,
+}
+
+impl Drop for Usage {
+    fn drop(&mut self) {
+        let published = *self.published.get_mut();
+        let defaults = self.defaults.get_mut().len();
+        self.quota.published.fetch_sub(published, Ordering::Relaxed);
+        self.quota.defaults.fetch_sub(defaults, Ordering::Relaxed);
+    }
+}
+
+impl Usage {
+    /// Account for a new published path, return an error if the
+    /// publisher's user is over quota.
+    pub(super) fn publish(&self) -> Result<(), Chars> {
+        if take(&self.quota.published, self.quota.limit.max_published) {
+            self.published.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        } else {
+            let max = self.quota.limit.max_published.unwrap_or(usize::MAX);
+            Err(Chars::from(format!("quota of {} published paths exceeded", max)))
+        }
+    }
+
+    pub(super) fn unpublish(&self, n: usize) {
+        self.published.fetch_sub(n, Ordering::Relaxed);
+        self.quota.published.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Account for a default publisher at path, return an error if
+    /// the publisher's user is over quota.
+    pub(super) fn publish_default(&self, path: &Path) -> Result<(), Chars> {
+        let max = self.quota.limit.max_default_published;
+        let mut defaults = self.defaults.lock();
+        if defaults.contains(path) {
+            Ok(())
+        } else if !take(&self.quota.defaults, max) {
+            let max = max.unwrap_or(usize::MAX);
+            let m = format!("quota of {} default publishers exceeded", max);
+            Err(Chars::from(m))
+        } else {
+            defaults.insert(path.clone());
+            Ok(())
+        }
+    }
+
+    pub(super) fn unpublish_default(&self, path: &Path) {
+        if self.defaults.lock().remove(path) {
+            self.quota.defaults.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn clear_defaults(&self) {
+        let mut defaults = self.defaults.lock();
+        self.quota.defaults.fetch_sub(defaults.len(), Ordering::Relaxed);
+        defaults.clear()
+    }
+
+    /// Account for the metadata of path, return an error if it is
+    /// too big, or if it would put the publisher over it's metadata
+    /// quota. Empty metadata releases what path used.
+    pub(super) fn set_metadata(&self, path: &Path, md: &Metadata) -> Result<(), Chars> {
+        let mut metadata = self.metadata.lock();
+        let prev = metadata.by_path.get(path).copied().unwrap_or(0);
+        if md.is_empty() {
+            metadata.by_path.remove(path);
+            metadata.total -= prev;
+            return Ok(());
+        }
+        let len = md.encoded_len();
+        let limit = &self.quota.limit;
+        if let Some(max) = limit.max_metadata_bytes.filter(|max| len > *max) {
+            let m = format!("metadata of {} bytes exceeds the limit of {}", len, max);
+            return Err(Chars::from(m));
+        }
+        let total = metadata.total - prev + len;
+        if let Some(max) = limit.max_publisher_metadata_bytes.filter(|max| total > *max) {
+            let m = format!("quota of {} bytes of metadata exceeded", max);
+            return Err(Chars::from(m));
+        }
+        metadata.total = total;
+        metadata.by_path.insert(path.clone(), len);
+        Ok(())
+    }
+
+    /// Release the metadata of path, because it was unpublished
+    pub(super) fn unset_metadata(&self, path: &Path) {
+        let mut metadata = self.metadata.lock();
+        if let Some(len) = metadata.by_path.remove(path) {
+            metadata.total -= len;
+        }
+    }
+
+    pub(super) fn clear_metadata(&self) {
+        *self.metadata.lock() = MetadataUsage::default();
+    }
+
+    /// Record the load the publisher advertised
+    pub(super) fn set_load(&self, load: Load) {
+        let mut advertised = self.advertised.lock();
+        let advertised = Arc::make_mut(&mut *advertised);
+        advertised.entry(self.publisher.id).or_default().0 = Some(load)
+    }
+
+    /// Record the locality the publisher advertised
+    pub(super) fn set_locality(&self, locality: Locality) {
+        let mut advertised = self.advertised.lock();
+        let advertised = Arc::make_mut(&mut *advertised);
+        advertised.entry(self.publisher.id).or_default().1 = locality
+    }
+
+    fn get(&self) -> PublisherUsage {
+        PublisherUsage {
+            publisher: self.publisher.addr,
+            user: self.user.clone(),
+            published: self.published.load(Ordering::Relaxed) as u64,
+            max_published: self.quota.limit.max_published.map(|n| n as u64),
+            default_published: self.defaults.lock().len() as u64,
+            max_default_published: self
+                .quota
+                .limit
+                .max_default_published
+                .map(|n| n as u64),
+            user_published: self.quota.published.load(Ordering::Relaxed) as u64,
+            user_default_published: self.quota.defaults.load(Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+struct UsageMapInner {
+    by_publisher: FxHashMap<PublisherId, Arc<Usage>>,
+    by_user: FxHashMap<ArcStr, Weak<Quota>>,
+}
+
+/// The usage of every publisher connected to the resolver server
+#[derive(Clone)]
+pub(super) struct UsageMap(Arc<Mutex<UsageMapInner>>, Advertised);
+
+impl UsageMap {
+    pub(super) fn new() -> Self {
+        UsageMap(
+            Arc::new(Mutex::new(UsageMapInner {
+                by_publisher: FxHashMap::default(),
+                by_user: FxHashMap::default(),
+            })),
+            Arc::new(Mutex::new(Arc::new(FxHashMap::default()))),
+        )
+    }
+
+    pub(super) fn get(
+        &self,
+        limits: &Limits,
+        publisher: &Arc<Publisher>,
+        uifo: &UserInfo,
+    ) -> Arc<Usage> {
+        let mut inner = self.0.lock();
+        let UsageMapInner { by_publisher, by_user } = &mut *inner;
+        by_publisher
+            .entry(publisher.id)
+            .or_insert_with(|| {
+                let user = uifo.user_info.as_ref().map(|u| u.name.clone());
+                let new_quota = || {
+                    Arc::new(Quota {
+                        limit: limits.get(uifo),
+                        published: AtomicUsize::new(0),
+                        defaults: AtomicUsize::new(0),
+                    })
+                };
+                let quota = match &user {
+                    None => new_quota(),
+                    Some(user) => match by_user.get(user).and_then(Weak::upgrade) {
+                        Some(quota) => quota,
+                        None => {
+                            let quota = new_quota();
+                            by_user.insert(user.clone(), Arc::downgrade(&quota));
+                            quota
+                        }
+                    },
+                };
+                Arc::new(Usage {
+                    publisher: publisher.clone(),
+                    user,
+                    quota,
+                    published: AtomicUsize::new(0),
+                    defaults: Mutex::new(FxHashSet::default()),
+                    metadata: Mutex::new(MetadataUsage::default()),
+                    advertised: self.1.clone(),
+                })
+            })
+            .clone()
+    }
+
+    pub(super) fn remove(&self, id: &PublisherId) {
+        let mut inner = self.0.lock();
+        if let Some(usage) = inner.by_publisher.remove(id) {
+            let mut advertised = self.1.lock();
+            if advertised.contains_key(id) {
+                Arc::make_mut(&mut *advertised).remove(id);
+            }
+            drop(advertised);
+            if let Some(user) = usage.user.clone() {
+                drop(usage);
+                if let Some(q) = inner.by_user.get(&user) {
+                    if q.strong_count() == 0 {
+                        inner.by_user.remove(&user);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn usage(&self) -> Vec<PublisherUsage> {
+        self.0.lock().by_publisher.values().map(|u| u.get()).collect()
+    }
+
+    /// Fill in the last load and locality advertised by each publisher
+    pub(super) fn set_advertised(
+        &self,
+        publishers: &mut FxHashMap<PublisherId, Publisher>,
+    ) {
+        let advertised = self.1.lock().clone();
+        if advertised.is_empty() {
+            return;
+        }
+        for (id, pb) in publishers.iter_mut() {
+            if let Some((load, locality)) = advertised.get(id) {
+                pb.load = *load;
+                pb.locality = locality.clone();
+            }
+        }
+    }
+}
+
+/// A token bucket that allows bursts of up to one second of requests
+pub(super) struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub(super) fn new(rate: u32) -> Self {
+        let rate = rate as f64;
+        RateLimit { rate, tokens: rate, last: Instant::now() }
+    }
+
+    /// Take up to `n` tokens from the bucket, return the number taken
+    pub(super) fn take(&mut self, n: usize) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = f64::min(self.rate, self.tokens + elapsed * self.rate);
+        let taken = f64::min(self.tokens.floor(), n as f64);
+        self.tokens -= taken;
+        taken as usize
+    }
+}
+
+/// The read rate limits of every user connected to the resolver
+/// server. All of a user's read connections share one bucket,
+/// anonymous connections each have their own.
+#[derive(Clone)]
+pub(super) struct RateLimits(Arc<Mutex<FxHashMap<ArcStr, Weak<Mutex<RateLimit>>>>>);
+
+impl RateLimits {
+    pub(super) fn new() -> Self {
+        RateLimits(Arc::new(Mutex::new(FxHashMap::default())))
+    }
+
+    /// Return the bucket for `uifo`, or None if it isn't rate limited
+    pub(super) fn get(
+        &self,
+        limits: &Limits,
+        uifo: &UserInfo,
+    ) -> Option<Arc<Mutex<RateLimit>>> {
+        let rate = limits.get(uifo).reads_per_second?;
+        let new = || Arc::new(Mutex::new(RateLimit::new(rate)));
+        match &uifo.user_info {
+            None => Some(new()),
+            Some(u) => {
+                let mut buckets = self.0.lock();
+                buckets.retain(|_, b| b.strong_count() > 0);
+                match buckets.get(&u.name).and_then(Weak::upgrade) {
+                    Some(b) => Some(b),
+                    None => {
+                        let b = new();
+                        buckets.insert(u.name.clone(), Arc::downgrade(&b));
+                        Some(b)
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    channel,
+    path::Path,
+    protocol::{publisher::Hello, resolver::PublisherId},
+    transport,
+};
+use anyhow::Result;
+use futures::future::join_all;
+use fxhash::FxHashMap;
+use log::info;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::time;
+
+type ByPath = BTreeMap<Path, FxHashMap<PublisherId, SocketAddr>>;
+
+/// The default publishers that list their children on demand
+/// (`PublishFlags::LIST_ON_DEMAND`), by the path they are the
+/// default publisher of.
+#[derive(Debug, Clone)]
+pub(super) struct Listers(Arc<Mutex<ByPath>>);
+
+impl Listers {
+    pub(super) fn new() -> Self {
+        Listers(Arc::new(Mutex::new(BTreeMap::new())))
+    }
+
+    pub(super) fn insert(&self, path: Path, id: PublisherId, addr: SocketAddr) {
+        self.0.lock().entry(path).or_default().insert(id, addr);
+    }
+
+    pub(super) fn remove(&self, path: &Path, id: &PublisherId) {
+        let mut listers = self.0.lock();
+        if let Some(by_id) = listers.get_mut(path) {
+            by_id.remove(id);
+            if by_id.is_empty() {
+                listers.remove(path);
+            }
+        }
+    }
+
+    pub(super) fn clear(&self, id: &PublisherId) {
+        self.0.lock().retain(|_, by_id| {
+            by_id.remove(id);
+            !by_id.is_empty()
+        })
+    }
+
+    /// The publishers to ask before listing `path`
+    fn find(&self, path: &Path) -> Vec<SocketAddr> {
+        let listers = self.0.lock();
+        if listers.is_empty() {
+            return vec![];
+        }
+        Path::dirnames(path)
+            .filter_map(|p| listers.get(p))
+            .flat_map(|by_id| by_id.values().copied())
+            .collect()
+    }
+
+    /// Ask the default publishers of each path to advertise it's
+    /// children, and wait up to `timeout` for them to be advertised.
+    /// Publishers that fail to answer are skipped, the listing is
+    /// then made from whatever they advertised before.
+    pub(super) async fn ask<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a Path>,
+        timeout: Duration,
+    ) {
+        let mut asks = paths
+            .into_iter()
+            .flat_map(|path| self.find(path).into_iter().map(move |a| (a, path)))
+            .collect::<Vec<_>>();
+        asks.sort();
+        asks.dedup();
+        join_all(asks.into_iter().map(|(addr, path)| async move {
+            match time::timeout(timeout, ask(addr, path.clone())).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => info!("list request to {} failed {}", addr, e),
+                Err(_) => info!("list request to {} timed out", addr),
+            }
+        }))
+        .await;
+    }
+}
+
+async fn ask(addr: SocketAddr, path: Path) -> Result<()> {
+    let mut con = transport::connect(addr).await?;
+    channel::offer_version(&mut con, channel::VERSION).await?;
+    channel::write_raw(&mut con, &Hello::List(path.clone())).await?;
+    match channel::read_raw(&mut con).await? {
+        Hello::List(p) if p == path => Ok(()),
+        _ => bail!("unexpected response from publisher"),
+    }
+}
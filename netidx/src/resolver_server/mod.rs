@@ -1,5 +1,10 @@
-pub(crate) mod auth;
+mod aliases;
+mod audit;
+pub mod auth;
 pub mod config;
+mod limits;
+mod listers;
+mod referrals;
 pub(crate) mod secctx;
 mod shard_store;
 mod store;
@@ -9,42 +14,50 @@ mod test;
 use crate::{
     channel::{self, Channel, K5CtxWrap},
     chars::Chars,
-    pack::Pack,
+    pack::{Pack, Z64},
+    path::Path,
     pool::{Pool, Pooled},
     protocol::{
         publisher,
         resolver::{
-            AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite, FromWrite,
-            HashMethod, Publisher, PublisherId, ReadyForOwnershipCheck, Secret,
-            ServerHelloWrite, ToRead, ToWrite,
+            AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite, FromRead,
+            FromWrite, HashMethod, Locality, Publisher, PublisherId,
+            ReadyForOwnershipCheck, Referral, Secret, ServerHelloWrite, ToRead, ToWrite,
         },
     },
-    tls, utils,
+    token::{self, Claims},
+    transport::{self, MemListener, Socket},
+    utils,
 };
 use anyhow::{Context, Result};
+use arcstr::ArcStr;
+use audit::{Audit, Event, Mode};
 use auth::{UserInfo, ANONYMOUS};
 use config::{Config, MemberServer};
 use cross_krb5::{AcceptFlags, K5ServerCtx, ServerCtx, Step};
 use futures::{channel::oneshot, prelude::*, select_biased};
 use fxhash::FxHashMap;
+use limits::RateLimits;
 use log::{debug, error, info, trace, warn};
 use netidx_core::{pack::BoundedBytes, utils::make_sha3_token};
 use parking_lot::Mutex as SyncMutex;
 use rand::{thread_rng, Rng};
-use secctx::{K5SecData, LocalSecData, SecCtx, TlsSecData};
+use referrals::Registration;
+use secctx::{K5SecData, LocalSecData, SecCtx, TlsSecData, TokenSecData};
 use shard_store::Store;
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
     fmt::Debug,
-    mem,
+    iter, mem,
     net::SocketAddr,
     ops::Deref,
     sync::Arc,
     time::Duration,
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{Mutex, RwLock},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{watch, Mutex, RwLock},
     task,
     time::{self, Instant},
 };
@@ -160,6 +173,8 @@ impl ClinfosInner {
                                 hash_method: HashMethod::Sha3_512,
                                 target_auth: hello.auth.clone().try_into()?,
                                 user_info: None,
+                                load: None,
+                                locality: Locality::default(),
                             });
                             let (tx, rx) = oneshot::channel();
                             e.insert(ClientInfo::Running {
@@ -179,7 +194,8 @@ impl ClinfosInner {
                                         AuthWrite::Reuse => (),
                                         AuthWrite::Krb5 { .. }
                                         | AuthWrite::Local
-                                        | AuthWrite::Tls { .. } => {
+                                        | AuthWrite::Tls { .. }
+                                        | AuthWrite::Token => {
                                             let publisher = publisher.clone();
                                             *ifo = ClientInfo::CleaningUp(Vec::new());
                                             ctx.secctx.remove(&publisher.id).await;
@@ -237,7 +253,10 @@ struct Ctx {
     cfg: MemberServer,
     id: SocketAddr,
     store: Store,
+    audit: Audit,
     delay_reads: Option<Instant>,
+    drain: watch::Receiver<bool>,
+    rate_limits: RateLimits,
 }
 
 async fn client_loop_write(
@@ -248,11 +267,13 @@ async fn client_loop_write(
     rx_stop: oneshot::Receiver<()>,
     uifo: Arc<UserInfo>,
     publisher: Arc<Publisher>,
+    version: u64,
 ) -> Result<()> {
     debug!("starting write loop for {:?}", connection_id);
     let mut con = Some(con);
     let mut server_stop = server_stop.fuse();
     let mut rx_stop = rx_stop.fuse();
+    let mut drain = ctx.drain.clone();
     let mut batch = WRITE_BATCHES.take();
     let mut act = false;
     let mut timeout =
@@ -277,6 +298,17 @@ async fn client_loop_write(
         select_biased! {
             _ = server_stop => break Ok(()),
             _ = rx_stop => break Ok(()),
+            _ = drain.changed().fuse() => {
+                // older clients don't understand draining, they will
+                // just see the connection close
+                match con.as_mut() {
+                    Some(c) if version >= 4 => {
+                        let _ = c.send_one(&FromWrite::Draining).await;
+                    }
+                    Some(_) | None => (),
+                }
+                break Ok(())
+            },
             _ = timeout.tick().fuse() => {
                 if act {
 		    trace!("checking timeout, {:?} was active", connection_id);
@@ -316,15 +348,31 @@ async fn client_loop_write(
                         for m in batch.drain(..) {
                             match m {
                                 ToWrite::Heartbeat => (),
+                                ToWrite::TimedHeartbeat(ts) =>
+                                    c.queue_send(&FromWrite::HeartbeatReply(ts))?,
                                 ToWrite::Publish(_)
                                     | ToWrite::PublishDefault(_)
                                     | ToWrite::PublishWithFlags(_, _)
-                                    | ToWrite::PublishDefaultWithFlags(_, _) =>
+                                    | ToWrite::PublishDefaultWithFlags(_, _)
+                                    | ToWrite::SetMetadata(_, _) =>
                                     c.queue_send(&FromWrite::Published)?,
                                 ToWrite::Unpublish(_) =>
                                     c.queue_send(&FromWrite::Unpublished)?,
                                 ToWrite::UnpublishDefault(_) =>
                                     c.queue_send(&FromWrite::Unpublished)?,
+                                m @ (ToWrite::RegisterReferral(_)
+                                     | ToWrite::UnregisterReferral(_)
+                                     | ToWrite::SetAlias(_, _)
+                                     | ToWrite::RemoveAlias(_)
+                                     | ToWrite::SetLoad(_)
+                                     | ToWrite::SetLocality(_)) => {
+                                    ctx.store.handle_batch_write(
+                                        Some(c),
+                                        uifo.clone(),
+                                        publisher.clone(),
+                                        iter::once(m)
+                                    ).await?
+                                }
                                 ToWrite::Clear => {
 				    trace!("{:?} handling clear", connection_id);
                                     ctx.store.handle_clear(
@@ -358,17 +406,24 @@ async fn client_loop_write(
 
 const TOKEN_MAX: usize = 4096;
 
-async fn recv<T: Pack + Debug>(timeout: Duration, con: &mut TcpStream) -> Result<T> {
+async fn recv<T: Pack + Debug, S: AsyncRead + Unpin>(
+    timeout: Duration,
+    con: &mut S,
+) -> Result<T> {
     Ok(time::timeout(timeout, channel::read_raw(con)).await??)
 }
-async fn send(timeout: Duration, con: &mut TcpStream, msg: &impl Pack) -> Result<()> {
+async fn send<S: AsyncWrite + Unpin>(
+    timeout: Duration,
+    con: &mut S,
+    msg: &impl Pack,
+) -> Result<()> {
     Ok(time::timeout(timeout, channel::write_raw(con, msg)).await??)
 }
 
-pub(crate) async fn krb5_authentication(
+pub(crate) async fn krb5_authentication<S: AsyncRead + AsyncWrite + Unpin>(
     timeout: Duration,
     spn: Option<&str>,
-    con: &mut TcpStream,
+    con: &mut S,
 ) -> Result<ServerCtx> {
     // the GSS token shouldn't ever be bigger than 1 MB
     const L: usize = 1 * 1024 * 1024;
@@ -417,18 +472,45 @@ async fn ownership_check(
     ctx: &Ctx,
     con: &mut Channel,
     write_addr: SocketAddr,
+) -> Result<u128> {
+    let res = check_ownership(ctx, con, write_addr).await;
+    if let Err(e) = &res {
+        ctx.audit.log(|| Event::OwnershipCheckFailed { write_addr, error: e.to_string() })
+    }
+    res
+}
+
+async fn connect_publisher(
+    write_addr: SocketAddr,
+    version: u64,
+    timeout: Duration,
+) -> Result<Socket> {
+    let mut con = time::timeout(timeout, transport::connect(write_addr)).await??;
+    time::timeout(timeout, channel::offer_version(&mut con, version)).await??;
+    Ok(con)
+}
+
+async fn check_ownership(
+    ctx: &Ctx,
+    con: &mut Channel,
+    write_addr: SocketAddr,
 ) -> Result<u128> {
     let secret = thread_rng().gen::<u128>();
     let timeout = ctx.cfg.hello_timeout;
     time::timeout(timeout, con.send_one(&Secret(secret))).await??;
     let _: ReadyForOwnershipCheck = time::timeout(timeout, con.receive()).await??;
     info!("hello_write connecting to {:?} for listener ownership check", write_addr);
-    let con = time::timeout(timeout, TcpStream::connect(write_addr)).await??;
-    let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
-    time::timeout(timeout, con.send_one(&3u64)).await??;
-    if time::timeout(timeout, con.receive::<u64>()).await?? != 3 {
-        bail!("incompatible protocol version")
-    }
+    let con = match connect_publisher(write_addr, channel::VERSION, timeout).await {
+        Ok(con) => con,
+        Err(e) => match e.downcast_ref::<channel::OlderPeer>() {
+            None => return Err(e),
+            // the publisher will hang up, try again with it's version
+            Some(channel::OlderPeer(v)) => {
+                connect_publisher(write_addr, *v, timeout).await?
+            }
+        },
+    };
+    let mut con = Channel::new::<ServerCtx, Socket>(None, con);
     use publisher::Hello as PHello;
     let n = thread_rng().gen::<u128>();
     let answer =
@@ -447,7 +529,7 @@ type AuthResult = Result<(Channel, Arc<UserInfo>, Arc<Publisher>, oneshot::Recei
 
 async fn write_client_anonymous_auth(
     ctx: &Arc<Ctx>,
-    mut con: TcpStream,
+    mut con: Socket,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
     let uifo = &*ANONYMOUS;
@@ -466,7 +548,7 @@ async fn write_client_anonymous_auth(
         Err(e)?;
     }
     Ok((
-        Channel::new::<ServerCtx, TcpStream>(None, con),
+        Channel::new::<ServerCtx, Socket>(None, con),
         ANONYMOUS.clone(),
         publisher,
         rx_stop,
@@ -475,7 +557,7 @@ async fn write_client_anonymous_auth(
 
 async fn write_client_local_auth(
     ctx: &Arc<Ctx>,
-    mut con: TcpStream,
+    mut con: Socket,
     a: &Arc<(secctx::LocalAuth, RwLock<secctx::SecCtxData<secctx::LocalSecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
@@ -491,7 +573,7 @@ async fn write_client_local_auth(
     };
     debug!("hello_write sending {:?}", h);
     send(ctx.cfg.hello_timeout, &mut con, &h).await?;
-    let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
+    let mut con = Channel::new::<ServerCtx, Socket>(None, con);
     let secret = ownership_check(&ctx, &mut con, hello.write_addr).await?;
     let (publisher, _, rx_stop) =
         ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
@@ -502,7 +584,7 @@ async fn write_client_local_auth(
 
 async fn write_client_reuse_local(
     ctx: &Arc<Ctx>,
-    con: TcpStream,
+    con: Socket,
     a: &Arc<(secctx::LocalAuth, RwLock<secctx::SecCtxData<secctx::LocalSecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
@@ -510,7 +592,79 @@ async fn write_client_reuse_local(
     let id = ctx.clinfos.lock().await.id(wa).ok_or_else(|| anyhow!("missing"))?;
     let d = a.1.read().await.get(&id).ok_or_else(|| anyhow!("missing"))?.clone();
     let uifo = a.1.write().await.users.ifo(ctx.id, Some(&*d.user)).await?;
-    let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
+    let mut con = Channel::new::<ServerCtx, Socket>(None, con);
+    challenge_auth(&ctx.cfg, &mut con, d.secret).await?;
+    let (publisher, ttl_expired, rx_stop) =
+        ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
+    let h = ServerHelloWrite {
+        ttl: ctx.cfg.writer_ttl.as_secs(),
+        ttl_expired,
+        resolver_id: ctx.id,
+        auth: AuthWrite::Reuse,
+    };
+    match time::timeout(ctx.cfg.hello_timeout, con.send_one(&h)).await {
+        Ok(Ok(())) => (),
+        Err(e) => {
+            ctx.clinfos.lock().await.remove(&ctx, &publisher, &uifo).await?;
+            Err(e)?
+        }
+        Ok(Err(e)) => {
+            ctx.clinfos.lock().await.remove(&ctx, &publisher, &uifo).await?;
+            Err(e)?
+        }
+    }
+    Ok((con, uifo, publisher, rx_stop))
+}
+
+async fn token_uifo(
+    ctx: &Ctx,
+    a: &Arc<(secctx::TokenAuth, RwLock<secctx::SecCtxData<TokenSecData>>)>,
+    claims: &Claims,
+) -> Arc<UserInfo> {
+    let primary_group = ArcStr::from(claims.primary_group.as_str());
+    let groups = claims.groups.iter().map(|g| ArcStr::from(g.as_str())).collect();
+    a.1.write().await.users.make_ifo(ctx.id, &claims.user, primary_group, groups)
+}
+
+async fn write_client_token_auth(
+    ctx: &Arc<Ctx>,
+    mut con: Socket,
+    a: &Arc<(secctx::TokenAuth, RwLock<secctx::SecCtxData<TokenSecData>>)>,
+    hello: &ClientHelloWrite,
+) -> AuthResult {
+    let tok: BoundedBytes<{ token::TOKEN_MAX }> =
+        recv(ctx.cfg.hello_timeout, &mut con).await?;
+    let claims = a.0.authenticate(&*tok).await?;
+    let uifo = token_uifo(ctx, a, &claims).await;
+    info!("hello_write token auth succeeded");
+    let h = ServerHelloWrite {
+        ttl: ctx.cfg.writer_ttl.as_secs(),
+        ttl_expired: true, // re auth always clears
+        resolver_id: ctx.id,
+        auth: AuthWrite::Token,
+    };
+    debug!("hello_write sending {:?}", h);
+    send(ctx.cfg.hello_timeout, &mut con, &h).await?;
+    let mut con = Channel::new::<ServerCtx, Socket>(None, con);
+    let secret = ownership_check(&ctx, &mut con, hello.write_addr).await?;
+    let (publisher, _, rx_stop) =
+        ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
+    let d = TokenSecData { claims, secret };
+    a.1.write().await.insert(publisher.id, d);
+    Ok((con, uifo, publisher, rx_stop))
+}
+
+async fn write_client_reuse_token(
+    ctx: &Arc<Ctx>,
+    con: Socket,
+    a: &Arc<(secctx::TokenAuth, RwLock<secctx::SecCtxData<TokenSecData>>)>,
+    hello: &ClientHelloWrite,
+) -> AuthResult {
+    let wa = &hello.write_addr;
+    let id = ctx.clinfos.lock().await.id(wa).ok_or_else(|| anyhow!("missing"))?;
+    let d = a.1.read().await.get(&id).ok_or_else(|| anyhow!("missing"))?.clone();
+    let uifo = token_uifo(ctx, a, &d.claims).await;
+    let mut con = Channel::new::<ServerCtx, Socket>(None, con);
     challenge_auth(&ctx.cfg, &mut con, d.secret).await?;
     let (publisher, ttl_expired, rx_stop) =
         ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
@@ -536,7 +690,7 @@ async fn write_client_reuse_local(
 
 async fn write_client_krb5_auth(
     ctx: &Arc<Ctx>,
-    mut con: TcpStream,
+    mut con: Socket,
     a: &Arc<(Chars, RwLock<secctx::SecCtxData<secctx::K5SecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
@@ -566,7 +720,7 @@ async fn write_client_krb5_auth(
 
 async fn write_client_reuse_krb5(
     ctx: &Arc<Ctx>,
-    con: TcpStream,
+    con: Socket,
     a: &Arc<(Chars, RwLock<secctx::SecCtxData<secctx::K5SecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
@@ -604,19 +758,33 @@ async fn write_client_reuse_krb5(
 
 async fn get_tls_uifo(
     id: SocketAddr,
-    tls: &tokio_rustls::server::TlsStream<TcpStream>,
-    a: &Arc<(tokio_rustls::TlsAcceptor, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
+    tls: &tokio_rustls::server::TlsStream<Socket>,
+    a: &Arc<(secctx::TlsAuth, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
 ) -> Result<Arc<UserInfo>> {
     let (_, server_con) = tls.get_ref();
     match server_con.peer_certificates() {
         Some([cert, ..]) => {
-            let names = tls::get_names(&cert.0).context("getting tls names")?;
-            Ok(a.1
-                .write()
-                .await
+            let ident = a.0.identity(&cert.0).context("getting tls identity")?;
+            let mut store = a.1.write().await;
+            let uifo = store
                 .users
-                .ifo(id, names.as_ref().map(|names| names.cn.as_str()))
-                .await.context("getting user info")?)
+                .ifo(id, Some(&ident.user))
+                .await
+                .context("getting user info")?;
+            match &uifo.user_info {
+                Some(ui) if !ident.groups.is_empty() => {
+                    let mut groups = ui.groups.to_vec();
+                    for g in ident.groups {
+                        let g = ArcStr::from(g);
+                        if !groups.contains(&g) {
+                            groups.push(g)
+                        }
+                    }
+                    let primary_group = ui.primary_group.clone();
+                    Ok(store.users.make_ifo(id, &ident.user, primary_group, groups))
+                }
+                Some(_) | None => Ok(uifo),
+            }
         }
         Some(_) | None => bail!("tls handshake should be complete by now"),
     }
@@ -624,14 +792,14 @@ async fn get_tls_uifo(
 
 async fn write_client_tls_auth(
     ctx: &Arc<Ctx>,
-    con: TcpStream,
-    a: &Arc<(tokio_rustls::TlsAcceptor, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
+    con: Socket,
+    a: &Arc<(secctx::TlsAuth, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
-    let tls = a.0.accept(con).await?;
+    let tls = a.0.acceptor().await?.accept(con).await?;
     let uifo = get_tls_uifo(ctx.id, &tls, a).await?;
     let mut con =
-        Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<TcpStream>>(None, tls);
+        Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<Socket>>(None, tls);
     info!("hello_write all traffic now encrypted");
     let h = ServerHelloWrite {
         ttl: ctx.cfg.writer_ttl.as_secs(),
@@ -652,17 +820,17 @@ async fn write_client_tls_auth(
 
 async fn write_client_reuse_tls(
     ctx: &Arc<Ctx>,
-    con: TcpStream,
-    a: &Arc<(tokio_rustls::TlsAcceptor, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
+    con: Socket,
+    a: &Arc<(secctx::TlsAuth, RwLock<secctx::SecCtxData<secctx::TlsSecData>>)>,
     hello: &ClientHelloWrite,
 ) -> AuthResult {
-    let tls = a.0.accept(con).await?;
+    let tls = a.0.acceptor().await?.accept(con).await?;
     let wa = &hello.write_addr;
     let id = ctx.clinfos.lock().await.id(wa).ok_or_else(|| anyhow!("missing"))?;
     let d = a.1.read().await.get(&id).ok_or_else(|| anyhow!("missing"))?.clone();
     let uifo = get_tls_uifo(ctx.id, &tls, a).await?;
     let mut con =
-        Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<TcpStream>>(None, tls);
+        Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<Socket>>(None, tls);
     info!("hello_write all traffic now encrypted");
     challenge_auth(&ctx.cfg, &mut con, d.0).await?;
     let (publisher, ttl_expired, rx_stop) =
@@ -689,40 +857,94 @@ async fn write_client_reuse_tls(
     Ok((con, uifo, publisher, rx_stop))
 }
 
+fn audit_auth<T>(
+    ctx: &Ctx,
+    client: SocketAddr,
+    mode: Mode,
+    mechanism: &'static str,
+    res: &Result<T>,
+    uifo: impl FnOnce(&T) -> &Arc<UserInfo>,
+) {
+    ctx.audit.log(|| match res {
+        Ok(r) => {
+            let user = audit::user(uifo(r));
+            Event::Authenticated { client, mode, mechanism, user }
+        }
+        Err(e) => {
+            let error = e.to_string();
+            Event::AuthenticationFailed { client, mode, mechanism, error }
+        }
+    })
+}
+
 async fn hello_client_write(
     ctx: Arc<Ctx>,
     connection_id: CId,
-    con: TcpStream,
+    con: Socket,
+    client: SocketAddr,
     server_stop: oneshot::Receiver<()>,
     hello: ClientHelloWrite,
+    version: u64,
 ) -> Result<()> {
     static NO: &str = "authentication mechanism not supported";
     info!("hello_write starting negotiation");
     debug!("hello_write client_hello: {:?}", hello);
     utils::check_addr(hello.write_addr.ip(), &[(ctx.id, ())])?;
-    let (con, uifo, publisher, rx_stop) = match hello.auth {
-        AuthWrite::Anonymous => write_client_anonymous_auth(&ctx, con, &hello).await?,
+    let mechanism = match hello.auth {
+        AuthWrite::Anonymous => "anonymous",
+        AuthWrite::Local => "local",
+        AuthWrite::Krb5 { .. } => "krb5",
+        AuthWrite::Tls { .. } => "tls",
+        AuthWrite::Token => "token",
+        AuthWrite::Reuse => "reuse",
+    };
+    let res = match hello.auth {
+        AuthWrite::Anonymous => write_client_anonymous_auth(&ctx, con, &hello).await,
         AuthWrite::Local => match &ctx.secctx {
-            SecCtx::Local(a) => write_client_local_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
+            SecCtx::Local(a) => write_client_local_auth(&ctx, con, a, &hello).await,
+            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) | SecCtx::Token(_) => {
+                Err(anyhow!(NO))
+            }
         },
         AuthWrite::Krb5 { .. } => match &ctx.secctx {
-            SecCtx::Krb5(a) => write_client_krb5_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
+            SecCtx::Krb5(a) => write_client_krb5_auth(&ctx, con, a, &hello).await,
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) | SecCtx::Token(_) => {
+                Err(anyhow!(NO))
+            }
         },
         AuthWrite::Tls { .. } => match &ctx.secctx {
-            SecCtx::Tls(a) => write_client_tls_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
+            SecCtx::Tls(a) => write_client_tls_auth(&ctx, con, a, &hello).await,
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) | SecCtx::Token(_) => {
+                Err(anyhow!(NO))
+            }
+        },
+        AuthWrite::Token => match &ctx.secctx {
+            SecCtx::Token(a) => write_client_token_auth(&ctx, con, a, &hello).await,
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) | SecCtx::Tls(_) => {
+                Err(anyhow!(NO))
+            }
         },
         AuthWrite::Reuse => match &ctx.secctx {
-            SecCtx::Local(a) => write_client_reuse_local(&ctx, con, a, &hello).await?,
-            SecCtx::Krb5(a) => write_client_reuse_krb5(&ctx, con, a, &hello).await?,
-            SecCtx::Tls(a) => write_client_reuse_tls(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous => bail!(NO),
+            SecCtx::Local(a) => write_client_reuse_local(&ctx, con, a, &hello).await,
+            SecCtx::Krb5(a) => write_client_reuse_krb5(&ctx, con, a, &hello).await,
+            SecCtx::Tls(a) => write_client_reuse_tls(&ctx, con, a, &hello).await,
+            SecCtx::Token(a) => write_client_reuse_token(&ctx, con, a, &hello).await,
+            SecCtx::Anonymous => Err(anyhow!(NO)),
         },
     };
-    Ok(client_loop_write(ctx, connection_id, con, server_stop, rx_stop, uifo, publisher)
-        .await?)
+    audit_auth(&ctx, client, Mode::Write, mechanism, &res, |r| &r.1);
+    let (con, uifo, publisher, rx_stop) = res?;
+    Ok(client_loop_write(
+        ctx,
+        connection_id,
+        con,
+        server_stop,
+        rx_stop,
+        uifo,
+        publisher,
+        version,
+    )
+    .await?)
 }
 
 async fn client_loop_read(
@@ -730,15 +952,26 @@ async fn client_loop_read(
     mut con: Channel,
     server_stop: oneshot::Receiver<()>,
     uifo: Arc<UserInfo>,
+    version: u64,
 ) -> Result<()> {
     let mut batch = READ_BATCHES.take();
     let mut server_stop = server_stop.fuse();
+    let mut drain = ctx.drain.clone();
     let mut act = false;
     let mut timeout =
         time::interval_at(Instant::now() + ctx.cfg.reader_ttl, ctx.cfg.reader_ttl);
+    let rate = ctx.rate_limits.get(&ctx.cfg.limits, &uifo);
     loop {
         select_biased! {
             _ = server_stop => break Ok(()),
+            _ = drain.changed().fuse() => {
+                // older clients don't understand draining, they will
+                // just see the connection close
+                if version >= 4 {
+                    let _ = con.send_one(&FromRead::Draining).await;
+                }
+                break Ok(())
+            },
             _ = timeout.tick().fuse() => {
                 if act {
                     act = false;
@@ -749,11 +982,28 @@ async fn client_loop_read(
             m = con.receive_batch(&mut batch).fuse() => {
                 m?;
                 act = true;
+                let allowed = match &rate {
+                    None => batch.len(),
+                    Some(rate) => rate.lock().take(batch.len()),
+                };
                 ctx.store.handle_batch_read(
                     &mut con,
                     uifo.clone(),
-                    batch.drain(..)
+                    batch.drain(..allowed)
                 ).await?;
+                if !batch.is_empty() {
+                    if version >= 4 {
+                        let n = Z64(batch.len() as u64);
+                        con.queue_send(&FromRead::RateLimited(n))?
+                    } else {
+                        let e = Chars::from("read rate limit exceeded");
+                        for _ in 0..batch.len() {
+                            con.queue_send(&FromRead::Error(e.clone()))?
+                        }
+                    }
+                    batch.clear();
+                    con.flush().await?
+                }
             },
         }
     }
@@ -761,15 +1011,35 @@ async fn client_loop_read(
 
 async fn hello_client_read(
     ctx: Arc<Ctx>,
-    mut con: TcpStream,
+    con: Socket,
+    client: SocketAddr,
     server_stop: oneshot::Receiver<()>,
     hello: AuthRead,
+    version: u64,
 ) -> Result<()> {
+    let mechanism = match hello {
+        AuthRead::Anonymous => "anonymous",
+        AuthRead::Local => "local",
+        AuthRead::Krb5 => "krb5",
+        AuthRead::Tls => "tls",
+        AuthRead::Token => "token",
+    };
+    let res = read_client_auth(&ctx, con, hello).await;
+    audit_auth(&ctx, client, Mode::Read, mechanism, &res, |r| &r.1);
+    let (con, uifo) = res?;
+    Ok(client_loop_read(ctx, con, server_stop, uifo, version).await?)
+}
+
+async fn read_client_auth(
+    ctx: &Arc<Ctx>,
+    mut con: Socket,
+    hello: AuthRead,
+) -> Result<(Channel, Arc<UserInfo>)> {
     static NO: &str = "authentication mechanism not supported";
-    let (con, uifo) = match hello {
+    Ok(match hello {
         AuthRead::Anonymous => {
             send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Anonymous).await?;
-            (Channel::new::<ServerCtx, TcpStream>(None, con), ANONYMOUS.clone())
+            (Channel::new::<ServerCtx, Socket>(None, con), ANONYMOUS.clone())
         }
         AuthRead::Local => match &ctx.secctx {
             SecCtx::Local(a) => {
@@ -778,9 +1048,11 @@ async fn hello_client_read(
                 let cred = a.0.authenticate(&*tok)?;
                 let uifo = a.1.write().await.users.ifo(ctx.id, Some(&cred.user)).await?;
                 send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Local).await?;
-                (Channel::new::<ServerCtx, TcpStream>(None, con), uifo)
+                (Channel::new::<ServerCtx, Socket>(None, con), uifo)
+            }
+            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) | SecCtx::Token(_) => {
+                bail!(NO)
             }
-            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
         },
         AuthRead::Krb5 => match &ctx.secctx {
             SecCtx::Krb5(a) => {
@@ -789,43 +1061,58 @@ async fn hello_client_read(
                         .await?;
                 send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Krb5).await?;
                 let k5ctx = K5CtxWrap::new(k5ctx);
-                let con = Channel::new::<ServerCtx, TcpStream>(Some(k5ctx.clone()), con);
+                let con = Channel::new::<ServerCtx, Socket>(Some(k5ctx.clone()), con);
                 let client = k5ctx.lock().client()?;
                 let uifo = a.1.write().await.users.ifo(ctx.id, Some(&client)).await?;
                 (con, uifo)
             }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) | SecCtx::Token(_) => {
+                bail!(NO)
+            }
         },
         AuthRead::Tls => match &ctx.secctx {
             SecCtx::Tls(a) => {
-                let tls = a.0.accept(con).await.context("accepting tls connection")?;
+                let tls = a.0.acceptor().await?.accept(con).await;
+                let tls = tls.context("accepting tls connection")?;
                 let uifo = get_tls_uifo(ctx.id, &tls, a).await.context("getting tls info")?;
                 let mut con = Channel::new::<
                     ServerCtx,
-                    tokio_rustls::server::TlsStream<TcpStream>,
+                    tokio_rustls::server::TlsStream<Socket>,
                 >(None, tls);
                 time::timeout(ctx.cfg.hello_timeout, con.send_one(&AuthRead::Tls))
                     .await.context("saying hello")??;
                 (con, uifo)
             }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) | SecCtx::Token(_) => {
+                bail!(NO)
+            }
         },
-    };
-    Ok(client_loop_read(ctx, con, server_stop, uifo).await?)
+        AuthRead::Token => match &ctx.secctx {
+            SecCtx::Token(a) => {
+                let tok: BoundedBytes<{ token::TOKEN_MAX }> =
+                    recv(ctx.cfg.hello_timeout, &mut con).await?;
+                let claims = a.0.authenticate(&*tok).await?;
+                let uifo = token_uifo(ctx, a, &claims).await;
+                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Token).await?;
+                (Channel::new::<ServerCtx, Socket>(None, con), uifo)
+            }
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) | SecCtx::Tls(_) => {
+                bail!(NO)
+            }
+        },
+    })
 }
 
 async fn hello_client(
     ctx: Arc<Ctx>,
     connection_id: CId,
-    mut s: TcpStream,
+    mut s: Socket,
     server_stop: oneshot::Receiver<()>,
 ) -> Result<()> {
     s.set_nodelay(true)?;
-    send(ctx.cfg.hello_timeout, &mut s, &3u64).await?;
-    let version: u64 = recv(ctx.cfg.hello_timeout, &mut s).await?;
-    if version != 3 {
-        bail!("unsupported protocol version")
-    }
+    let client = s.peer_addr()?;
+    let version =
+        time::timeout(ctx.cfg.hello_timeout, channel::accept_version(&mut s)).await??;
     let hello: ClientHello = recv(ctx.cfg.hello_timeout, &mut s).await?;
     match hello {
         ClientHello::ReadOnly(hello) => {
@@ -834,18 +1121,53 @@ async fn hello_client(
                     bail!("no read clients allowed yet");
                 }
             }
-            Ok(hello_client_read(ctx, s, server_stop, hello).await?)
+            Ok(hello_client_read(ctx, s, client, server_stop, hello, version).await?)
         }
         ClientHello::WriteOnly(hello) => {
-            Ok(hello_client_write(ctx, connection_id, s, server_stop, hello).await?)
+            Ok(hello_client_write(
+                ctx,
+                connection_id,
+                s,
+                client,
+                server_stop,
+                hello,
+                version,
+            )
+            .await?)
+        }
+    }
+}
+
+type Drain = (Duration, oneshot::Sender<()>);
+
+async fn accept_client(
+    ctx: &Arc<Ctx>,
+    client_stops: &mut Vec<oneshot::Sender<()>>,
+    client: Socket,
+) {
+    let (tx, rx) = oneshot::channel();
+    client_stops.push(tx);
+    let connection_id = ctx.ctracker.open();
+    task::spawn({
+        let ctx = Arc::clone(ctx);
+        async move {
+            let r = hello_client(Arc::clone(&ctx), connection_id, client, rx).await;
+            ctx.ctracker.close(connection_id);
+            info!("server_loop client shutting down {:?}", r);
         }
+    });
+    while ctx.ctracker.num_open() > ctx.cfg.max_connections {
+        time::sleep(Duration::from_millis(10u64)).await;
     }
+    debug!("I have {} writers", ctx.clinfos.lock().await.0.len())
 }
 
 async fn server_loop(
     cfg: Config,
     delay_reads: bool,
+    loopback: bool,
     stop: oneshot::Receiver<()>,
+    drain: oneshot::Receiver<Drain>,
     ready: oneshot::Sender<SocketAddr>,
     id: usize,
 ) -> Result<()> {
@@ -857,16 +1179,21 @@ async fn server_loop(
     let id = member.addr;
     debug!("creating security context");
     let secctx = SecCtx::new(&cfg, &member).await?;
+    let audit = Audit::new(id, member.audit.as_ref());
     debug!("creating resolver store");
     let store = Store::new(
         cfg.parent.clone().map(|s| s.into()),
         cfg.children.iter().map(|(p, s)| (p.clone(), s.clone().into())).collect(),
         secctx.clone(),
+        audit.clone(),
+        member.limits.clone(),
+        member.hello_timeout,
         id,
     );
     let listen_addr = SocketAddr::new(member.bind_addr, id.port());
     debug!("creating tcp listener on {:?}", listen_addr);
     let listener = TcpListener::bind(listen_addr).await?;
+    let (start_drain, draining) = watch::channel(false);
     let ctx = Arc::new(Ctx {
         cfg: member,
         secctx,
@@ -875,15 +1202,32 @@ async fn server_loop(
         id,
         delay_reads,
         store,
+        audit,
+        drain: draining,
+        rate_limits: RateLimits::new(),
     });
     let mut stop = stop.fuse();
+    let mut drain = drain.fuse();
     let mut client_stops: Vec<oneshot::Sender<()>> = Vec::new();
-    let max_connections = ctx.cfg.max_connections;
     debug!("signaling ready");
     let mut listen_addr = listener.local_addr()?;
     listen_addr.set_ip(id.ip());
+    let mut loopback = transport::listen(listen_addr, loopback);
+    let _registration = cfg.register.as_ref().map(|register| {
+        let addrs = cfg
+            .member_servers
+            .iter()
+            .map(|m| {
+                let addr = if m.addr == id { listen_addr } else { m.addr };
+                (addr, m.auth.clone().into())
+            })
+            .collect();
+        let path = Path::from(ArcStr::from(cfg.root()));
+        let referral = Referral { path, ttl: None, addrs: Pooled::orphan(addrs) };
+        Registration::start(register, referral)
+    });
     let _ = ready.send(listen_addr);
-    loop {
+    let (timeout, finished) = loop {
         select_biased! {
             _ = stop => {
 		debug!("server loop stop requested");
@@ -892,38 +1236,46 @@ async fn server_loop(
                 }
                 return Ok(())
             },
+            r = drain => match r {
+                Ok(r) => break r,
+                Err(_) => {
+                    for cl in client_stops.drain(..) {
+                        let _ = cl.send(());
+                    }
+                    return Ok(())
+                }
+            },
             cl = listener.accept().fuse() => match cl {
                 Err(e) => warn!("accept failed: {}", e),
                 Ok((client, _)) => {
-                    let (tx, rx) = oneshot::channel();
-                    client_stops.push(tx);
-                    let connection_id = ctx.ctracker.open();
-                    task::spawn({
-                        let ctx = Arc::clone(&ctx);
-                        async move {
-                            let r = hello_client(
-                                Arc::clone(&ctx),
-                                connection_id,
-                                client,
-                                rx
-                            ).await;
-                            ctx.ctracker.close(connection_id);
-                            info!("server_loop client shutting down {:?}", r);
-                        }
-                    });
-                    while ctx.ctracker.num_open() > max_connections {
-                        time::sleep(Duration::from_millis(10u64)).await;
-                    }
-                    debug!("I have {} writers", ctx.clinfos.lock().await.0.len())
+                    accept_client(&ctx, &mut client_stops, Socket::Tcp(client)).await
                 }
             },
+            cl = transport::accept_mem(&mut loopback).fuse() => match cl {
+                None => loopback = None,
+                Some(client) => accept_client(&ctx, &mut client_stops, client).await,
+            },
         }
+    };
+    info!("draining {} connections", ctx.ctracker.num_open());
+    drop(listener);
+    drop(loopback);
+    let _ = start_drain.send(true);
+    let deadline = Instant::now() + timeout;
+    while ctx.ctracker.num_open() > 0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(10u64)).await;
+    }
+    for cl in client_stops.drain(..) {
+        let _ = cl.send(());
     }
+    let _ = finished.send(());
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct Server {
     stop: Option<oneshot::Sender<()>>,
+    drain: Option<oneshot::Sender<Drain>>,
     local_addr: SocketAddr,
 }
 
@@ -937,10 +1289,36 @@ impl Drop for Server {
 
 impl Server {
     pub async fn new(cfg: Config, delay_reads: bool, id: usize) -> Result<Server> {
+        Self::new_internal(cfg, delay_reads, id, false).await
+    }
+
+    /// Start a server that also accepts clients over the in process
+    /// transport in `testing`.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) async fn new_loopback(cfg: Config, id: usize) -> Result<Server> {
+        Self::new_internal(cfg, false, id, true).await
+    }
+
+    async fn new_internal(
+        cfg: Config,
+        delay_reads: bool,
+        id: usize,
+        loopback: bool,
+    ) -> Result<Server> {
         let (send_stop, recv_stop) = oneshot::channel();
+        let (send_drain, recv_drain) = oneshot::channel();
         let (send_ready, recv_ready) = oneshot::channel();
         task::spawn(async move {
-            let res = server_loop(cfg, delay_reads, recv_stop, send_ready, id).await;
+            let res = server_loop(
+                cfg,
+                delay_reads,
+                loopback,
+                recv_stop,
+                recv_drain,
+                send_ready,
+                id,
+            )
+            .await;
             match &res {
                 Ok(_) => info!("resolver server shutdown"),
                 Err(e) => error!("resolver server failed {}", e),
@@ -951,10 +1329,23 @@ impl Server {
 	    Err(_) => bail!("resolver server shutdown"),
 	    Ok(addr) => addr,
 	};
-        Ok(Server { stop: Some(send_stop), local_addr })
+        Ok(Server { stop: Some(send_stop), drain: Some(send_drain), local_addr })
     }
 
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
+
+    /// Shut down gracefully. Stop accepting connections, tell
+    /// connected clients to move to the other members of the
+    /// cluster, and wait up to `timeout` for batches in flight to
+    /// finish before shutting down.
+    pub async fn drain(mut self, timeout: Duration) {
+        if let Some(drain) = self.drain.take() {
+            let (tx, rx) = oneshot::channel();
+            if drain.send((timeout, tx)).is_ok() {
+                let _ = rx.await;
+            }
+        }
+    }
 }
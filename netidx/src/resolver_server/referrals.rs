@@ -0,0 +1,286 @@
+use super::{
+    audit::{self, Action, Audit, Event},
+    auth::{PMap, Permissions, UserInfo},
+    config::Register,
+};
+use crate::{
+    config::Config as ClientConfig,
+    path::Path,
+    protocol::resolver::{FromWrite, Referral},
+    publisher,
+    resolver_client::ResolverWrite,
+};
+use anyhow::Result;
+use futures::{channel::oneshot, prelude::*, select_biased};
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::{
+    cmp::max,
+    collections::{
+        BTreeMap, Bound,
+        Bound::{Included, Unbounded},
+    },
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    task,
+    time::{self, Instant},
+};
+
+#[derive(Debug)]
+struct RegistryInner {
+    parent: Option<Referral>,
+    children: BTreeMap<Path, Referral>,
+    registered: BTreeMap<Path, (Instant, Referral)>,
+    snapshot: Arc<BTreeMap<Path, Referral>>,
+}
+
+impl RegistryInner {
+    fn update_snapshot(&mut self) {
+        self.snapshot = Arc::new(
+            self.registered.iter().map(|(p, (_, r))| (p.clone(), r.clone())).collect(),
+        );
+    }
+
+    // if path belongs to some other server return the referral to it
+    fn check_referral(&self, path: &Path) -> Option<Referral> {
+        if let Some(r) = self.parent.as_ref() {
+            if !Path::is_parent(&r.path, path) {
+                return Some(Referral {
+                    path: Path::from("/"),
+                    ttl: r.ttl,
+                    addrs: r.addrs.clone(),
+                });
+            }
+        }
+        let child = self
+            .children
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Unbounded,
+                Included(path.as_ref()),
+            ))
+            .next_back();
+        if let Some((p, r)) = child {
+            if Path::is_parent(p, path) {
+                return Some(r.clone());
+            }
+        }
+        let registered = self
+            .registered
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Unbounded,
+                Included(path.as_ref()),
+            ))
+            .next_back();
+        match registered {
+            Some((p, (_, r))) if p != path && Path::is_parent(p, path) => Some(r.clone()),
+            None | Some(_) => None,
+        }
+    }
+}
+
+/// The child clusters that registered themselves with this
+/// server. Registrations are shared by all the shards, each batch is
+/// processed against a snapshot so that the shards always agree
+/// about referrals.
+#[derive(Debug, Clone)]
+pub(super) struct Registry(Arc<Mutex<RegistryInner>>);
+
+impl Registry {
+    pub(super) fn new(
+        parent: Option<Referral>,
+        children: BTreeMap<Path, Referral>,
+    ) -> Self {
+        Registry(Arc::new(Mutex::new(RegistryInner {
+            parent,
+            children,
+            registered: BTreeMap::new(),
+            snapshot: Arc::new(BTreeMap::new()),
+        })))
+    }
+
+    /// Return the current registrations, expiring any that have
+    /// outlived their ttl.
+    pub(super) fn snapshot(&self) -> Arc<BTreeMap<Path, Referral>> {
+        let mut inner = self.0.lock();
+        let now = Instant::now();
+        let len = inner.registered.len();
+        inner.registered.retain(|p, (exp, _)| {
+            let live = *exp > now;
+            if !live {
+                info!("registration of child {} expired", p)
+            }
+            live
+        });
+        if inner.registered.len() != len {
+            inner.update_snapshot();
+        }
+        inner.snapshot.clone()
+    }
+
+    /// If path belongs to some other server return the referral to it
+    pub(super) fn referral(&self, path: &Path) -> Option<Referral> {
+        self.0.lock().check_referral(path)
+    }
+
+    pub(super) fn register(
+        &self,
+        pmap: Option<&PMap>,
+        audit: &Audit,
+        uifo: &UserInfo,
+        referral: Referral,
+    ) -> FromWrite {
+        let path = referral.path.clone();
+        let ttl = match referral.ttl {
+            None | Some(0) => {
+                return FromWrite::Error("a non zero ttl is required".into())
+            }
+            Some(ttl) => ttl,
+        };
+        if !Path::is_absolute(&*path) {
+            return FromWrite::Error("absolute paths required".into());
+        }
+        if referral.addrs.is_empty() {
+            return FromWrite::Error("at least one address is required".into());
+        }
+        let mut inner = self.0.lock();
+        if let Some(r) = inner.check_referral(&path) {
+            return FromWrite::Referral(r);
+        }
+        let root = inner.parent.as_ref().map(|r| r.path.as_ref()).unwrap_or("/");
+        if Path::levels(&*path) <= Path::levels(root) {
+            return FromWrite::Error("children must be deeper than the root".into());
+        }
+        let below = inner
+            .children
+            .keys()
+            .chain(inner.registered.keys())
+            .find(|p| *p != &path && Path::is_parent(&path, p));
+        if let Some(p) = below {
+            let e = format!("can't put a referral {} above {}", path, p);
+            return FromWrite::Error(e.into());
+        }
+        if !pmap.map(|p| p.allowed(&*path, Permissions::REGISTER, uifo)).unwrap_or(true) {
+            audit.log(|| Event::Denied {
+                user: audit::user(uifo),
+                action: Action::Register,
+                path: path.clone(),
+            });
+            return FromWrite::Denied;
+        }
+        let expires = Instant::now() + Duration::from_secs(ttl as u64);
+        let changed = match inner.registered.get(&path) {
+            None => true,
+            Some((_, r)) => r.ttl != referral.ttl || *r.addrs != *referral.addrs,
+        };
+        if changed {
+            audit.log(|| Event::Registered {
+                user: audit::user(uifo),
+                path: path.clone(),
+                addrs: referral.addrs.iter().map(|(a, _)| *a).collect(),
+            });
+        }
+        inner.registered.insert(path, (expires, referral));
+        if changed {
+            inner.update_snapshot();
+        }
+        FromWrite::Published
+    }
+
+    pub(super) fn unregister(
+        &self,
+        pmap: Option<&PMap>,
+        audit: &Audit,
+        uifo: &UserInfo,
+        path: Path,
+    ) -> FromWrite {
+        if !Path::is_absolute(&*path) {
+            return FromWrite::Error("absolute paths required".into());
+        }
+        let mut inner = self.0.lock();
+        if let Some(r) = inner.check_referral(&path) {
+            return FromWrite::Referral(r);
+        }
+        if !pmap.map(|p| p.allowed(&*path, Permissions::REGISTER, uifo)).unwrap_or(true) {
+            audit.log(|| Event::Denied {
+                user: audit::user(uifo),
+                action: Action::Register,
+                path: path.clone(),
+            });
+            return FromWrite::Denied;
+        }
+        if inner.registered.remove(&path).is_some() {
+            audit.log(|| Event::Unregistered { user: audit::user(uifo), path });
+            inner.update_snapshot();
+        }
+        FromWrite::Unpublished
+    }
+}
+
+async fn register_loop(
+    config: Option<std::path::PathBuf>,
+    referral: Referral,
+    stop: oneshot::Receiver<()>,
+) {
+    let mut stop = stop.fuse();
+    let ttl = referral.ttl.unwrap_or(1) as u64;
+    let interval = Duration::from_secs(max(1, ttl / 2));
+    let mut tick = time::interval(interval);
+    // a bare write client, plus a listener that only answers the
+    // parent's listener ownership checks
+    let mut writer: Option<(ResolverWrite, task::JoinHandle<()>)> = None;
+    loop {
+        select_biased! {
+            _ = stop => break,
+            _ = tick.tick().fuse() => {
+                let res: Result<()> = async {
+                    let (writer, _) = match &writer {
+                        Some(writer) => writer,
+                        None => {
+                            let cfg = match &config {
+                                None => ClientConfig::load_default()?,
+                                Some(config) => ClientConfig::load(config)?,
+                            };
+                            let (addr, listener) =
+                                publisher::bind(&cfg.default_bind_config, &cfg).await?;
+                            let auth = cfg.default_auth();
+                            let w = ResolverWrite::new(cfg, auth, addr)?;
+                            let checks =
+                                publisher::answer_ownership_checks(listener, w.secrets());
+                            writer.insert((w, task::spawn(checks)))
+                        }
+                    };
+                    writer.register_referral(referral.clone()).await
+                }
+                .await;
+                if let Err(e) = res {
+                    warn!("failed to register {} with the parent, will retry: {}", referral.path, e)
+                }
+            }
+        }
+    }
+    if let Some((writer, checks)) = writer {
+        let path = referral.path.clone();
+        let res = time::timeout(interval, writer.unregister_referral(path)).await;
+        checks.abort();
+        match res {
+            Ok(Ok(())) => info!("unregistered {} from the parent", referral.path),
+            Ok(Err(e)) => warn!("failed to unregister {} {}", referral.path, e),
+            Err(_) => warn!("timed out unregistering {}", referral.path),
+        }
+    }
+}
+
+/// Keeps this cluster registered with it's parent, the registration
+/// is removed when this is dropped.
+pub(super) struct Registration(#[allow(dead_code)] oneshot::Sender<()>);
+
+impl Registration {
+    pub(super) fn start(cfg: &Register, referral: Referral) -> Self {
+        let (tx, rx) = oneshot::channel();
+        let referral = Referral { ttl: Some(cfg.ttl), ..referral };
+        task::spawn(register_loop(cfg.config.clone(), referral, rx));
+        Registration(tx)
+    }
+}
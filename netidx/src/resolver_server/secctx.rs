@@ -11,15 +11,21 @@ use crate::{
     },
     protocol::resolver::PublisherId,
     tls,
+    token::{Claims, KeySet},
 };
 use anyhow::{bail, Result};
 use arcstr::ArcStr;
+use chrono::prelude::*;
 use cross_krb5::{K5Ctx, ServerCtx};
 use fxhash::FxHashMap;
-use log::debug;
+use log::{debug, warn};
 use netidx_core::pack::Pack;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{RwLock, RwLockReadGuard};
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
+use tokio::{
+    fs,
+    sync::{RwLock, RwLockReadGuard},
+    task,
+};
 
 pub(super) struct LocalAuth(AuthServer);
 
@@ -44,6 +50,99 @@ impl LocalAuth {
     }
 }
 
+/// Verifies bearer tokens. The key set is reloaded when the file
+/// changes so keys can be rotated without restarting the resolver
+/// server.
+pub(super) struct TokenAuth {
+    path: Chars,
+    keys: parking_lot::RwLock<(Option<SystemTime>, Arc<KeySet>)>,
+}
+
+impl TokenAuth {
+    pub(super) async fn new(path: &Chars) -> Result<Self> {
+        let modified = fs::metadata(&**path).await?.modified()?;
+        let keys = KeySet::parse(&fs::read_to_string(&**path).await?)?;
+        let keys = parking_lot::RwLock::new((Some(modified), Arc::new(keys)));
+        Ok(Self { path: path.clone(), keys })
+    }
+
+    async fn keys(&self) -> Arc<KeySet> {
+        let modified = match fs::metadata(&*self.path).await.and_then(|m| m.modified()) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                warn!("failed to stat token key set {}: {}", self.path, e);
+                None
+            }
+        };
+        let (last, keys) = {
+            let k = self.keys.read();
+            (k.0, Arc::clone(&k.1))
+        };
+        if modified.is_none() || modified == last {
+            return keys;
+        }
+        let res = fs::read_to_string(&*self.path)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|s| KeySet::parse(&s));
+        let mut k = self.keys.write();
+        match res {
+            Ok(keys) => *k = (modified, Arc::new(keys)),
+            Err(e) => {
+                warn!("failed to reload token key set {}: {}", self.path, e);
+                k.0 = modified;
+            }
+        }
+        Arc::clone(&k.1)
+    }
+
+    pub(super) async fn authenticate(&self, token: &[u8]) -> Result<Claims> {
+        self.keys().await.verify(token)
+    }
+}
+
+/// Accepts tls connections, and maps client certificates to users
+/// according to the configured rules.
+pub(super) struct TlsAuth {
+    acceptor: Arc<tls::Reloading<tokio_rustls::TlsAcceptor>>,
+    user: Vec<tls::CertField>,
+    groups: Vec<tls::CertField>,
+}
+
+impl TlsAuth {
+    fn new(
+        trusted: &Chars,
+        crl: &Option<Chars>,
+        certificate: &Chars,
+        private_key: &Chars,
+        user: &Vec<tls::CertField>,
+        groups: &Vec<tls::CertField>,
+    ) -> Result<Self> {
+        let acceptor = tls::Reloading::new(
+            None,
+            trusted,
+            crl.as_ref().map(|c| &**c),
+            certificate,
+            private_key,
+            tls::create_tls_acceptor,
+        )?;
+        Ok(Self {
+            acceptor: Arc::new(acceptor),
+            user: user.clone(),
+            groups: groups.clone(),
+        })
+    }
+
+    pub(super) async fn acceptor(&self) -> Result<tokio_rustls::TlsAcceptor> {
+        let acceptor = Arc::clone(&self.acceptor);
+        Ok(task::spawn_blocking(move || acceptor.load()).await?)
+    }
+
+    pub(super) fn identity(&self, cert: &[u8]) -> Result<tls::CertIdentity> {
+        tls::get_identity(cert, &self.user, &self.groups)
+    }
+}
+
 pub(super) trait SecDataCommon {
     fn secret(&self) -> u128;
 }
@@ -115,6 +214,30 @@ impl SecCtxData<LocalSecData> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub(super) struct TokenSecData {
+    pub(super) claims: Claims,
+    pub(super) secret: u128,
+}
+
+impl SecDataCommon for TokenSecData {
+    fn secret(&self) -> u128 {
+        self.secret
+    }
+}
+
+impl SecCtxData<TokenSecData> {
+    pub(super) fn get(&self, id: &PublisherId) -> Option<&TokenSecData> {
+        self.data.get(id).and_then(|d| {
+            if d.claims.expires > Utc::now() {
+                Some(d)
+            } else {
+                None
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(super) struct TlsSecData(pub(super) u128);
 
@@ -135,6 +258,7 @@ pub(super) enum SecCtxDataReadGuard<'a> {
     Krb5(RwLockReadGuard<'a, SecCtxData<K5SecData>>),
     Local(RwLockReadGuard<'a, SecCtxData<LocalSecData>>),
     Tls(RwLockReadGuard<'a, SecCtxData<TlsSecData>>),
+    Token(RwLockReadGuard<'a, SecCtxData<TokenSecData>>),
 }
 
 impl<'a> SecCtxDataReadGuard<'a> {
@@ -144,6 +268,7 @@ impl<'a> SecCtxDataReadGuard<'a> {
             SecCtxDataReadGuard::Krb5(r) => Some(&r.pmap),
             SecCtxDataReadGuard::Local(r) => Some(&r.pmap),
             SecCtxDataReadGuard::Tls(r) => Some(&r.pmap),
+            SecCtxDataReadGuard::Token(r) => Some(&r.pmap),
         }
     }
 }
@@ -153,7 +278,8 @@ pub(super) enum SecCtx {
     Anonymous,
     Krb5(Arc<(Chars, RwLock<SecCtxData<K5SecData>>)>),
     Local(Arc<(LocalAuth, RwLock<SecCtxData<LocalSecData>>)>),
-    Tls(Arc<(tokio_rustls::TlsAcceptor, RwLock<SecCtxData<TlsSecData>>)>),
+    Tls(Arc<(TlsAuth, RwLock<SecCtxData<TlsSecData>>)>),
+    Token(Arc<(TokenAuth, RwLock<SecCtxData<TokenSecData>>)>),
 }
 
 impl SecCtx {
@@ -171,13 +297,27 @@ impl SecCtx {
                 let store = RwLock::new(SecCtxData::new(cfg, member).await?);
                 SecCtx::Krb5(Arc::new((spn.clone(), store)))
             }
-            Auth::Tls { name: _, trusted, certificate, private_key } => {
+            Auth::Tls {
+                name: _,
+                trusted,
+                crl,
+                certificate,
+                private_key,
+                user,
+                groups,
+            } => {
                 debug!("creating tls acceptor");
                 let auth =
-                    tls::create_tls_acceptor(None, trusted, certificate, private_key)?;
+                    TlsAuth::new(trusted, crl, certificate, private_key, user, groups)?;
                 let store = RwLock::new(SecCtxData::new(cfg, member).await?);
                 SecCtx::Tls(Arc::new((auth, store)))
             }
+            Auth::Token { keys } => {
+                debug!("loading token key set {}", keys);
+                let auth = TokenAuth::new(keys).await?;
+                let store = RwLock::new(SecCtxData::new(cfg, member).await?);
+                SecCtx::Token(Arc::new((auth, store)))
+            }
         };
         Ok(t)
     }
@@ -188,6 +328,7 @@ impl SecCtx {
             SecCtx::Krb5(a) => SecCtxDataReadGuard::Krb5(a.1.read().await),
             SecCtx::Local(a) => SecCtxDataReadGuard::Local(a.1.read().await),
             SecCtx::Tls(a) => SecCtxDataReadGuard::Tls(a.1.read().await),
+            SecCtx::Token(a) => SecCtxDataReadGuard::Token(a.1.read().await),
         }
     }
 
@@ -196,6 +337,7 @@ impl SecCtx {
             SecCtx::Krb5(a) => a.1.write().await.remove(id),
             SecCtx::Local(a) => a.1.write().await.remove(id),
             SecCtx::Tls(a) => a.1.write().await.remove(id),
+            SecCtx::Token(a) => a.1.write().await.remove(id),
             SecCtx::Anonymous => (),
         }
     }
@@ -1,23 +1,34 @@
 use super::{
+    aliases::{self, Aliases, Fixup},
+    audit::{self, Action, Audit, Event},
     auth::{Permissions, UserInfo},
+    config::Limits,
+    limits::{Usage, UsageMap},
+    listers::Listers,
+    referrals::Registry,
     secctx::{SecCtx, SecCtxDataReadGuard},
-    store::{self, COLS_POOL, MAX_READ_BATCH, MAX_WRITE_BATCH, PATH_POOL, REF_POOL},
+    store::{
+        self, COLS_POOL, MAX_READ_BATCH, MAX_WRITE_BATCH, MD_POOL, PATH_POOL, REF_POOL,
+    },
 };
 use chrono::prelude::*;
 use crate::{
     channel::Channel,
+    chars::Chars,
     pack::Z64,
     path::Path,
     pool::{Pool, Pooled},
     protocol::{
         glob::Scope,
         resolver::{
-            FromRead, FromWrite, GetChangeNr, ListMatching, Publisher, PublisherId,
-            Referral, Resolved, Table, ToRead, ToWrite,
+            Explanation, FromRead, FromWrite, GetChangeNr, ListMatching, Publisher,
+            PublisherId, Referral, Resolved, Search, Table, ToRead, ToWrite,
         },
     },
+    publisher::PublishFlags,
 };
 use anyhow::Result;
+use arcstr::ArcStr;
 use futures::{
     channel::{
         mpsc::{unbounded, UnboundedSender},
@@ -27,7 +38,7 @@ use futures::{
     prelude::*,
     select,
 };
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use log::{info, trace};
 use std::{
     collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
@@ -36,7 +47,7 @@ use std::{
     net::SocketAddr,
     result,
     sync::Arc,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 use tokio::task;
 
@@ -62,6 +73,7 @@ lazy_static! {
 
 struct ReadRequest {
     uifo: Arc<UserInfo>,
+    registered: Arc<BTreeMap<Path, Referral>>,
     batch: Pooled<ReadB>,
 }
 
@@ -72,8 +84,11 @@ struct ReadResponse {
 
 struct WriteRequest {
     uifo: Arc<UserInfo>,
+    registered: Arc<BTreeMap<Path, Referral>>,
     publisher: Arc<Publisher>,
     batch: Pooled<WriteB>,
+    usage: Arc<Usage>,
+    audit: bool,
 }
 
 #[derive(Clone)]
@@ -89,6 +104,9 @@ impl Shard {
         parent: Option<Referral>,
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
+        audit: Audit,
+        limits: Arc<Limits>,
+        usage: UsageMap,
         resolver: SocketAddr,
     ) -> Self {
         let (read, read_rx) = unbounded();
@@ -110,6 +128,9 @@ impl Shard {
                                 shard,
                                 &mut store,
                                 &secctx,
+                                &audit,
+                                &limits,
+                                &usage,
                                 resolver,
                                 req
                             ).await;
@@ -121,8 +142,10 @@ impl Shard {
                         Some((req, reply)) => {
 			    let secctx = secctx.read().await;
                             let r = Shard::process_write_batch(
+                                shard,
                                 &mut store,
                                 &secctx,
+                                &audit,
                                 req
                             ).await;
                             let _ = reply.send(r);
@@ -150,6 +173,9 @@ impl Shard {
         shard: usize,
         store: &mut store::Store,
         secctx: &SecCtxDataReadGuard<'a>,
+        audit: &Audit,
+        limits: &Limits,
+        usage: &UsageMap,
         resolver: SocketAddr,
         mut req: ReadRequest,
     ) -> ReadResponse {
@@ -161,7 +187,19 @@ impl Shard {
             batch: FROM_READ_POOL.take(),
         };
         let uifo = req.uifo;
+        store.set_registered(req.registered);
         let pmap = secctx.pmap();
+        // requests sent to every shard are audited only by shard 0
+        let denied = |action, path: &Path| {
+            audit.log(|| {
+                Event::Denied { user: audit::user(&uifo), action, path: path.clone() }
+            })
+        };
+        let read = |action, path: &Path| {
+            audit.log_read(|| {
+                Event::Read { user: audit::user(&uifo), action, path: path.clone() }
+            })
+        };
 	let mut n = 0;
 	for (id, m) in req.batch.drain(..) {
 	    if n > 10_000 {
@@ -176,6 +214,7 @@ impl Shard {
                     } else {
 			match pmap {
                             None => {
+				read(Action::Resolve, &path);
 				let (flags, publishers) =
                                     store.resolve(&mut resp.publishers, &path);
 				let a = Resolved {
@@ -184,14 +223,17 @@ impl Shard {
                                     timestamp: now,
                                     permissions: Permissions::all().bits(),
                                     flags,
+                                    target: None,
 				};
 				(id, FromRead::Resolved(a))
                             }
                             Some(pmap) => {
 				let perm = pmap.permissions(&*path, &*uifo);
 				if !perm.contains(Permissions::SUBSCRIBE) {
+				    denied(Action::Resolve, &path);
                                     (id, FromRead::Denied)
 				} else {
+				    read(Action::Resolve, &path);
                                     let (flags, publishers) = store.resolve_and_sign(
 					&mut resp.publishers,
 					&secctx,
@@ -206,6 +248,7 @@ impl Shard {
 					timestamp: now,
 					permissions: perm.bits(),
 					flags,
+					target: None,
                                     };
                                     (id, FromRead::Resolved(a))
 				}
@@ -222,8 +265,14 @@ impl Shard {
                             .map(|pmap| pmap.allowed(&*path, Permissions::LIST, &*uifo))
                             .unwrap_or(true);
 			if allowed {
+			    if shard == 0 {
+				read(Action::List, &path);
+			    }
                             (id, FromRead::List(store.list(&path)))
 			} else {
+			    if shard == 0 {
+				denied(Action::List, &path);
+			    }
                             (id, FromRead::Denied)
 			}
                     }
@@ -252,6 +301,16 @@ impl Shard {
                             })
 			})
 			.unwrap_or(true);
+                    if shard == 0 {
+			for g in set.iter() {
+			    let path = Path::from(ArcStr::from(&**g.raw()));
+			    if allowed {
+				read(Action::ListMatching, &path)
+			    } else {
+				denied(Action::ListMatching, &path)
+			    }
+			}
+                    }
                     if !allowed {
 			let lm = ListMatching { referrals, matched: PATH_BPOOL.take() };
 			(id, FromRead::ListMatching(lm))
@@ -262,6 +321,47 @@ impl Shard {
 			(id, FromRead::ListMatching(lm))
                     }
 		}
+		ToRead::Search(set, query) => {
+		    n += 1000;
+                    let mut referrals = REF_POOL.take();
+                    if shard == 0 {
+			for glob in set.iter() {
+                            store.referrals_in_scope(
+				&mut *referrals,
+				glob.base(),
+				glob.scope(),
+                            )
+			}
+                    }
+                    let allowed = pmap
+			.map(|pmap| {
+                            set.iter().all(|g| {
+				pmap.allowed_in_scope(
+                                    g.base(),
+                                    g.scope(),
+                                    Permissions::LIST,
+                                    &*uifo,
+				)
+                            })
+			})
+			.unwrap_or(true);
+                    if shard == 0 {
+			for g in set.iter() {
+			    let path = Path::from(ArcStr::from(&**g.raw()));
+			    if allowed {
+				read(Action::Search, &path)
+			    } else {
+				denied(Action::Search, &path)
+			    }
+			}
+                    }
+                    let matched = if allowed {
+			store.search(&set, &query)
+                    } else {
+			MD_POOL.take()
+                    };
+                    (id, FromRead::Search(Search { matched, referrals }))
+		}
 		ToRead::GetChangeNr(path) => {
 		    n += 1;
                     let mut referrals = REF_POOL.take();
@@ -290,27 +390,71 @@ impl Shard {
                             .map(|pmap| pmap.allowed(&*path, Permissions::LIST, &*uifo))
                             .unwrap_or(true);
 			if !allowed {
+			    if shard == 0 {
+				denied(Action::Table, &path);
+			    }
                             (id, FromRead::Denied)
 			} else {
+			    if shard == 0 {
+				read(Action::Table, &path);
+			    }
                             let rows = store.list(&path);
                             let cols = store.columns(&path);
                             (id, FromRead::Table(Table { rows, cols }))
 			}
                     }
 		}
+		ToRead::Explain(path) => {
+		    n += 1;
+                    if let Some(r) = store.check_referral(&path) {
+			(id, FromRead::Referral(r))
+                    } else {
+			let e = match pmap {
+                            Some(pmap) => pmap.explain(resolver, &path, &*uifo),
+                            None => Explanation {
+				resolver,
+				path,
+				user: uifo.user_info.clone(),
+				enforced: false,
+				permissions: Permissions::all().bits(),
+				entries: vec![],
+                            },
+			};
+			(id, FromRead::Explain(e))
+                    }
+		}
+		ToRead::Usage => {
+		    n += 100;
+		    if pmap.is_none() || limits.is_admin(&uifo) {
+			(id, FromRead::Usage(usage.usage()))
+		    } else {
+			(id, FromRead::Denied)
+		    }
+		}
             })
 	}
         resp
     }
 
     async fn process_write_batch<'a>(
+        shard: usize,
         store: &mut store::Store,
         secctx: &SecCtxDataReadGuard<'a>,
+        audit: &Audit,
         mut req: WriteRequest,
     ) -> Pooled<WriteR> {
         let uifo = &*req.uifo;
+        store.set_registered(req.registered.clone());
         let publisher = req.publisher;
+        let usage = &req.usage;
         let pmap = secctx.pmap();
+        // default publishes, unpublishes, and clears are sent to every
+        // shard, they are audited only by shard 0
+        let log = |default: bool, f: &dyn Fn() -> Event| {
+            if req.audit && (!default || shard == 0) {
+                audit.log(f)
+            }
+        };
         let publish = |s: &mut store::Store,
                        path: Path,
                        default: bool,
@@ -327,9 +471,30 @@ impl Shard {
                     Permissions::PUBLISH
                 };
                 if pmap.map(|p| p.allowed(&*path, perm, uifo)).unwrap_or(true) {
+                    // default publishers are accounted for by the Store
+                    if !default && !s.is_published(&publisher.id, &path) {
+                        if let Err(e) = usage.publish() {
+                            return FromWrite::Error(e);
+                        }
+                    }
+                    log(default, &|| Event::Published {
+                        user: audit::user(uifo),
+                        publisher: publisher.addr,
+                        path: path.clone(),
+                        default,
+                    });
                     s.publish(path, &publisher, default, flags);
                     FromWrite::Published
                 } else {
+                    log(default, &|| Event::Denied {
+                        user: audit::user(uifo),
+                        action: if default {
+                            Action::PublishDefault
+                        } else {
+                            Action::Publish
+                        },
+                        path: path.clone(),
+                    });
                     FromWrite::Denied
                 }
             }
@@ -342,9 +507,44 @@ impl Shard {
 		task::yield_now().await;
 	    }
 	    resp.push_back(match m {
-		ToWrite::Heartbeat => unreachable!(),
+		ToWrite::Heartbeat
+		    | ToWrite::TimedHeartbeat(_)
+		    | ToWrite::RegisterReferral(_)
+		    | ToWrite::UnregisterReferral(_)
+		    | ToWrite::SetAlias(_, _)
+		    | ToWrite::RemoveAlias(_)
+		    | ToWrite::SetLoad(_)
+		    | ToWrite::SetLocality(_) => unreachable!(),
+		ToWrite::SetMetadata(path, metadata) => {
+		    n += 1;
+                    if !Path::is_absolute(&*path) {
+			(id, FromWrite::Error("absolute paths required".into()))
+                    } else if let Some(r) = store.check_referral(&path) {
+			(id, FromWrite::Referral(r))
+                    } else if !store.is_published_by(&publisher.id, &path) {
+			log(false, &|| Event::Denied {
+			    user: audit::user(uifo),
+			    action: Action::Metadata,
+			    path: path.clone(),
+			});
+			(id, FromWrite::Denied)
+                    } else if let Err(e) = usage.set_metadata(&path, &metadata) {
+			(id, FromWrite::Error(e))
+                    } else {
+			store.set_metadata(path, metadata);
+			(id, FromWrite::Published)
+                    }
+		}
 		ToWrite::Clear => {
 		    n += 1000;
+                    if shard == 0 {
+                        audit.log(|| Event::Cleared {
+                            user: audit::user(uifo),
+                            publisher: publisher.addr,
+                        })
+                    }
+                    usage.unpublish(store.published_count(&publisher.id));
+                    usage.clear_metadata();
                     store.clear(&publisher);
                     (id, FromWrite::Unpublished)
 		}
@@ -371,6 +571,16 @@ impl Shard {
                     } else if let Some(r) = store.check_referral(&path) {
 			(id, FromWrite::Referral(r))
                     } else {
+			log(false, &|| Event::Unpublished {
+			    user: audit::user(uifo),
+			    publisher: publisher.addr,
+			    path: path.clone(),
+			    default: false,
+			});
+			if store.is_published(&publisher.id, &path) {
+			    usage.unpublish(1);
+			}
+			usage.unset_metadata(&path);
 			store.unpublish(&publisher, false, path);
 			(id, FromWrite::Unpublished)
                     }
@@ -382,6 +592,13 @@ impl Shard {
                     } else if let Some(r) = store.check_referral(&path) {
 			(id, FromWrite::Referral(r))
                     } else {
+			log(true, &|| Event::Unpublished {
+			    user: audit::user(uifo),
+			    publisher: publisher.addr,
+			    path: path.clone(),
+			    default: true,
+			});
+			usage.unset_metadata(&path);
 			store.unpublish(&publisher, true, path);
 			(id, FromWrite::Unpublished)
                     }
@@ -408,6 +625,15 @@ macro_rules! same {
 pub(super) struct Store {
     shards: Vec<Shard>,
     shard_mask: usize,
+    limits: Arc<Limits>,
+    usage: UsageMap,
+    registry: Registry,
+    aliases: Aliases,
+    listers: Listers,
+    // how long to wait for default publishers to list their children
+    list_timeout: Duration,
+    secctx: SecCtx,
+    audit: Audit,
 }
 
 impl Store {
@@ -415,17 +641,45 @@ impl Store {
         parent: Option<Referral>,
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
+        audit: Audit,
+        limits: Limits,
+        list_timeout: Duration,
         resolver: SocketAddr,
     ) -> Self {
+        let limits = Arc::new(limits);
+        let usage = UsageMap::new();
+        let registry = Registry::new(parent.clone(), children.clone());
         let shards = std::cmp::max(1, num_cpus::get().next_power_of_two());
         let shard_mask = shards - 1;
         let shards = (0..shards)
             .into_iter()
             .map(|i| {
-                Shard::new(i, parent.clone(), children.clone(), secctx.clone(), resolver)
+                Shard::new(
+                    i,
+                    parent.clone(),
+                    children.clone(),
+                    secctx.clone(),
+                    audit.clone(),
+                    limits.clone(),
+                    usage.clone(),
+                    resolver,
+                )
             })
             .collect();
-        Store { shards, shard_mask }
+        let aliases = Aliases::new();
+        let listers = Listers::new();
+        Store {
+            shards,
+            shard_mask,
+            limits,
+            usage,
+            registry,
+            aliases,
+            listers,
+            list_timeout,
+            secctx,
+            audit,
+        }
     }
 
     fn shard(&self, path: &Path) -> usize {
@@ -452,11 +706,44 @@ impl Store {
         uifo: Arc<UserInfo>,
         mut msgs: impl Iterator<Item = ToRead>,
     ) -> Result<()> {
+        let max_glob_results = self.limits.get(&uifo).max_glob_results;
+        let check_glob = |r: FromRead| match (r, max_glob_results) {
+            (FromRead::ListMatching(lm), Some(max))
+                if lm.matched.iter().map(|m| m.len()).sum::<usize>() > max =>
+            {
+                let e = format!("list_matching matched more than {} paths", max);
+                FromRead::Error(Chars::from(e))
+            }
+            (FromRead::Search(s), Some(max)) if s.matched.len() > max => {
+                let e = format!("search matched more than {} paths", max);
+                FromRead::Error(Chars::from(e))
+            }
+            (r, _) => r,
+        };
         let mut finished = false;
         loop {
             let mut n = 0;
             let mut c = 0;
             let mut by_shard = self.read_shard_batch();
+            let aliases = self.aliases.snapshot();
+            let mut fixups: FxHashMap<u64, Fixup> = HashMap::default();
+            let mut rewrite = |n: u64, path: Path, list: bool| {
+                let rewritten = aliases::rewrite(&aliases, &path);
+                match rewritten {
+                    Some((alias, target)) => {
+                        let alias = alias.clone();
+                        let fixup = Fixup::Aliased { alias, target: target.clone() };
+                        fixups.insert(n, fixup);
+                        target
+                    }
+                    None => {
+                        if list && !aliases.is_empty() {
+                            fixups.insert(n, Fixup::List(path.clone()));
+                        }
+                        path
+                    }
+                }
+            };
             while c < MAX_READ_BATCH {
                 match msgs.next() {
                     None => {
@@ -464,10 +751,20 @@ impl Store {
                         break;
                     }
                     Some(ToRead::Resolve(path)) => {
+                        let path = rewrite(n, path, false);
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToRead::Resolve(path)));
                         c += 1;
                     }
+                    Some(ToRead::Explain(path)) => {
+                        let s = self.shard(&path);
+                        by_shard[s].push((n, ToRead::Explain(path)));
+                        c += 1;
+                    }
+                    Some(ToRead::Usage) => {
+                        by_shard[0].push((n, ToRead::Usage));
+                        c += 100;
+                    }
                     Some(ToRead::GetChangeNr(path)) => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToRead::GetChangeNr(path.clone())));
@@ -475,12 +772,14 @@ impl Store {
                         c += 1;
                     }
                     Some(ToRead::List(path)) => {
+                        let path = rewrite(n, path, true);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToRead::List(path.clone())));
                         }
                         c += 10000;
                     }
                     Some(ToRead::Table(path)) => {
+                        let path = rewrite(n, path, true);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToRead::Table(path.clone())));
                         }
@@ -492,6 +791,12 @@ impl Store {
                         }
                         c += 100000;
                     }
+                    Some(ToRead::Search(set, query)) => {
+                        for b in by_shard.iter_mut() {
+                            b.push((n, ToRead::Search(set.clone(), query.clone())));
+                        }
+                        c += 100000;
+                    }
                 }
                 n += 1;
             }
@@ -499,10 +804,21 @@ impl Store {
                 assert!(finished);
                 break Ok(());
             }
+            // lists and tables are sent to every shard
+            let listed = by_shard[0].iter().filter_map(|(_, m)| match m {
+                ToRead::List(path) | ToRead::Table(path) => Some(path),
+                _ => None,
+            });
+            self.listers.ask(listed, self.list_timeout).await;
+            let registered = self.registry.snapshot();
             let mut replies =
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
-                    let req = ReadRequest { uifo: uifo.clone(), batch };
+                    let req = ReadRequest {
+                        uifo: uifo.clone(),
+                        registered: registered.clone(),
+                        batch,
+                    };
                     let _ = self.shards[i].read.unbounded_send((req, tx));
                     rx
                 }))
@@ -513,6 +829,7 @@ impl Store {
             for r in replies.iter_mut() {
                 publishers.extend(r.publishers.drain());
             }
+            self.usage.set_advertised(&mut publishers);
             for (_, p) in publishers.drain() {
                 con.queue_send(&FromRead::Publisher(p))?;
             }
@@ -521,6 +838,10 @@ impl Store {
                 r.extend(replies.into_iter().map(|r| r.batch));
                 r
             };
+            let fixup = |i: u64, r: FromRead| match fixups.get(&i) {
+                None => r,
+                Some(f) => f.apply(&aliases, r),
+            };
             for i in 0..n {
                 if replies.len() == 1
                     || !replies
@@ -538,13 +859,24 @@ impl Store {
                         })
                         .unwrap()
                         .1;
-                    con.queue_send(&r)?;
+                    con.queue_send(&fixup(i, check_glob(r)))?;
                 } else {
                     match replies[0].pop_front().unwrap() {
                         (_, FromRead::Publisher(_)) => unreachable!(),
                         (_, FromRead::Resolved(_)) => unreachable!(),
+                        (_, FromRead::Explain(_)) => unreachable!(),
+                        (_, FromRead::Usage(_)) => unreachable!(),
+                        (_, FromRead::Draining) => unreachable!(),
+                        (_, FromRead::RateLimited(_)) => unreachable!(),
                         (_, m @ FromRead::Referral(_)) => {
-                            same!(con, replies, &m, "desynced referral");
+                            if fixups.contains_key(&i) {
+                                for i in 1..replies.len() {
+                                    replies[i].pop_front().unwrap();
+                                }
+                                con.queue_send(&fixup(i, m))?;
+                            } else {
+                                same!(con, replies, &m, "desynced referral");
+                            }
                         }
                         (_, m @ FromRead::Denied) => {
                             same!(con, replies, &m, "desynced permissions");
@@ -569,7 +901,7 @@ impl Store {
                             }
                             let mut paths = PATH_POOL.take();
                             paths.extend(hpaths.drain());
-                            con.queue_send(&FromRead::List(paths))?;
+                            con.queue_send(&fixup(i, FromRead::List(paths)))?;
                         }
                         (_, FromRead::ListMatching(mut lm)) => {
                             let referrals = lm.referrals;
@@ -584,10 +916,21 @@ impl Store {
                                     panic!("desynced listmatching")
                                 }
                             }
-                            con.queue_send(&FromRead::ListMatching(ListMatching {
-                                matched,
-                                referrals,
-                            }))?;
+                            con.queue_send(&check_glob(FromRead::ListMatching(
+                                ListMatching { matched, referrals },
+                            )))?;
+                        }
+                        (_, FromRead::Search(mut sr)) => {
+                            for i in 1..replies.len() {
+                                if let (_, FromRead::Search(mut s)) =
+                                    replies[i].pop_front().unwrap()
+                                {
+                                    sr.matched.extend(s.matched.drain(..));
+                                } else {
+                                    panic!("desynced search")
+                                }
+                            }
+                            con.queue_send(&check_glob(FromRead::Search(sr)))?;
                         }
                         (_, FromRead::GetChangeNr(cn)) => {
                             let referrals = cn.referrals;
@@ -631,7 +974,8 @@ impl Store {
                             let mut cols = COLS_POOL.take();
                             rows.extend(hrows.drain());
                             cols.extend(hcols.drain());
-                            con.queue_send(&FromRead::Table(Table { rows, cols }))?;
+                            let table = FromRead::Table(Table { rows, cols });
+                            con.queue_send(&fixup(i, table))?;
                         }
                     }
                 }
@@ -651,10 +995,21 @@ impl Store {
         mut msgs: impl Iterator<Item = ToWrite>,
     ) -> Result<()> {
 	trace!("handling write from {:?}", &publisher);
+        // the individual unpublishes done by handle_clear are not
+        // audited, only the clear itself.
+        let audit = con.is_some();
+        let usage = self.usage.get(&self.limits, &publisher, &uifo);
         let mut finished = false;
         loop {
             let mut n = 0;
             let mut by_shard = self.write_shard_batch();
+            // replies decided here without consulting the shards
+            let mut direct: VecDeque<(u64, FromWrite)> = VecDeque::new();
+            // default publishers that are accounted for, but may
+            // still be rejected by the shards
+            let mut tentative: FxHashMap<u64, Path> = HashMap::default();
+            // the tentative default publishers that list on demand
+            let mut lists: FxHashSet<u64> = HashSet::default();
             for _ in 0..MAX_WRITE_BATCH {
                 match msgs.next() {
                     None => {
@@ -662,7 +1017,12 @@ impl Store {
                         break;
                     }
                     Some(ToWrite::Heartbeat) => continue,
+                    Some(ToWrite::TimedHeartbeat(ts)) => {
+                        direct.push_back((n, FromWrite::HeartbeatReply(ts)))
+                    }
                     Some(ToWrite::Clear) => {
+                        usage.clear_defaults();
+                        self.listers.clear(&publisher.id);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::Clear));
                         }
@@ -676,40 +1036,124 @@ impl Store {
                         by_shard[s].push((n, ToWrite::Unpublish(path)));
                     }
                     Some(ToWrite::UnpublishDefault(path)) => {
+                        usage.unpublish_default(&path);
+                        self.listers.remove(&path, &publisher.id);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::UnpublishDefault(path.clone())));
                         }
                     }
                     Some(ToWrite::PublishDefault(path)) => {
-                        for b in by_shard.iter_mut() {
-                            b.push((n, ToWrite::PublishDefault(path.clone())));
+                        match usage.publish_default(&path) {
+                            Err(e) => direct.push_back((n, FromWrite::Error(e))),
+                            Ok(()) => {
+                                tentative.insert(n, path.clone());
+                                for b in by_shard.iter_mut() {
+                                    b.push((n, ToWrite::PublishDefault(path.clone())));
+                                }
+                            }
                         }
                     }
                     Some(ToWrite::PublishWithFlags(path, flags)) => {
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::PublishWithFlags(path, flags)));
                     }
+                    Some(ToWrite::SetMetadata(path, metadata)) => {
+                        let s = self.shard(&path);
+                        by_shard[s].push((n, ToWrite::SetMetadata(path, metadata)));
+                    }
+                    Some(ToWrite::RegisterReferral(referral)) => {
+                        let secctx = self.secctx.read().await;
+                        let r = self.registry.register(
+                            secctx.pmap(),
+                            &self.audit,
+                            &uifo,
+                            referral,
+                        );
+                        direct.push_back((n, r))
+                    }
+                    Some(ToWrite::UnregisterReferral(path)) => {
+                        let secctx = self.secctx.read().await;
+                        let r = self.registry.unregister(
+                            secctx.pmap(),
+                            &self.audit,
+                            &uifo,
+                            path,
+                        );
+                        direct.push_back((n, r))
+                    }
+                    Some(ToWrite::SetLoad(load)) => {
+                        usage.set_load(load);
+                        direct.push_back((n, FromWrite::Published))
+                    }
+                    Some(ToWrite::SetLocality(locality)) => {
+                        usage.set_locality(locality);
+                        direct.push_back((n, FromWrite::Published))
+                    }
+                    Some(ToWrite::SetAlias(path, target)) => {
+                        let secctx = self.secctx.read().await;
+                        let r = self.aliases.set(
+                            secctx.pmap(),
+                            &self.audit,
+                            &uifo,
+                            &self.registry,
+                            path,
+                            target,
+                        );
+                        direct.push_back((n, r))
+                    }
+                    Some(ToWrite::RemoveAlias(path)) => {
+                        let secctx = self.secctx.read().await;
+                        let r = self.aliases.remove(
+                            secctx.pmap(),
+                            &self.audit,
+                            &uifo,
+                            &self.registry,
+                            path,
+                        );
+                        direct.push_back((n, r))
+                    }
                     Some(ToWrite::PublishDefaultWithFlags(path, flags)) => {
-                        for b in by_shard.iter_mut() {
-                            b.push((
-                                n,
-                                ToWrite::PublishDefaultWithFlags(path.clone(), flags),
-                            ));
+                        match usage.publish_default(&path) {
+                            Err(e) => direct.push_back((n, FromWrite::Error(e))),
+                            Ok(()) => {
+                                tentative.insert(n, path.clone());
+                                if flags & PublishFlags::LIST_ON_DEMAND.bits() != 0 {
+                                    lists.insert(n);
+                                }
+                                for b in by_shard.iter_mut() {
+                                    b.push((
+                                        n,
+                                        ToWrite::PublishDefaultWithFlags(
+                                            path.clone(),
+                                            flags,
+                                        ),
+                                    ));
+                                }
+                            }
                         }
                     }
                 }
                 n += 1;
             }
 	    trace!("handle_write_batch dispatching {} messages to shards", n);
-            if by_shard.iter().all(|v| v.is_empty()) {
+            if by_shard.iter().all(|v| v.is_empty()) && direct.is_empty() {
                 assert!(finished);
                 break Ok(());
             }
+            let registered = self.registry.snapshot();
             let mut replies =
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
                     let publisher = publisher.clone();
-                    let req = WriteRequest { uifo: uifo.clone(), publisher, batch };
+                    let usage = usage.clone();
+                    let req = WriteRequest {
+                        uifo: uifo.clone(),
+                        registered: registered.clone(),
+                        publisher,
+                        batch,
+                        usage,
+                        audit,
+                    };
                     let _ = self.shards[i].write.unbounded_send((req, tx));
                     rx
                 }))
@@ -717,9 +1161,23 @@ impl Store {
                 .into_iter()
                 .collect::<result::Result<Vec<Pooled<WriteR>>, Canceled>>()?;
 	    trace!("handle_write_batch {} shards replied", replies.len());
+            for (i, path) in tentative.drain() {
+                let published = replies.iter().any(|r| {
+                    r.iter().any(|(j, r)| *j == i && r == &FromWrite::Published)
+                });
+                if !published {
+                    usage.unpublish_default(&path);
+                } else if lists.remove(&i) {
+                    self.listers.insert(path, publisher.id, publisher.addr);
+                } else {
+                    self.listers.remove(&path, &publisher.id);
+                }
+            }
             if let Some(ref mut c) = con {
                 for i in 0..n {
-                    if replies.len() == 1
+                    if direct.front().map(|v| v.0 == i).unwrap_or(false) {
+                        c.queue_send(&direct.pop_front().unwrap().1)?;
+                    } else if replies.len() == 1
                         || !replies
                             .iter()
                             .all(|v| v.front().map(|v| i == v.0).unwrap_or(false))
@@ -752,6 +1210,9 @@ impl Store {
                             (_, m @ FromWrite::Unpublished) => {
                                 same!(c, replies, &m, "desynced unpublish");
                             }
+                            (_, FromWrite::Draining | FromWrite::HeartbeatReply(_)) => {
+                                unreachable!()
+                            }
                         }
                     }
                 }
@@ -787,8 +1248,9 @@ impl Store {
         self.handle_batch_write(None, uifo.clone(), publisher.clone(), iter).await?;
         // clear out anything left over that was sent to all shards,
         // e.g. default publishers.
-        self.handle_batch_write(None, uifo, publisher, iter::once(ToWrite::Clear))
+        self.handle_batch_write(None, uifo, publisher.clone(), iter::once(ToWrite::Clear))
             .await?;
+        self.usage.remove(&publisher.id);
         Ok(())
     }
 }
@@ -8,7 +8,9 @@ use crate::{
     pool::{Pool, Pooled},
     protocol::{
         glob::{GlobSet, Scope},
-        resolver::{Publisher, PublisherId, PublisherRef, Referral},
+        resolver::{
+            Metadata, MetadataQuery, Publisher, PublisherId, PublisherRef, Referral,
+        },
     },
     utils,
 };
@@ -38,6 +40,7 @@ lazy_static! {
     pub(super) static ref PATH_POOL: Pool<Vec<Path>> = Pool::new(100, 10_000);
     pub(super) static ref COLS_POOL: Pool<Vec<(Path, Z64)>> = Pool::new(100, 10_000);
     pub(super) static ref REF_POOL: Pool<Vec<Referral>> = Pool::new(100, 100);
+    pub(super) static ref MD_POOL: Pool<Vec<(Path, Metadata)>> = Pool::new(100, 10_000);
 }
 
 type Set<T> = ISet<T, 8>;
@@ -127,6 +130,7 @@ pub(super) struct Store {
     publishers_by_addr: FxHashMap<SocketAddr, PublisherId>,
     published_by_path: HashMap<Path, Set<PublisherId>>,
     flags_by_path: HashMap<Path, u32>,
+    metadata_by_path: HashMap<Path, Metadata>,
     published_by_id: FxHashMap<PublisherId, HashSet<Path>>,
     published_by_level: FxHashMap<usize, BTreeMap<Path, Z64>>,
     columns: HashMap<Path, HashMap<Path, Z64>>,
@@ -134,6 +138,7 @@ pub(super) struct Store {
     defaults_by_id: FxHashMap<PublisherId, HashSet<Path>>,
     parent: Option<Referral>,
     children: BTreeMap<Path, Referral>,
+    registered: Arc<BTreeMap<Path, Referral>>,
     sets: HCSet<PublisherId>,
 }
 
@@ -147,6 +152,7 @@ impl Store {
             publishers_by_addr: HashMap::default(),
             published_by_path: HashMap::default(),
             flags_by_path: HashMap::default(),
+            metadata_by_path: HashMap::default(),
             published_by_id: HashMap::default(),
             published_by_level: HashMap::default(),
             columns: HashMap::new(),
@@ -154,6 +160,7 @@ impl Store {
             defaults_by_id: HashMap::default(),
             parent,
             children,
+            registered: Arc::new(BTreeMap::new()),
             sets: HCSet::new(),
         };
         let children = t.children.keys().cloned().collect::<Vec<_>>();
@@ -173,6 +180,7 @@ impl Store {
 	self.publishers_by_addr.shrink_to_fit();
 	self.published_by_path.shrink_to_fit();
 	self.flags_by_path.shrink_to_fit();
+	self.metadata_by_path.shrink_to_fit();
 	self.published_by_id.shrink_to_fit();
 	for v in self.published_by_id.values_mut() {
 	    v.shrink_to_fit()
@@ -249,6 +257,11 @@ impl Store {
         }
     }
 
+    /// Set the children that registered themselves dynamically
+    pub(super) fn set_registered(&mut self, registered: Arc<BTreeMap<Path, Referral>>) {
+        self.registered = registered;
+    }
+
     pub(super) fn check_referral(&self, path: &Path) -> Option<Referral> {
         if let Some(r) = self.parent.as_ref() {
             if !Path::is_parent(&r.path, path) {
@@ -266,6 +279,17 @@ impl Store {
                 Included(path.as_ref()),
             ))
             .next_back();
+        match r {
+            Some((p, r)) if Path::is_parent(p, path) => return Some(r.clone()),
+            None | Some(_) => (),
+        }
+        let r = self
+            .registered
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Unbounded,
+                Included(path.as_ref()),
+            ))
+            .next_back();
         match r {
             None => None,
             Some((p, r)) if Path::is_parent(p, path) => Some(r.clone()),
@@ -289,7 +313,7 @@ impl Store {
                 });
             }
         }
-        for (p, r) in self.children.iter() {
+        for (p, r) in self.children.iter().chain(self.registered.iter()) {
             if Path::is_parent(base_path, p) && scope.contains(Path::levels(&*p)) {
                 refs.push(r.clone())
             } else if Path::is_parent(p, base_path) {
@@ -480,6 +504,7 @@ impl Store {
                 && !self.defaults.contains_key(&path)
             {
                 self.flags_by_path.remove(&path);
+                self.metadata_by_path.remove(&path);
                 if let Some(s) = self.published_by_level.get_mut(&n) {
                     s.remove(&path);
                 };
@@ -493,6 +518,19 @@ impl Store {
         }
     }
 
+    pub(super) fn is_published(&self, id: &PublisherId, path: &Path) -> bool {
+        self.published_by_id.get(id).map(|s| s.contains(path)).unwrap_or(false)
+    }
+
+    pub(super) fn is_published_by(&self, id: &PublisherId, path: &Path) -> bool {
+        self.is_published(id, path)
+            || self.defaults_by_id.get(id).map(|s| s.contains(path)).unwrap_or(false)
+    }
+
+    pub(super) fn published_count(&self, id: &PublisherId) -> usize {
+        self.published_by_id.get(id).map(|s| s.len()).unwrap_or(0)
+    }
+
     pub(super) fn published_for_id(&self, id: &PublisherId) -> HashSet<Path> {
         self.published_by_id.get(id).map(|s| s.clone()).unwrap_or_else(HashSet::new)
     }
@@ -528,6 +566,7 @@ impl Store {
                     SecCtxDataReadGuard::Local(sec) => sec.secret(id),
                     SecCtxDataReadGuard::Krb5(sec) => sec.secret(id),
                     SecCtxDataReadGuard::Tls(sec) => sec.secret(id),
+                    SecCtxDataReadGuard::Token(sec) => sec.secret(id),
                 };
                 let user_info = uifo.user_info.clone();
                 if let (Some(secret), Some(mut user_info)) = (secret, user_info) {
@@ -618,6 +657,7 @@ impl Store {
                 SecCtxDataReadGuard::Local(sec) => sec.secret(&id),
                 SecCtxDataReadGuard::Krb5(sec) => sec.secret(&id),
                 SecCtxDataReadGuard::Tls(sec) => sec.secret(&id),
+                SecCtxDataReadGuard::Token(sec) => sec.secret(&id),
             };
             match secret {
                 None => PublisherRef { id, token: Bytes::new() },
@@ -675,6 +715,24 @@ impl Store {
                     .cloned(),
                 )
             }
+            // registered children aren't in the levels, add them and
+            // their parents below parent
+            for p in self.registered.keys() {
+                if Path::levels(p) > n && Path::is_parent(parent, p) {
+                    let mut p: &str = p.as_ref();
+                    while Path::levels(p) > n + 1 {
+                        p = Path::dirname(p).unwrap_or("/");
+                    }
+                    let listed = self
+                        .published_by_level
+                        .get(&(n + 1))
+                        .map(|l| l.contains_key(p))
+                        .unwrap_or(false);
+                    if !listed && !paths.iter().any(|l| &**l == p) {
+                        paths.push(Path::from(String::from(p)));
+                    }
+                }
+            }
             paths
         })
     }
@@ -719,6 +777,30 @@ impl Store {
         paths
     }
 
+    /// Set the metadata of path, the caller must check that path is
+    /// published.
+    pub(super) fn set_metadata(&mut self, path: Path, metadata: Metadata) {
+        if metadata.is_empty() {
+            self.metadata_by_path.remove(&path);
+        } else {
+            self.metadata_by_path.insert(path, metadata);
+        }
+    }
+
+    pub(super) fn search(
+        &self,
+        pat: &GlobSet,
+        query: &MetadataQuery,
+    ) -> Pooled<Vec<(Path, Metadata)>> {
+        let mut matched = MD_POOL.take();
+        for (path, md) in self.metadata_by_path.iter() {
+            if pat.is_match(path) && query.matches(md) {
+                matched.push((path.clone(), md.clone()));
+            }
+        }
+        matched
+    }
+
     pub(super) fn get_change_nr(&self, path: &Path) -> Z64 {
         self.published_by_level
             .get(&Path::levels(path))
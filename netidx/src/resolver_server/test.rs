@@ -1,8 +1,15 @@
-use super::store::Store;
+use super::{
+    auth::{self, Identity, Permissions},
+    config::Config,
+    store::Store,
+};
 use crate::{
     pack::Z64,
     path::Path,
-    protocol::resolver::{HashMethod, Publisher, PublisherId, PublisherRef, TargetAuth},
+    protocol::resolver::{
+        Explanation, HashMethod, Locality, PermissionEntry, Publisher, PublisherId,
+        PublisherRef, TargetAuth,
+    },
 };
 use bytes::Bytes;
 use fxhash::FxHashMap;
@@ -39,6 +46,8 @@ fn test_resolver_store() {
             resolver: addr,
             target_auth: TargetAuth::Anonymous,
             user_info: None,
+            load: None,
+            locality: Locality::default(),
         });
         if thread_rng().gen() {
             let path = Path::from(String::from(Path::dirname(&parsed[0]).unwrap()));
@@ -181,3 +190,187 @@ fn test_resolver_store() {
     let cols = store.columns(&Path::from("/app/test"));
     assert_eq!(cols.len(), 0);
 }
+
+#[test]
+fn test_explain_permissions() {
+    let cfg = Config::parse(
+        r#"{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": {"Local": "/tmp/netidx-test-explain"},
+      "id_map_type": "DoNotMap"
+    }
+  ],
+  "perms": {
+    "/": { "eng": "sl" },
+    "/app": { "alice": "p", "eng": "p", "contractors": "!l" },
+    "/home/$[user]": { "$[user]": "swlp" }
+  }
+}"#,
+    )
+    .unwrap();
+    let alice = Identity::Explicit {
+        user: "alice".into(),
+        primary_group: "eng".into(),
+        groups: vec!["contractors".into()],
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(auth::explain(&cfg, 0, &alice, &Path::from("/app/foo"))).unwrap();
+    assert!(e.enforced);
+    assert_eq!(e.permissions, (Permissions::SUBSCRIBE | Permissions::PUBLISH).bits());
+    fn entry(e: &Explanation, path: &str, entity: &str) -> PermissionEntry {
+        e.entries
+            .iter()
+            .find(|ent| &*ent.path == path && &*ent.entity == entity)
+            .unwrap()
+            .clone()
+    }
+    let eng = entry(&e, "/", "eng");
+    assert_eq!(eng.granted, (Permissions::SUBSCRIBE | Permissions::LIST).bits());
+    let contractors = entry(&e, "/app", "contractors");
+    assert_eq!(contractors.denied, Permissions::LIST.bits());
+    let alice_ent = entry(&e, "/app", "alice");
+    assert_eq!(alice_ent.granted, Permissions::PUBLISH.bits());
+    // alice already got publish from her own entry
+    let eng_app = entry(&e, "/app", "eng");
+    assert_eq!(eng_app.permissions, Permissions::PUBLISH.bits());
+    assert_eq!(eng_app.granted, 0);
+    let e =
+        rt.block_on(auth::explain(&cfg, 0, &alice, &Path::from("/home/alice"))).unwrap();
+    assert_eq!(
+        e.permissions,
+        (Permissions::SUBSCRIBE
+            | Permissions::WRITE
+            | Permissions::LIST
+            | Permissions::PUBLISH)
+            .bits()
+    );
+    let home = entry(&e, "/home/$[user]", "$[user]");
+    assert_eq!(home.granted, (Permissions::WRITE | Permissions::PUBLISH).bits());
+    let e = rt
+        .block_on(auth::explain(&cfg, 0, &Identity::Anonymous, &Path::from("/app")))
+        .unwrap();
+    assert_eq!(e.permissions, 0);
+    assert!(e.entries.is_empty());
+    assert!(rt.block_on(auth::explain(&cfg, 1, &alice, &Path::from("/app"))).is_err());
+}
+
+#[test]
+fn test_file_id_map() {
+    use crate::os::id_file::FileMap;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+    let dir =
+        std::env::temp_dir().join(format!("netidx-test-idmap-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let json = dir.join("groups.json");
+    fs::write(
+        &json,
+        r#"{
+  "alice": { "primary_group": "eng", "groups": ["eng", "contractors"], "uid": 1000 },
+  "bob": { "primary_group": "eng" }
+}"#,
+    )
+    .unwrap();
+    let cfg = Config::parse(&format!(
+        r#"{{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {{
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": {{"Local": "/tmp/netidx-test-idmap"}},
+      "id_map_type": "File",
+      "id_map_command": {:?}
+    }}
+  ],
+  "perms": {{
+    "/": {{ "eng": "sl" }},
+    "/app": {{ "contractors": "p" }}
+  }}
+}}"#,
+        json
+    ))
+    .unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let explain = |user: &str| {
+        let id = Identity::Mapped(user.into());
+        rt.block_on(auth::explain(&cfg, 0, &id, &Path::from("/app"))).unwrap()
+    };
+    let all = Permissions::SUBSCRIBE | Permissions::LIST | Permissions::PUBLISH;
+    assert_eq!(explain("alice").permissions, all.bits());
+    let bob = (Permissions::SUBSCRIBE | Permissions::LIST).bits();
+    assert_eq!(explain("bob").permissions, bob);
+    let e = rt.block_on(auth::explain(
+        &cfg,
+        0,
+        &Identity::Mapped("mallory".into()),
+        &Path::from("/app"),
+    ));
+    assert!(e.is_err());
+    // the table is reloaded when the file changes
+    let map = rt.block_on(FileMap::new(json.to_str().unwrap())).unwrap();
+    assert_eq!(&*rt.block_on(map.user(1000)).unwrap(), "alice");
+    fs::write(&json, r#"{ "bob": { "primary_group": "ops", "uid": 1000 } }"#).unwrap();
+    let later = SystemTime::now() + Duration::from_secs(10);
+    fs::File::options().write(true).open(&json).unwrap().set_modified(later).unwrap();
+    assert_eq!(&*rt.block_on(map.user(1000)).unwrap(), "bob");
+    let (primary, groups) = rt.block_on(map.groups("bob")).unwrap();
+    assert_eq!(&*primary, "ops");
+    assert!(groups.is_empty());
+    assert!(rt.block_on(map.groups("alice")).is_err());
+    // an invalid file keeps the last good table
+    fs::write(&json, "{ not json").unwrap();
+    let later = later + Duration::from_secs(10);
+    fs::File::options().write(true).open(&json).unwrap().set_modified(later).unwrap();
+    assert_eq!(&*rt.block_on(map.user(1000)).unwrap(), "bob");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_getgrouplist_id_map() {
+    let cfg = Config::parse(
+        r#"{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": {"Local": "/tmp/netidx-test-getgrouplist"},
+      "id_map_type": "GetGroupList"
+    }
+  ],
+  "perms": {
+    "/": { "root": "sl" }
+  }
+}"#,
+    )
+    .unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let root = Identity::Mapped("root".into());
+    let e = rt.block_on(auth::explain(&cfg, 0, &root, &Path::from("/app"))).unwrap();
+    assert_eq!(e.permissions, (Permissions::SUBSCRIBE | Permissions::LIST).bits());
+    let nobody = Identity::Mapped("netidx-no-such-user".into());
+    assert!(rt.block_on(auth::explain(&cfg, 0, &nobody, &Path::from("/app"))).is_err());
+}
@@ -1,6 +1,6 @@
 use super::{
-    ConId, DvDead, DvState, Event, NoSuchValue, PermissionDenied, SubId, SubStatus,
-    SubscribeValRequest, Subscriber, SubscriberInner, SubscriberWeak, ToCon,
+    ConId, ConnectionInfo, DvDead, DvState, Event, NoSuchValue, PermissionDenied, SubId,
+    SubStatus, SubscribeValRequest, Subscriber, SubscriberInner, SubscriberWeak, ToCon,
     UpdatesFlags, Val, ValInner, ValWeak, WUpdateChan, BATCHES, DECODE_BATCHES,
 };
 pub use crate::protocol::value::{FromValue, Value};
@@ -8,6 +8,7 @@ pub use crate::resolver_client::DesiredAuth;
 use crate::{
     batch_channel::BatchReceiver,
     channel::{self, Channel, K5CtxWrap, ReadChannel, WriteChannel},
+    chars::Chars,
     path::Path,
     pool::Pooled,
     protocol::{
@@ -16,7 +17,7 @@ use crate::{
         resolver::TargetAuth,
     },
     resolver_client::common::krb5_authentication,
-    tls,
+    tls, transport,
     utils::{ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
@@ -31,7 +32,7 @@ use futures::{
     stream::FuturesUnordered,
 };
 use fxhash::{FxHashMap, FxHashSet};
-use log::{info, trace};
+use log::{info, trace, warn};
 use parking_lot::Mutex;
 use protocol::resolver::UserInfo;
 use smallvec::SmallVec;
@@ -40,11 +41,14 @@ use std::{
     mem,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 use tokio::{
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite},
     task,
     time::{self, Instant},
 };
@@ -55,9 +59,45 @@ struct Sub {
     sub_id: SubId,
     streams: SmallVec<[(ChanId, ChanWrap<Pooled<Vec<(SubId, Event)>>>); 1]>,
     last: Option<TArc<Mutex<Event>>>,
+    // the value patches apply to when we aren't collecting last
+    patch_base: Option<Value>,
+    // set until the first update of a value restored from a snapshot
+    stale: Option<Arc<AtomicBool>>,
     val: ValWeak,
 }
 
+impl Sub {
+    fn current(&self) -> Option<Value> {
+        match &self.last {
+            None => self.patch_base.clone(),
+            Some(last) => match &*last.lock() {
+                Event::Update(v) => Some(v.clone()),
+                Event::Unsubscribed => None,
+            },
+        }
+    }
+
+    fn update(&mut self, by_chan: &mut ByChan, m: Value) {
+        if let Some(stale) = self.stale.take() {
+            stale.store(false, Ordering::Relaxed)
+        }
+        for (chan_id, c) in self.streams.iter() {
+            by_chan
+                .entry(*chan_id)
+                .or_insert_with(|| (c.clone(), BATCHES.take()))
+                .1
+                .push((self.sub_id, Event::Update(m.clone())))
+        }
+        match &self.last {
+            Some(last) => *last.lock() = Event::Update(m),
+            None => match m {
+                Value::Array(_) => self.patch_base = Some(m),
+                _ => self.patch_base = None,
+            },
+        }
+    }
+}
+
 type ByChan = FxHashMap<
     ChanId,
     (ChanWrap<Pooled<Vec<(SubId, Event)>>>, Pooled<Vec<(SubId, Event)>>),
@@ -70,7 +110,27 @@ fn unsubscribe(
     id: Id,
     conid: ConId,
 ) {
+    // if a durable subscription migrated away from this one then
+    // it's streams must not see the unsubscribe
+    let migrated = subscriber
+        .durable_alive
+        .get(&sub.path)
+        .and_then(|w| w.upgrade())
+        .and_then(|ds| {
+            let dv = ds.0.lock();
+            match &dv.sub {
+                DvState::Subscribed(val) if val.0.id != id || val.0.conid != conid => {
+                    Some(dv.streams.clone())
+                }
+                DvState::Subscribed(_) | DvState::Dead(_) => None,
+            }
+        });
     for (chan_id, c) in sub.streams.iter() {
+        if let Some(streams) = &migrated {
+            if streams.iter().any(|(_, s)| s == c) {
+                continue;
+            }
+        }
         by_chan
             .entry(*chan_id)
             .or_insert_with(|| (c.clone(), BATCHES.take()))
@@ -80,11 +140,15 @@ fn unsubscribe(
     if let Some(last) = &sub.last {
         *last.lock() = Event::Unsubscribed;
     }
-    if let Some(dsw) = subscriber
-        .durable_alive
-        .remove(&sub.path)
-        .or_else(|| subscriber.durable_pending.remove(&sub.path))
-    {
+    let durable = if migrated.is_some() {
+        None
+    } else {
+        subscriber
+            .durable_alive
+            .remove(&sub.path)
+            .or_else(|| subscriber.durable_pending.remove(&sub.path))
+    };
+    if let Some(dsw) = durable {
         if let Some(ds) = dsw.upgrade() {
             let mut inner = ds.0.lock();
             inner.sub = DvState::Dead(Box::new(DvDead {
@@ -92,6 +156,8 @@ fn unsubscribe(
                 waiting: Vec::new(),
                 tries: 0,
                 next_try: Instant::now(),
+                last_error: None,
+                gave_up: false,
             }));
             subscriber.durable_dead.insert(sub.path.clone(), dsw);
             let _ = subscriber.trigger_resub.unbounded_send(());
@@ -115,18 +181,19 @@ fn unsubscribe(
     }
 }
 
-async fn hello_publisher(
-    mut con: TcpStream,
+async fn hello_publisher<S>(
+    mut con: S,
     tls_ctx: Option<tls::CachedConnector>,
     uifo: Option<UserInfo>,
     desired_auth: &DesiredAuth,
     target_auth: &TargetAuth,
-) -> Result<Channel> {
+    version: u64,
+) -> Result<Channel>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     use protocol::publisher::Hello;
-    channel::write_raw(&mut con, &3u64).await?;
-    if channel::read_raw::<u64, _>(&mut con).await? != 3 {
-        bail!("incompatible protocol version")
-    }
+    channel::offer_version(&mut con, version).await?;
     match (desired_auth, target_auth) {
         (DesiredAuth::Anonymous, TargetAuth::Anonymous) => {
             channel::write_raw(&mut con, &Hello::Anonymous).await?;
@@ -134,22 +201,31 @@ async fn hello_publisher(
                 Hello::Anonymous => (),
                 _ => bail!("unexpected response from publisher"),
             }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            Ok(Channel::new::<ClientCtx, S>(None, con))
         }
         (
             DesiredAuth::Anonymous,
-            TargetAuth::Local { .. } | TargetAuth::Krb5 { .. } | TargetAuth::Tls { .. },
+            TargetAuth::Local { .. }
+            | TargetAuth::Krb5 { .. }
+            | TargetAuth::Tls { .. }
+            | TargetAuth::Token,
         ) => {
             bail!("anonymous access not allowed")
         }
         (
-            DesiredAuth::Local | DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. },
+            DesiredAuth::Local
+            | DesiredAuth::Krb5 { .. }
+            | DesiredAuth::Tls { .. }
+            | DesiredAuth::Token { .. },
             TargetAuth::Anonymous,
         ) => {
             bail!("authentication not supported")
         }
         (
-            DesiredAuth::Local | DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. },
+            DesiredAuth::Local
+            | DesiredAuth::Krb5 { .. }
+            | DesiredAuth::Tls { .. }
+            | DesiredAuth::Token { .. },
             TargetAuth::Local,
         ) => {
             channel::write_raw(&mut con, &Hello::Local(uifo)).await?;
@@ -157,11 +233,28 @@ async fn hello_publisher(
                 Hello::Local(_) => (),
                 _ => bail!("unexpected response from publisher"),
             }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            Ok(Channel::new::<ClientCtx, S>(None, con))
+        }
+        (
+            DesiredAuth::Local
+            | DesiredAuth::Krb5 { .. }
+            | DesiredAuth::Tls { .. }
+            | DesiredAuth::Token { .. },
+            TargetAuth::Token,
+        ) => {
+            channel::write_raw(&mut con, &Hello::Token(uifo)).await?;
+            match channel::read_raw(&mut con).await? {
+                Hello::Token(_) => (),
+                _ => bail!("unexpected response from publisher"),
+            }
+            Ok(Channel::new::<ClientCtx, S>(None, con))
         }
         (DesiredAuth::Local, TargetAuth::Krb5 { .. } | TargetAuth::Tls { .. }) => {
             bail!("local auth not supported")
         }
+        (DesiredAuth::Token { .. }, TargetAuth::Krb5 { .. } | TargetAuth::Tls { .. }) => {
+            bail!("token auth not supported")
+        }
         (DesiredAuth::Krb5 { upn, .. }, TargetAuth::Krb5 { spn }) => {
             let upn = upn.as_ref().map(|p| p.as_str());
             channel::write_raw(&mut con, &Hello::Krb5(uifo)).await?;
@@ -188,7 +281,7 @@ async fn hello_publisher(
             let tls = ctx.connect(name, con).await?;
             let mut con = Channel::new::<
                 ClientCtx,
-                tokio_rustls::client::TlsStream<TcpStream>,
+                tokio_rustls::client::TlsStream<S>,
             >(None, tls);
             match con.receive::<Hello>().await? {
                 Hello::Tls(_) => (),
@@ -203,10 +296,18 @@ async fn hello_publisher(
 }
 
 const PERIOD: Duration = Duration::from_secs(100);
+const LATENCY_PERIOD: Duration = Duration::from_secs(10);
+
+// publishers older than this don't understand timestamped heartbeats
+const HEARTBEAT_VERSION: u64 = 4;
+
+// publishers older than this don't understand write batches
+const WRITE_BATCH_VERSION: u64 = 4;
 
 fn decode_task(
     mut con: ReadChannel,
     stop: oneshot::Receiver<()>,
+    bytes_in: Arc<AtomicU64>,
 ) -> Receiver<Result<(Pooled<Vec<From>>, bool)>> {
     let (mut send, recv) = mpsc::channel(3);
     let mut stop = stop.fuse();
@@ -228,6 +329,7 @@ fn decode_task(
                         try_cf!(send.send(Err(e)).await)
                     }
                     Ok(()) => {
+                        bytes_in.store(con.bytes_received(), Ordering::Relaxed);
                         let batch = mem::replace(&mut buf, DECODE_BATCHES.take());
                         try_cf!(send.send(Ok((batch, only_updates))).await)
                     }
@@ -250,16 +352,23 @@ pub(super) struct ConnectionCtx {
     tls_ctx: Option<tls::CachedConnector>,
     uifo: Option<UserInfo>,
     from_sub: BatchReceiver<ToCon>,
-    pending: HashMap<Path, SubscribeValRequest>,
+    // by the path on the publisher, requests for different paths
+    // that are aliases of the same path wait on the same entry
+    pending: HashMap<Path, SmallVec<[SubscribeValRequest; 1]>>,
     subscriptions: FxHashMap<Id, Sub>,
     msg_recvd: bool,
     pending_flushes: Vec<oneshot::Sender<()>>,
     pending_writes: FxHashMap<Id, FxHashMap<WriteId, oneshot::Sender<Value>>>,
+    pending_batch_writes: FxHashMap<WriteId, oneshot::Sender<Value>>,
     by_receiver: FxHashMap<ChanWrap<Pooled<Vec<(SubId, Event)>>>, ChanId>,
     by_chan: ByChan,
     gc_chan: FxHashSet<ChanId>,
     blocked_channels: FuturesUnordered<BlockedChannelFut>,
-    timed_out: Vec<Path>,
+    info: Arc<Mutex<ConnectionInfo>>,
+    bytes_in: Arc<AtomicU64>,
+    epoch: Instant,
+    // the protocol version negotiated with the publisher
+    version: u64,
 }
 
 impl ConnectionCtx {
@@ -272,6 +381,7 @@ impl ConnectionCtx {
         target_auth: TargetAuth,
         desired_auth: DesiredAuth,
         from_sub: BatchReceiver<ToCon>,
+        info: Arc<Mutex<ConnectionInfo>>,
     ) -> Self {
         Self {
             addr,
@@ -287,35 +397,57 @@ impl ConnectionCtx {
             msg_recvd: false,
             pending_flushes: Vec::new(),
             pending_writes: HashMap::default(),
+            pending_batch_writes: HashMap::default(),
             by_receiver: HashMap::default(),
             by_chan: HashMap::default(),
             gc_chan: HashSet::default(),
             blocked_channels: FuturesUnordered::<BlockedChannelFut>::new(),
-            timed_out: Vec::new(),
+            info,
+            bytes_in: Arc::new(AtomicU64::new(0)),
+            epoch: Instant::now(),
+            version: channel::MIN_VERSION,
         }
     }
 
+    fn update_info(&self, write_con: &WriteChannel) {
+        let mut info = self.info.lock();
+        info.subscriptions = self.subscriptions.len();
+        info.queued_writes = self.pending_writes.values().map(|w| w.len()).sum::<usize>()
+            + self.pending_batch_writes.len();
+        info.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        info.bytes_out = write_con.bytes_sent();
+    }
+
     fn handle_heartbeat(&mut self, now: Instant) -> Result<()> {
         if !self.msg_recvd {
             bail!("hung publisher");
         } else {
             self.msg_recvd = false;
         }
-        for (path, req) in self.pending.iter() {
-            if let Some(deadline) = req.deadline {
-                if deadline < now {
-                    self.timed_out.push(path.clone());
+        self.pending.retain(|_, reqs| {
+            let mut i = 0;
+            while i < reqs.len() {
+                if reqs[i].deadline.map(|d| d < now).unwrap_or(false) {
+                    let _ = reqs.remove(i).finished.send(Err(anyhow!("timed out")));
+                } else {
+                    i += 1
                 }
             }
-        }
-        for path in self.timed_out.drain(..) {
-            if let Some(req) = self.pending.remove(&path) {
-                let _ = req.finished.send(Err(anyhow!("timed out")));
-            }
-        }
+            !reqs.is_empty()
+        });
         Ok(())
     }
 
+    // the publisher answers subscriptions to the same path in order
+    fn take_pending(&mut self, path: &Path) -> Option<SubscribeValRequest> {
+        let reqs = self.pending.get_mut(path)?;
+        let req = reqs.remove(0);
+        if reqs.is_empty() {
+            self.pending.remove(path);
+        }
+        Some(req)
+    }
+
     fn handle_connect_stream(
         &mut self,
         id: Id,
@@ -361,7 +493,11 @@ impl ConnectionCtx {
             }
             if flags.contains(UpdatesFlags::STOP_COLLECTING_LAST) {
                 trace!("no longer collecting last");
-                sub.last = None;
+                if let Some(last) = sub.last.take() {
+                    if let Event::Update(v @ Value::Array(_)) = &*last.lock() {
+                        sub.patch_base = Some(v.clone());
+                    }
+                }
             }
             if !already_have {
                 trace!("adding new channel to streams");
@@ -380,12 +516,13 @@ impl ConnectionCtx {
         for msg in batch.drain(..) {
             match msg {
                 ToCon::Subscribe(req) => {
-                    let path = req.path.clone();
+                    let path = req.target.clone();
                     let resolver = req.resolver;
                     let token = req.token.clone();
                     let permissions = req.permissions;
                     let timestamp = req.timestamp;
-                    self.pending.insert(path.clone(), req);
+                    let reqs = self.pending.entry(path.clone()).or_default();
+                    reqs.push(req);
                     write_con.queue_send(&To::Subscribe {
                         path,
                         resolver,
@@ -410,9 +547,59 @@ impl ConnectionCtx {
                             .insert(wid, tx);
                     }
                 }
+                ToCon::WriteBatch(batch, wid, tx) => {
+                    if self.version < WRITE_BATCH_VERSION {
+                        let e = "publisher does not support write batches";
+                        let _ = tx.send(Value::Error(Chars::from(e)));
+                    } else {
+                        write_con.queue_send(&To::WriteBatch(batch, true, wid))?;
+                        self.pending_batch_writes.insert(wid, tx);
+                    }
+                }
                 ToCon::Flush(tx) => self.pending_flushes.push(tx),
+                ToCon::Migrated => write_con.queue_send(&To::Migrated)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_migrate(
+        &mut self,
+        successor: SocketAddr,
+        con: &mut WriteChannel,
+        subscriber: &Subscriber,
+    ) -> Result<()> {
+        info!("publisher {} is migrating to {}", self.addr, successor);
+        let mut batch = Vec::new();
+        {
+            let mut t = subscriber.0.lock();
+            let now = Instant::now();
+            t.recently_failed.insert(self.addr, now);
+            t.successors.insert(successor, now);
+            for (id, sub) in self.subscriptions.iter() {
+                if let Some(ds) = t.durable_alive.get(&sub.path).and_then(|w| w.upgrade())
+                {
+                    let here = match &ds.0.lock().sub {
+                        DvState::Subscribed(val) => {
+                            val.0.id == *id && val.0.conid == self.conid
+                        }
+                        DvState::Dead(_) => false,
+                    };
+                    if here {
+                        batch.push((sub.path.clone(), ds.downgrade()));
+                    }
+                }
             }
         }
+        if batch.is_empty() {
+            con.queue_send(&To::Migrated)?
+        } else {
+            let subscriber = subscriber.clone();
+            let (addr, conid) = (self.addr, self.conid);
+            task::spawn(async move {
+                subscriber.migrate_durable(addr, conid, batch).await
+            });
+        }
         Ok(())
     }
 
@@ -424,22 +611,32 @@ impl ConnectionCtx {
     ) -> Result<()> {
         for m in batch.drain(..) {
             match m {
-                From::Update(i, m) => match self.subscriptions.get(&i) {
-                    Some(sub) => {
-                        for (chan_id, c) in sub.streams.iter() {
-                            self.by_chan
-                                .entry(*chan_id)
-                                .or_insert_with(|| (c.clone(), BATCHES.take()))
-                                .1
-                                .push((sub.sub_id, Event::Update(m.clone())));
-                        }
-                        if let Some(last) = &sub.last {
-                            *last.lock() = Event::Update(m);
+                From::Update(i, m) => match self.subscriptions.get_mut(&i) {
+                    Some(sub) => sub.update(&mut self.by_chan, m),
+                    None => con.queue_send(&To::Unsubscribe(i))?,
+                },
+                From::Patch(i, p) => match self.subscriptions.get_mut(&i) {
+                    Some(sub) => match sub.current().and_then(|v| p.apply(&v)) {
+                        Some(v) => sub.update(&mut self.by_chan, v),
+                        None => {
+                            warn!("could not apply patch to {}", sub.path);
+                            con.queue_send(&To::Unsubscribe(i))?
                         }
-                    }
+                    },
                     None => con.queue_send(&To::Unsubscribe(i))?,
                 },
-                From::Heartbeat => (),
+                From::Heartbeat => {
+                    self.info.lock().last_heartbeat = Some(std::time::Instant::now())
+                }
+                From::HeartbeatReply(ts) => {
+                    let sent = Duration::from_micros(ts);
+                    let mut info = self.info.lock();
+                    info.latency = Some(self.epoch.elapsed().saturating_sub(sent));
+                    info.last_heartbeat = Some(std::time::Instant::now());
+                }
+                From::Migrate(successor) => {
+                    self.handle_migrate(successor, con, subscriber)?
+                }
                 From::WriteResult(id, v, wid) => {
                     if let Entry::Occupied(mut e) = self.pending_writes.entry(id) {
                         let tbl = e.get_mut();
@@ -451,13 +648,18 @@ impl ConnectionCtx {
                         }
                     }
                 }
+                From::WriteBatchResult(v, wid) => {
+                    if let Some(tx) = self.pending_batch_writes.remove(&wid) {
+                        let _ = tx.send(v);
+                    }
+                }
                 From::NoSuchValue(path) => {
-                    if let Some(r) = self.pending.remove(&path) {
+                    if let Some(r) = self.take_pending(&path) {
                         let _ = r.finished.send(Err(Error::from(NoSuchValue)));
                     }
                 }
                 From::Denied(path) => {
-                    if let Some(r) = self.pending.remove(&path) {
+                    if let Some(r) = self.take_pending(&path) {
                         let _ = r.finished.send(Err(Error::from(PermissionDenied)));
                     }
                 }
@@ -467,8 +669,8 @@ impl ConnectionCtx {
                         unsubscribe(&mut *t, &mut self.by_chan, s, id, self.conid);
                     }
                 }
-                From::Subscribed(p, id, m) => {
-                    match self.pending.remove(&p) {
+                From::Subscribed(p, id, m, stale) => {
+                    match self.take_pending(&p) {
                         None => {
                             trace!("subscribed for id with no subscription");
                             con.queue_send(&To::Unsubscribe(id))?
@@ -499,12 +701,14 @@ impl ConnectionCtx {
                             None => {
                                 trace!("subscribe success");
                                 let last = TArc::new(Mutex::new(Event::Update(m)));
+                                let flag = Arc::new(AtomicBool::new(stale));
                                 let s = Val(Arc::new(ValInner {
                                     sub_id: req.sub_id,
                                     id,
                                     conid: self.conid,
                                     connection: req.con,
                                     last: last.clone(),
+                                    stale: flag.clone(),
                                 }));
                                 match req.finished.send(Ok(s.clone())) {
                                     Err(e) => {
@@ -519,6 +723,8 @@ impl ConnectionCtx {
                                                 path: req.path,
                                                 sub_id: req.sub_id,
                                                 last: Some(last),
+                                                patch_base: None,
+                                                stale: stale.then_some(flag),
                                                 streams: SmallVec::new(),
                                                 val: s.downgrade(),
                                             },
@@ -550,17 +756,8 @@ impl ConnectionCtx {
     fn process_updates_batch(&mut self, mut batch: Pooled<Vec<From>>) {
         for m in batch.drain(..) {
             if let From::Update(i, m) = m {
-                if let Some(sub) = self.subscriptions.get(&i) {
-                    for (chan_id, c) in sub.streams.iter() {
-                        self.by_chan
-                            .entry(*chan_id)
-                            .or_insert_with(|| (c.clone(), BATCHES.take()))
-                            .1
-                            .push((sub.sub_id, Event::Update(m.clone())))
-                    }
-                    if let Some(last) = &sub.last {
-                        *last.lock() = Event::Update(m);
-                    }
+                if let Some(sub) = self.subscriptions.get_mut(&i) {
+                    sub.update(&mut self.by_chan, m)
                 }
             }
         }
@@ -666,6 +863,7 @@ impl ConnectionCtx {
             }
         }
         let mut periodic = time::interval_at(Instant::now() + PERIOD, PERIOD);
+        let mut ping = time::interval(LATENCY_PERIOD);
         loop {
             select_biased! {
                 // this has to come first because batch_channel isn't cancel safe
@@ -680,6 +878,10 @@ impl ConnectionCtx {
                         break Ok(())
                     }
                 },
+                _ = ping.tick().fuse() => if self.version >= HEARTBEAT_VERSION {
+                    let ts = self.epoch.elapsed().as_micros() as u64;
+                    write_con.queue_send(&To::Heartbeat(ts))?
+                },
                 r = read_batch(
                     &mut batches,
                     &mut self.blocked_channels
@@ -697,33 +899,44 @@ impl ConnectionCtx {
                     None => break Err(anyhow!("EOF")),
                 }
             }
+            self.update_info(write_con);
         }
     }
 
-    pub(super) async fn start(mut self) -> Result<()> {
-        let soc = time::timeout(PERIOD, TcpStream::connect(self.addr)).await??;
-        soc.set_nodelay(true)?;
+    async fn connect(&self, version: u64) -> Result<Channel> {
         const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
-        let con = time::timeout(
-            HELLO_TIMEOUT,
-            hello_publisher(
-                soc,
-                self.tls_ctx.clone(),
-                self.uifo.take(),
-                &self.desired_auth,
-                &self.target_auth,
-            ),
-        )
-        .await??;
+        let (tls_ctx, uifo) = (self.tls_ctx.clone(), self.uifo.clone());
+        let (auth, target) = (&self.desired_auth, &self.target_auth);
+        let soc = time::timeout(PERIOD, transport::connect(self.addr)).await??;
+        let hello = hello_publisher(soc, tls_ctx, uifo, auth, target, version);
+        time::timeout(HELLO_TIMEOUT, hello).await?
+    }
+
+    pub(super) async fn start(mut self) -> Result<()> {
+        let con = match self.connect(channel::VERSION).await {
+            Ok(con) => {
+                self.version = channel::VERSION;
+                con
+            }
+            Err(e) => match e.downcast_ref::<channel::OlderPeer>() {
+                None => return Err(e),
+                Some(channel::OlderPeer(v)) => {
+                    // the publisher will hang up, try again with its version
+                    self.version = *v;
+                    self.connect(*v).await?
+                }
+            },
+        };
         let (read_con, mut write_con) = con.split();
         let (tx_stop, rx_stop) = oneshot::channel();
-        let res = self.run(decode_task(read_con, rx_stop), &mut write_con).await;
+        let batches = decode_task(read_con, rx_stop, self.bytes_in.clone());
+        let res = self.run(batches, &mut write_con).await;
         let _ = tx_stop.send(());
         if let Some(subscriber) = self.subscriber.upgrade() {
             let mut batch = DECODE_BATCHES.take();
             batch.extend(self.subscriptions.keys().map(|id| From::Unsubscribed(*id)));
             self.process_batch(batch, &mut write_con, &subscriber)?;
-            for (_, req) in self.pending {
+            for req in self.pending.into_values().flatten() {
                 let _ = req.finished.send(Err(anyhow!("connection died")));
             }
         }
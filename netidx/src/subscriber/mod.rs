@@ -1,5 +1,8 @@
 mod connection;
-pub use crate::protocol::value::{FromValue, Typ, Value};
+pub use crate::protocol::{
+    publisher::WriteDenied,
+    value::{FromValue, Typ, Value},
+};
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
     batch_channel::{self, BatchSender},
@@ -9,7 +12,7 @@ use crate::{
     pool::{Pool, Pooled},
     protocol::{
         publisher::{From, Id, WriteId},
-        resolver::{Publisher, PublisherId, Resolved, TargetAuth},
+        resolver::{Load, Locality, Publisher, PublisherId, Resolved, TargetAuth},
     },
     publisher::PublishFlags,
     resolver_client::ResolverRead,
@@ -36,14 +39,17 @@ use rand::Rng;
 use smallvec::SmallVec;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::{
-    cmp::{max, Eq, PartialEq},
+    cmp::{max, min, Eq, PartialEq},
     collections::{hash_map::Entry, HashMap, VecDeque},
     error, fmt,
     hash::Hash,
     iter, mem,
     net::SocketAddr,
     result,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
     time::Duration,
 };
 use tokio::{
@@ -106,6 +112,14 @@ bitflags! {
         /// channel, do not send the last again to that
         /// channel.
         const NO_SPURIOUS          = 0x04;
+
+        /// If set then while the subscription is dead the channel
+        /// will also receive the progress of resubscription attempts
+        /// as `Event::Update(ResubscribeStatus::to_value())`. Use
+        /// `ResubscribeStatus::from_value` to tell them apart from
+        /// values sent by the publisher. They are never stored as
+        /// the last value.
+        const RESUBSCRIBE_STATUS   = 0x08;
     }
 }
 
@@ -117,6 +131,9 @@ type Streams = SmallVec<[(UpdatesFlags, WUpdateChan); 1]>;
 #[derive(Debug)]
 struct SubscribeValRequest {
     path: Path,
+    /// the path on the publisher, which differs from `path` when
+    /// `path` is below an alias
+    target: Path,
     sub_id: SubId,
     timestamp: u64,
     permissions: u32,
@@ -134,7 +151,9 @@ enum ToCon {
     Unsubscribe(Id),
     Stream { id: Id, sub_id: SubId, tx: WUpdateChan, flags: UpdatesFlags },
     Write(Id, Value, WriteId, Option<oneshot::Sender<Value>>),
+    WriteBatch(Vec<(Id, Value)>, WriteId, oneshot::Sender<Value>),
     Flush(oneshot::Sender<()>),
+    Migrated,
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
@@ -169,6 +188,46 @@ impl Pack for Event {
     }
 }
 
+/// The progress of a durable subscription that is trying to
+/// resubscribe, see `UpdatesFlags::RESUBSCRIBE_STATUS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResubscribeStatus {
+    /// A resubscription attempt failed, and will be tried
+    /// again. Holds the number of failed attempts so far.
+    Retrying(u32),
+    /// The maximum number of resubscription attempts was reached,
+    /// the subscription will not be retried again.
+    GaveUp,
+}
+
+impl ResubscribeStatus {
+    const TAG: &'static str = "resubscribe-status";
+
+    /// The value sent to update channels that asked for it
+    pub fn to_value(&self) -> Value {
+        let st = match self {
+            ResubscribeStatus::Retrying(n) => Value::U32(*n),
+            ResubscribeStatus::GaveUp => Value::Null,
+        };
+        Value::Array(Arc::from([Value::String(Self::TAG.into()), st]))
+    }
+
+    /// If `v` is a resubscription status return it
+    pub fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Array(a) => match &**a {
+                [Value::String(tag), st] if &**tag == Self::TAG => match st {
+                    Value::U32(n) => Some(ResubscribeStatus::Retrying(*n)),
+                    Value::Null => Some(ResubscribeStatus::GaveUp),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ValInner {
     sub_id: SubId,
@@ -176,6 +235,7 @@ struct ValInner {
     conid: ConId,
     connection: BatchSender<ToCon>,
     last: TArc<Mutex<Event>>,
+    stale: Arc<AtomicBool>,
 }
 
 impl Drop for ValInner {
@@ -208,6 +268,13 @@ impl Val {
         self.0.last.lock().clone()
     }
 
+    /// Return true if the publisher restored the value from a
+    /// snapshot and hasn't updated it since, see
+    /// `PublisherBuilder::restore_from`.
+    pub fn is_stale(&self) -> bool {
+        self.0.stale.load(Ordering::Relaxed)
+    }
+
     /// Register `tx` to receive updates to this `Val`.
     ///
     /// You may register multiple different channels to receive
@@ -275,6 +342,8 @@ struct DvDead {
     waiting: Vec<oneshot::Sender<()>>,
     tries: usize,
     next_try: Instant,
+    last_error: Option<String>,
+    gave_up: bool,
 }
 
 #[derive(Debug)]
@@ -313,8 +382,9 @@ impl DvalWeak {
 ///   `Dval` will transparently move to another one.
 ///
 /// - a publisher is restarted (possibly on a different
-///   machine). `Dval` will wait using linear backoff for the publisher
-///   to come back, and then it will resubscribe.
+///   machine). `Dval` will wait using the backoff configured in the
+///   subscriber's `ResubscribePolicy` for the publisher to come back,
+///   and then it will resubscribe.
 ///
 /// - The resolver server cluster is restarted. In this case existing
 ///   subscriptions won't die, but new ones will fail while the
@@ -359,6 +429,15 @@ impl Dval {
         }
     }
 
+    /// Return true if the current subscription is to a value the
+    /// publisher restored from a snapshot and hasn't updated since.
+    pub fn is_stale(&self) -> bool {
+        match &self.0.lock().sub {
+            DvState::Dead(_) => false,
+            DvState::Subscribed(val) => val.is_stale(),
+        }
+    }
+
     /// Register `tx` to receive updates to this `Dval`.
     ///
     /// You may register multiple different channels to receive
@@ -386,14 +465,22 @@ impl Dval {
     /// the `Dval` is subscribed. If the `Dval` is subscribed when
     /// this method is called, it will return immediatly without
     /// allocating any resources.
+    ///
+    /// If the subscriber gives up trying to resubscribe, as
+    /// configured by it's `ResubscribePolicy`, then this method
+    /// returns an error.
     pub async fn wait_subscribed(&self) -> Result<()> {
-        let (tx, rx) = oneshot::channel();
-        match &mut self.0.lock().sub {
-            DvState::Subscribed(_) => return Ok(()),
-            DvState::Dead(d) => d.waiting.push(tx),
+        loop {
+            let (tx, rx) = oneshot::channel();
+            match &mut self.0.lock().sub {
+                DvState::Subscribed(_) => return Ok(()),
+                DvState::Dead(d) if d.gave_up => bail!("gave up resubscribing"),
+                DvState::Dead(d) => d.waiting.push(tx),
+            }
+            // waiters are also dropped when the subscription changes
+            // state, so check it again
+            let _ = rx.await;
         }
-        let _ = rx.await;
-        Ok(())
     }
 
     /// Write a value back to the publisher, see `Val::write`. If we
@@ -476,11 +563,7 @@ enum SubStatus {
 }
 
 const REMEBER_FAILED: Duration = Duration::from_secs(60);
-
-fn pick(n: usize) -> usize {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(0..n)
-}
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 struct Connection {
@@ -508,6 +591,95 @@ impl Connection {
     fn is_empty(&self) -> bool {
         self.primary.is_none() && self.isolated.is_empty()
     }
+
+    fn get(&self, id: ConId) -> Option<&BatchSender<ToCon>> {
+        match &self.primary {
+            Some((other, c)) if *other == id => Some(c),
+            Some(_) | None => self.isolated.get(&id),
+        }
+    }
+}
+
+/// How a subscriber chooses between publishers of values published
+/// with `PublishFlags::LOAD_BALANCE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// Choose randomly, in proportion to the weight each publisher
+    /// advertises
+    WeightedRandom,
+    /// Choose the publisher with the lowest advertised load relative
+    /// to it's weight. Load is only what each publisher reports with
+    /// `Publisher::set_load`, the resolver does not count connections,
+    /// so publishers that never report load count as idle, weight 1.
+    LeastLoaded,
+    /// Take turns, ignoring weight and load
+    RoundRobin,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Balance::WeightedRandom
+    }
+}
+
+/// How long a durable subscription waits after a failed
+/// resubscription attempt before trying again. The wait is chosen
+/// uniformly at random between zero and the ceiling computed from the
+/// number of failed attempts, so that many subscriptions that died at
+/// the same time do not all retry at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// The ceiling is `step * attempts`, but never more than `max`
+    Linear { step: Duration, max: Duration },
+    /// The ceiling is `base * 2 ^ attempts`, but never more than `max`
+    Exponential { base: Duration, max: Duration },
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::Linear { step: Duration::from_millis(50), max: Duration::MAX }
+    }
+}
+
+impl Backoff {
+    fn wait(&self, tries: usize) -> Duration {
+        let tries = u32::try_from(tries).unwrap_or(u32::MAX);
+        let ceiling = match self {
+            Backoff::Linear { step, max } => min(step.saturating_mul(tries), *max),
+            Backoff::Exponential { base, max } => {
+                let m = 1u32.checked_shl(tries).unwrap_or(u32::MAX);
+                min(base.saturating_mul(m), *max)
+            }
+        };
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Controls how the subscriber resubscribes durable subscriptions
+/// that have died.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResubscribePolicy {
+    /// How long to wait between attempts, default linear with a step
+    /// of 50ms and no maximum.
+    pub backoff: Backoff,
+    /// Give up after this many failed attempts. When the subscriber
+    /// gives up on a `Dval` it will stay unsubscribed, channels
+    /// registered with `UpdatesFlags::RESUBSCRIBE_STATUS` will
+    /// receive `ResubscribeStatus::GaveUp`, and `wait_subscribed`
+    /// will return an error. Default `None`, never give up.
+    pub max_attempts: Option<usize>,
+    /// The maximum number of resubscriptions that may be in flight
+    /// at once. When many subscriptions die at the same time, e.g. a
+    /// large publisher crashed, this limits the load placed on the
+    /// resolver servers and the remaining publishers. Default
+    /// 100,000.
+    pub max_concurrent: usize,
+}
+
+impl Default for ResubscribePolicy {
+    fn default() -> Self {
+        Self { backoff: Backoff::default(), max_attempts: None, max_concurrent: 100_000 }
+    }
 }
 
 struct Chosen {
@@ -524,6 +696,8 @@ struct SubscriberInner {
     resolver: ResolverRead,
     connections: FxHashMap<SocketAddr, Connection>,
     recently_failed: FxHashMap<SocketAddr, Instant>,
+    successors: FxHashMap<SocketAddr, Instant>,
+    con_info: FxHashMap<ConId, Arc<Mutex<ConnectionInfo>>>,
     subscribed: HashMap<Path, SubStatus>,
     durable_dead: HashMap<Path, DvalWeak>,
     durable_pending: HashMap<Path, DvalWeak>,
@@ -532,6 +706,10 @@ struct SubscriberInner {
     desired_auth: DesiredAuth,
     tls_ctx: Option<tls::CachedConnector>,
     interfaces: Vec<NetworkInterface>,
+    balance: Balance,
+    round_robin: usize,
+    locality: Locality,
+    resubscribe: ResubscribePolicy,
 }
 
 impl SubscriberInner {
@@ -608,11 +786,114 @@ impl SubscriberInner {
         }
         if flags.contains(PublishFlags::PREFER_LOCAL) {
             self.choose_local_addr(true, publishers, resolved, flags)
+        } else {
+            self.choose_any_addr(publishers, resolved, flags)
+        }
+    }
+
+    fn choose_balanced_addr(
+        &mut self,
+        publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
+        resolved: &Resolved,
+        flags: PublishFlags,
+    ) -> Option<Chosen> {
+        use rand::seq::SliceRandom;
+        let mut buf = SmallVec::<[(&PublisherRef, &Publisher); 16]>::new();
+        buf.extend(
+            resolved
+                .publishers
+                .iter()
+                .filter_map(|r| publishers.get(&r.id).map(|pb| (r, pb)))
+                .filter(|(_, p)| !self.recently_failed.contains_key(&p.addr)),
+        );
+        if buf.is_empty() {
+            return self.choose_random_addr(publishers, resolved, flags);
+        }
+        let load = |pb: &Publisher| pb.load.unwrap_or(Load { weight: 1, load: 0 });
+        let chosen = match self.balance {
+            Balance::WeightedRandom => {
+                let total: u64 = buf.iter().map(|(_, pb)| load(pb).weight as u64).sum();
+                if total == 0 {
+                    buf.choose(&mut rand::thread_rng())
+                } else {
+                    let mut n = rand::thread_rng().gen_range(0..total);
+                    buf.iter().find(|(_, pb)| {
+                        let w = load(pb).weight as u64;
+                        if n < w {
+                            true
+                        } else {
+                            n -= w;
+                            false
+                        }
+                    })
+                }
+            }
+            Balance::LeastLoaded => {
+                // break ties randomly
+                buf.shuffle(&mut rand::thread_rng());
+                buf.iter().min_by(|(_, pb0), (_, pb1)| {
+                    let (l0, l1) = (load(pb0), load(pb1));
+                    (l0.weight == 0).cmp(&(l1.weight == 0)).then_with(|| {
+                        let r0 = l0.load as u64 * l1.weight as u64;
+                        let r1 = l1.load as u64 * l0.weight as u64;
+                        r0.cmp(&r1)
+                    })
+                })
+            }
+            Balance::RoundRobin => {
+                buf.sort_by_key(|(_, pb)| pb.addr);
+                let i = self.round_robin % buf.len();
+                self.round_robin = self.round_robin.wrapping_add(1);
+                buf.get(i)
+            }
+        };
+        chosen.map(|(pref, pb)| Chosen {
+            addr: pb.addr,
+            target_auth: pb.target_auth.clone(),
+            token: pref.token.clone(),
+            uifo: pb.user_info.clone(),
+            flags,
+        })
+    }
+
+    fn choose_any_addr(
+        &mut self,
+        publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
+        resolved: &Resolved,
+        flags: PublishFlags,
+    ) -> Option<Chosen> {
+        if flags.contains(PublishFlags::LOAD_BALANCE) {
+            self.choose_balanced_addr(publishers, resolved, flags)
         } else {
             self.choose_random_addr(publishers, resolved, flags)
         }
     }
 
+    // choose between the publishers that share the most leading
+    // locality labels with us
+    fn choose_near_addr(
+        &mut self,
+        publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
+        resolved: &Resolved,
+        flags: PublishFlags,
+    ) -> Option<Chosen> {
+        let closeness = |pref: &PublisherRef| {
+            publishers
+                .get(&pref.id)
+                .filter(|pb| !self.recently_failed.contains_key(&pb.addr))
+                .map(|pb| self.locality.closeness(&pb.locality))
+                .unwrap_or(0)
+        };
+        let best = resolved.publishers.iter().map(closeness).max().unwrap_or(0);
+        if best == 0 {
+            self.choose_any_addr(publishers, resolved, flags)
+        } else {
+            let mut near = resolved.clone();
+            near.publishers.retain(|pref| closeness(pref) == best);
+            self.choose_any_addr(publishers, &near, flags)
+        }
+    }
+
     fn choose_local_addr(
         &mut self,
         tried_existing: bool,
@@ -620,7 +901,10 @@ impl SubscriberInner {
         resolved: &Resolved,
         flags: PublishFlags,
     ) -> Option<Chosen> {
-        use std::{cmp::min, net::IpAddr};
+        use std::{
+            cmp::{min, Reverse},
+            net::IpAddr,
+        };
         fn mv4(ip: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
             let mut masked = [0u8; 4];
             let ip = ip.octets();
@@ -679,13 +963,13 @@ impl SubscriberInner {
             if pri < 2 {
                 all_far = false;
             }
-            pri
+            (pri, Reverse(self.locality.closeness(&pb.locality)))
         });
         if all_far || buf.len() == 0 {
             if !tried_existing && flags.contains(PublishFlags::USE_EXISTING) {
                 self.choose_existing_addr(publishers, resolved, flags)
             } else {
-                self.choose_random_addr(publishers, resolved, flags)
+                self.choose_near_addr(publishers, resolved, flags)
             }
         } else {
             buf.first().map(|(pref, pb)| Chosen {
@@ -698,6 +982,31 @@ impl SubscriberInner {
         }
     }
 
+    // choose a publisher that another publisher recently migrated
+    // it's subscribers to, if one publishes the path
+    fn choose_successor_addr(
+        &mut self,
+        publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
+        resolved: &Resolved,
+        flags: PublishFlags,
+    ) -> Option<Chosen> {
+        if self.successors.is_empty() {
+            return None;
+        }
+        resolved
+            .publishers
+            .iter()
+            .filter_map(|pref| publishers.get(&pref.id).map(|pb| (pref, pb)))
+            .find(|(_, pb)| self.successors.contains_key(&pb.addr))
+            .map(|(pref, pb)| Chosen {
+                addr: pb.addr,
+                target_auth: pb.target_auth.clone(),
+                token: pref.token.clone(),
+                uifo: pb.user_info.clone(),
+                flags,
+            })
+    }
+
     fn choose_addr(
         &mut self,
         publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
@@ -709,20 +1018,23 @@ impl SubscriberInner {
         {
             flags &= !PublishFlags::PREFER_LOCAL;
         }
-        if flags.contains(PublishFlags::FORCE_LOCAL) {
+        if let Some(chosen) = self.choose_successor_addr(publishers, resolved, flags) {
+            Some(chosen)
+        } else if flags.contains(PublishFlags::FORCE_LOCAL) {
             self.choose_local_addr(false, publishers, resolved, flags)
         } else if flags.contains(PublishFlags::USE_EXISTING) {
             self.choose_existing_addr(publishers, resolved, flags)
         } else if flags.contains(PublishFlags::PREFER_LOCAL) {
             self.choose_local_addr(false, publishers, resolved, flags)
         } else {
-            self.choose_random_addr(publishers, resolved, flags)
+            self.choose_any_addr(publishers, resolved, flags)
         }
     }
 
     fn gc_recently_failed(&mut self) {
         let now = Instant::now();
-        self.recently_failed.retain(|_, v| (now - *v) < REMEBER_FAILED)
+        self.recently_failed.retain(|_, v| (now - *v) < REMEBER_FAILED);
+        self.successors.retain(|_, v| (now - *v) < REMEBER_FAILED)
     }
 }
 
@@ -742,20 +1054,70 @@ pub struct DurableStats {
     pub dead: usize,
 }
 
+/// Diagnostic information about a connection to a publisher
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The address of the publisher
+    pub addr: SocketAddr,
+    /// The authentication mechanism used with the publisher
+    pub auth: TargetAuth,
+    /// true if this connection only carries `PublishFlags::ISOLATED`
+    /// subscriptions
+    pub isolated: bool,
+    /// The number of values subscribed through this connection
+    pub subscriptions: usize,
+    /// The total number of bytes received from the publisher
+    pub bytes_in: u64,
+    /// The total number of bytes sent to the publisher
+    pub bytes_out: u64,
+    /// The number of writes waiting for a result from the publisher
+    pub queued_writes: usize,
+    /// When we last received a heartbeat from the publisher
+    pub last_heartbeat: Option<std::time::Instant>,
+    /// The most recently measured round trip time to the publisher,
+    /// always `None` if the publisher is too old to measure it
+    pub latency: Option<Duration>,
+}
+
+/// Diagnostic information about a subscription
+#[derive(Debug, Clone)]
+pub struct SubscriptionInfo {
+    pub path: Path,
+    pub durable: bool,
+    /// The publisher the subscription is bound to, or `None` if it
+    /// is not currently subscribed
+    pub publisher: Option<SocketAddr>,
+    /// The number of failed resubscription attempts since the
+    /// subscription was last alive
+    pub resubscribe_attempts: usize,
+    /// The error from the last failed resubscription attempt
+    pub last_error: Option<String>,
+}
+
 pub struct SubscriberBuilder {
     cfg: Option<Config>,
     desired_auth: Option<DesiredAuth>,
+    balance: Balance,
+    resubscribe: ResubscribePolicy,
 }
 
 impl SubscriberBuilder {
     pub fn new() -> Self {
-        Self { cfg: None, desired_auth: None }
+        Self {
+            cfg: None,
+            desired_auth: None,
+            balance: Balance::default(),
+            resubscribe: ResubscribePolicy::default(),
+        }
     }
 
     pub fn build(&mut self) -> Result<Subscriber> {
         let cfg = self.cfg.take().ok_or_else(|| anyhow!("config is required"))?;
         let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
-        Subscriber::new(cfg, desired_auth)
+        let subscriber = Subscriber::new(cfg, desired_auth)?;
+        subscriber.set_balance(self.balance);
+        subscriber.set_resubscribe_policy(self.resubscribe);
+        Ok(subscriber)
     }
 
     pub fn config(&mut self, cfg: Config) -> &mut Self {
@@ -767,6 +1129,20 @@ impl SubscriberBuilder {
         self.desired_auth = Some(auth);
         self
     }
+
+    /// How to choose between publishers of values published with
+    /// `PublishFlags::LOAD_BALANCE`, default `WeightedRandom`.
+    pub fn balance(&mut self, balance: Balance) -> &mut Self {
+        self.balance = balance;
+        self
+    }
+
+    /// How to resubscribe durable subscriptions that have died,
+    /// see `ResubscribePolicy`.
+    pub fn resubscribe_policy(&mut self, policy: ResubscribePolicy) -> &mut Self {
+        self.resubscribe = policy;
+        self
+    }
 }
 
 /// create subscriptions
@@ -778,6 +1154,7 @@ impl Subscriber {
     pub fn new(resolver: Config, desired_auth: DesiredAuth) -> Result<Subscriber> {
         let (tx, rx) = mpsc::unbounded();
         let tls_ctx = resolver.tls.clone().map(tls::CachedConnector::new);
+        let locality = resolver.locality.clone();
         let resolver = ResolverRead::new(resolver, desired_auth.clone());
         let t = Subscriber(Arc::new(Mutex::new(SubscriberInner {
             id: SubscriberId::new(),
@@ -785,6 +1162,8 @@ impl Subscriber {
             desired_auth,
             connections: HashMap::default(),
             recently_failed: HashMap::default(),
+            successors: HashMap::default(),
+            con_info: HashMap::default(),
             subscribed: HashMap::default(),
             durable_dead: HashMap::default(),
             durable_pending: HashMap::default(),
@@ -792,6 +1171,10 @@ impl Subscriber {
             trigger_resub: tx,
             tls_ctx,
             interfaces: get_if_addrs()?,
+            balance: Balance::default(),
+            round_robin: 0,
+            locality,
+            resubscribe: ResubscribePolicy::default(),
         })));
         t.start_resub_task(rx);
         Ok(t)
@@ -804,6 +1187,19 @@ impl Subscriber {
         self.0.lock().id
     }
 
+    /// Set how this subscriber chooses between publishers of values
+    /// published with `PublishFlags::LOAD_BALANCE`
+    pub fn set_balance(&self, balance: Balance) {
+        self.0.lock().balance = balance;
+    }
+
+    /// Set how this subscriber resubscribes durable subscriptions
+    /// that have died. Subscriptions that are already waiting to
+    /// retry keep their current schedule.
+    pub fn set_resubscribe_policy(&self, policy: ResubscribePolicy) {
+        self.0.lock().resubscribe = policy;
+    }
+
     /// return stats about durable subscriptions
     pub fn durable_stats(&self) -> DurableStats {
         let t = self.0.lock();
@@ -814,6 +1210,60 @@ impl Subscriber {
         }
     }
 
+    /// return diagnostic information about every open connection to
+    /// a publisher
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.0.lock().con_info.values().map(|i| i.lock().clone()).collect()
+    }
+
+    /// return diagnostic information about every subscription,
+    /// durable or not
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        let t = self.0.lock();
+        let publisher = |val: &Val| t.con_info.get(&val.0.conid).map(|i| i.lock().addr);
+        let mut res = Vec::new();
+        let durable = t
+            .durable_alive
+            .iter()
+            .chain(t.durable_pending.iter())
+            .chain(t.durable_dead.iter());
+        for (path, w) in durable {
+            if let Some(dv) = w.upgrade() {
+                let dv = dv.0.lock();
+                let (publisher, resubscribe_attempts, last_error) = match &dv.sub {
+                    DvState::Subscribed(val) => (publisher(val), 0, None),
+                    DvState::Dead(d) => (None, d.tries, d.last_error.clone()),
+                };
+                res.push(SubscriptionInfo {
+                    path: path.clone(),
+                    durable: true,
+                    publisher,
+                    resubscribe_attempts,
+                    last_error,
+                });
+            }
+        }
+        for (path, st) in t.subscribed.iter() {
+            let durable = t.durable_alive.contains_key(path)
+                || t.durable_pending.contains_key(path)
+                || t.durable_dead.contains_key(path);
+            if !durable {
+                if let SubStatus::Subscribed(w) = st {
+                    if let Some(val) = w.upgrade() {
+                        res.push(SubscriptionInfo {
+                            path: path.clone(),
+                            durable: false,
+                            publisher: publisher(&val),
+                            resubscribe_attempts: 0,
+                            last_error: None,
+                        });
+                    }
+                }
+            }
+        }
+        res
+    }
+
     pub fn is_subscribed_or_pending(&self, path: &Path) -> bool {
         let t = self.0.lock();
         t.subscribed.contains_key(path)
@@ -865,15 +1315,22 @@ impl Subscriber {
         async fn do_resub(
             subscriber: &SubscriberWeak,
             retry: &mut Option<Instant>,
+            in_flight: &mut usize,
         ) -> Option<FuturesUnordered<impl Future<Output = (Path, Result<Val>)>>> {
             let subscriber = subscriber.upgrade()?;
-            info!("doing resubscriptions");
-            let now = Instant::now();
             let (batch, timeout) = {
                 let mut dead = Vec::new();
                 let mut batch: Vec<(Path, Streams)> = Vec::new();
                 let mut subscriber = subscriber.0.lock();
                 let subscriber = &mut *subscriber;
+                let limit =
+                    subscriber.resubscribe.max_concurrent.saturating_sub(*in_flight);
+                if limit == 0 {
+                    // finishing the in flight batches will try again
+                    return None;
+                }
+                info!("doing resubscriptions");
+                let now = Instant::now();
                 let durable_dead = &mut subscriber.durable_dead;
                 let durable_pending = &mut subscriber.durable_pending;
                 let mut max_tries = 1;
@@ -898,7 +1355,7 @@ impl Subscriber {
                                 durable_pending.insert(p.clone(), w.clone());
                                 max_tries = max(max_tries, tries);
                                 total_retries += 1;
-                                if total_retries >= 100_000 {
+                                if total_retries >= limit {
                                     break;
                                 }
                             }
@@ -917,9 +1374,31 @@ impl Subscriber {
                 None
             } else {
                 update_retry(&mut *subscriber.0.lock(), retry);
+                *in_flight += batch.len();
                 Some(subscriber.subscribe_nondurable_internal(batch, Some(timeout)).await)
             }
         }
+        fn resubscribe_status(dv: &mut DvalInner, st: ResubscribeStatus) {
+            let sub_id = dv.sub_id;
+            dv.streams.retain(|(f, tx)| {
+                if !f.contains(UpdatesFlags::RESUBSCRIBE_STATUS) {
+                    return true;
+                }
+                let mut batch = BATCHES.take();
+                batch.push((sub_id, Event::Update(st.to_value())));
+                match tx.0.clone().try_send(batch) {
+                    Ok(()) => true,
+                    Err(e) if e.is_disconnected() => false,
+                    Err(e) => {
+                        let (mut tx, batch) = (tx.0.clone(), e.into_inner());
+                        task::spawn(async move {
+                            let _ = tx.send(batch).await;
+                        });
+                        true
+                    }
+                }
+            })
+        }
         fn finish_resubscription_batch(
             subscriber: &SubscriberWeak,
             batch: &mut Vec<(Path, Result<Val>)>,
@@ -927,6 +1406,7 @@ impl Subscriber {
         ) {
             if let Some(subscriber) = subscriber.upgrade() {
                 let mut subscriber = subscriber.0.lock();
+                let policy = subscriber.resubscribe;
                 let now = Instant::now();
                 for (p, r) in batch.drain(..) {
                     if let Some(ds) =
@@ -939,15 +1419,30 @@ impl Subscriber {
                                 DvState::Subscribed(_) => unreachable!(),
                                 DvState::Dead(d) => {
                                     d.tries += 1;
-                                    let wait =
-                                        Duration::from_millis(pick(d.tries) as u64 * 50);
-                                    d.next_try = now + wait;
-                                    let s = wait.as_secs_f32();
-                                    warn!(
-                                        "resubscription error {}: {}, next try: {}s",
-                                        p, e, s
-                                    );
-                                    subscriber.durable_dead.insert(p.clone(), dsw);
+                                    d.last_error = Some(e.to_string());
+                                    let tries = d.tries;
+                                    if policy.max_attempts.map_or(false, |m| tries >= m) {
+                                        warn!(
+                                            "resubscription error {}: {}, giving up",
+                                            p, e
+                                        );
+                                        d.gave_up = true;
+                                        d.waiting.clear();
+                                        let st = ResubscribeStatus::GaveUp;
+                                        resubscribe_status(&mut dv, st);
+                                    } else {
+                                        let wait = policy.backoff.wait(tries);
+                                        d.next_try = now + wait;
+                                        let s = wait.as_secs_f32();
+                                        warn!(
+                                            "resubscription error {}: {}, next try: {}s",
+                                            p, e, s
+                                        );
+                                        let n = u32::try_from(tries).unwrap_or(u32::MAX);
+                                        let st = ResubscribeStatus::Retrying(n);
+                                        resubscribe_status(&mut dv, st);
+                                        subscriber.durable_dead.insert(p.clone(), dsw);
+                                    }
                                 }
                             },
                             Ok(sub) => {
@@ -1014,19 +1509,32 @@ impl Subscriber {
             let mut subscriptions = VecDeque::new();
             let mut subscription_batch = Vec::new();
             let mut retry: Option<Instant> = None;
+            let mut in_flight: usize = 0;
             loop {
+                // when the concurrency limit is reached, wait for in
+                // flight resubscriptions to finish instead of the timer
+                let saturated = subscriber
+                    .upgrade()
+                    .map(|s| in_flight >= s.0.lock().resubscribe.max_concurrent)
+                    .unwrap_or(false);
+                let next_retry = if saturated { None } else { retry };
                 select_biased! {
                     m = incoming.next() => match m {
                         None => break,
                         Some(BatchItem::InBatch(())) => (),
                         Some(BatchItem::EndBatch) => {
-                            if let Some(set) = do_resub(&subscriber, &mut retry).await {
+                            let r =
+                                do_resub(&subscriber, &mut retry, &mut in_flight).await;
+                            if let Some(set) = r {
                                 subscriptions.push_back(Batched::new(set, 100_000));
                             }
                         }
                     },
                     m = next_subscription_result(&mut subscriptions).fuse() => match m {
-                        BatchItem::InBatch((p, r)) => subscription_batch.push((p, r)),
+                        BatchItem::InBatch((p, r)) => {
+                            in_flight = in_flight.saturating_sub(1);
+                            subscription_batch.push((p, r))
+                        }
                         BatchItem::EndBatch => {
                             finish_resubscription_batch(
                                 &subscriber,
@@ -1035,15 +1543,19 @@ impl Subscriber {
                             );
                             if let Some(t) = retry {
                                 if Instant::now() >= t {
-                                    if let Some(set) = do_resub(&subscriber, &mut retry).await {
+                                    let r =
+                                        do_resub(&subscriber, &mut retry, &mut in_flight)
+                                            .await;
+                                    if let Some(set) = r {
                                         subscriptions.push_back(Batched::new(set, 100_000));
                                     }
                                 }
                             }
                         }
                     },
-                    _ = wait_retry(retry).fuse() => {
-                        if let Some(set) = do_resub(&subscriber, &mut retry).await {
+                    _ = wait_retry(next_retry).fuse() => {
+                        let r = do_resub(&subscriber, &mut retry, &mut in_flight).await;
+                        if let Some(set) = r {
                             subscriptions.push_back(Batched::new(set, 100_000));
                         }
                     },
@@ -1059,13 +1571,28 @@ impl Subscriber {
         addr: SocketAddr,
         target_auth: &TargetAuth,
         desired_auth: &DesiredAuth,
+        isolated: bool,
     ) -> (ConId, BatchSender<ToCon>) {
         let (tx, rx) = batch_channel::channel();
         let subscriber = self.downgrade();
         let desired_auth = desired_auth.clone();
         let conid = ConId::new();
         let target_auth = target_auth.clone();
+        let info = Arc::new(Mutex::new(ConnectionInfo {
+            addr,
+            auth: target_auth.clone(),
+            isolated,
+            subscriptions: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            queued_writes: 0,
+            last_heartbeat: None,
+            latency: None,
+        }));
         task::spawn(async move {
+            if let Some(subscriber) = subscriber.upgrade() {
+                subscriber.0.lock().con_info.insert(conid, info.clone());
+            }
             let res = connection::ConnectionCtx::new(
                 addr,
                 subscriber.clone(),
@@ -1075,10 +1602,12 @@ impl Subscriber {
                 target_auth,
                 desired_auth,
                 rx,
+                info,
             )
             .start()
             .await;
             if let Some(subscriber) = subscriber.upgrade() {
+                subscriber.0.lock().con_info.remove(&conid);
                 if let Entry::Occupied(mut e) =
                     subscriber.0.lock().connections.entry(addr)
                 {
@@ -1102,6 +1631,62 @@ impl Subscriber {
         (conid, tx)
     }
 
+    // Move durable subscriptions away from a publisher that is
+    // migrating to a successor. Each `Dval` keeps it's old
+    // subscription until the new one succeeds, and then swaps it
+    // in, so it's streams never see `Event::Unsubscribed`. If the
+    // new subscription fails the `Dval` stays where it is, and will
+    // be resubscribed normally when the old publisher goes away.
+    // Either way the old publisher is told we're done once every
+    // `Dval` has been dealt with.
+    async fn migrate_durable(
+        &self,
+        addr: SocketAddr,
+        conid: ConId,
+        batch: Vec<(Path, DvalWeak)>,
+    ) {
+        {
+            let mut t = self.0.lock();
+            for (p, _) in &batch {
+                if let Some(SubStatus::Subscribed(_)) = t.subscribed.get(p) {
+                    t.subscribed.remove(p);
+                }
+            }
+        }
+        let paths = batch.iter().map(|(p, _)| (p.clone(), []));
+        let mut res =
+            self.subscribe_nondurable_internal(paths, Some(MIGRATE_TIMEOUT)).await;
+        let dvals = batch.into_iter().collect::<HashMap<_, _>>();
+        while let Some((p, r)) = res.next().await {
+            match r {
+                Err(e) => warn!("failed to migrate {}: {}", p, e),
+                Ok(sub) => {
+                    if let Some(ds) = dvals.get(&p).and_then(|w| w.upgrade()) {
+                        let mut dv = ds.0.lock();
+                        if let DvState::Subscribed(_) = &dv.sub {
+                            info!("migrated {}", p);
+                            for (f, tx) in &dv.streams {
+                                sub.0.connection.send(ToCon::Stream {
+                                    tx: tx.clone(),
+                                    sub_id: dv.sub_id,
+                                    id: sub.0.id,
+                                    flags: *f
+                                        | UpdatesFlags::BEGIN_WITH_LAST
+                                        | UpdatesFlags::NO_SPURIOUS,
+                                });
+                            }
+                            dv.sub = DvState::Subscribed(sub);
+                        }
+                    }
+                }
+            }
+        }
+        let t = self.0.lock();
+        if let Some(c) = t.connections.get(&addr).and_then(|c| c.get(conid)) {
+            c.send(ToCon::Migrated);
+        }
+    }
+
     /// Subscribe to the specified set of values.
     ///
     /// To minimize round trips and amortize locking path resolution
@@ -1256,6 +1841,7 @@ impl Subscriber {
                                     ch.addr,
                                     &ch.target_auth,
                                     &desired_auth,
+                                    true,
                                 );
                                 con.isolated.insert(id, c.clone());
                                 c
@@ -1269,6 +1855,7 @@ impl Subscriber {
                                             ch.addr,
                                             &ch.target_auth,
                                             &desired_auth,
+                                            false,
                                         );
                                         con.primary = Some((id, c.clone()));
                                         c
@@ -1281,8 +1868,11 @@ impl Subscriber {
                                 Some(St::Resolve(streams)) => streams,
                                 _ => unreachable!(),
                             };
+                            let target =
+                                resolved.target.clone().unwrap_or_else(|| p.clone());
                             let r = con.send(ToCon::Subscribe(SubscribeValRequest {
                                 path: p.clone(),
+                                target,
                                 sub_id,
                                 timestamp: resolved.timestamp,
                                 permissions: resolved.permissions as u32,
@@ -1460,6 +2050,8 @@ impl Subscriber {
                 waiting: Vec::new(),
                 tries: 0,
                 next_try: Instant::now(),
+                last_error: None,
+                gave_up: false,
             })),
             streams: SmallVec::from_iter(
                 updates.into_iter().map(|(f, c)| (f, ChanWrap(c))),
@@ -1525,4 +2117,56 @@ impl Subscriber {
             let _ = flush.await;
         }
     }
+
+    /// Write to several `Dval`s at once. Writes to `Dval`s that are
+    /// subscribed over the same connection to a publisher are sent in
+    /// one message, and that publisher delivers them to it's write
+    /// handlers together, or not at all if any of them is not
+    /// permitted. Nothing else written to the publisher will be
+    /// handled between them. The publisher rejects the batch if the
+    /// `Dval`s in it are not all handled by the same write channels,
+    /// see `Publisher::writes`.
+    ///
+    /// One receipt is returned for each connection written to,
+    /// holding the reply to the whole batch, along with the positions
+    /// in `writes` of the writes in that batch. Receipts are in the
+    /// order of their first write. Writes to different publishers, or
+    /// over different connections (see `PublishFlags::ISOLATED`), are
+    /// not atomic with respect to each other. Publishers too old to
+    /// support batches reply with an error.
+    ///
+    /// Every `Dval` must be subscribed, if any of them is not then
+    /// nothing is written and an error is returned.
+    pub fn write_batch<'a, I>(
+        &self,
+        writes: I,
+    ) -> Result<Vec<(Vec<usize>, oneshot::Receiver<Value>)>>
+    where
+        I: IntoIterator<Item = (&'a Dval, Value)>,
+    {
+        let mut by_con: FxHashMap<ConId, usize> = HashMap::default();
+        let mut batches: Vec<(BatchSender<ToCon>, Vec<usize>, Vec<(Id, Value)>)> =
+            Vec::new();
+        for (i, (dv, v)) in writes.into_iter().enumerate() {
+            match &dv.0.lock().sub {
+                DvState::Dead(_) => bail!("not subscribed"),
+                DvState::Subscribed(val) => {
+                    let n = *by_con.entry(val.0.conid).or_insert_with(|| {
+                        batches.push((val.0.connection.clone(), vec![], vec![]));
+                        batches.len() - 1
+                    });
+                    batches[n].1.push(i);
+                    batches[n].2.push((val.0.id, v));
+                }
+            }
+        }
+        Ok(batches
+            .into_iter()
+            .map(|(con, idx, batch)| {
+                let (tx, rx) = oneshot::channel();
+                con.send(ToCon::WriteBatch(batch, WriteId::new(), tx));
+                (idx, rx)
+            })
+            .collect())
+    }
 }
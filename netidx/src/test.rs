@@ -1,15 +1,38 @@
+// A path in the temp dir unique to `name` and this process
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("netidx-test-{}-{}", name, std::process::id()))
+}
+
+// Load the resolver server config at `path`, editing it's json with
+// `f` before it is parsed.
+fn server_config(
+    path: &str,
+    f: impl FnOnce(&mut serde_json::Value),
+) -> crate::resolver_server::config::Config {
+    let s = std::fs::read_to_string(path).expect("read server config");
+    let mut cfg: serde_json::Value = serde_json::from_str(&s).expect("server config");
+    f(&mut cfg);
+    crate::resolver_server::config::Config::parse(&cfg.to_string())
+        .expect("parse server config")
+}
+
 mod resolver {
+    use super::{server_config, temp_path};
     use crate::{
         chars::Chars,
         config::Config as ClientConfig,
         path::Path,
+        pool::Pooled,
         protocol::glob::{Glob, GlobSet},
         publisher::PublishFlags,
         resolver_client::{ChangeTracker, DesiredAuth, ResolverRead, ResolverWrite},
         resolver_server::{config::Config as ServerConfig, Server},
     };
-    use netidx_netproto::resolver::TargetAuth;
+    use netidx_netproto::resolver::{
+        Auth, AuthRead, FromRead, Metadata, MetadataQuery, Referral, TargetAuth,
+    };
     use rand::{thread_rng, Rng};
+    use serde_json::json;
     use std::{iter, net::SocketAddr, time::Duration};
     use tokio::{runtime::Runtime, time};
 
@@ -52,6 +75,72 @@ mod resolver {
         });
     }
 
+    #[test]
+    fn metadata_search() {
+        let _ = env_logger::try_init();
+        Runtime::new().unwrap().block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            let paths = vec![p("/sensors/t0"), p("/sensors/t1"), p("/sensors/p0")];
+            w.publish(paths.clone()).await.unwrap();
+            let md = |units: &'static str, tags: &[&'static str]| Metadata {
+                doc: Some(Chars::from("a sensor reading")),
+                typ: Some(Chars::from("f64")),
+                units: Some(Chars::from(units)),
+                tags: tags.iter().map(|t| Chars::from(*t)).collect(),
+            };
+            w.set_metadata(vec![
+                (p("/sensors/t0"), md("C", &["temperature", "critical"])),
+                (p("/sensors/t1"), md("C", &["temperature"])),
+                (p("/sensors/p0"), md("kPa", &["pressure"])),
+            ])
+            .await
+            .unwrap();
+            // metadata can't be attached to paths we don't publish
+            assert!(w
+                .set_metadata(iter::once((p("/sensors/t2"), md("C", &[]))))
+                .await
+                .is_err());
+            let all = GlobSet::new(
+                true,
+                iter::once(Glob::new(Chars::from("/sensors/*")).unwrap()),
+            )
+            .unwrap();
+            let search = |q: MetadataQuery| {
+                let r = &r;
+                let all = &all;
+                async move {
+                    let mut m = r.search(all, &q).await.unwrap();
+                    m.sort_by(|(p0, _), (p1, _)| p0.cmp(p1));
+                    m.into_iter().map(|(p, _)| p).collect::<Vec<_>>()
+                }
+            };
+            let q = MetadataQuery::Tag(Chars::from("temperature"));
+            assert_eq!(search(q).await, vec![p("/sensors/t0"), p("/sensors/t1")]);
+            let q = MetadataQuery::Units(Chars::from("kPa"));
+            assert_eq!(search(q).await, vec![p("/sensors/p0")]);
+            let q = MetadataQuery::Key(Chars::from("doc"));
+            assert_eq!(search(q).await.len(), 3);
+            let q = MetadataQuery::Doc(Chars::from("reading"));
+            assert_eq!(search(q).await.len(), 3);
+            w.set_metadata(iter::once((p("/sensors/t1"), Metadata::default())))
+                .await
+                .unwrap();
+            w.unpublish(iter::once(p("/sensors/t0"))).await.unwrap();
+            let q = MetadataQuery::Tag(Chars::from("temperature"));
+            assert_eq!(search(q).await, Vec::<Path>::new());
+            drop(server)
+        });
+    }
+
     #[test]
     fn publish_default() {
         let _ = env_logger::try_init();
@@ -87,6 +176,309 @@ mod resolver {
         });
     }
 
+    #[test]
+    fn audit_log() {
+        Runtime::new().unwrap().block_on(async {
+            let log = temp_path("audit.log");
+            let _ = std::fs::remove_file(&log);
+            let server_cfg = server_config("../cfg/simple-server.json", |cfg| {
+                cfg["member_servers"][0]["audit"] =
+                    json!({ "sink": { "File": { "path": log } } })
+            });
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            w.publish(iter::once(p("/audit/foo"))).await.unwrap();
+            r.resolve(iter::once(p("/audit/foo"))).await.unwrap();
+            r.list(p("/audit")).await.unwrap();
+            w.clear().await.unwrap();
+            time::sleep(Duration::from_millis(500)).await;
+            let events = std::fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+                .map(|v| {
+                    let path = v.get("path").and_then(|p| p.as_str()).unwrap_or("");
+                    format!("{} {}", v["event"].as_str().unwrap(), path)
+                })
+                .collect::<Vec<_>>();
+            let _ = std::fs::remove_file(&log);
+            for e in [
+                "authenticated ",
+                "published /audit/foo",
+                "read /audit/foo",
+                "read /audit",
+                "cleared ",
+            ] {
+                assert!(events.iter().any(|ev| ev == e), "missing {} in {:?}", e, events)
+            }
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn limits() {
+        Runtime::new().unwrap().block_on(async {
+            let server_cfg = server_config("../cfg/simple-server.json", |cfg| {
+                cfg["member_servers"][0]["limits"] = json!({
+                    "default": {
+                        "max_published": 2,
+                        "max_default_published": 1,
+                        "max_glob_results": 2,
+                        "max_metadata_bytes": 64,
+                        "max_publisher_metadata_bytes": 100
+                    }
+                })
+            });
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            w.publish(vec![p("/a"), p("/b")]).await.unwrap();
+            // republishing doesn't count against the quota
+            w.publish(iter::once(p("/a"))).await.unwrap();
+            assert!(w.publish(iter::once(p("/c"))).await.is_err());
+            w.unpublish(iter::once(p("/a"))).await.unwrap();
+            w.publish(iter::once(p("/c"))).await.unwrap();
+            w.publish_default(iter::once(p("/d"))).await.unwrap();
+            assert!(w.publish_default(iter::once(p("/e"))).await.is_err());
+            let usage = r.usage().await.unwrap();
+            assert_eq!(usage.len(), 1);
+            assert_eq!(usage[0].publisher, paddr);
+            assert_eq!(usage[0].published, 2);
+            assert_eq!(usage[0].max_published, Some(2));
+            assert_eq!(usage[0].default_published, 1);
+            let glob = |g: &'static str| {
+                GlobSet::new(false, iter::once(Glob::new(Chars::from(g)).unwrap()))
+                    .unwrap()
+            };
+            assert!(r.list_matching(&glob("/*")).await.is_err());
+            let m = r.list_matching(&glob("/b*")).await.unwrap();
+            assert_eq!(m.iter().map(|m| m.len()).sum::<usize>(), 1);
+            let doc = |n: usize| Metadata {
+                doc: Some(Chars::from("x".repeat(n))),
+                ..Metadata::default()
+            };
+            assert!(w.set_metadata(iter::once((p("/b"), doc(100)))).await.is_err());
+            w.set_metadata(iter::once((p("/b"), doc(50)))).await.unwrap();
+            assert!(w.set_metadata(iter::once((p("/c"), doc(50)))).await.is_err());
+            // replacing metadata only counts the new size
+            w.set_metadata(iter::once((p("/b"), doc(40)))).await.unwrap();
+            w.unpublish(iter::once(p("/b"))).await.unwrap();
+            w.set_metadata(iter::once((p("/c"), doc(50)))).await.unwrap();
+            w.clear().await.unwrap();
+            let usage = r.usage().await.unwrap();
+            assert!(usage.iter().all(|u| u.published == 0 && u.default_published == 0));
+            drop(server)
+        });
+    }
+
+    async fn wait_list(r: &ResolverRead, path: Path, listed: bool) {
+        for _ in 0..100 {
+            let l = r.list(p("/")).await.unwrap();
+            if l.contains(&path) == listed {
+                return;
+            }
+            time::sleep(Duration::from_millis(100)).await
+        }
+        panic!("timed out waiting for {} listed: {}", path, listed)
+    }
+
+    #[test]
+    fn register_referral() {
+        Runtime::new().unwrap().block_on(async {
+            let parent_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let parent = Server::new(parent_cfg, false, 0).await.expect("start parent");
+            let mut parent_client = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            parent_client.addrs[0].0 = *parent.local_addr();
+            let parent_client_file = temp_path("register.json");
+            let addrs = json!([[parent.local_addr(), "Anonymous"]]);
+            let client =
+                json!({ "addrs": addrs, "base": "/", "default_auth": "Anonymous" });
+            std::fs::write(&parent_client_file, client.to_string()).unwrap();
+            let child_cfg = server_config("../cfg/simple-server.json", |cfg| {
+                cfg["parent"] = json!({ "path": "/child", "ttl": 60, "addrs": addrs });
+                cfg["register"] = json!({ "config": parent_client_file, "ttl": 2 });
+            });
+            let child = Server::new(child_cfg, false, 0).await.expect("start child");
+            let mut child_client = parent_client.clone();
+            child_client.addrs[0].0 = *child.local_addr();
+            child_client.base = p("/child");
+            let r = ResolverRead::new(parent_client.clone(), DesiredAuth::Anonymous);
+            wait_list(&r, p("/child"), true).await;
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w =
+                ResolverWrite::new(child_client, DesiredAuth::Anonymous, paddr).unwrap();
+            w.publish(iter::once(p("/child/foo"))).await.unwrap();
+            let (publishers, resolved) =
+                r.resolve(iter::once(p("/child/foo"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            assert_eq!(publishers[&resolved[0].publishers[0].id].addr, paddr);
+            // registrations that aren't renewed expire
+            let w =
+                ResolverWrite::new(parent_client, DesiredAuth::Anonymous, paddr).unwrap();
+            let referral = |path: &'static str, ttl| Referral {
+                path: p(path),
+                ttl,
+                addrs: Pooled::orphan(vec![(*child.local_addr(), Auth::Anonymous)]),
+            };
+            assert!(w.register_referral(referral("/gone", None)).await.is_err());
+            assert!(w.register_referral(referral("/", Some(1))).await.is_err());
+            w.register_referral(referral("/gone", Some(1))).await.unwrap();
+            wait_list(&r, p("/gone"), true).await;
+            wait_list(&r, p("/gone"), false).await;
+            // and stopping the child removes it's registration
+            drop(child);
+            wait_list(&r, p("/child"), false).await;
+            let _ = std::fs::remove_file(&parent_client_file);
+            drop(parent)
+        });
+    }
+
+    #[test]
+    fn aliases() {
+        Runtime::new().unwrap().block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let addr41: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let addr42: SocketAddr = "127.0.0.1:2".parse().unwrap();
+            let w41 =
+                ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, addr41)
+                    .unwrap();
+            let w42 =
+                ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, addr42)
+                    .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            w41.publish(iter::once(p("/prod/pricing/v41/a"))).await.unwrap();
+            w42.publish([p("/prod/pricing/v42/a"), p("/prod/pricing/v42/b")])
+                .await
+                .unwrap();
+            let current = p("/prod/pricing/current");
+            let resolve = |path: &'static str| {
+                let r = r.clone();
+                async move {
+                    let (publishers, resolved) =
+                        r.resolve(iter::once(p(path))).await.unwrap();
+                    resolved[0]
+                        .publishers
+                        .iter()
+                        .map(|pr| publishers[&pr.id].addr)
+                        .collect::<Vec<_>>()
+                }
+            };
+            let list = |path: &'static str| {
+                let r = r.clone();
+                async move {
+                    let mut l = r.list(p(path)).await.unwrap();
+                    l.sort();
+                    l.drain(..).collect::<Vec<_>>()
+                }
+            };
+            w41.set_alias(current.clone(), p("/prod/pricing/v41")).await.unwrap();
+            assert_eq!(resolve("/prod/pricing/current/a").await, [addr41]);
+            assert_eq!(
+                list("/prod/pricing/current").await,
+                [p("/prod/pricing/current/a")]
+            );
+            assert_eq!(
+                list("/prod/pricing").await,
+                [current.clone(), p("/prod/pricing/v41"), p("/prod/pricing/v42")]
+            );
+            let table = r.table(current.clone()).await.unwrap();
+            assert_eq!(&table.rows[..], [p("/prod/pricing/current/a")]);
+            // a table of the alias's parent has a row for the alias
+            let mut table = r.table(p("/prod/pricing")).await.unwrap();
+            table.rows.sort();
+            assert_eq!(
+                &table.rows[..],
+                [current.clone(), p("/prod/pricing/v41"), p("/prod/pricing/v42")]
+            );
+            // switching the alias is atomic
+            w41.set_alias(current.clone(), p("/prod/pricing/v42")).await.unwrap();
+            assert_eq!(resolve("/prod/pricing/current/a").await, [addr42]);
+            assert_eq!(
+                list("/prod/pricing/current").await,
+                [p("/prod/pricing/current/a"), p("/prod/pricing/current/b")]
+            );
+            let table = r.table(current.clone()).await.unwrap();
+            assert_eq!(table.rows.len(), 2);
+            assert!(w41.set_alias(p("/"), p("/prod")).await.is_err());
+            assert!(w41.set_alias(p("/prod/pricing/current/x"), p("/x")).await.is_err());
+            assert!(w41.set_alias(p("/y"), p("/prod/pricing/current/a")).await.is_err());
+            assert!(w41.set_alias(p("/prod/x"), p("/prod/x/y")).await.is_err());
+            w41.remove_alias(current.clone()).await.unwrap();
+            assert!(resolve("/prod/pricing/current/a").await.is_empty());
+            assert_eq!(
+                list("/prod/pricing").await,
+                [p("/prod/pricing/v41"), p("/prod/pricing/v42")]
+            );
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn drain() {
+        Runtime::new().unwrap().block_on(async {
+            let server_cfg = server_config("../cfg/simple-server.json", |cfg| {
+                let member = |port: u16| {
+                    let mut m = cfg["member_servers"][0].clone();
+                    m["addr"] = json!(format!("127.0.0.1:{}", port));
+                    m
+                };
+                let members = json!([member(1240), member(1241)]);
+                cfg["member_servers"] = members;
+            });
+            let client_cfg = ClientConfig::parse(
+                r#"{
+  "addrs": [["127.0.0.1:1240", "Anonymous"], ["127.0.0.1:1241", "Anonymous"]],
+  "base": "/"
+}"#,
+            )
+            .expect("parse drain client config");
+            let server0 =
+                Server::new(server_cfg.clone(), false, 0).await.expect("server 0");
+            let server1 = Server::new(server_cfg, false, 1).await.expect("server 1");
+            let addr0 = *server0.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            w.publish(iter::once(p("/drain/foo"))).await.unwrap();
+            let (_, resolved) = r.resolve(iter::once(p("/drain/foo"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            server0.drain(Duration::from_secs(10)).await;
+            assert!(tokio::net::TcpStream::connect(addr0).await.is_err());
+            // the reader should move to the other member without
+            // backing off
+            for _ in 0..10 {
+                let (_, resolved) = time::timeout(
+                    Duration::from_secs(5),
+                    r.resolve(iter::once(p("/drain/foo"))),
+                )
+                .await
+                .expect("resolve timed out")
+                .unwrap();
+                assert_eq!(resolved[0].publishers.len(), 1);
+            }
+            drop(server1)
+        });
+    }
+
     struct Ctx {
         _local: Server,
         _root: (Server, Server),
@@ -273,6 +665,42 @@ mod resolver {
         assert_eq!(i, paths.len());
     }
 
+    async fn check_trace(ctx: &Ctx, waddrs: &[SocketAddr]) {
+        let r = ResolverRead::new(ctx.cfg_local.clone(), DesiredAuth::Anonymous);
+        let path = p("/tmp/x");
+        let t = r.trace_resolve(path.clone()).await.unwrap();
+        assert_eq!(t.path, path);
+        // local's parent is huge1/sub, whose parent is huge1, whose
+        // parent is root
+        let hops = t.hops.iter().map(|h| h.referral.addrs[0].0).collect::<Vec<_>>();
+        let expected =
+            [&ctx.cfg_local, &ctx.cfg_huge1_sub, &ctx.cfg_huge1, &ctx.cfg_root]
+                .iter()
+                .map(|c| c.addrs[0].0)
+                .collect::<Vec<_>>();
+        assert_eq!(hops, expected);
+        assert!(t.hops.iter().all(|h| !h.cached));
+        for h in &t.hops {
+            assert!(h.referral.addrs.iter().any(|(a, _)| *a == h.resolver));
+            assert_eq!(h.auth, AuthRead::Anonymous);
+        }
+        for h in &t.hops[..3] {
+            assert!(matches!(h.reply, FromRead::Referral(_)))
+        }
+        match &t.hops[3].reply {
+            FromRead::Resolved(r) => assert_eq!(r.publishers.len(), waddrs.len()),
+            m => panic!("unexpected reply {:?}", m),
+        }
+        let mut addrs = t.publishers.iter().map(|p| p.addr).collect::<Vec<_>>();
+        addrs.sort();
+        assert_eq!(addrs, waddrs);
+        // the referrals followed above are now cached
+        let t = r.trace_resolve(path).await.unwrap();
+        assert_eq!(t.hops.len(), 1);
+        assert!(t.hops[0].cached);
+        assert_eq!(t.hops[0].referral.addrs[0].0, ctx.cfg_root.addrs[0].0);
+    }
+
     async fn run_publish_resolve_complex() {
         let ctx = Ctx::new().await;
         let waddrs =
@@ -337,6 +765,7 @@ mod resolver {
         check_list(true, &r_local).await;
         check_resolve(&ctx, &r_local, &paths, &waddrs).await;
         check_resolve(&ctx, &r_local, &local_paths, &[waddrs[0]]).await;
+        check_trace(&ctx, &waddrs).await;
         let r_huge0 = ResolverRead::new(ctx.cfg_huge0.clone(), DesiredAuth::Anonymous);
         check_list(false, &r_huge0).await;
         check_resolve(&ctx, &r_huge0, &paths, &waddrs).await;
@@ -372,23 +801,35 @@ mod resolver {
 }
 
 mod publisher {
+    use super::{server_config, temp_path};
     use crate::{
+        chars::Chars,
         config::Config as ClientConfig,
+        path::Path,
         publisher::{
-            BindCfg, DesiredAuth, Event as PEvent, PublishFlags, Publisher, Val,
+            BindCfg, DesiredAuth, Event as PEvent, Locality, PublishFlags, Publisher,
+            PublisherBuilder, Val, WriteDenied,
         },
         resolver_server::{config::Config as ServerConfig, Server},
-        subscriber::{Event, Subscriber, UpdatesFlags, Value},
+        subscriber::{
+            Backoff, Balance, Event, ResubscribePolicy, ResubscribeStatus, Subscriber,
+            SubscriberBuilder, UpdatesFlags, Value,
+        },
+        testing::Harness,
     };
     use futures::{channel::mpsc, channel::oneshot, prelude::*, select_biased};
     use parking_lot::Mutex;
+    use serde_json::json;
     use std::{
         iter,
         net::{IpAddr, SocketAddr},
         sync::Arc,
         time::Duration,
     };
-    use tokio::{runtime::Runtime, task, time};
+    use tokio::{
+        runtime::{Builder, Runtime},
+        task, time,
+    };
 
     #[test]
     fn bindcfg() {
@@ -417,7 +858,7 @@ mod publisher {
         auth: DesiredAuth,
     ) {
         let check_user = match &auth {
-            DesiredAuth::Tls { .. } => true,
+            DesiredAuth::Tls { .. } | DesiredAuth::Token { .. } => true,
             _ => false,
         };
         let publisher =
@@ -554,17 +995,15 @@ mod publisher {
         });
     }
 
-    #[test]
-    fn tls_publish_subscribe() {
+    fn tls_publish_subscribe_with(server: &str, publisher: &str, subscriber: &str) {
         let _ = env_logger::try_init();
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
-            let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver.json")
-                .expect("load tls server config");
-            let mut pub_cfg = ClientConfig::load("../cfg/tls/publisher/client.json")
+            let server_cfg = ServerConfig::load(server).expect("load tls server config");
+            let mut pub_cfg = ClientConfig::load(publisher)
                 .expect("failed to load tls publisher config");
-            let mut sub_cfg = ClientConfig::load("../cfg/tls/client/client.json")
-                .expect("failed to load subscriber cfg");
+            let mut sub_cfg =
+                ClientConfig::load(subscriber).expect("failed to load subscriber cfg");
             let default_destroyed = Arc::new(Mutex::new(false));
             let (tx, ready) = oneshot::channel();
             let server = Server::new(server_cfg, false, 0).await.expect("start server");
@@ -586,4 +1025,929 @@ mod publisher {
             drop(server)
         })
     }
+
+    #[test]
+    fn tls_publish_subscribe() {
+        tls_publish_subscribe_with(
+            "../cfg/tls/resolver/resolver.json",
+            "../cfg/tls/publisher/client.json",
+            "../cfg/tls/client/client.json",
+        )
+    }
+
+    #[test]
+    fn tls_crl_publish_subscribe() {
+        tls_publish_subscribe_with(
+            "../cfg/tls/crl/resolver.json",
+            "../cfg/tls/crl/publisher.json",
+            "../cfg/tls/crl/client.json",
+        )
+    }
+
+    // write a token for `user` signed with `key` to dir
+    fn token_auth(
+        keys: &crate::token::KeySet,
+        dir: &std::path::Path,
+        user: &str,
+        key: &str,
+    ) -> DesiredAuth {
+        let claims = crate::token::Claims {
+            key: key.into(),
+            user: user.into(),
+            primary_group: "netidx".into(),
+            groups: vec![],
+            expires: chrono::Utc::now() + chrono::Duration::days(1),
+        };
+        let path = dir.join(user);
+        std::fs::write(&path, keys.sign(&claims).unwrap()).unwrap();
+        DesiredAuth::Token { token: Some(path.to_string_lossy().into_owned()) }
+    }
+
+    #[test]
+    fn token_publish_subscribe() {
+        use crate::token::{Claims, KeySet};
+        use chrono::prelude::*;
+        use std::fs;
+        let _ = env_logger::try_init();
+        let keys = KeySet::load("../cfg/token/keys.json").expect("load key set");
+        let day = Duration::from_secs(86400);
+        let tok = keys.issue("pub", "netidx", &[], day).unwrap();
+        let claims = keys.verify(tok.as_bytes()).unwrap();
+        assert_eq!(claims.key, "k1");
+        assert_eq!(claims.user, "pub");
+        // tampering with the claims invalidates the signature
+        let (payload, sig) = tok.split_once('.').unwrap();
+        let mut forged = keys.verify(tok.as_bytes()).unwrap();
+        forged.groups.push("wheel".into());
+        let forged = keys.sign(&forged).unwrap();
+        let (forged_payload, _) = forged.split_once('.').unwrap();
+        assert_ne!(payload, forged_payload);
+        assert!(keys.verify(format!("{}.{}", forged_payload, sig).as_bytes()).is_err());
+        // expired tokens and unknown keys are rejected
+        let expired =
+            Claims { expires: Utc::now() - chrono::Duration::seconds(1), ..claims };
+        assert!(keys.verify(keys.sign(&expired).unwrap().as_bytes()).is_err());
+        let other = KeySet::parse(&format!(
+            r#"{{"keys": {{"k1": "{}"}}}}"#,
+            KeySet::generate_key()
+        ))
+        .unwrap();
+        assert!(keys
+            .verify(other.issue("pub", "netidx", &[], day).unwrap().as_bytes())
+            .is_err());
+        let dir = temp_path("token");
+        fs::create_dir_all(&dir).unwrap();
+        let auth = |user: &str, key: &str| token_auth(&keys, &dir, user, key);
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/token/resolver.json")
+                .expect("load token server config");
+            let mut client_cfg = ClientConfig::load("../cfg/token/client.json")
+                .expect("load token client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let default_destroyed = Arc::new(Mutex::new(false));
+            let (tx, ready) = oneshot::channel();
+            task::spawn(run_publisher(
+                client_cfg.clone(),
+                default_destroyed.clone(),
+                tx,
+                auth("publisher", "k1"),
+            ));
+            time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
+            // tokens signed by any key in the set are accepted
+            run_subscriber(client_cfg, default_destroyed, auth("subscriber", "k0")).await;
+            drop(server)
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_balance() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let path = |i: usize| Path::from(format!("/lb/{}", i));
+            let mut publishers = vec![];
+            let mut vals = vec![];
+            for i in 0..3 {
+                let publisher = PublisherBuilder::new(client_cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                    .build()
+                    .await
+                    .unwrap();
+                for j in 0..6 {
+                    let f = PublishFlags::LOAD_BALANCE;
+                    vals.push(
+                        publisher.publish_with_flags(f, path(j), Value::U64(i)).unwrap(),
+                    );
+                }
+                publishers.push(publisher);
+            }
+            // relative to weight the loads are 2, 1, and 2.5
+            for (publisher, (weight, load)) in
+                publishers.iter().zip([(1, 2), (2, 2), (2, 5)])
+            {
+                publisher.set_weight(weight);
+                publisher.set_load(load);
+                publisher.flushed().await;
+            }
+            let chosen = |balance| {
+                let subscriber = SubscriberBuilder::new()
+                    .config(client_cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .balance(balance)
+                    .build()
+                    .unwrap();
+                async move {
+                    let mut chosen = [0; 3];
+                    for j in 0..6 {
+                        let s = subscriber
+                            .subscribe_nondurable_one(path(j), None)
+                            .await
+                            .unwrap();
+                        match s.last() {
+                            Event::Update(Value::U64(i)) => chosen[i as usize] += 1,
+                            e => panic!("unexpected event {:?}", e),
+                        }
+                    }
+                    chosen
+                }
+            };
+            assert_eq!(chosen(Balance::LeastLoaded).await, [0, 6, 0]);
+            assert_eq!(chosen(Balance::RoundRobin).await, [2, 2, 2]);
+            for publisher in &publishers[1..] {
+                publisher.set_weight(0);
+                publisher.flushed().await;
+            }
+            assert_eq!(chosen(Balance::WeightedRandom).await, [6, 0, 0]);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn prefer_locality() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let locality = |labels: &[&'static str]| {
+                Locality::new(labels.iter().map(|l| Chars::from(*l)))
+            };
+            let path = |i: usize| Path::from(format!("/loc/{}", i));
+            let mut publishers = vec![];
+            let mut vals = vec![];
+            for (i, labels) in
+                [["r1", "z1"], ["r1", "z2"], ["r2", "z2"]].iter().enumerate()
+            {
+                let publisher = PublisherBuilder::new(client_cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                    .locality(locality(labels))
+                    .build()
+                    .await
+                    .unwrap();
+                for j in 0..4 {
+                    let f = PublishFlags::PREFER_LOCAL;
+                    vals.push(
+                        publisher
+                            .publish_with_flags(f, path(j), Value::U64(i as u64))
+                            .unwrap(),
+                    );
+                }
+                publisher.flushed().await;
+                publishers.push(publisher);
+            }
+            let chosen = |labels: &[&'static str]| {
+                let mut cfg = client_cfg.clone();
+                cfg.locality = locality(labels);
+                let subscriber = SubscriberBuilder::new()
+                    .config(cfg)
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .build()
+                    .unwrap();
+                async move {
+                    let mut chosen = [0; 3];
+                    for j in 0..4 {
+                        let s = subscriber
+                            .subscribe_nondurable_one(path(j), None)
+                            .await
+                            .unwrap();
+                        match s.last() {
+                            Event::Update(Value::U64(i)) => chosen[i as usize] += 1,
+                            e => panic!("unexpected event {:?}", e),
+                        }
+                    }
+                    chosen
+                }
+            };
+            assert_eq!(chosen(&["r1", "z2", "k7"]).await, [0, 4, 0]);
+            assert_eq!(chosen(&["r2", "z2"]).await, [0, 0, 4]);
+            assert_eq!(chosen(&["r1", "z3"]).await[2], 0);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn metrics() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .metrics(Some(Path::from("/metrics")))
+                .build()
+                .await
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            let v = publisher.publish(Path::from("/app/v"), Value::U64(0)).unwrap();
+            publisher.writes(v.id(), tx);
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let s = subscriber
+                .subscribe_nondurable_one(Path::from("/app/v"), None)
+                .await
+                .unwrap();
+            s.write(Value::U64(1));
+            rx.next().await.unwrap();
+            let mut ub = publisher.start_batch();
+            v.update(&mut ub, Value::U64(1));
+            ub.commit(None).await;
+            time::sleep(Duration::from_secs(3)).await;
+            let get = |path: &'static str| {
+                let subscriber = subscriber.clone();
+                async move {
+                    subscriber
+                        .subscribe_nondurable_one(Path::from(path), None)
+                        .await
+                        .unwrap()
+                        .last()
+                }
+            };
+            assert_eq!(get("/metrics/clients").await, Event::Update(Value::U64(1)));
+            assert_eq!(get("/metrics/writes").await, Event::Update(Value::U64(1)));
+            assert_eq!(
+                get("/metrics/flush-timeouts").await,
+                Event::Update(Value::U64(0))
+            );
+            let clients = subscriber.resolver().list(Path::from("/metrics/client")).await;
+            assert_eq!(clients.unwrap().len(), 1);
+            // the metrics' own updates aren't counted
+            assert_eq!(
+                get("/metrics/updates-per-second").await,
+                Event::Update(Value::F64(0.))
+            );
+            let resolvers =
+                subscriber.resolver().list(Path::from("/metrics/resolver-rtt")).await;
+            assert_eq!(resolvers.unwrap().len(), 1);
+            match get("/metrics/pools/updates/capacity").await {
+                Event::Update(Value::U64(n)) => assert!(n > 0),
+                e => panic!("unexpected pool capacity {:?}", e),
+            }
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn write_authorization() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            publisher.set_write_authorization(Box::new(|req, _| match &req.value {
+                Value::U64(13) => Err(WriteDenied::new("unlucky", "13 is unlucky")),
+                _ => Ok(()),
+            }));
+            let (tx, mut rx) = mpsc::channel(10);
+            let v = publisher.publish(Path::from("/app/v"), Value::U64(0)).unwrap();
+            publisher.writes(v.id(), tx);
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let s = subscriber
+                .subscribe_nondurable_one(Path::from("/app/v"), None)
+                .await
+                .unwrap();
+            let r = s.write_with_recipt(Value::U64(13)).await.unwrap();
+            assert_eq!(
+                WriteDenied::from_value(&r),
+                Some(WriteDenied::new("unlucky", "13 is unlucky"))
+            );
+            let r = s.write_with_recipt(Value::U64(42));
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].value, Value::U64(42));
+            batch.clear();
+            assert_eq!(r.await.unwrap(), Value::Ok);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn write_batch() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            let a = publisher.publish(Path::from("/app/a"), Value::U64(0)).unwrap();
+            let b = publisher.publish(Path::from("/app/b"), Value::U64(0)).unwrap();
+            let _c = publisher.publish(Path::from("/app/c"), Value::U64(0)).unwrap();
+            let d = publisher.publish(Path::from("/app/d"), Value::U64(0)).unwrap();
+            let (tx_d, _rx_d) = mpsc::channel(10);
+            publisher.writes(a.id(), tx.clone());
+            publisher.writes(b.id(), tx);
+            publisher.writes(d.id(), tx_d);
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let sa = subscriber.subscribe(Path::from("/app/a"));
+            let sb = subscriber.subscribe(Path::from("/app/b"));
+            let sc = subscriber.subscribe(Path::from("/app/c"));
+            let sd = subscriber.subscribe(Path::from("/app/d"));
+            for s in [&sa, &sb, &sc, &sd] {
+                s.wait_subscribed().await.unwrap();
+            }
+            let mut receipts = subscriber
+                .write_batch([(&sa, Value::U64(1)), (&sb, Value::U64(2))])
+                .unwrap();
+            assert_eq!(receipts.len(), 1);
+            assert_eq!(receipts[0].0, vec![0, 1]);
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(batch.len(), 2);
+            assert!(batch.iter().all(|r| r.atomic));
+            assert_eq!(batch[0].id, a.id());
+            assert_eq!(batch[0].value, Value::U64(1));
+            assert_eq!(batch[1].id, b.id());
+            assert_eq!(batch[1].value, Value::U64(2));
+            batch[1].send_result.take().unwrap().send(Value::U64(42));
+            batch.clear();
+            assert_eq!(receipts.pop().unwrap().1.await.unwrap(), Value::U64(42));
+            // c doesn't accept writes, so none of the batch is delivered
+            let mut receipts = subscriber
+                .write_batch([(&sa, Value::U64(3)), (&sc, Value::U64(4))])
+                .unwrap();
+            assert!(match receipts.pop().unwrap().1.await.unwrap() {
+                Value::Error(_) => true,
+                _ => false,
+            });
+            // a and d are handled by different channels, so the batch
+            // can't be delivered atomically and is rejected
+            let mut receipts = subscriber
+                .write_batch([(&sa, Value::U64(3)), (&sd, Value::U64(4))])
+                .unwrap();
+            assert!(match receipts.pop().unwrap().1.await.unwrap() {
+                Value::Error(_) => true,
+                _ => false,
+            });
+            sa.write(Value::U64(5));
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(batch.len(), 1);
+            assert!(!batch[0].atomic);
+            assert_eq!(batch[0].value, Value::U64(5));
+            batch.clear();
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn subscribe_alias() {
+        use crate::resolver_client::ResolverWrite;
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let _v41 =
+                publisher.publish(Path::from("/prod/v41/a"), Value::U64(41)).unwrap();
+            let _v42 =
+                publisher.publish(Path::from("/prod/v42/a"), Value::U64(42)).unwrap();
+            publisher.flushed().await;
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let current = Path::from("/prod/current");
+            w.set_alias(current.clone(), Path::from("/prod/v41")).await.unwrap();
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            // the alias and it's target are subscribed in the same
+            // batch, over the same connection
+            let paths = [Path::from("/prod/current/a"), Path::from("/prod/v41/a")];
+            let mut subs = subscriber.subscribe_nondurable(paths.into_iter(), None).await;
+            while let Some((path, r)) = subs.next().await {
+                let v = r.unwrap_or_else(|e| panic!("subscribe {} failed {}", path, e));
+                assert_eq!(v.last(), Event::Update(Value::U64(41)));
+            }
+            w.set_alias(current.clone(), Path::from("/prod/v42")).await.unwrap();
+            let dv = subscriber.subscribe(Path::from("/prod/current/a"));
+            dv.wait_subscribed().await.unwrap();
+            assert_eq!(dv.last(), Event::Update(Value::U64(42)));
+            drop(subscriber);
+            drop(publisher);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let file = temp_path("snapshot");
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let a = publisher.publish(Path::from("/app/a"), Value::U64(0)).unwrap();
+            let _b = publisher.publish(Path::from("/app/b"), Value::U64(1)).unwrap();
+            let mut batch = publisher.start_batch();
+            a.update(&mut batch, Value::U64(42));
+            batch.commit(None).await;
+            publisher.snapshot_to(file.clone(), Duration::from_secs(60)).await.unwrap();
+            publisher.shutdown().await;
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .restore_from(Some(file.clone()))
+                .build()
+                .await
+                .unwrap();
+            publisher.flushed().await;
+            let mut stale = publisher.stale();
+            stale.sort();
+            assert_eq!(stale, vec![Path::from("/app/a"), Path::from("/app/b")]);
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let s = subscriber
+                .subscribe_nondurable_one(Path::from("/app/a"), None)
+                .await
+                .unwrap();
+            assert_eq!(s.last(), Event::Update(Value::U64(42)));
+            assert!(s.is_stale());
+            let a = publisher.publish(Path::from("/app/a"), Value::U64(0)).unwrap();
+            assert!(publisher.is_stale(a.id()));
+            assert_eq!(publisher.current(&a.id()), Some(Value::U64(42)));
+            let mut batch = publisher.start_batch();
+            a.update(&mut batch, Value::U64(43));
+            batch.commit(None).await;
+            assert!(!publisher.is_stale(a.id()));
+            for _ in 0..100 {
+                if s.last() == Event::Update(Value::U64(43)) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await
+            }
+            assert_eq!(s.last(), Event::Update(Value::U64(43)));
+            assert!(!s.is_stale());
+            publisher.unpublish_unclaimed();
+            assert_eq!(publisher.stale(), vec![]);
+            assert_eq!(publisher.id("/app/b"), None);
+            let _ = std::fs::remove_file(&file);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn migrate() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let file = temp_path("migrate");
+            let old = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let ov = old.publish(Path::from("/app/v"), Value::U64(1)).unwrap();
+            let _ow = old.publish(Path::from("/app/w"), Value::U64(1)).unwrap();
+            old.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            let dv = subscriber.subscribe(Path::from("/app/v"));
+            dv.updates(UpdatesFlags::BEGIN_WITH_LAST, tx);
+            dv.wait_subscribed().await.unwrap();
+            // non durable subscriptions don't move, and must not hold
+            // up the migration
+            let _w = subscriber
+                .subscribe_nondurable_one(Path::from("/app/w"), None)
+                .await
+                .unwrap();
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].1, Event::Update(Value::U64(1)));
+            batch.clear();
+            old.snapshot_to(file.clone(), Duration::from_secs(60)).await.unwrap();
+            let new = PublisherBuilder::new(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .restore_from(Some(file.clone()))
+                .build()
+                .await
+                .unwrap();
+            new.flushed().await;
+            let start = time::Instant::now();
+            old.migrate(new.addr(), Duration::from_secs(10)).await;
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert_eq!(old.subscribed_len(&ov.id()), 0);
+            assert_eq!(new.clients(), 1);
+            old.shutdown().await;
+            let v = new.publish(Path::from("/app/v"), Value::U64(0)).unwrap();
+            let mut batch = new.start_batch();
+            v.update(&mut batch, Value::U64(2));
+            batch.commit(None).await;
+            loop {
+                let mut batch = rx.next().await.unwrap();
+                for (_, e) in batch.drain(..) {
+                    assert_ne!(e, Event::Unsubscribed);
+                }
+                if dv.last() == Event::Update(Value::U64(2)) {
+                    break;
+                }
+            }
+            let _ = std::fs::remove_file(&file);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn patch() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let mut a = (0..1000).map(Value::U64).collect::<Vec<_>>();
+            let v = publisher
+                .publish(Path::from("/app/v"), Value::Array(Arc::from(a.clone())))
+                .unwrap();
+            publisher.flushed().await;
+            let mut subs = Vec::new();
+            for flags in [UpdatesFlags::empty(), UpdatesFlags::STOP_COLLECTING_LAST] {
+                let subscriber = SubscriberBuilder::new()
+                    .config(client_cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .build()
+                    .unwrap();
+                let s = subscriber
+                    .subscribe_nondurable_one(Path::from("/app/v"), None)
+                    .await
+                    .unwrap();
+                let (tx, rx) = mpsc::channel(10);
+                s.updates(flags, tx);
+                s.flush().await.unwrap();
+                subs.push((subscriber, s, rx));
+            }
+            for i in 0..3 {
+                a[500] = Value::U64(1000 + i);
+                a.push(Value::U64(i));
+                let mut batch = publisher.start_batch();
+                v.update(&mut batch, Value::Array(Arc::from(a.clone())));
+                batch.commit(None).await;
+                let expected = Event::Update(Value::Array(Arc::from(a.clone())));
+                for (_, _, rx) in subs.iter_mut() {
+                    let mut batch = rx.next().await.unwrap();
+                    assert_eq!(batch.len(), 1);
+                    assert_eq!(batch[0].1, expected);
+                    batch.clear();
+                }
+            }
+            assert_eq!(subs[0].1.last(), Event::Update(Value::Array(Arc::from(a))));
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn diagnostics() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = PublisherBuilder::new(client_cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                .build()
+                .await
+                .unwrap();
+            let _v = publisher.publish(Path::from("/app/v"), Value::U64(0)).unwrap();
+            let _w = publisher.publish(Path::from("/app/w"), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .build()
+                .unwrap();
+            let v = subscriber.subscribe(Path::from("/app/v"));
+            v.wait_subscribed().await.unwrap();
+            let _w = subscriber
+                .subscribe_nondurable_one(Path::from("/app/w"), None)
+                .await
+                .unwrap();
+            let _missing = subscriber.subscribe(Path::from("/app/missing"));
+            let info = loop {
+                let mut cons = subscriber.connections();
+                let failed = subscriber
+                    .subscriptions()
+                    .iter()
+                    .any(|s| &*s.path == "/app/missing" && s.resubscribe_attempts > 0);
+                if failed && cons.first().map(|c| c.latency.is_some()).unwrap_or(false) {
+                    break cons.pop().unwrap();
+                }
+                time::sleep(Duration::from_millis(10)).await
+            };
+            assert_eq!(info.addr, publisher.addr());
+            assert!(!info.isolated);
+            assert_eq!(info.subscriptions, 2);
+            assert!(info.bytes_in > 0);
+            assert!(info.bytes_out > 0);
+            assert!(info.last_heartbeat.is_some());
+            let mut subs = subscriber.subscriptions();
+            subs.sort_by(|s0, s1| s0.path.cmp(&s1.path));
+            assert_eq!(subs.len(), 3);
+            assert_eq!(subs[0].path, Path::from("/app/missing"));
+            assert!(subs[0].durable);
+            assert_eq!(subs[0].publisher, None);
+            assert!(subs[0].resubscribe_attempts > 0);
+            assert!(subs[0].last_error.is_some());
+            assert_eq!(subs[1].path, Path::from("/app/v"));
+            assert!(subs[1].durable);
+            assert_eq!(subs[1].publisher, Some(publisher.addr()));
+            assert_eq!(subs[2].path, Path::from("/app/w"));
+            assert!(!subs[2].durable);
+            assert_eq!(subs[2].publisher, Some(publisher.addr()));
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn resubscribe_policy() {
+        let _ = env_logger::try_init();
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/simple-server.json")
+                .expect("load simple server config");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let subscriber = SubscriberBuilder::new()
+                .config(client_cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .resubscribe_policy(ResubscribePolicy {
+                    backoff: Backoff::Exponential {
+                        base: Duration::from_millis(10),
+                        max: Duration::from_millis(100),
+                    },
+                    max_attempts: Some(3),
+                    max_concurrent: 1,
+                })
+                .build()
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            let dv = subscriber.subscribe(Path::from("/app/missing"));
+            dv.updates(UpdatesFlags::RESUBSCRIBE_STATUS, tx);
+            let mut status = vec![];
+            while status.last() != Some(&ResubscribeStatus::GaveUp) {
+                let mut batch = time::timeout(Duration::from_secs(30), rx.next())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                for (_, ev) in batch.drain(..) {
+                    match ev {
+                        Event::Update(v) => {
+                            status.push(ResubscribeStatus::from_value(&v).unwrap())
+                        }
+                        Event::Unsubscribed => (),
+                    }
+                }
+            }
+            assert_eq!(
+                status,
+                vec![
+                    ResubscribeStatus::Retrying(1),
+                    ResubscribeStatus::Retrying(2),
+                    ResubscribeStatus::GaveUp
+                ]
+            );
+            assert!(dv.wait_subscribed().await.is_err());
+            assert_eq!(dv.last(), Event::Unsubscribed);
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn harness_faults() {
+        async fn next<T>(rx: &mut mpsc::Receiver<T>) -> T {
+            time::timeout(Duration::from_secs(30), rx.next()).await.unwrap().unwrap()
+        }
+        let _ = env_logger::try_init();
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            // nothing below touches the network, so every wait follows
+            // the paused clock and takes no real time
+            time::pause();
+            let harness = Harness::new().await.unwrap();
+            let publisher = harness.publisher().await.unwrap();
+            let subscriber = harness
+                .subscriber_builder()
+                .resubscribe_policy(ResubscribePolicy {
+                    backoff: Backoff::Linear {
+                        step: Duration::from_millis(10),
+                        max: Duration::from_millis(100),
+                    },
+                    ..ResubscribePolicy::default()
+                })
+                .build()
+                .unwrap();
+            let v = publisher.publish(Path::from("/app/v"), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            let (tx, mut rx) = mpsc::channel(10);
+            let dv = subscriber.subscribe_updates(
+                Path::from("/app/v"),
+                [(UpdatesFlags::BEGIN_WITH_LAST, tx)],
+            );
+            assert_eq!(next(&mut rx).await[0].1, Event::Update(Value::U64(1)));
+            // the connection is reset, the subscriber reconnects at once
+            harness.drop_connections(&publisher);
+            assert_eq!(next(&mut rx).await[0].1, Event::Unsubscribed);
+            assert_eq!(next(&mut rx).await[0].1, Event::Update(Value::U64(1)));
+            // updates are held during a delay and delivered after it
+            harness.delay(&publisher, Duration::from_millis(200));
+            let mut batch = publisher.start_batch();
+            v.update(&mut batch, Value::U64(2));
+            batch.commit(None).await;
+            time::advance(Duration::from_millis(100)).await;
+            assert!(rx.next().now_or_never().is_none());
+            time::advance(Duration::from_millis(100)).await;
+            assert_eq!(next(&mut rx).await[0].1, Event::Update(Value::U64(2)));
+            // a partitioned publisher can't be reached until it heals
+            harness.partition(&publisher);
+            assert_eq!(next(&mut rx).await[0].1, Event::Unsubscribed);
+            let mut batch = publisher.start_batch();
+            v.update(&mut batch, Value::U64(3));
+            batch.commit(None).await;
+            // let the subscriber fail to resubscribe for a while
+            time::sleep(Duration::from_secs(1)).await;
+            assert!(dv.wait_subscribed().now_or_never().is_none());
+            harness.heal(&publisher);
+            assert_eq!(next(&mut rx).await[0].1, Event::Update(Value::U64(3)));
+            assert_eq!(dv.last(), Event::Update(Value::U64(3)));
+        });
+    }
+
+    #[test]
+    fn user_limits() {
+        use crate::{resolver_client::ResolverRead, token::KeySet};
+        let _ = env_logger::try_init();
+        let keys = KeySet::load("../cfg/token/keys.json").expect("load key set");
+        let dir = temp_path("user-limits");
+        std::fs::create_dir_all(&dir).unwrap();
+        let auth = token_auth(&keys, &dir, "quota", "k1");
+        let server_cfg = server_config("../cfg/token/resolver.json", |cfg| {
+            cfg["member_servers"][0]["limits"] = json!({
+                "default": { "max_published": 2, "reads_per_second": 2 },
+                "admins": ["quota"]
+            })
+        });
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut client_cfg = ClientConfig::load("../cfg/token/client.json")
+                .expect("load token client config");
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let publisher = |auth| {
+                let cfg = client_cfg.clone();
+                async move {
+                    PublisherBuilder::new(cfg)
+                        .desired_auth(auth)
+                        .bind_cfg(Some("127.0.0.1/32".parse().unwrap()))
+                        .build()
+                        .await
+                }
+            };
+            // both publishers are the same user, so they share a quota
+            let p0 = publisher(auth.clone()).await.unwrap();
+            let p1 = publisher(auth.clone()).await.unwrap();
+            let _a = p0.publish(Path::from("/app/a"), Value::U64(0)).unwrap();
+            let _b = p0.publish(Path::from("/app/b"), Value::U64(0)).unwrap();
+            p0.flushed().await;
+            let _c = p1.publish(Path::from("/app/c"), Value::U64(0)).unwrap();
+            p1.flushed().await;
+            let r = ResolverRead::new(client_cfg, auth);
+            let mut usage = r.usage().await.unwrap();
+            usage.sort_by_key(|u| u.published);
+            assert_eq!(usage.len(), 2);
+            assert_eq!(usage[0].publisher, p1.addr());
+            assert_eq!(usage[0].published, 0);
+            assert_eq!(usage[1].published, 2);
+            assert!(usage.iter().all(|u| u.user_published == 2));
+            // the rest of the batch is rejected once the user's reads
+            // run out
+            time::sleep(Duration::from_secs(1)).await;
+            let paths = ["/app/a", "/app/b", "/app/c"].map(Path::from);
+            assert!(r.resolve(paths).await.is_err());
+            drop(server)
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,297 @@
+//! A harness for testing applications built on netidx, available
+//! with the `testing` feature.
+//!
+//! `Harness` starts a resolver server inside the current process and
+//! builds publishers and subscribers that use it. Publishers and
+//! subscribers talk to the resolver, and to each other, over an in
+//! memory transport instead of TCP, and that transport can be broken
+//! on demand to test failover and durable resubscription without
+//! timing dependent socket tricks.
+//!
+//! ```no_run
+//! # async fn f() -> anyhow::Result<()> {
+//! use netidx::{path::Path, testing::Harness};
+//! let harness = Harness::new().await?;
+//! let publisher = harness.publisher().await?;
+//! let subscriber = harness.subscriber()?;
+//! let _v = publisher.publish(Path::from("/test/v"), 42u64)?;
+//! publisher.flushed().await;
+//! let dv = subscriber.subscribe(Path::from("/test/v"));
+//! dv.wait_subscribed().await?;
+//! // the subscription dies, and is resubscribed after the partition heals
+//! harness.partition(&publisher);
+//! harness.heal(&publisher);
+//! # Ok(()) }
+//! ```
+//!
+//! No harness traffic touches the network, so the tokio clock
+//! controls reexported here work as expected. Run the test on a
+//! current thread runtime and `pause` the clock, then heartbeats,
+//! timeouts, and resubscription backoff all follow the paused clock,
+//! which you can move with `advance`, or which the runtime advances
+//! by itself whenever every task is waiting for a timer.
+use crate::{
+    config::Config,
+    publisher::{BindCfg, Publisher, PublisherBuilder},
+    resolver_client::DesiredAuth,
+    resolver_server::{config::Config as ServerConfig, Server},
+    subscriber::{Subscriber, SubscriberBuilder},
+};
+use anyhow::Result;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+    select_biased,
+};
+use fxhash::FxHashMap;
+use parking_lot::Mutex;
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::watch,
+    task, time,
+};
+
+pub use tokio::time::{advance, pause, resume};
+
+const BUF: usize = 1 << 16;
+
+static SERVER_CFG: &str = r#"{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": "Anonymous"
+    }
+  ],
+  "perms": {}
+}"#;
+
+static CLIENT_CFG: &str = r#"{
+  "addrs": [["127.0.0.1:0", "Anonymous"]],
+  "base": "/"
+}"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Up,
+    Stalled,
+    Down,
+}
+
+// A publisher or resolver accepting connections over the in memory
+// transport. Every connection shares the link, when the link goes
+// down it is replaced so that new connections start out up.
+struct Listener {
+    connections: UnboundedSender<DuplexStream>,
+    partitioned: bool,
+    link: watch::Sender<Link>,
+}
+
+lazy_static! {
+    static ref LISTENERS: Mutex<FxHashMap<SocketAddr, Listener>> =
+        Mutex::new(FxHashMap::default());
+}
+
+/// Accept in memory connections to `addr`
+pub(crate) fn listen(addr: SocketAddr) -> UnboundedReceiver<DuplexStream> {
+    let (tx, rx) = mpsc::unbounded();
+    let (link, _) = watch::channel(Link::Up);
+    LISTENERS.lock().insert(addr, Listener { connections: tx, partitioned: false, link });
+    rx
+}
+
+async fn down(link: &mut watch::Receiver<Link>) {
+    loop {
+        if *link.borrow() == Link::Down {
+            break;
+        }
+        if link.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+// returns false if the link went down while stalled
+async fn unstalled(link: &mut watch::Receiver<Link>) -> bool {
+    loop {
+        let state = *link.borrow();
+        match state {
+            Link::Up => break true,
+            Link::Down => break false,
+            Link::Stalled => (),
+        }
+        if link.changed().await.is_err() {
+            break false;
+        }
+    }
+}
+
+// copy from r to w until either side closes or the link goes down
+async fn pump<R, W>(mut r: R, mut w: W, mut link: watch::Receiver<Link>, stall: bool)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUF];
+    loop {
+        let n = select_biased! {
+            () = down(&mut link).fuse() => break,
+            res = r.read(&mut buf).fuse() => match res {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            },
+        };
+        if stall && !unstalled(&mut link).await {
+            break;
+        }
+        select_biased! {
+            () = down(&mut link).fuse() => break,
+            res = w.write_all(&buf[..n]).fuse() => if res.is_err() {
+                break
+            },
+        }
+    }
+    let _ = w.shutdown().await;
+}
+
+/// Connect to `addr` over the in memory transport. None if nothing is
+/// listening on `addr` in memory, in which case the caller should use
+/// TCP.
+pub(crate) fn connect(addr: SocketAddr) -> Option<Result<DuplexStream>> {
+    let mut listeners = LISTENERS.lock();
+    let l = listeners.get(&addr)?;
+    if l.connections.is_closed() {
+        listeners.remove(&addr);
+        return None;
+    }
+    if l.partitioned {
+        let e = io::Error::new(io::ErrorKind::ConnectionRefused, "partitioned");
+        return Some(Err(e.into()));
+    }
+    let (subscriber, sub_relay) = tokio::io::duplex(BUF);
+    let (publisher, pub_relay) = tokio::io::duplex(BUF);
+    if l.connections.unbounded_send(publisher).is_err() {
+        listeners.remove(&addr);
+        return None;
+    }
+    let (sub_rx, sub_tx) = tokio::io::split(sub_relay);
+    let (pub_rx, pub_tx) = tokio::io::split(pub_relay);
+    task::spawn(pump(sub_rx, pub_tx, l.link.subscribe(), false));
+    task::spawn(pump(pub_rx, sub_tx, l.link.subscribe(), true));
+    Some(Ok(subscriber))
+}
+
+fn with_listener(publisher: &Publisher, f: impl FnOnce(&mut Listener)) {
+    if let Some(l) = LISTENERS.lock().get_mut(&publisher.addr()) {
+        f(l)
+    }
+}
+
+fn drop_link(l: &mut Listener) {
+    let _ = l.link.send(Link::Down);
+    l.link = watch::channel(Link::Up).0;
+}
+
+/// An in process resolver server, and the means to build publishers
+/// and subscribers that use it. Dropping the harness stops the
+/// resolver server.
+pub struct Harness {
+    config: Config,
+    _server: Server,
+}
+
+impl Harness {
+    /// Start a resolver server on the loopback interface with
+    /// anonymous authentication.
+    pub async fn new() -> Result<Harness> {
+        let server = Server::new_loopback(ServerConfig::parse(SERVER_CFG)?, 0).await?;
+        let mut config = Config::parse(CLIENT_CFG)?;
+        config.addrs[0].0 = *server.local_addr();
+        Ok(Harness { config, _server: server })
+    }
+
+    /// The client config for the harness resolver
+    pub fn config(&self) -> Config {
+        self.config.clone()
+    }
+
+    /// A builder for a publisher that accepts subscribers over the
+    /// in memory transport.
+    pub fn publisher_builder(&self) -> PublisherBuilder {
+        let mut b = PublisherBuilder::new(self.config());
+        b.desired_auth(DesiredAuth::Anonymous)
+            .bind_cfg(Some("127.0.0.1/32".parse::<BindCfg>().unwrap()))
+            .loopback(true);
+        b
+    }
+
+    /// Build a publisher with the defaults of `publisher_builder`
+    pub async fn publisher(&self) -> Result<Publisher> {
+        self.publisher_builder().build().await
+    }
+
+    /// A builder for a subscriber that uses the harness resolver
+    pub fn subscriber_builder(&self) -> SubscriberBuilder {
+        let mut b = SubscriberBuilder::new();
+        b.config(self.config()).desired_auth(DesiredAuth::Anonymous);
+        b
+    }
+
+    /// Build a subscriber with the defaults of `subscriber_builder`
+    pub fn subscriber(&self) -> Result<Subscriber> {
+        self.subscriber_builder().build()
+    }
+
+    /// Close every in memory connection to `publisher`, as if the
+    /// network had reset them. Subscribers are free to reconnect.
+    pub fn drop_connections(&self, publisher: &Publisher) {
+        with_listener(publisher, drop_link)
+    }
+
+    /// Delay heartbeats from `publisher` to its current subscribers
+    /// by `duration`. Heartbeats share the connection with updates and
+    /// write replies, so all traffic from the publisher is held, not
+    /// just heartbeats. Held traffic is delivered in order when the
+    /// delay ends, unless the connection is dropped first. Traffic
+    /// from subscribers is not held.
+    pub fn delay(&self, publisher: &Publisher, duration: Duration) {
+        let addr = publisher.addr();
+        with_listener(publisher, |l| {
+            let _ = l.link.send(Link::Stalled);
+        });
+        task::spawn(async move {
+            time::sleep(duration).await;
+            if let Some(l) = LISTENERS.lock().get_mut(&addr) {
+                if *l.link.borrow() == Link::Stalled {
+                    let _ = l.link.send(Link::Up);
+                }
+            }
+        });
+    }
+
+    /// Cut `publisher` off from subscribers. Existing connections are
+    /// dropped, and new connections are refused until `heal` is
+    /// called. The publisher stays registered in the resolver, so
+    /// subscribers still try to reach it. The resolver can't reach
+    /// the publisher either, so if the publisher uses an
+    /// authentication mechanism that requires the resolver to check it
+    /// owns its address, then it can't register again until healed.
+    pub fn partition(&self, publisher: &Publisher) {
+        with_listener(publisher, |l| {
+            l.partitioned = true;
+            drop_link(l)
+        })
+    }
+
+    /// Allow subscribers to connect to `publisher` again
+    pub fn heal(&self, publisher: &Publisher) {
+        with_listener(publisher, |l| l.partitioned = false)
+    }
+}
@@ -6,10 +6,11 @@ use parking_lot::Mutex;
 use smallvec::SmallVec;
 use std::{
     collections::{BTreeMap, HashMap},
-    fmt, mem,
+    fmt, fs, mem,
     sync::Arc,
+    time::SystemTime,
 };
-use x509_parser::prelude::GeneralName;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
 pub(crate) fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
     use std::{fs, io::BufReader};
@@ -20,6 +21,217 @@ pub(crate) fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
         .collect())
 }
 
+/// Load the certificate revocation lists in `path`, which may be a
+/// pem file containing any number of CRLs, or a single DER encoded
+/// CRL.
+pub(crate) fn load_crls(
+    path: &str,
+) -> Result<Vec<rustls::server::UnparsedCertRevocationList>> {
+    use std::io::BufReader;
+    let data = fs::read(path)?;
+    let mut crls = rustls_pemfile::crls(&mut BufReader::new(&data[..]))
+        .context("parsing crl pem")?;
+    if crls.is_empty() {
+        crls.push(data)
+    }
+    let crls = crls.into_iter().map(rustls::server::UnparsedCertRevocationList);
+    let crls = crls.collect::<Vec<_>>();
+    for crl in &crls {
+        crl.parse().map_err(|e| anyhow!("invalid crl {}: {:?}", path, e))?;
+    }
+    Ok(crls)
+}
+
+/// A field of a certificate from which a name can be taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CertField {
+    /// The subject common name
+    CommonName,
+    /// The subject organizational unit(s)
+    OrganizationalUnit,
+    /// The subject attribute(s) with the specified OID in dotted
+    /// decimal form, e.g. "0.9.2342.19200300.100.1.1" (uid)
+    Attribute(String),
+    /// The DNS subjectAltName(s)
+    Dns,
+    /// The URI subjectAltName(s) that begin with the specified
+    /// prefix. The name is the rest of the URI after the prefix,
+    /// e.g. with the prefix "spiffe://example.com/user/" the URI
+    /// "spiffe://example.com/user/eric" yields "eric"
+    Uri(String),
+}
+
+impl CertField {
+    /// Check that the field is well formed
+    pub fn check(&self) -> Result<()> {
+        use std::str::FromStr;
+        use x509_parser::der_parser::oid::Oid;
+        match self {
+            Self::Attribute(oid) => match Oid::from_str(oid) {
+                Ok(_) => Ok(()),
+                Err(e) => bail!("invalid oid {}: {:?}", oid, e),
+            },
+            Self::CommonName | Self::OrganizationalUnit | Self::Dns | Self::Uri(_) => {
+                Ok(())
+            }
+        }
+    }
+
+    fn get(&self, cert: &X509Certificate, names: &mut Vec<String>) -> Result<()> {
+        use std::str::FromStr;
+        use x509_parser::{der_parser::oid::Oid, oid_registry};
+        let mut attr = |oid: &Oid| {
+            for a in cert.subject().iter_by_oid(oid) {
+                if let Ok(s) = a.as_str() {
+                    names.push(String::from(s))
+                }
+            }
+        };
+        match self {
+            Self::CommonName => attr(&oid_registry::OID_X509_COMMON_NAME),
+            Self::OrganizationalUnit => attr(&oid_registry::OID_X509_ORGANIZATIONAL_UNIT),
+            Self::Attribute(oid) => match Oid::from_str(oid) {
+                Ok(oid) => attr(&oid),
+                Err(e) => bail!("invalid oid {}: {:?}", oid, e),
+            },
+            Self::Dns | Self::Uri(_) => {
+                let alt_names = match cert.subject_alternative_name()? {
+                    None => return Ok(()),
+                    Some(ext) => &ext.value.general_names,
+                };
+                for gn in alt_names {
+                    match (self, gn) {
+                        (Self::Dns, GeneralName::DNSName(d)) => names.push(d.to_string()),
+                        (Self::Uri(prefix), GeneralName::URI(u)) => {
+                            if let Some(name) = u.strip_prefix(prefix.as_str()) {
+                                if !name.is_empty() {
+                                    names.push(String::from(name))
+                                }
+                            }
+                        }
+                        (_, _) => (),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The user and groups of a certificate according to a set of rules
+#[derive(Debug)]
+pub struct CertIdentity {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+/// Find the identity of the DER encoded `cert`. The user is the
+/// first name found by trying each field in `user` in order, and
+/// the groups are all the names found in all the fields in
+/// `groups`.
+pub(crate) fn get_identity(
+    cert: &[u8],
+    user: &[CertField],
+    groups: &[CertField],
+) -> Result<CertIdentity> {
+    let (_, cert) = X509Certificate::from_der(cert)?;
+    let mut names = vec![];
+    for f in user {
+        f.get(&cert, &mut names)?;
+        if !names.is_empty() {
+            break;
+        }
+    }
+    let user = match names.drain(..).next() {
+        Some(user) => user,
+        None => bail!("no user name could be found in the certificate"),
+    };
+    for f in groups {
+        f.get(&cert, &mut names)?;
+    }
+    names.sort();
+    names.dedup();
+    Ok(CertIdentity { user, groups: names })
+}
+
+/// Checks the server certificate against a set of certificate
+/// revocation lists in addition to the usual verification.
+struct CrlVerifier {
+    inner: rustls::client::WebPkiVerifier,
+    roots: Vec<rustls::Certificate>,
+    crls: Vec<rustls_webpki::OwnedCertRevocationList>,
+}
+
+impl rustls::client::ServerCertVerifier for CrlVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        use rustls::CertificateError;
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pki_error = |e: rustls_webpki::Error| match e {
+            rustls_webpki::Error::CertRevoked => {
+                rustls::Error::InvalidCertificate(CertificateError::Revoked)
+            }
+            e => rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(e))),
+        };
+        let anchors = self
+            .roots
+            .iter()
+            .map(|c| rustls_webpki::TrustAnchor::try_from_cert_der(&c.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pki_error)?;
+        let intermediates = intermediates.iter().map(|c| &c.0[..]).collect::<Vec<_>>();
+        let crls = self
+            .crls
+            .iter()
+            .map(|crl| crl as &dyn rustls_webpki::CertRevocationList)
+            .collect::<Vec<_>>();
+        let time = rustls_webpki::Time::try_from(now)
+            .map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        rustls_webpki::EndEntityCert::try_from(&end_entity.0[..])
+            .and_then(|cert| {
+                cert.verify_for_usage(
+                    SUPPORTED_SIG_ALGS,
+                    &anchors,
+                    &intermediates,
+                    time,
+                    rustls_webpki::KeyUsage::server_auth(),
+                    &crls,
+                )
+            })
+            .map_err(pki_error)?;
+        Ok(verified)
+    }
+}
+
+static SUPPORTED_SIG_ALGS: &[&rustls_webpki::SignatureAlgorithm] = &[
+    &rustls_webpki::ECDSA_P256_SHA256,
+    &rustls_webpki::ECDSA_P256_SHA384,
+    &rustls_webpki::ECDSA_P384_SHA256,
+    &rustls_webpki::ECDSA_P384_SHA384,
+    &rustls_webpki::ED25519,
+    &rustls_webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &rustls_webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &rustls_webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &rustls_webpki::RSA_PKCS1_2048_8192_SHA256,
+    &rustls_webpki::RSA_PKCS1_2048_8192_SHA384,
+    &rustls_webpki::RSA_PKCS1_2048_8192_SHA512,
+    &rustls_webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
 #[derive(Debug)]
 pub struct Names {
     /// this can be anything and will be used to identify the user via map-ids
@@ -170,21 +382,41 @@ pub fn load_private_key(askpass: Option<&str>, path: &str) -> Result<rustls::Pri
 pub(crate) fn create_tls_connector(
     askpass: Option<&str>,
     root_certificates: &str,
+    crl: Option<&str>,
     certificate: &str,
     private_key: &str,
 ) -> Result<tokio_rustls::TlsConnector> {
+    let roots = load_certs(root_certificates).context("loading root certs")?;
     let mut root_store = rustls::RootCertStore::empty();
-    for cert in load_certs(root_certificates).context("loading root certs")? {
-        root_store.add(&cert).context("adding root cert")?;
+    for cert in &roots {
+        root_store.add(cert).context("adding root cert")?;
     }
     let certs = load_certs(certificate).context("loading user cert")?;
     let private_key =
         load_private_key(askpass, private_key).context("loading user private key")?;
-    let mut config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_client_auth_cert(certs, private_key)
-        .context("building rustls client config")?;
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let config = match crl {
+        None => builder
+            .with_root_certificates(root_store)
+            .with_client_auth_cert(certs, private_key),
+        Some(crl) => {
+            let crls = load_crls(crl).context("loading crls")?;
+            let crls = crls
+                .into_iter()
+                .map(|crl| crl.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow!("parsing crls {:?}", e))?;
+            let inner = rustls::client::WebPkiVerifier::new(root_store, None);
+            builder
+                .with_custom_certificate_verifier(Arc::new(CrlVerifier {
+                    inner,
+                    roots,
+                    crls,
+                }))
+                .with_client_auth_cert(certs, private_key)
+        }
+    };
+    let mut config = config.context("building rustls client config")?;
     config.resumption = rustls::client::Resumption::in_memory_sessions(256);
     Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
 }
@@ -192,6 +424,7 @@ pub(crate) fn create_tls_connector(
 pub(crate) fn create_tls_acceptor(
     askpass: Option<&str>,
     root_certificates: &str,
+    crl: Option<&str>,
     certificate: &str,
     private_key: &str,
 ) -> Result<tokio_rustls::TlsAcceptor> {
@@ -202,7 +435,15 @@ pub(crate) fn create_tls_acceptor(
         for cert in load_certs(root_certificates)? {
             root_store.add(&cert)?;
         }
-        rustls::server::AllowAnyAuthenticatedClient::new(root_store).boxed()
+        let verifier = rustls::server::AllowAnyAuthenticatedClient::new(root_store);
+        match crl {
+            None => verifier.boxed(),
+            Some(crl) => {
+                debug!("loading certificate revocation lists");
+                let crls = load_crls(crl)?;
+                verifier.with_crls(crls).map_err(|e| anyhow!("{:?}", e))?.boxed()
+            }
+        }
     };
     debug!("loading server certificate");
     let certs = load_certs(certificate)?;
@@ -230,9 +471,91 @@ pub(crate) fn get_match<'a: 'b, 'b, U>(
     })
 }
 
+type Builder<T> = fn(Option<&str>, &str, Option<&str>, &str, &str) -> Result<T>;
+
+/// A tls context built from a set of files that is rebuilt when any
+/// of the files' modification time changes. Sessions established
+/// with the previous context are unaffected, only new connections
+/// use the new material. If the rebuild fails the error is logged and
+/// the previous context continues to be used until the files change
+/// again.
+pub(crate) struct Reloading<T> {
+    askpass: Option<String>,
+    trusted: String,
+    crl: Option<String>,
+    certificate: String,
+    private_key: String,
+    f: Builder<T>,
+    cur: Mutex<(Vec<SystemTime>, T)>,
+}
+
+impl<T: Clone> Reloading<T> {
+    /// The modification times of all the files, or None if any of
+    /// them could not be read.
+    fn stamp(&self) -> Option<Vec<SystemTime>> {
+        let files = [&self.trusted, &self.certificate, &self.private_key];
+        files
+            .into_iter()
+            .chain(self.crl.as_ref())
+            .map(|path| match fs::metadata(path).and_then(|m| m.modified()) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    warn!("failed to stat {}: {}", path, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn new(
+        askpass: Option<&str>,
+        trusted: &str,
+        crl: Option<&str>,
+        certificate: &str,
+        private_key: &str,
+        f: Builder<T>,
+    ) -> Result<Self> {
+        let t = f(askpass, trusted, crl, certificate, private_key)?;
+        let mut t = Self {
+            askpass: askpass.map(String::from),
+            trusted: String::from(trusted),
+            crl: crl.map(String::from),
+            certificate: String::from(certificate),
+            private_key: String::from(private_key),
+            f,
+            cur: Mutex::new((vec![], t)),
+        };
+        t.cur.get_mut().0 = t.stamp().unwrap_or_default();
+        Ok(t)
+    }
+
+    /// Get the current context, rebuilding it first if any of the
+    /// files have changed. This does blocking io.
+    pub(crate) fn load(&self) -> T {
+        let stamp = self.stamp();
+        let mut cur = self.cur.lock();
+        let stamp = match stamp {
+            Some(stamp) if stamp != cur.0 => stamp,
+            Some(_) | None => return cur.1.clone(),
+        };
+        info!("reloading tls material from {}", self.certificate);
+        let askpass = self.askpass.as_ref().map(|s| s.as_str());
+        let crl = self.crl.as_ref().map(|s| s.as_str());
+        match (self.f)(askpass, &self.trusted, crl, &self.certificate, &self.private_key)
+        {
+            Ok(t) => *cur = (stamp, t),
+            Err(e) => {
+                warn!("failed to reload tls material from {}: {:?}", self.certificate, e);
+                cur.0 = stamp;
+            }
+        }
+        cur.1.clone()
+    }
+}
+
 struct CachedInnerLocked<T> {
     tmp: String,
-    cached: BTreeMap<String, T>,
+    cached: BTreeMap<String, Arc<Reloading<T>>>,
 }
 
 struct CachedInner<T> {
@@ -268,18 +591,16 @@ impl<T: Clone + 'static> Cached<T> {
         self.0.tls.identities.get(id)
     }
 
-    fn load(
-        &self,
-        identity: &str,
-        f: fn(Option<&str>, &str, &str, &str) -> Result<T>,
-    ) -> Result<T> {
+    fn load(&self, identity: &str, f: Builder<T>) -> Result<T> {
         let rev_identity = {
             let mut inner = self.0.t.lock();
             inner.tmp.clear();
             inner.tmp.push_str(&identity);
             Tls::reverse_domain_name(&mut inner.tmp);
             if let Some(v) = get_match(&inner.cached, &inner.tmp) {
-                return Ok(v.clone());
+                let v = Arc::clone(v);
+                drop(inner);
+                return Ok(v.load());
             }
             mem::replace(&mut inner.tmp, String::new())
         };
@@ -288,11 +609,14 @@ impl<T: Clone + 'static> Cached<T> {
                 self.0.t.lock().tmp = rev_identity;
                 bail!("no plausible identity matches {}", identity)
             }
-            Some(TlsIdentity { name: _, trusted, certificate, private_key }) => {
+            Some(TlsIdentity { name: _, trusted, crl, certificate, private_key }) => {
                 let askpass = self.0.tls.askpass.as_ref().map(|s| s.as_str());
-                let con = f(askpass, trusted, certificate, private_key)?;
-                self.0.t.lock().cached.insert(rev_identity, con.clone());
-                Ok(con)
+                let crl = crl.as_ref().map(|s| s.as_str());
+                let con =
+                    Reloading::new(askpass, trusted, crl, certificate, private_key, f)?;
+                let t = con.load();
+                self.0.t.lock().cached.insert(rev_identity, Arc::new(con));
+                Ok(t)
             }
         }
     }
@@ -350,5 +674,153 @@ mod test {
         let r = get_match(&m, "com.mydomain.qux.").copied();
         assert_eq!(r, Some(1));
     }
+
+    #[test]
+    fn test_reload() {
+        use std::{
+            fs,
+            time::{Duration, SystemTime},
+        };
+        fn certs(
+            _: Option<&str>,
+            _: &str,
+            _: Option<&str>,
+            cert: &str,
+            _: &str,
+        ) -> Result<Vec<u8>> {
+            match load_certs(cert)?.pop() {
+                None => bail!("no certificate"),
+                Some(c) => Ok(c.0),
+            }
+        }
+        let dir = std::env::temp_dir()
+            .join(format!("netidx-test-tls-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cert.pem");
+        let path = path.to_str().unwrap();
+        let pem = |b64: &str| {
+            format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", b64)
+        };
+        // give each version a distinct mtime, regardless of the
+        // resolution of the filesystem clock
+        let write = |n: u64, contents: &str| {
+            fs::write(path, contents).unwrap();
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + n);
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        write(0, &pem("AAEC"));
+        let r = Reloading::new(None, path, None, path, path, certs).unwrap();
+        assert_eq!(r.load(), vec![0, 1, 2]);
+        write(1, &pem("AwQF"));
+        assert_eq!(r.load(), vec![3, 4, 5]);
+        // a failed reload keeps the previous material
+        write(2, "garbage");
+        assert_eq!(r.load(), vec![3, 4, 5]);
+        write(3, &pem("BgcI"));
+        assert_eq!(r.load(), vec![6, 7, 8]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cert_identity() {
+        let cert = load_certs("../cfg/tls/spiffe/certificate").unwrap().pop().unwrap();
+        let uri = CertField::Uri("spiffe://example.com/user/".into());
+        let id = get_identity(&cert.0, &[CertField::CommonName], &[]).unwrap();
+        assert_eq!(id.user, "spiffe.example.com");
+        assert!(id.groups.is_empty());
+        let groups =
+            [CertField::OrganizationalUnit, CertField::Attribute("2.5.4.10".into())];
+        let id = get_identity(&cert.0, &[uri.clone()], &groups).unwrap();
+        assert_eq!(id.user, "alice");
+        assert_eq!(id.groups, vec!["dev", "example", "ops"]);
+        // the first field that is present is used
+        let user = [CertField::Uri("spiffe://other.com/".into()), CertField::Dns];
+        let id = get_identity(&cert.0, &user, &[]).unwrap();
+        assert_eq!(id.user, "spiffe.example.com");
+        // certificates that don't match any rule have no user
+        let cert = load_certs("../cfg/tls/client/certificate").unwrap().pop().unwrap();
+        assert!(get_identity(&cert.0, &[uri], &[]).is_err());
+        assert!(CertField::Attribute("not an oid".into()).check().is_err());
+    }
+
+    #[test]
+    fn test_crl() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+            runtime::Runtime,
+            task,
+        };
+        const CA: &str = "../cfg/tls/ca/certificate";
+        const CRL: Option<&str> = Some("../cfg/tls/ca/crl");
+        // the client and resolver certificates issued for the crl
+        // tests live under crl, their keys are shared
+        fn id(name: &str) -> (String, String) {
+            let cert = match name {
+                "client" | "resolver" => format!("../cfg/tls/crl/{}/certificate", name),
+                name => format!("../cfg/tls/{}/certificate", name),
+            };
+            (cert, format!("../cfg/tls/{}/private.key", name))
+        }
+        // returns the result of the handshake on the server and client
+        async fn handshake(
+            server: (&str, Option<&str>),
+            client: (&str, Option<&str>),
+            name: &str,
+        ) -> (Result<()>, Result<()>) {
+            let (cert, key) = id(server.0);
+            let acceptor = create_tls_acceptor(None, CA, server.1, &cert, &key).unwrap();
+            let (cert, key) = id(client.0);
+            let connector =
+                create_tls_connector(None, CA, client.1, &cert, &key).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = task::spawn(async move {
+                let (con, _) = listener.accept().await?;
+                let mut con = acceptor.accept(con).await?;
+                con.write_all(b"x").await?;
+                con.flush().await?;
+                Ok::<_, anyhow::Error>(())
+            });
+            let name = rustls::ServerName::try_from(name).unwrap();
+            let client = async move {
+                let con = TcpStream::connect(addr).await?;
+                let mut con = connector.connect(name, con).await?;
+                con.read_u8().await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            let client = client.await;
+            (server.await.unwrap(), client)
+        }
+        let _ = env_logger::try_init();
+        assert!(load_crls(CRL.unwrap()).unwrap().len() == 1);
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let resolver = "resolver.example.com";
+            let (s, c) = handshake(("resolver", CRL), ("client", CRL), resolver).await;
+            assert!(s.is_ok() && c.is_ok());
+            // revoked client certificates are rejected by the acceptor
+            let (s, c) = handshake(("resolver", CRL), ("revoked", None), resolver).await;
+            assert!(s.is_err() && c.is_err());
+            let (s, c) = handshake(("resolver", None), ("revoked", None), resolver).await;
+            assert!(s.is_ok() && c.is_ok());
+            // revoked server certificates are rejected by the connector
+            let revoked = "revoked.example.com";
+            let (s, c) = handshake(("revoked", None), ("client", CRL), revoked).await;
+            assert!(s.is_err() && c.is_err());
+            let (s, c) = handshake(("revoked", None), ("client", None), revoked).await;
+            assert!(s.is_ok() && c.is_ok());
+            // certificates not signed by the trusted CA are rejected
+            let (s, c) = handshake(("resolver", CRL), ("untrusted", CRL), resolver).await;
+            assert!(s.is_err() && c.is_err());
+            let untrusted = "untrusted.example.com";
+            let (s, c) = handshake(("untrusted", CRL), ("client", CRL), untrusted).await;
+            assert!(s.is_err() && c.is_err());
+            // the server name must match the certificate
+            let (s, c) = handshake(("resolver", CRL), ("client", CRL), revoked).await;
+            assert!(s.is_err() && c.is_err());
+        })
+    }
 }
 
@@ -0,0 +1,174 @@
+//! Shared secret bearer tokens.
+//!
+//! A token carries a user, their groups, and an expiration time, and
+//! is signed with HMAC-SHA3-512 using one of the keys in a `KeySet`
+//! shared between whoever issues tokens and the resolver
+//! servers. Keys are named, and a token records the name of the key
+//! that signed it, so keys can be rotated by adding a new key,
+//! issuing new tokens with it, and removing the old key once all the
+//! tokens it signed have expired.
+//!
+//! The encoded form of a token is
+//! `base64url(json(claims)).base64url(hmac)`.
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use bytes::Bytes;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha3::Sha3_512;
+use std::{collections::BTreeMap, env, fs, path::Path, time::Duration};
+
+type HmacSha3 = Hmac<Sha3_512>;
+
+/// The maximum size of an encoded token
+pub const TOKEN_MAX: usize = 16384;
+
+/// The environment variable clients read their token from if no
+/// token file is specified.
+pub const TOKEN_ENV: &str = "NETIDX_TOKEN";
+
+/// The on disk format of a key set, encoded as JSON
+pub mod file {
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct KeySet {
+        /// The key used to issue new tokens
+        #[serde(default)]
+        pub signing_key: Option<String>,
+        /// key name => base64 encoded key
+        pub keys: BTreeMap<String, String>,
+    }
+}
+
+/// The contents of a token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Claims {
+    /// The name of the key that signed the token
+    pub key: String,
+    pub user: String,
+    pub primary_group: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub expires: DateTime<Utc>,
+}
+
+/// A set of named signing keys
+#[derive(Clone)]
+pub struct KeySet {
+    signing_key: Option<String>,
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl KeySet {
+    pub fn parse(s: &str) -> Result<KeySet> {
+        let ks: file::KeySet = serde_json::from_str(s)?;
+        if ks.keys.is_empty() {
+            bail!("at least one key is required")
+        }
+        if let Some(k) = &ks.signing_key {
+            if !ks.keys.contains_key(k) {
+                bail!("the signing key {} must exist", k)
+            }
+        }
+        let mut keys = BTreeMap::new();
+        for (name, key) in ks.keys {
+            let key =
+                BASE64.decode(key.trim()).with_context(|| format!("key {}", name))?;
+            if key.len() < 32 {
+                bail!("key {} is too short, keys must be at least 32 bytes", name)
+            }
+            keys.insert(name, key);
+        }
+        Ok(KeySet { signing_key: ks.signing_key, keys })
+    }
+
+    pub fn load<P: AsRef<Path>>(file: P) -> Result<KeySet> {
+        KeySet::parse(&fs::read_to_string(file)?)
+    }
+
+    /// Generate a new random base64 encoded key suitable for adding
+    /// to a key set.
+    pub fn generate_key() -> String {
+        let mut key = [0u8; 64];
+        thread_rng().fill_bytes(&mut key);
+        BASE64.encode(key)
+    }
+
+    fn mac(&self, key: &str) -> Result<HmacSha3> {
+        match self.keys.get(key) {
+            None => bail!("unknown key {}", key),
+            Some(k) => Ok(HmacSha3::new_from_slice(k)?),
+        }
+    }
+
+    /// Sign `claims` with the key they name and return the encoded
+    /// token.
+    pub fn sign(&self, claims: &Claims) -> Result<String> {
+        let mut mac = self.mac(&claims.key)?;
+        let payload = BASE64.encode(serde_json::to_vec(claims)?);
+        mac.update(payload.as_bytes());
+        let sig = BASE64.encode(mac.finalize().into_bytes());
+        Ok(format!("{}.{}", payload, sig))
+    }
+
+    /// Issue a token for `user` that is valid for `valid_for`,
+    /// signed with the signing key.
+    pub fn issue(
+        &self,
+        user: &str,
+        primary_group: &str,
+        groups: &[String],
+        valid_for: Duration,
+    ) -> Result<String> {
+        let key = match &self.signing_key {
+            Some(k) => k.clone(),
+            None if self.keys.len() == 1 => self.keys.keys().next().unwrap().clone(),
+            None => bail!("signing_key must be specified"),
+        };
+        self.sign(&Claims {
+            key,
+            user: user.into(),
+            primary_group: primary_group.into(),
+            groups: groups.into(),
+            expires: Utc::now() + chrono::Duration::from_std(valid_for)?,
+        })
+    }
+
+    /// Verify the signature and expiration of `token` and return its
+    /// claims.
+    pub fn verify(&self, token: &[u8]) -> Result<Claims> {
+        let token = std::str::from_utf8(token)?.trim();
+        let (payload, sig) =
+            token.split_once('.').ok_or_else(|| anyhow!("malformed token"))?;
+        let claims: Claims = serde_json::from_slice(&BASE64.decode(payload)?)?;
+        let mut mac = self.mac(&claims.key)?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&BASE64.decode(sig)?).map_err(|_| anyhow!("invalid token"))?;
+        if claims.expires <= Utc::now() {
+            bail!("token expired")
+        }
+        Ok(claims)
+    }
+}
+
+/// Read the client's token from `path`, or from the `NETIDX_TOKEN`
+/// environment variable if `path` is `None`.
+pub(crate) fn load(path: &Option<String>) -> Result<Bytes> {
+    let token = match path {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("reading token file {}", path))?,
+        None => env::var(TOKEN_ENV)
+            .with_context(|| format!("no token file and {} is not set", TOKEN_ENV))?,
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        bail!("empty token")
+    } else if token.len() > TOKEN_MAX {
+        bail!("token is too large")
+    }
+    Ok(Bytes::copy_from_slice(token.as_bytes()))
+}
@@ -0,0 +1,155 @@
+//! The byte streams connections run over. Normally TCP, but when the
+//! `testing` transport is compiled in, connections to addresses with
+//! an in memory listener don't touch the network at all.
+use anyhow::Result;
+use futures::prelude::*;
+use std::{
+    io::{self, IoSlice},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+#[cfg(any(test, feature = "testing"))]
+use futures::channel::mpsc::UnboundedReceiver;
+#[cfg(any(test, feature = "testing"))]
+use tokio::io::DuplexStream;
+
+/// Incoming in memory connections
+#[cfg(any(test, feature = "testing"))]
+pub(crate) type MemListener = UnboundedReceiver<DuplexStream>;
+
+/// Incoming in memory connections, which can't exist without the
+/// `testing` transport.
+#[cfg(not(any(test, feature = "testing")))]
+pub(crate) enum MemListener {}
+
+#[derive(Debug)]
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(any(test, feature = "testing"))]
+    Mem(DuplexStream),
+}
+
+impl Socket {
+    pub(crate) fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.set_nodelay(nodelay),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Socket::Tcp(s) => s.peer_addr(),
+            // in memory peers are always in this process
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(_) => Ok(SocketAddr::from(([127, 0, 0, 1], 0))),
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Socket::Tcp(s) => s.is_write_vectored(),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(test, feature = "testing"))]
+            Socket::Mem(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connect to `addr`, in memory if something is listening on it in
+/// memory, otherwise over TCP with nodelay set.
+pub(crate) async fn connect(addr: SocketAddr) -> Result<Socket> {
+    #[cfg(any(test, feature = "testing"))]
+    if let Some(s) = crate::testing::connect(addr) {
+        return Ok(Socket::Mem(s?));
+    }
+    let s = TcpStream::connect(addr).await?;
+    s.set_nodelay(true)?;
+    Ok(Socket::Tcp(s))
+}
+
+/// Accept in memory connections to `addr` as well as TCP connections
+/// if `enabled`. Does nothing unless the `testing` transport is
+/// compiled in.
+pub(crate) fn listen(addr: SocketAddr, enabled: bool) -> Option<MemListener> {
+    #[cfg(any(test, feature = "testing"))]
+    if enabled {
+        return Some(crate::testing::listen(addr));
+    }
+    let _ = (addr, enabled);
+    None
+}
+
+/// Wait for the next in memory connection. Never returns if there is
+/// no in memory listener, and returns None if it has been closed.
+pub(crate) async fn accept_mem(listener: &mut Option<MemListener>) -> Option<Socket> {
+    match listener {
+        None => future::pending().await,
+        #[cfg(any(test, feature = "testing"))]
+        Some(l) => l.next().await.map(Socket::Mem),
+        #[cfg(not(any(test, feature = "testing")))]
+        Some(l) => match *l {},
+    }
+}